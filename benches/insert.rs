@@ -0,0 +1,135 @@
+//! Insert throughput through the DB layer, against preparing each statement
+//! afresh as it did before statements were cached. Run with `cargo bench`.
+
+extern crate failure;
+extern crate rusqlite;
+extern crate time;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde_derive;
+
+#[allow(dead_code)]
+#[path = "../src/db.rs"]
+mod db;
+
+use db::{Table, Update, DB};
+use failure::Error;
+use rusqlite::types::ToSql;
+use rusqlite::{Connection, Row};
+use std::time::{Duration, Instant};
+
+const ROWS: i64 = 20_000;
+
+struct Entry {
+    entry_id: i64,
+    entry_name: String,
+}
+
+struct EntryTable;
+
+impl Table for EntryTable {
+    type TableRow = Entry;
+
+    const TABLE_NAME: &'static str = "entry";
+
+    const ID_COLUMN: &'static str = "entry_id";
+
+    const CREATE_TABLE: &'static str = "CREATE TABLE entry (
+            entry_id            INTEGER NOT NULL PRIMARY KEY,
+            entry_name          TEXT NOT NULL
+        )";
+
+    fn from_row(r: &Row) -> Result<Self::TableRow, Error> {
+        let entry_id = r.get_checked("entry_id")?;
+        let entry_name = r.get_checked("entry_name")?;
+        Ok(Entry {
+            entry_id,
+            entry_name,
+        })
+    }
+
+    fn do_insert(table: &Update<Self>, r: &Self::TableRow) -> Result<(), Error> {
+        table.insert(
+            "(entry_id, entry_name) VALUES (?1, ?2)",
+            &[&r.entry_id, &r.entry_name],
+        )
+    }
+}
+
+fn entry(i: i64) -> Entry {
+    Entry {
+        entry_id: i,
+        entry_name: format!("entry {}", i),
+    }
+}
+
+fn open() -> Result<Connection, Error> {
+    let conn = Connection::open_read_write(db::IN_MEMORY_PATH)?;
+    conn.create_table::<EntryTable>()?;
+    Ok(conn)
+}
+
+/// Each insert prepares its statement again.
+fn insert_uncached() -> Result<Duration, Error> {
+    let conn = open()?;
+    let start = Instant::now();
+    conn.execute_batch("BEGIN")?;
+    for i in 0..ROWS {
+        let r = entry(i);
+        let mut stmt = conn.prepare("INSERT INTO entry (entry_id, entry_name) VALUES (?1, ?2)")?;
+        stmt.insert(&[&r.entry_id as &ToSql, &r.entry_name])?;
+    }
+    conn.execute_batch("COMMIT")?;
+    Ok(start.elapsed())
+}
+
+/// Inserts through `DB::insert`, which reuses the cached statement.
+fn insert_cached() -> Result<Duration, Error> {
+    let conn = open()?;
+    let start = Instant::now();
+    conn.execute_batch("BEGIN")?;
+    for i in 0..ROWS {
+        conn.insert::<EntryTable>(&entry(i))?;
+    }
+    conn.execute_batch("COMMIT")?;
+    Ok(start.elapsed())
+}
+
+/// Looks each row up again by its id through `Select::one_where`.
+fn select_cached() -> Result<Duration, Error> {
+    let conn = open()?;
+    conn.execute_batch("BEGIN")?;
+    for i in 0..ROWS {
+        conn.insert::<EntryTable>(&entry(i))?;
+    }
+    conn.execute_batch("COMMIT")?;
+    let start = Instant::now();
+    for i in 0..ROWS {
+        let r = conn
+            .select::<EntryTable>()
+            .one_where("entry_id = ?1", &[&i])?;
+        assert_eq!(r.entry_id, i);
+    }
+    Ok(start.elapsed())
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+    println!(
+        "{:<16} {:>8} rows in {:>8.3}s, {:>10.0} rows/s",
+        name,
+        ROWS,
+        secs,
+        ROWS as f64 / secs
+    );
+}
+
+fn main() -> Result<(), Error> {
+    report("insert uncached", insert_uncached()?);
+    report("insert cached", insert_cached()?);
+    report("select cached", select_cached()?);
+    Ok(())
+}
+
+// vi: ts=8 sts=4 et
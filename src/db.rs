@@ -1,8 +1,42 @@
-use failure::{err_msg, Error};
-use rusqlite::types::ToSql;
-use rusqlite::{Connection, OpenFlags, Row};
+use failure::{err_msg, format_err, Error};
+use rusqlite::types::{ToSql, Value};
+use rusqlite::{ffi, Connection, ErrorCode, OpenFlags, Row};
 use std::marker::PhantomData;
 use std::path::Path;
+use time::Timespec;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Order {
+    Oldest,
+    Newest,
+}
+
+impl Default for Order {
+    fn default() -> Self {
+        Order::Oldest
+    }
+}
+
+/// What an update of one row by its id did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateOutcome {
+    Updated,
+    /// the row is there but the rest of the WHERE clause didn't match it,
+    /// usually because it is already as the update would leave it
+    NoChange,
+    NotFound,
+}
+
+impl UpdateOutcome {
+    /// For callers that checked the row beforehand, so that anything but
+    /// an update is an error.
+    pub fn updated(self) -> Result<(), Error> {
+        match self {
+            UpdateOutcome::Updated => Ok(()),
+            _ => Err(err_msg("no rows updated")),
+        }
+    }
+}
 
 pub struct Select<'a, T>
 where
@@ -26,17 +60,48 @@ where
 {
     pub fn one(&self) -> Result<T::TableRow, Error> {
         let query_str = format!("SELECT * FROM {}", T::TABLE_NAME);
-        self.conn.query_row(&query_str, &[], T::from_row)?
+        debug!("{}", query_str);
+        let mut stmt = self.conn.prepare_cached(&query_str)?;
+        stmt.query_row(&[], T::from_row)?
     }
 
     pub fn one_where(&self, query: &str, params: &[&ToSql]) -> Result<T::TableRow, Error> {
         let query_str = format!("SELECT * FROM {} WHERE {}", T::TABLE_NAME, query);
-        self.conn.query_row(&query_str, params, T::from_row)?
+        debug!("{}", query_str);
+        let mut stmt = self.conn.prepare_cached(&query_str)?;
+        stmt.query_row(params, T::from_row)?
+    }
+
+    /// Whether the table has been created.
+    pub fn table_exists(&self) -> Result<bool, Error> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            &[&T::TABLE_NAME],
+            |r| r.get_checked(0),
+        )??;
+        Ok(count > 0)
+    }
+
+    pub fn count(&self) -> Result<i64, Error> {
+        let query_str = format!("SELECT COUNT(*) FROM {}", T::TABLE_NAME);
+        debug!("{}", query_str);
+        let mut stmt = self.conn.prepare_cached(&query_str)?;
+        let count = stmt.query_row(&[], |r| r.get_checked(0))??;
+        Ok(count)
+    }
+
+    pub fn count_where(&self, query: &str, params: &[&ToSql]) -> Result<i64, Error> {
+        let query_str = format!("SELECT COUNT(*) FROM {} WHERE {}", T::TABLE_NAME, query);
+        debug!("{}", query_str);
+        let mut stmt = self.conn.prepare_cached(&query_str)?;
+        let count = stmt.query_row(params, |r| r.get_checked(0))??;
+        Ok(count)
     }
 
     pub fn all(&self) -> Result<Vec<T::TableRow>, Error> {
         let query_str = format!("SELECT * FROM {}", T::TABLE_NAME);
-        let mut stmt = self.conn.prepare(&query_str)?;
+        debug!("{}", query_str);
+        let mut stmt = self.conn.prepare_cached(&query_str)?;
         let rows = stmt.query_and_then(&[], T::from_row)?;
         let mut items = Vec::new();
         for result in rows {
@@ -46,9 +111,42 @@ where
         Ok(items)
     }
 
+    /// Rows created no later than the time.
+    pub fn created_by(&self, time: Timespec) -> Result<Vec<T::TableRow>, Error> {
+        self.all_where("creation_time <= ?1", &[&time])
+    }
+
+    pub fn all_paged(
+        &self,
+        limit: u32,
+        offset: u32,
+        order: Order,
+    ) -> Result<Vec<T::TableRow>, Error> {
+        let direction = match order {
+            Order::Oldest => "ASC",
+            Order::Newest => "DESC",
+        };
+        let query_str = format!(
+            "SELECT * FROM {} ORDER BY creation_time {}, rowid {} LIMIT ?1 OFFSET ?2",
+            T::TABLE_NAME,
+            direction,
+            direction
+        );
+        debug!("{}", query_str);
+        let mut stmt = self.conn.prepare_cached(&query_str)?;
+        let rows = stmt.query_and_then(&[&limit, &offset], T::from_row)?;
+        let mut items = Vec::new();
+        for result in rows {
+            let item = result?;
+            items.push(item);
+        }
+        Ok(items)
+    }
+
     pub fn all_where(&self, query: &str, params: &[&ToSql]) -> Result<Vec<T::TableRow>, Error> {
         let query_str = format!("SELECT * FROM {} WHERE {}", T::TABLE_NAME, query);
-        let mut stmt = self.conn.prepare(&query_str)?;
+        debug!("{}", query_str);
+        let mut stmt = self.conn.prepare_cached(&query_str)?;
         let rows = stmt.query_and_then(params, T::from_row)?;
         let mut items = Vec::new();
         for result in rows {
@@ -57,6 +155,43 @@ where
         }
         Ok(items)
     }
+
+    /// The id followed by the named columns of each row matching, as
+    /// stored. Only columns in `T::COLUMNS` can be named, since they go
+    /// into the query text.
+    pub fn columns_where(
+        &self,
+        columns: &[&str],
+        query: &str,
+        params: &[&ToSql],
+    ) -> Result<Vec<Vec<Value>>, Error> {
+        if let Some(column) = columns.iter().find(|c| !T::COLUMNS.contains(c)) {
+            return Err(format_err!("no column {} in {}", column, T::TABLE_NAME));
+        }
+        let mut names = vec![T::ID_COLUMN];
+        names.extend_from_slice(columns);
+        let query_str = format!(
+            "SELECT {} FROM {} WHERE {}",
+            names.join(", "),
+            T::TABLE_NAME,
+            query
+        );
+        debug!("{}", query_str);
+        let mut stmt = self.conn.prepare_cached(&query_str)?;
+        let rows = stmt.query_and_then(params, |r| -> Result<Vec<Value>, Error> {
+            let mut values = Vec::new();
+            for i in 0..names.len() {
+                values.push(r.get_checked(i as i32)?);
+            }
+            Ok(values)
+        })?;
+        let mut items = Vec::new();
+        for result in rows {
+            let item = result?;
+            items.push(item);
+        }
+        Ok(items)
+    }
 }
 
 impl<'a, T> Update<'a, T>
@@ -65,14 +200,16 @@ where
 {
     pub fn insert(&self, query: &str, params: &[&ToSql]) -> Result<(), Error> {
         let query_str = format!("INSERT INTO {} {}", T::TABLE_NAME, query);
-        let mut stmt = self.conn.prepare(&query_str)?;
+        debug!("{}", query_str);
+        let mut stmt = self.conn.prepare_cached(&query_str)?;
         stmt.insert(&params)?;
         Ok(())
     }
 
     pub fn update_one(&self, query: &str, params: &[&ToSql]) -> Result<(), Error> {
         let query_str = format!("UPDATE {} SET {}", T::TABLE_NAME, query);
-        let mut stmt = self.conn.prepare(&query_str)?;
+        debug!("{}", query_str);
+        let mut stmt = self.conn.prepare_cached(&query_str)?;
         let count = stmt.execute(params)?;
         if count == 1 {
             Ok(())
@@ -83,9 +220,43 @@ where
         }
     }
 
+    /// Like `update_one`, for updates that may fairly match nothing. The id
+    /// is ?1 and is looked up afterwards to tell why nothing was updated.
+    pub fn update_by_id(
+        &self,
+        id_column: &str,
+        query: &str,
+        params: &[&ToSql],
+    ) -> Result<UpdateOutcome, Error> {
+        let query_str = format!("UPDATE {} SET {}", T::TABLE_NAME, query);
+        debug!("{}", query_str);
+        let mut stmt = self.conn.prepare_cached(&query_str)?;
+        let count = stmt.execute(params)?;
+        if count == 1 {
+            return Ok(UpdateOutcome::Updated);
+        } else if count > 1 {
+            return Err(err_msg("multiple rows updated"));
+        }
+        let exists_str = format!(
+            "SELECT COUNT(*) FROM {} WHERE {} = ?1",
+            T::TABLE_NAME,
+            id_column
+        );
+        debug!("{}", exists_str);
+        let exists: i64 = self
+            .conn
+            .query_row(&exists_str, &params[..1], |r| r.get_checked(0))??;
+        if exists > 0 {
+            Ok(UpdateOutcome::NoChange)
+        } else {
+            Ok(UpdateOutcome::NotFound)
+        }
+    }
+
     pub fn update_many(&self, query: &str, params: &[&ToSql]) -> Result<(), Error> {
         let query_str = format!("UPDATE {} SET {}", T::TABLE_NAME, query);
-        let mut stmt = self.conn.prepare(&query_str)?;
+        debug!("{}", query_str);
+        let mut stmt = self.conn.prepare_cached(&query_str)?;
         let count = stmt.execute(params)?;
         if count > 0 {
             Ok(())
@@ -93,6 +264,74 @@ where
             Err(err_msg("no rows updated"))
         }
     }
+
+    /// Like `update_many`, but any number of rows is fine and the number
+    /// updated is returned.
+    pub fn update_count(&self, query: &str, params: &[&ToSql]) -> Result<usize, Error> {
+        let query_str = format!("UPDATE {} SET {}", T::TABLE_NAME, query);
+        debug!("{}", query_str);
+        let mut stmt = self.conn.prepare_cached(&query_str)?;
+        let count = stmt.execute(params)?;
+        Ok(count as usize)
+    }
+
+    pub fn delete_one(&self, query: &str, params: &[&ToSql]) -> Result<(), Error> {
+        let query_str = format!("DELETE FROM {} WHERE {}", T::TABLE_NAME, query);
+        debug!("{}", query_str);
+        let mut stmt = self.conn.prepare_cached(&query_str)?;
+        let count = stmt.execute(params)?;
+        if count == 1 {
+            Ok(())
+        } else if count > 1 {
+            Err(err_msg("multiple rows deleted"))
+        } else {
+            Err(err_msg("no rows deleted"))
+        }
+    }
+}
+
+/// Whether an error came from the database being locked by another
+/// connection, in which case the transaction can be tried again.
+pub fn is_busy(err: &Error) -> bool {
+    match err.downcast_ref::<rusqlite::Error>() {
+        Some(rusqlite::Error::SqliteFailure(e, _)) => {
+            e.code == ErrorCode::DatabaseBusy || e.code == ErrorCode::DatabaseLocked
+        }
+        _ => false,
+    }
+}
+
+/// The columns of the unique constraint an error came from violating, as
+/// SQLite names them, or None if it was some other error.
+pub fn unique_violation(err: &Error) -> Option<String> {
+    match err.downcast_ref::<rusqlite::Error>() {
+        Some(rusqlite::Error::SqliteFailure(e, msg))
+            if e.extended_code == ffi::SQLITE_CONSTRAINT_UNIQUE
+                || e.extended_code == ffi::SQLITE_CONSTRAINT_PRIMARYKEY =>
+        {
+            let msg = msg.as_ref().map(String::as_str).unwrap_or("");
+            Some(String::from(
+                msg.trim_start_matches("UNIQUE constraint failed: "),
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Rows whose foreign keys refer to nothing, as (table, rowid, parent
+/// table). SQLite finds these whether or not foreign keys are enforced.
+pub fn foreign_key_violations(conn: &Connection) -> Result<Vec<(String, i64, String)>, Error> {
+    let query_str = "PRAGMA foreign_key_check";
+    debug!("{}", query_str);
+    let mut stmt = conn.prepare(query_str)?;
+    let rows = stmt.query_and_then(&[], |r| -> Result<_, Error> {
+        Ok((r.get_checked(0)?, r.get_checked(1)?, r.get_checked(2)?))
+    })?;
+    let mut violations = Vec::new();
+    for result in rows {
+        violations.push(result?);
+    }
+    Ok(violations)
 }
 
 pub trait Table
@@ -102,6 +341,12 @@ where
     type TableRow: Sized;
     const TABLE_NAME: &'static str;
     const CREATE_TABLE: &'static str;
+    /// run after CREATE_TABLE, for columns the selectors filter on
+    const CREATE_INDEXES: &'static [&'static str] = &[];
+    /// the column rows are identified by
+    const ID_COLUMN: &'static str = "rowid";
+    /// the columns that can be selected by name with `columns_where`
+    const COLUMNS: &'static [&'static str] = &[];
     fn from_row(r: &Row) -> Result<Self::TableRow, Error>;
     fn do_insert(table: &Update<Self>, r: &Self::TableRow) -> Result<(), Error>;
 }
@@ -110,6 +355,7 @@ pub trait DB
 where
     Self: Sized,
 {
+    /// `IN_MEMORY_PATH` opens a fresh database that isn't saved anywhere.
     fn open_read_write<P: AsRef<Path>>(path: P) -> Result<Self, Error>;
     fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self, Error>;
     fn create_table<T: Table>(&self) -> Result<(), Error>;
@@ -118,20 +364,34 @@ where
     fn update<'a, T: Table>(&'a self) -> Update<'a, T>;
 }
 
+/// The path SQLite opens as a new in-memory database rather than a file.
+pub const IN_MEMORY_PATH: &str = ":memory:";
+
+/// Prepared statements kept per connection. Queries are built from fixed
+/// strings, so there are only as many distinct ones as there are selectors.
+const STATEMENT_CACHE_CAPACITY: usize = 64;
+
 impl DB for Connection {
     fn open_read_write<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
         let conn = Connection::open(path)?;
         conn.execute("PRAGMA foreign_keys = ON", &[])?;
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
         Ok(conn)
     }
 
     fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
         let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
         Ok(conn)
     }
 
     fn create_table<T: Table>(&self) -> Result<(), Error> {
+        debug!("{}", T::CREATE_TABLE);
         self.execute(T::CREATE_TABLE, &[])?;
+        for create_index in T::CREATE_INDEXES {
+            debug!("{}", create_index);
+            self.execute(create_index, &[])?;
+        }
         Ok(())
     }
 
@@ -3,6 +3,10 @@ extern crate getopts;
 extern crate rusqlite;
 extern crate time;
 
+extern crate env_logger;
+#[macro_use]
+extern crate log;
+
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -13,6 +17,11 @@ extern crate uuid;
 extern crate actix;
 extern crate actix_web;
 extern crate futures;
+extern crate hmac;
+extern crate rmp_serde;
+extern crate sha2;
+extern crate tokio_timer;
+extern crate tokio_uds;
 
 pub mod db;
 pub mod market;
@@ -20,43 +29,125 @@ pub mod server;
 
 use failure::{err_msg, format_err, Error};
 use getopts::Options;
-use std::collections::HashMap;
+use rusqlite::Connection;
 use std::env;
+use std::fs::File;
+use std::process;
+use std::time::Duration;
 
 use db::DB;
 use market::msgs::{Item, ItemUpdate, Query, Request, Response};
 use market::types::{
-    ArgList, Cond, Depend, Dollars, Entity, Identity, Offer, OfferDetails, Pred, Rel, Timesecs,
-    Transfer, User, ID, IOU,
+    ArgList, Cond, Depend, Dollars, Entity, Identity, Offer, OfferDetails, OfferStatus, Pred,
+    PredValueType, Quantity, Rel, Timesecs, Transfer, User, ID, IOU,
 };
-use market::Market;
-use server::run_server;
+use market::{ClearingOptions, CrossPolicy, IdFormat, Market, VersionInfo};
+use server::{run_server, BindAddress, ServerOptions};
 
 struct Config {
     help: bool,
     db_filename: String,
+    bind_address: String,
+    busy_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    rate_limit: Option<f64>,
+    rate_burst: Option<u32>,
+    max_body_size: Option<usize>,
+    max_iterations: Option<usize>,
+    check_credit: bool,
+    pretty: bool,
+    max_offer_quantity: Option<u32>,
+    cross_policy: Option<CrossPolicy>,
+    busy_retries: Option<u32>,
+    record_events: bool,
+    id_format: Option<IdFormat>,
+    no_auth: bool,
+    read_only: bool,
+    log_level: Option<String>,
     time: Timesecs,
 }
 
+/// Optional settings read from the `--config` file, overridden by flags.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    db_filename: Option<String>,
+    bind_address: Option<String>,
+    /// milliseconds to wait for a locked database
+    busy_timeout: Option<u64>,
+    /// milliseconds before the server gives up on a request
+    request_timeout: Option<u64>,
+    /// requests per second allowed from each client
+    rate_limit: Option<f64>,
+    /// requests a client can make at once before being limited
+    rate_burst: Option<u32>,
+    /// largest request body the server accepts, in bytes
+    max_body_size: Option<usize>,
+    /// rounds of matching per book before clearing gives up
+    max_iterations: Option<usize>,
+    /// leave out trades that would take a user past their credit limit
+    check_credit: Option<bool>,
+    /// most IOUs an offer can buy or sell
+    max_offer_quantity: Option<u32>,
+    /// Allow, Match or Reject offers that cross the book when posted
+    cross_policy: Option<CrossPolicy>,
+    /// times to retry a request that finds the database locked
+    busy_retries: Option<u32>,
+    /// keep every change as an event, so `market rebuild` can replay them
+    record_events: Option<bool>,
+    /// Simple, Hyphenated, Urn or TimeOrdered ids for new records
+    id_format: Option<IdFormat>,
+    /// what to log, as a level or RUST_LOG style filters
+    log_level: Option<String>,
+}
+
 #[derive(Clone)]
 enum Command {
     Usage,
     Init,
     Dummy,
     Status,
-    Server(String),
+    Check,
+    Rebuild,
+    Version,
+    Server,
+    Clear(String),
+    Cond(CondCommand),
     User(UserCommand),
+    Key(KeyCommand),
+    Credit(CreditCommand),
+}
+
+#[derive(Clone)]
+enum CondCommand {
+    Close(String),
 }
 
 #[derive(Clone)]
 enum UserCommand {
     Add(String),
+    Lock(String),
+    Unlock(String),
+    Admin(String),
+    Unadmin(String),
+}
+
+#[derive(Clone)]
+enum CreditCommand {
+    Grant(String),
+    Set(String, String),
+}
+
+#[derive(Clone)]
+enum KeyCommand {
+    Add(String),
 }
 
 enum Handler<'a> {
     None,
     Cmd(Command),
     Arg(&'a str, &'a Fn(&String) -> Command),
+    Arg2(&'a str, &'a str, &'a Fn(&String, &String) -> Command),
     Switch(Option<Command>, &'a Fn(&str) -> Handler<'a>),
 }
 
@@ -76,6 +167,12 @@ impl<'a> Handler<'a> {
                 1 => Ok(f(&args[0])),
                 _ => Err(format_err!("unexpected argument: {}", args[1])),
             },
+            Handler::Arg2(name1, name2, f) => match args.len() {
+                0 => Err(format_err!("missing argument: {}", name1)),
+                1 => Err(format_err!("missing argument: {}", name2)),
+                2 => Ok(f(&args[0], &args[1])),
+                _ => Err(format_err!("unexpected argument: {}", args[2])),
+            },
             Handler::Switch(default, f) => {
                 if args.is_empty() {
                     if let Some(command) = default {
@@ -94,6 +191,18 @@ impl<'a> Handler<'a> {
     }
 }
 
+/// Log to stderr, filtered by the level if there is one, otherwise by
+/// RUST_LOG. Statements are logged with their parameters left out, so
+/// nothing like a key secret ends up in the log.
+fn init_logging(log_level: Option<&str>) -> Result<(), Error> {
+    let mut builder = env_logger::Builder::from_default_env();
+    if let Some(log_level) = log_level {
+        builder.parse(log_level);
+    }
+    builder.try_init()?;
+    Ok(())
+}
+
 fn print_usage(program: &str, opts: &Options) {
     let brief = format!("Usage: {} [OPTIONS] COMMAND", program);
     print!("{}", opts.usage(&brief));
@@ -101,8 +210,16 @@ fn print_usage(program: &str, opts: &Options) {
     println!("    init");
     println!("    dummy");
     println!("    status");
+    println!("    check");
+    println!("    rebuild");
+    println!("    version");
     println!("    server");
-    println!("    user [add]");
+    println!("    clear CONDID");
+    println!("    cond close CONDID");
+    println!("    user [add|lock|unlock|admin|unadmin]");
+    println!("    key add USERID");
+    println!("    credit grant DOLLARS");
+    println!("    credit set USERID DOLLARS");
 }
 
 fn main() {
@@ -110,6 +227,7 @@ fn main() {
         Ok(()) => {}
         Err(err) => {
             println!("{}", err);
+            process::exit(1);
         }
     }
 }
@@ -119,35 +237,145 @@ fn main2() -> Result<(), Error> {
 
     let mut opts = Options::new();
     opts.optflag("h", "help", "print help");
+    opts.optopt("c", "config", "config filename (JSON)", "FILE");
     opts.optopt("f", "file", "database filename [market.db]", "FILE");
+    opts.optopt(
+        "b",
+        "bind",
+        "server address, or unix:PATH for a socket [127.0.0.1:8000]",
+        "ADDR",
+    );
     opts.optopt("t", "time", "time of operation [current time]", "TIME");
+    opts.optflag("", "no-auth", "accept unsigned requests, for local use");
+    opts.optflag(
+        "",
+        "read-only",
+        "serve queries from a read-only database, refusing changes",
+    );
+    opts.optopt(
+        "",
+        "log-level",
+        "what to log, eg. info or market::db=debug [RUST_LOG]",
+        "LEVEL",
+    );
+    opts.optopt(
+        "",
+        "max-iterations",
+        "clearing rounds per book [10000]",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "check-credit",
+        "clear only trades within the users' credit limits",
+    );
+    opts.optflag(
+        "",
+        "pretty",
+        "print status indented, under a header per item type",
+    );
 
     let matches = opts.parse(&args[1..])?;
 
+    let config_file = match matches.opt_str("c") {
+        None => ConfigFile::default(),
+        Some(c) => read_config_file(&c)?,
+    };
+
     let help = matches.opt_present("h");
-    let db_filename = match matches.opt_str("f") {
-        None => String::from("market.db"),
-        Some(f) => f,
+    let db_filename = matches
+        .opt_str("f")
+        .or(config_file.db_filename)
+        .unwrap_or_else(|| String::from("market.db"));
+    let bind_address = matches
+        .opt_str("b")
+        .or(config_file.bind_address)
+        .unwrap_or_else(|| String::from("127.0.0.1:8000"));
+    let busy_timeout = config_file.busy_timeout.map(Duration::from_millis);
+    let request_timeout = config_file.request_timeout.map(Duration::from_millis);
+    let max_iterations = match matches.opt_str("max-iterations") {
+        None => config_file.max_iterations,
+        Some(n) => Some(n.parse()?),
     };
     let time = match matches.opt_str("t") {
         None => Timesecs::now(),
-        Some(t) => Timesecs::parse_datetime(&t)?,
+        Some(t) => t
+            .parse::<Timesecs>()
+            .and_then(|time| time.check_operation_time(Timesecs::now()))
+            .map_err(|e| format_err!("invalid time {}: {}", t, e))?,
     };
     let config = Config {
         help,
         db_filename,
+        bind_address,
+        busy_timeout,
+        request_timeout,
+        rate_limit: config_file.rate_limit,
+        rate_burst: config_file.rate_burst,
+        max_body_size: config_file.max_body_size,
+        max_iterations,
+        check_credit: matches.opt_present("check-credit")
+            || config_file.check_credit.unwrap_or(false),
+        pretty: matches.opt_present("pretty"),
+        max_offer_quantity: config_file.max_offer_quantity,
+        cross_policy: config_file.cross_policy,
+        busy_retries: config_file.busy_retries,
+        record_events: config_file.record_events.unwrap_or(false),
+        id_format: config_file.id_format,
+        no_auth: matches.opt_present("no-auth"),
+        read_only: matches.opt_present("read-only"),
+        log_level: matches.opt_str("log-level").or(config_file.log_level),
         time,
     };
 
+    init_logging(config.log_level.as_ref().map(String::as_str))?;
+
     let handler = Handler::Switch(Some(Command::Usage), &|cmd| match cmd {
         "init" => Handler::Cmd(Command::Init),
         "dummy" => Handler::Cmd(Command::Dummy),
         "status" => Handler::Cmd(Command::Status),
-        "server" => Handler::Cmd(Command::Server(String::from("127.0.0.1:8000"))),
+        "check" => Handler::Cmd(Command::Check),
+        "rebuild" => Handler::Cmd(Command::Rebuild),
+        "version" => Handler::Cmd(Command::Version),
+        "server" => Handler::Cmd(Command::Server),
+        "clear" => Handler::Arg("condid", &|cond_id| Command::Clear(cond_id.clone())),
+        "cond" => Handler::Switch(None, &|cmd| match cmd {
+            "close" => Handler::Arg("condid", &|cond_id| {
+                Command::Cond(CondCommand::Close(cond_id.clone()))
+            }),
+            _ => Handler::None,
+        }),
         "user" => Handler::Switch(None, &|cmd| match cmd {
             "add" => Handler::Arg("username", &|user_name| {
                 Command::User(UserCommand::Add(user_name.clone()))
             }),
+            "lock" => Handler::Arg("userid", &|user_id| {
+                Command::User(UserCommand::Lock(user_id.clone()))
+            }),
+            "unlock" => Handler::Arg("userid", &|user_id| {
+                Command::User(UserCommand::Unlock(user_id.clone()))
+            }),
+            "admin" => Handler::Arg("userid", &|user_id| {
+                Command::User(UserCommand::Admin(user_id.clone()))
+            }),
+            "unadmin" => Handler::Arg("userid", &|user_id| {
+                Command::User(UserCommand::Unadmin(user_id.clone()))
+            }),
+            _ => Handler::None,
+        }),
+        "key" => Handler::Switch(None, &|cmd| match cmd {
+            "add" => Handler::Arg("userid", &|user_id| {
+                Command::Key(KeyCommand::Add(user_id.clone()))
+            }),
+            _ => Handler::None,
+        }),
+        "credit" => Handler::Switch(None, &|cmd| match cmd {
+            "grant" => Handler::Arg("amount", &|amount| {
+                Command::Credit(CreditCommand::Grant(amount.clone()))
+            }),
+            "set" => Handler::Arg2("userid", "amount", &|user_id, amount| {
+                Command::Credit(CreditCommand::Set(user_id.clone(), amount.clone()))
+            }),
             _ => Handler::None,
         }),
         _ => Handler::Cmd(Command::Usage),
@@ -168,52 +396,272 @@ fn main2() -> Result<(), Error> {
         Command::Init => init(&config),
         Command::Dummy => dummy(&config),
         Command::Status => status(&config),
-        Command::Server(addr) => server(&config, &addr),
+        Command::Check => check(&config),
+        Command::Rebuild => rebuild(&config),
+        Command::Version => version(&config),
+        Command::Server => server(&config),
+        Command::Clear(cond_id) => clear(&config, cond_id),
+        Command::Cond(cond_cmd) => cond_command(&config, cond_cmd),
         Command::User(user_cmd) => user_command(&config, user_cmd),
+        Command::Key(key_cmd) => key_command(&config, key_cmd),
+        Command::Credit(credit_cmd) => credit_command(&config, credit_cmd),
+    }
+}
+
+fn read_config_file(filename: &str) -> Result<ConfigFile, Error> {
+    let file = File::open(filename).map_err(|e| format_err!("{}: {}", filename, e))?;
+    let config_file =
+        serde_json::from_reader(file).map_err(|e| format_err!("{}: {}", filename, e))?;
+    Ok(config_file)
+}
+
+impl Config {
+    fn open_read_write(&self) -> Result<Connection, Error> {
+        let db = DB::open_read_write(&self.db_filename)?;
+        self.set_busy_timeout(&db)?;
+        Ok(db)
+    }
+
+    /// The market, for commands that change it.
+    fn open_market(&self) -> Result<Market, Error> {
+        let mut market = Market::open_existing(self.open_read_write()?)?;
+        market.set_record_events(self.record_events);
+        if let Some(id_format) = self.id_format {
+            market.set_id_format(id_format);
+        }
+        Ok(market)
+    }
+
+    /// Settings that change how requests are handled, which a replay of the
+    /// events needs as much as the server.
+    fn set_market_options(&self, market: &mut Market) {
+        market.set_max_offer_quantity(self.max_offer_quantity);
+        if let Some(cross_policy) = self.cross_policy {
+            market.set_cross_policy(cross_policy);
+        }
+    }
+
+    fn open_read_only(&self) -> Result<Connection, Error> {
+        let db = DB::open_read_only(&self.db_filename)?;
+        self.set_busy_timeout(&db)?;
+        Ok(db)
+    }
+
+    fn set_busy_timeout(&self, db: &Connection) -> Result<(), Error> {
+        if let Some(busy_timeout) = self.busy_timeout {
+            db.busy_timeout(busy_timeout)?;
+        }
+        Ok(())
+    }
+}
+
+fn cond_command(config: &Config, cond_cmd: CondCommand) -> Result<(), Error> {
+    let mut market = config.open_market()?;
+    match cond_cmd {
+        CondCommand::Close(cond_id) => {
+            let request = Request::Update {
+                id: ID(cond_id.clone()),
+                item_update: ItemUpdate::Close,
+            };
+            match market.do_request_at(None, request, config.time)? {
+                Response::Count(count) => {
+                    println!(
+                        "closed cond with id {}, cancelling {} offers",
+                        cond_id, count
+                    );
+                    Ok(())
+                }
+                Response::Error(err) => Err(format_err!("{}", err)),
+                _ => Err(err_msg("unexpected response")),
+            }
+        }
     }
 }
 
 fn user_command(config: &Config, user_cmd: UserCommand) -> Result<(), Error> {
-    let db = DB::open_read_write(&config.db_filename)?;
-    let mut market = Market::open_existing(db)?;
+    let mut market = config.open_market()?;
     match user_cmd {
         UserCommand::Add(user_name) => {
             let user = User {
                 user_name: user_name.clone(),
                 user_locked: false,
+                user_credit_limit: Dollars::ZERO,
+                user_display_name: None,
             };
-            match market.do_create(Item::User(user), config.time)? {
-                Ok(user_id) => {
+            let request = Request::Create(Item::User(user));
+            match market.do_request_at(None, request, config.time)? {
+                Response::Error(err) => Err(format_err!("{}", err)),
+                response => {
+                    let user_id = response.unwrap_id();
                     println!("added user {} with id {:?}", user_name, user_id);
                     Ok(())
                 }
-                Err(err) => Err(format_err!("{:?}", err)),
             }
         }
+        UserCommand::Lock(user_id) => user_lock(&mut market, config, user_id, true),
+        UserCommand::Unlock(user_id) => user_lock(&mut market, config, user_id, false),
+        UserCommand::Admin(user_id) => {
+            market.set_user_admin(&ID(user_id.clone()), true, config.time)?;
+            println!("made user with id {} an admin", user_id);
+            Ok(())
+        }
+        UserCommand::Unadmin(user_id) => {
+            market.set_user_admin(&ID(user_id.clone()), false, config.time)?;
+            println!("user with id {} is no longer an admin", user_id);
+            Ok(())
+        }
+    }
+}
+
+fn credit_command(config: &Config, credit_cmd: CreditCommand) -> Result<(), Error> {
+    let mut market = config.open_market()?;
+    match credit_cmd {
+        CreditCommand::Grant(amount) => {
+            let request = Request::IncrementCredit {
+                amount: amount.parse::<Dollars>()?,
+            };
+            match market.do_request_at(None, request, config.time)? {
+                Response::Count(count) => {
+                    println!("granted ${} credit to {} users", amount, count);
+                    Ok(())
+                }
+                Response::Error(err) => Err(format_err!("{}", err)),
+                _ => Err(err_msg("unexpected response")),
+            }
+        }
+        CreditCommand::Set(user_id, amount) => {
+            let user_credit_limit = amount.parse::<Dollars>()?;
+            let request = Request::Update {
+                id: ID(user_id.clone()),
+                item_update: ItemUpdate::CreditLimit { user_credit_limit },
+            };
+            match market.do_request_at(None, request, config.time)? {
+                Response::Error(err) => Err(format_err!("{}", err)),
+                _ => {
+                    println!(
+                        "set credit limit of user with id {} to ${}",
+                        user_id, amount
+                    );
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+fn key_command(config: &Config, key_cmd: KeyCommand) -> Result<(), Error> {
+    let mut market = config.open_market()?;
+    match key_cmd {
+        KeyCommand::Add(user_id) => {
+            let key = market.create_api_key(&ID(user_id.clone()), config.time)?;
+            println!("added key for user with id {}", user_id);
+            println!("key id: {}", key.key_id);
+            println!("secret: {}", key.key_secret);
+            Ok(())
+        }
+    }
+}
+
+fn user_lock(
+    market: &mut Market,
+    config: &Config,
+    user_id: String,
+    user_locked: bool,
+) -> Result<(), Error> {
+    let request = Request::Update {
+        id: ID(user_id.clone()),
+        item_update: ItemUpdate::UserLock { user_locked },
+    };
+    match market.do_request_at(None, request, config.time)? {
+        Response::Error(err) => Err(format_err!("{}", err)),
+        _ => {
+            let action = if user_locked { "locked" } else { "unlocked" };
+            println!("{} user with id {}", action, user_id);
+            Ok(())
+        }
     }
 }
 
-fn server(config: &Config, addr: &str) -> Result<(), Error> {
-    let db = DB::open_read_write(&config.db_filename)?;
-    let market = Market::open_existing(db)?;
-    run_server(market, addr)
+fn clear(config: &Config, cond_id: String) -> Result<(), Error> {
+    let mut market = config.open_market()?;
+    let max_iterations = config
+        .max_iterations
+        .unwrap_or(ClearingOptions::default().max_iterations);
+    let request = Request::Clear {
+        cond_id: ID(cond_id),
+        max_iterations: Some(max_iterations),
+        check_credit: config.check_credit,
+    };
+    match market.do_request_at(None, request, config.time)? {
+        Response::Traded { trades, complete } => {
+            for trade in &trades {
+                println!(
+                    "{} buys {} from {} at {}",
+                    trade.buyer.0,
+                    trade.units,
+                    trade.seller.0,
+                    trade.price.to_millibucks()
+                );
+            }
+            if !complete {
+                println!(
+                    "stopped after {} iterations, offers may still cross",
+                    max_iterations
+                );
+            }
+            Ok(())
+        }
+        Response::Error(err) => Err(format_err!("{}", err)),
+        _ => Err(err_msg("unexpected response")),
+    }
+}
+
+fn server(config: &Config) -> Result<(), Error> {
+    let mut market = if config.read_only {
+        Market::open_read_only(config.open_read_only()?)?
+    } else {
+        config.open_market()?
+    };
+    config.set_market_options(&mut market);
+    if let Some(busy_retries) = config.busy_retries {
+        market.set_busy_retries(busy_retries);
+    }
+    let mut options = ServerOptions::default();
+    if let Some(request_timeout) = config.request_timeout {
+        options.request_timeout = request_timeout;
+    }
+    if let Some(rate_limit) = config.rate_limit {
+        options.rate_limit = rate_limit;
+    }
+    if let Some(rate_burst) = config.rate_burst {
+        options.rate_burst = rate_burst;
+    }
+    if let Some(max_body_size) = config.max_body_size {
+        options.max_body_size = max_body_size;
+    }
+    if !config.no_auth {
+        options.api_keys = Some(config.db_filename.clone());
+    }
+    run_server(market, &BindAddress::parse(&config.bind_address), options)
 }
 
 fn init(config: &Config) -> Result<(), Error> {
-    let db = DB::open_read_write(&config.db_filename)?;
+    let db = config.open_read_write()?;
     Market::create_new(db)?;
     println!("initialised {}", config.db_filename);
     Ok(())
 }
 
 fn dummy(config: &Config) -> Result<(), Error> {
-    let db = DB::open_read_write(&config.db_filename)?;
-    let mut market = Market::open_existing(db)?;
+    let mut market = config.open_market()?;
+    market.set_request_time(Some(config.time));
 
     let mrfoo = market
         .do_request(Request::Create(Item::User(User {
             user_name: String::from("MrFoo"),
             user_locked: false,
+            user_credit_limit: Dollars::ZERO,
+            user_display_name: None,
         })))?
         .unwrap_id();
 
@@ -221,6 +669,8 @@ fn dummy(config: &Config) -> Result<(), Error> {
         .do_request(Request::Create(Item::User(User {
             user_name: String::from("MrBar"),
             user_locked: false,
+            user_credit_limit: Dollars::ZERO,
+            user_display_name: None,
         })))?
         .unwrap_id();
 
@@ -229,6 +679,7 @@ fn dummy(config: &Config) -> Result<(), Error> {
         identity_service: String::from("tumblr"),
         identity_account_name: String::from("mr--foo"),
         identity_attested_time: Timesecs::from(0),
+        identity_verified: false,
     })))?;
 
     let trump = market
@@ -276,6 +727,7 @@ fn dummy(config: &Config) -> Result<(), Error> {
             pred_name: String::from("Party nominee for 2020 election"),
             pred_args: ArgList::from("party,person"),
             pred_value: None,
+            pred_value_type: PredValueType::Bool,
         })))?
         .unwrap_id();
 
@@ -284,6 +736,7 @@ fn dummy(config: &Config) -> Result<(), Error> {
             pred_name: String::from("Candidate wins 2020 election"),
             pred_args: ArgList::from("person"),
             pred_value: None,
+            pred_value_type: PredValueType::Bool,
         })))?
         .unwrap_id();
 
@@ -292,6 +745,7 @@ fn dummy(config: &Config) -> Result<(), Error> {
             pred_name: String::from("Party wins 2020 election"),
             pred_args: ArgList::from("party"),
             pred_value: None,
+            pred_value_type: PredValueType::Bool,
         })))?
         .unwrap_id();
 
@@ -313,16 +767,30 @@ fn dummy(config: &Config) -> Result<(), Error> {
         depend_args2: ArgList::from("x.party"),
     })))?;
 
-    market.do_request(Request::Create(Item::Pred(Pred {
-        pred_name: String::from("Atmospheric CO2 levels pass 500ppm"),
-        pred_args: ArgList::from("time"),
-        pred_value: None,
+    let co2_500ppm = market
+        .do_request(Request::Create(Item::Pred(Pred {
+            pred_name: String::from("Atmospheric CO2 levels pass 500ppm"),
+            pred_args: ArgList::from("time"),
+            pred_value: None,
+            pred_value_type: PredValueType::Date,
+        })))?
+        .unwrap_id();
+
+    market.do_request(Request::Create(Item::Cond(Cond {
+        cond_pred: co2_500ppm,
+        cond_args: vec![],
+        cond_arg_time: Some(Timesecs::parse_datetime("2030-01-01 00:00:00")?),
+        cond_value: None,
+        cond_closed: false,
     })))?;
 
     let trump_elected = market
         .do_request(Request::Create(Item::Cond(Cond {
             cond_pred: candidate2020.clone(),
             cond_args: vec![trump.clone()],
+            cond_arg_time: None,
+            cond_value: None,
+            cond_closed: false,
         })))?
         .unwrap_id();
 
@@ -334,9 +802,11 @@ fn dummy(config: &Config) -> Result<(), Error> {
             offer_details: OfferDetails {
                 offer_buy_price: Dollars::from_millibucks(340),
                 offer_sell_price: Dollars::from_millibucks(450),
-                offer_buy_quantity: 100,
-                offer_sell_quantity: 200,
+                offer_buy_quantity: Quantity::from_units(100),
+                offer_sell_quantity: Quantity::from_units(200),
             },
+            offer_status: OfferStatus::Open,
+            offer_expiry: None,
         })))?
         .unwrap_id();
 
@@ -345,22 +815,24 @@ fn dummy(config: &Config) -> Result<(), Error> {
         item_update: ItemUpdate::Offer(OfferDetails {
             offer_buy_price: Dollars::from_millibucks(360),
             offer_sell_price: Dollars::from_millibucks(430),
-            offer_buy_quantity: 150,
-            offer_sell_quantity: 180,
+            offer_buy_quantity: Quantity::from_units(150),
+            offer_sell_quantity: Quantity::from_units(180),
         }),
     })?;
 
+    let iou = IOU {
+        iou_issuer: mrfoo.clone(),
+        iou_holder: mrbar.clone(),
+        iou_value: Dollars::from_millibucks(170),
+        iou_cond_id: Some(trump_elected),
+        iou_cond_flag: true,
+        iou_cond_time: None,
+        iou_split: None,
+        iou_void: false,
+        iou_memo: None,
+    };
     let iou_id = market
-        .do_request(Request::Create(Item::IOU(IOU {
-            iou_issuer: mrfoo.clone(),
-            iou_holder: mrbar.clone(),
-            iou_value: Dollars::from_millibucks(170),
-            iou_cond_id: Some(trump_elected),
-            iou_cond_flag: true,
-            iou_cond_time: None,
-            iou_split: None,
-            iou_void: false,
-        })))?
+        .do_request(Request::Create(Item::IOU(iou.clone())))?
         .unwrap_id();
     /*
         market.do_request(Request::Update {
@@ -368,10 +840,8 @@ fn dummy(config: &Config) -> Result<(), Error> {
             item_update: ItemUpdate::Void
         })?;
     */
-    let mut holders = HashMap::new();
-    holders.insert(mrfoo.clone(), Dollars::from_millibucks(120));
-    holders.insert(mrbar.clone(), Dollars::from_millibucks(50));
-    let transfer = Transfer { holders };
+    // mrbar hands $0.12 back to mrfoo, voiding that much, and keeps $0.05
+    let transfer = Transfer::partial(&iou, mrfoo.clone(), Dollars::from_millibucks(120));
 
     market.do_request(Request::Update {
         id: iou_id,
@@ -381,18 +851,81 @@ fn dummy(config: &Config) -> Result<(), Error> {
     Ok(())
 }
 
+fn version(config: &Config) -> Result<(), Error> {
+    let db = config.open_read_only()?;
+    let info = VersionInfo::new(Market::stored_version(&db)?);
+    println!("market {}", info.crate_version);
+    println!("schema version {}", info.schema_version);
+    match info.db_version {
+        Some(db_version) => println!("database version {}", db_version),
+        None => println!("database not initialized"),
+    }
+    Ok(())
+}
+
 fn status(config: &Config) -> Result<(), Error> {
-    let db = DB::open_read_only(&config.db_filename)?;
-    let mut market = Market::open_existing(db)?;
-    println!("{:?}", market.info);
-    market.do_request(Request::Query(Query::AllUser))?.print();
-    market.do_request(Request::Query(Query::AllIOU))?.print();
-    market.do_request(Request::Query(Query::AllCond))?.print();
-    market.do_request(Request::Query(Query::AllOffer))?.print();
-    market.do_request(Request::Query(Query::AllEntity))?.print();
-    market.do_request(Request::Query(Query::AllRel))?.print();
-    market.do_request(Request::Query(Query::AllPred))?.print();
-    market.do_request(Request::Query(Query::AllDepend))?.print();
+    let db = config.open_read_only()?;
+    let mut market = Market::open_read_only(db)?;
+    let queries = vec![
+        ("users", Query::AllUser),
+        ("identities", Query::AllIdentity),
+        ("IOUs", Query::AllIOU),
+        ("conds", Query::AllCond),
+        ("offers", Query::AllOffer),
+        ("entities", Query::AllEntity),
+        ("rels", Query::AllRel),
+        ("preds", Query::AllPred),
+        ("depends", Query::AllDepend),
+    ];
+    if config.pretty {
+        println!("{:#?}", market.info);
+    } else {
+        println!("{:?}", market.info);
+    }
+    for (header, query) in queries {
+        let response = market.do_request(Request::Query(query))?;
+        if config.pretty {
+            println!("\n{}:", header);
+        }
+        response.print(config.pretty);
+    }
+    Ok(())
+}
+
+/// Look for inconsistencies without changing anything, such as after a crash.
+fn check(config: &Config) -> Result<(), Error> {
+    let db = config.open_read_only()?;
+    let info = VersionInfo::new(Market::stored_version(&db)?);
+    match info.db_version {
+        None => return Err(err_msg("database not initialized; run `market init`")),
+        Some(version) if version != info.schema_version => {
+            return Err(format_err!(
+                "schema version {} is not the current {}; open it read-write to migrate",
+                version,
+                info.schema_version
+            ));
+        }
+        Some(_) => {}
+    }
+    let market = Market::open_read_only(db)?;
+    let problems = market.integrity_problems()?;
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if problems.is_empty() {
+        println!("no problems found");
+        Ok(())
+    } else {
+        Err(format_err!("{} problems found", problems.len()))
+    }
+}
+
+/// Replay the recorded events and check they give the same market.
+fn rebuild(config: &Config) -> Result<(), Error> {
+    let mut market = Market::open_read_only(config.open_read_only()?)?;
+    config.set_market_options(&mut market);
+    Market::rebuild_from_events(market)?;
+    println!("replaying the events gives the same market");
     Ok(())
 }
 
@@ -400,14 +933,17 @@ impl Response {
     fn unwrap_id(self) -> ID {
         match self {
             Response::Created(id) => id,
-            Response::Updated => panic!("expected ID!"),
-            Response::Items(_) => panic!("expected ID!"),
-            Response::Error(_) => panic!("expected ID!"),
+            _ => panic!("expected ID!"),
         }
     }
 
-    fn print(&self) {
-        println!("{}", serde_json::to_string(self).unwrap())
+    fn print(&self, pretty: bool) {
+        let json = if pretty {
+            serde_json::to_string_pretty(self)
+        } else {
+            serde_json::to_string(self)
+        };
+        println!("{}", json.unwrap())
     }
 }
 
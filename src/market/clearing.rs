@@ -0,0 +1,570 @@
+use failure::Error;
+use std::cmp;
+use std::collections::HashSet;
+
+use crate::market::tables::Record;
+use crate::market::types::{Dollars, Offer, Side, ID};
+
+/// How the price of a trade is chosen from the two crossing offers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PricePolicy {
+    /// halfway between the bid and the ask
+    Midpoint,
+    /// the price of the offer that was made first
+    Maker,
+    /// the bid and the ask averaged by their open quantities
+    Weighted,
+}
+
+impl Default for PricePolicy {
+    fn default() -> Self {
+        PricePolicy::Midpoint
+    }
+}
+
+impl PricePolicy {
+    /// The trade price for a bid and an ask, rounding down. This never falls
+    /// outside the two prices.
+    pub fn price(self, bid: &Record<Offer>, ask: &Record<Offer>) -> Dollars {
+        let bid_price = bid.fields.offer_details.offer_buy_price;
+        let ask_price = ask.fields.offer_details.offer_sell_price;
+        match self {
+            PricePolicy::Midpoint => Dollars::midpoint(bid_price, ask_price),
+            PricePolicy::Maker => {
+                if bid.creation_time < ask.creation_time {
+                    bid_price
+                } else if ask.creation_time < bid.creation_time {
+                    ask_price
+                } else {
+                    Dollars::midpoint(bid_price, ask_price)
+                }
+            }
+            PricePolicy::Weighted => {
+                let bid_quantity = i64::from(bid.fields.open_quantity(Side::Buy));
+                let ask_quantity = i64::from(ask.fields.open_quantity(Side::Sell));
+                Dollars::from_millibucks(
+                    (bid_price.to_millibucks() * bid_quantity
+                        + ask_price.to_millibucks() * ask_quantity)
+                        / (bid_quantity + ask_quantity),
+                )
+            }
+        }
+    }
+}
+
+/// What to do with a new offer that crosses the book, trading straight away
+/// against an open offer from another user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CrossPolicy {
+    /// post it and leave the book crossed until it is next cleared
+    Allow,
+    /// post it and clear its condition at once, within credit limits
+    Match,
+    /// refuse it with `CrossedBook`
+    Reject,
+}
+
+impl Default for CrossPolicy {
+    fn default() -> Self {
+        CrossPolicy::Allow
+    }
+}
+
+/// Clearing stops after this many rounds of matching by default.
+pub const DEFAULT_MAX_ITERATIONS: usize = 10000;
+
+pub struct ClearingOptions {
+    pub price_policy: PricePolicy,
+    /// rounds of matching in each book before giving up on clearing it
+    pub max_iterations: usize,
+    /// skip trades that would take either user past their credit limit,
+    /// counting the IOUs they already hold or issued
+    pub check_credit: bool,
+}
+
+impl Default for ClearingOptions {
+    fn default() -> Self {
+        ClearingOptions {
+            price_policy: PricePolicy::default(),
+            max_iterations: DEFAULT_MAX_ITERATIONS,
+            check_credit: false,
+        }
+    }
+}
+
+/// The matches made in a book, and whether it was left uncrossed or
+/// clearing stopped at the iteration cap.
+pub struct Matches {
+    pub matches: Vec<Match>,
+    pub cleared: bool,
+}
+
+/// A trade between a buying offer and a selling offer, by index into the book.
+#[derive(Debug)]
+pub struct Match {
+    pub buy: usize,
+    pub sell: usize,
+    pub units: u32,
+    pub price: Dollars,
+}
+
+/// Match the best bid against the best ask from another user until the book
+/// no longer crosses, filling the offers in place. All offers must be for the
+/// same condition and cond_time. Each match takes the smaller of the two
+/// posted quantities, so it stops when either side is exhausted.
+///
+/// Offers at the same price and creation time are tied, and share the
+/// quantity on the other side in proportion to their own quantities.
+///
+/// Every round uses up at least one side of an offer, so a book of N offers
+/// clears in at most 2N rounds, but matching stops early at the cap.
+#[cfg(test)]
+pub fn find_matches(book: &mut [Record<Offer>], options: &ClearingOptions) -> Matches {
+    find_matches_with(book, options, |_, _, _| Ok(true)).unwrap()
+}
+
+/// Match as `find_matches` does, but only make the matches that `accept`
+/// allows, given the buying and selling offers before they are filled. A
+/// refused match fills nothing, so both offers stay open to trade with
+/// others, and that pair isn't tried again. The book may still cross
+/// between refused pairs once it is cleared.
+pub fn find_matches_with<F>(
+    book: &mut [Record<Offer>],
+    options: &ClearingOptions,
+    mut accept: F,
+) -> Result<Matches, Error>
+where
+    F: FnMut(&Offer, &Offer, &Match) -> Result<bool, Error>,
+{
+    let mut matches = Vec::new();
+    let mut refused = HashSet::new();
+    let mut iterations = 0;
+    while let Some((buy, sell)) = best_cross(book, &refused) {
+        if iterations == options.max_iterations {
+            return Ok(Matches {
+                matches,
+                cleared: false,
+            });
+        }
+        iterations += 1;
+        let price = options.price_policy.price(&book[buy], &book[sell]);
+        let buyer = book[buy].fields.offer_user.clone();
+        let seller = book[sell].fields.offer_user.clone();
+        let bids = tied(book, buy, Side::Buy, &seller);
+        let asks = tied(book, sell, Side::Sell, &buyer);
+        // split one offer across the group it is tied against
+        let (one, one_side, mut group, group_side) = if bids.len() > 1 {
+            (sell, Side::Sell, bids, Side::Buy)
+        } else {
+            (buy, Side::Buy, asks, Side::Sell)
+        };
+        let pair = move |i| match group_side {
+            Side::Buy => (i, one),
+            Side::Sell => (one, i),
+        };
+        group.retain(|&i| !refused.contains(&pair(i)));
+        let quantities: Vec<u32> = group
+            .iter()
+            .map(|&i| book[i].fields.open_quantity(group_side))
+            .collect();
+        let total = cmp::min(
+            book[one].fields.open_quantity(one_side),
+            quantities.iter().sum(),
+        );
+        for (&i, units) in group.iter().zip(pro_rata(total, &quantities)) {
+            if units == 0 {
+                continue;
+            }
+            let (buy, sell) = pair(i);
+            let m = Match {
+                buy,
+                sell,
+                units,
+                price,
+            };
+            if !accept(&book[buy].fields, &book[sell].fields, &m)? {
+                refused.insert((buy, sell));
+                continue;
+            }
+            book[one].fields.fill(one_side, units);
+            book[i].fields.fill(group_side, units);
+            matches.push(m);
+        }
+    }
+    Ok(Matches {
+        matches,
+        cleared: true,
+    })
+}
+
+/// Offers tied with `index` on one side: the same price and creation time,
+/// not from the counterparty, in book order.
+fn tied(book: &[Record<Offer>], index: usize, side: Side, counterparty: &ID) -> Vec<usize> {
+    let price = |r: &Record<Offer>| match side {
+        Side::Buy => r.fields.offer_details.offer_buy_price,
+        Side::Sell => r.fields.offer_details.offer_sell_price,
+    };
+    (0..book.len())
+        .filter(|&i| {
+            book[i].fields.open_quantity(side) > 0
+                && price(&book[i]) == price(&book[index])
+                && book[i].creation_time == book[index].creation_time
+                && &book[i].fields.offer_user != counterparty
+        })
+        .collect()
+}
+
+/// Divide `total` in proportion to `quantities`, never giving more than a
+/// quantity. Units left over from rounding down go to the earliest first.
+fn pro_rata(total: u32, quantities: &[u32]) -> Vec<u32> {
+    let sum: u64 = quantities.iter().map(|&q| u64::from(q)).sum();
+    if u64::from(total) >= sum {
+        return quantities.to_vec();
+    }
+    let mut shares: Vec<u32> = quantities
+        .iter()
+        .map(|&q| (u64::from(total) * u64::from(q) / sum) as u32)
+        .collect();
+    let mut remainder = total - shares.iter().sum::<u32>();
+    for (share, &q) in shares.iter_mut().zip(quantities) {
+        if remainder == 0 {
+            break;
+        }
+        if *share < q {
+            *share += 1;
+            remainder -= 1;
+        }
+    }
+    shares
+}
+
+/// Highest bid and lowest ask that cross, with earlier offers first at the
+/// same price and book order after that, as the sorts are stable. Users
+/// can't trade with themselves, and refused pairs are passed over.
+fn best_cross(book: &[Record<Offer>], refused: &HashSet<(usize, usize)>) -> Option<(usize, usize)> {
+    let mut bids: Vec<usize> = (0..book.len())
+        .filter(|&i| book[i].fields.open_quantity(Side::Buy) > 0)
+        .collect();
+    bids.sort_by(|&a, &b| {
+        let (a, b) = (&book[a], &book[b]);
+        let (a_price, b_price) = (
+            a.fields.offer_details.offer_buy_price,
+            b.fields.offer_details.offer_buy_price,
+        );
+        b_price
+            .cmp(&a_price)
+            .then(a.creation_time.cmp(&b.creation_time))
+    });
+    let mut asks: Vec<usize> = (0..book.len())
+        .filter(|&i| book[i].fields.open_quantity(Side::Sell) > 0)
+        .collect();
+    asks.sort_by(|&a, &b| {
+        let (a, b) = (&book[a], &book[b]);
+        let (a_price, b_price) = (
+            a.fields.offer_details.offer_sell_price,
+            b.fields.offer_details.offer_sell_price,
+        );
+        a_price
+            .cmp(&b_price)
+            .then(a.creation_time.cmp(&b.creation_time))
+    });
+    for &buy in &bids {
+        let bid = &book[buy].fields;
+        for &sell in &asks {
+            let ask = &book[sell].fields;
+            if ask.offer_details.offer_sell_price > bid.offer_details.offer_buy_price {
+                break;
+            }
+            if ask.offer_user != bid.offer_user && !refused.contains(&(buy, sell)) {
+                return Some((buy, sell));
+            }
+        }
+    }
+    None
+}
+
+/// Whether the offer would trade against one in the book from another user.
+/// The book must be open offers on the same condition and cond_time, as for
+/// `find_matches`.
+pub fn crosses(offer: &Offer, book: &[Record<Offer>]) -> bool {
+    let details = &offer.offer_details;
+    book.iter().map(|r| &r.fields).any(|other| {
+        let buys = offer.open_quantity(Side::Buy) > 0
+            && other.open_quantity(Side::Sell) > 0
+            && other.offer_details.offer_sell_price <= details.offer_buy_price;
+        let sells = offer.open_quantity(Side::Sell) > 0
+            && other.open_quantity(Side::Buy) > 0
+            && other.offer_details.offer_buy_price >= details.offer_sell_price;
+        other.offer_user != offer.offer_user && (buys || sells)
+    })
+}
+
+#[cfg(test)]
+fn test_offer(user: &str, buy: (i64, u32), sell: (i64, u32), time: i64) -> Record<Offer> {
+    use crate::market::types::{OfferDetails, OfferStatus, Quantity, Timesecs, ID};
+    let offer = Offer {
+        offer_user: ID(String::from(user)),
+        offer_cond_id: ID(String::from("cond")),
+        offer_cond_time: None,
+        offer_details: OfferDetails {
+            offer_buy_price: Dollars::from_millibucks(buy.0),
+            offer_sell_price: Dollars::from_millibucks(sell.0),
+            offer_buy_quantity: Quantity::from_units(buy.1),
+            offer_sell_quantity: Quantity::from_units(sell.1),
+        },
+        offer_status: OfferStatus::Open,
+        offer_expiry: None,
+    };
+    Record::new(ID(format!("{}{}", user, time)), offer, Timesecs::from(time))
+}
+
+#[test]
+fn partial_fill() {
+    use crate::market::types::OfferStatus;
+    let mut book = vec![
+        test_offer("alice", (500, 100), (600, 0), 1),
+        test_offer("bob", (300, 0), (400, 40), 2),
+    ];
+    let matches = find_matches(&mut book, &ClearingOptions::default()).matches;
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].buy, 0);
+    assert_eq!(matches[0].sell, 1);
+    assert_eq!(matches[0].units, 40);
+    assert_eq!(matches[0].price, Dollars::from_millibucks(450));
+    assert_eq!(
+        book[0].fields.offer_details.offer_buy_quantity.to_units(),
+        60
+    );
+    assert_eq!(book[0].fields.offer_status, OfferStatus::Open);
+    assert_eq!(
+        book[1].fields.offer_details.offer_sell_quantity.to_units(),
+        0
+    );
+    assert_eq!(book[1].fields.offer_status, OfferStatus::Filled);
+}
+
+#[test]
+fn uncrossed_book() {
+    let mut book = vec![
+        test_offer("alice", (500, 100), (600, 100), 1),
+        test_offer("bob", (400, 100), (550, 100), 2),
+    ];
+    assert!(find_matches(&mut book, &ClearingOptions::default())
+        .matches
+        .is_empty());
+}
+
+#[test]
+fn no_self_trade() {
+    let mut book = vec![
+        test_offer("alice", (500, 100), (600, 100), 1),
+        test_offer("alice", (300, 100), (400, 100), 2),
+    ];
+    assert!(find_matches(&mut book, &ClearingOptions::default())
+        .matches
+        .is_empty());
+}
+
+#[test]
+fn crossing_offers() {
+    let book = vec![test_offer("alice", (400, 100), (600, 100), 1)];
+    let offer = |user, buy, sell| test_offer(user, buy, sell, 2).fields;
+    assert!(!crosses(&offer("bob", (500, 100), (700, 100)), &book));
+    assert!(crosses(&offer("bob", (600, 100), (700, 100)), &book));
+    assert!(crosses(&offer("bob", (300, 100), (400, 100)), &book));
+    // only the sides with quantity left count
+    assert!(!crosses(&offer("bob", (600, 0), (400, 0)), &book));
+    assert!(!crosses(&offer("alice", (600, 100), (400, 100)), &book));
+}
+
+#[test]
+fn pro_rata_rounding() {
+    assert_eq!(pro_rata(10, &[1, 1, 1]), vec![1, 1, 1]);
+    assert_eq!(pro_rata(2, &[1, 1, 1]), vec![1, 1, 0]);
+    assert_eq!(pro_rata(7, &[5, 5]), vec![4, 3]);
+    assert_eq!(pro_rata(20, &[30, 10]), vec![15, 5]);
+    assert_eq!(pro_rata(1, &[1, 100]), vec![1, 0]);
+}
+
+#[test]
+fn tied_bids_share() {
+    let mut book = vec![
+        test_offer("alice", (500, 30), (900, 0), 1),
+        test_offer("bob", (500, 10), (900, 0), 1),
+        test_offer("dave", (100, 0), (400, 20), 2),
+    ];
+    let matches = find_matches(&mut book, &ClearingOptions::default()).matches;
+    let fills: Vec<(usize, usize, u32)> =
+        matches.iter().map(|m| (m.buy, m.sell, m.units)).collect();
+    assert_eq!(fills, vec![(0, 2, 15), (1, 2, 5)]);
+}
+
+#[test]
+fn tied_asks_share() {
+    let mut book = vec![
+        test_offer("alice", (500, 9), (900, 0), 1),
+        test_offer("bob", (100, 0), (400, 10), 2),
+        test_offer("carol", (100, 0), (400, 20), 2),
+    ];
+    let matches = find_matches(&mut book, &ClearingOptions::default()).matches;
+    let fills: Vec<(usize, usize, u32)> =
+        matches.iter().map(|m| (m.buy, m.sell, m.units)).collect();
+    assert_eq!(fills, vec![(0, 1, 3), (0, 2, 6)]);
+}
+
+#[test]
+fn cross_order() {
+    let mut book = vec![
+        test_offer("alice", (500, 1), (900, 0), 1),
+        test_offer("bob", (100, 0), (450, 1), 1),
+        test_offer("carol", (100, 0), (400, 1), 2),
+        test_offer("dave", (100, 0), (400, 1), 3),
+        test_offer("erin", (100, 0), (400, 1), 3),
+    ];
+    // lowest ask first, then earliest, then book order
+    assert_eq!(best_cross(&book, &HashSet::new()), Some((0, 2)));
+    book[2].fields.fill(Side::Sell, 1);
+    assert_eq!(best_cross(&book, &HashSet::new()), Some((0, 3)));
+    book[3].fields.fill(Side::Sell, 1);
+    assert_eq!(best_cross(&book, &HashSet::new()), Some((0, 4)));
+    book[4].fields.fill(Side::Sell, 1);
+    assert_eq!(best_cross(&book, &HashSet::new()), Some((0, 1)));
+}
+
+#[test]
+fn best_price_then_earliest() {
+    let mut book = vec![
+        test_offer("alice", (500, 10), (900, 0), 1),
+        test_offer("bob", (500, 10), (900, 0), 2),
+        test_offer("carol", (600, 10), (900, 0), 3),
+        test_offer("dave", (100, 0), (400, 25), 4),
+    ];
+    let matches = find_matches(&mut book, &ClearingOptions::default()).matches;
+    let order: Vec<(usize, u32)> = matches.iter().map(|m| (m.buy, m.units)).collect();
+    assert_eq!(order, vec![(2, 10), (0, 10), (1, 5)]);
+    assert_eq!(
+        book[1].fields.offer_details.offer_buy_quantity.to_units(),
+        5
+    );
+    assert_eq!(
+        book[3].fields.offer_details.offer_sell_quantity.to_units(),
+        0
+    );
+}
+
+#[test]
+fn price_policies() {
+    let bid = test_offer("alice", (500, 1), (900, 0), 1);
+    let ask = test_offer("bob", (100, 0), (401, 2), 2);
+    // (500 + 401) / 2 and (500 + 802) / 3 both truncate
+    assert_eq!(
+        PricePolicy::Midpoint.price(&bid, &ask),
+        Dollars::from_millibucks(450)
+    );
+    assert_eq!(
+        PricePolicy::Maker.price(&bid, &ask),
+        Dollars::from_millibucks(500)
+    );
+    assert_eq!(
+        PricePolicy::Maker.price(&test_offer("carol", (500, 1), (900, 0), 3), &ask),
+        Dollars::from_millibucks(401)
+    );
+    assert_eq!(
+        PricePolicy::Weighted.price(&bid, &ask),
+        Dollars::from_millibucks(434)
+    );
+}
+
+#[test]
+fn price_at_boundaries() {
+    // one millibuck apart rounds down to the ask, never below it
+    let bid = test_offer("alice", (401, 1), (900, 0), 1);
+    let ask = test_offer("bob", (100, 0), (400, 1), 2);
+    for &policy in &[PricePolicy::Midpoint, PricePolicy::Weighted] {
+        assert_eq!(policy.price(&bid, &ask), Dollars::from_millibucks(400));
+    }
+    let bid = test_offer("alice", (0, 1), (900, 0), 1);
+    let ask = test_offer("bob", (100, 0), (0, 1), 2);
+    assert_eq!(PricePolicy::Midpoint.price(&bid, &ask), Dollars::ZERO);
+    let bid = test_offer("alice", (1000, 1), (900, 0), 1);
+    let ask = test_offer("bob", (100, 0), (1000, 1), 2);
+    assert_eq!(PricePolicy::Weighted.price(&bid, &ask), Dollars::ONE);
+}
+
+#[test]
+fn maker_price_trade() {
+    let mut book = vec![
+        test_offer("alice", (100, 0), (400, 10), 1),
+        test_offer("bob", (500, 10), (900, 0), 2),
+    ];
+    let options = ClearingOptions {
+        price_policy: PricePolicy::Maker,
+        ..ClearingOptions::default()
+    };
+    let matches = find_matches(&mut book, &options).matches;
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].price, Dollars::from_millibucks(400));
+}
+
+#[test]
+fn iteration_cap() {
+    let mut book = vec![
+        test_offer("alice", (500, 10), (900, 0), 1),
+        test_offer("bob", (100, 0), (400, 5), 2),
+        test_offer("carol", (100, 0), (450, 5), 3),
+    ];
+    let options = ClearingOptions {
+        max_iterations: 1,
+        ..ClearingOptions::default()
+    };
+    let result = find_matches(&mut book, &options);
+    assert_eq!(result.matches.len(), 1);
+    assert!(!result.cleared);
+    // picking up where it stopped finishes the book
+    let result = find_matches(&mut book, &options);
+    assert_eq!(result.matches.len(), 1);
+    assert!(result.cleared);
+    assert!(find_matches(&mut book, &options).cleared);
+}
+
+#[test]
+fn crossed_book_clears() {
+    let mut book = Vec::new();
+    for i in 0..50 {
+        let user = format!("user{}", i);
+        book.push(test_offer(&user, (500 + i, 3), (900, 0), i));
+        book.push(test_offer(&user, (100, 0), (400 - i, 2), i));
+    }
+    let options = ClearingOptions {
+        max_iterations: 2 * book.len(),
+        ..ClearingOptions::default()
+    };
+    let result = find_matches(&mut book, &options);
+    assert!(result.cleared);
+    assert_eq!(best_cross(&book, &HashSet::new()), None);
+    let units: u32 = result.matches.iter().map(|m| m.units).sum();
+    assert_eq!(units, 100);
+}
+
+#[test]
+fn refused_match_fills_nothing() {
+    let mut book = vec![
+        test_offer("alice", (500, 10), (900, 0), 1),
+        test_offer("bob", (100, 0), (400, 10), 2),
+        test_offer("carol", (100, 0), (450, 10), 3),
+    ];
+    let bob = ID(String::from("bob"));
+    let result = find_matches_with(&mut book, &ClearingOptions::default(), |_, sell, _| {
+        Ok(sell.offer_user != bob)
+    })
+    .unwrap();
+    // bob's better price is passed over and alice fills against carol
+    assert!(result.cleared);
+    assert_eq!(result.matches.len(), 1);
+    assert_eq!((result.matches[0].buy, result.matches[0].sell), (0, 2));
+    assert_eq!(result.matches[0].units, 10);
+    assert_eq!(book[1].fields.open_quantity(Side::Sell), 10);
+    assert_eq!(book[0].fields.open_quantity(Side::Buy), 0);
+}
+
+// vi: ts=8 sts=4 et
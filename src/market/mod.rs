@@ -1,26 +1,194 @@
-use failure::{err_msg, Error};
+use failure::{err_msg, format_err, Error};
+use rusqlite::types::Value;
 use rusqlite::Connection;
-use std::collections::HashMap;
-use time::get_time;
+use serde_json;
+use std::any::Any;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::Duration;
+use time::{get_time, Timespec};
 use uuid::Uuid;
 
+mod clearing;
 pub mod msgs;
 mod tables;
 pub mod types;
 
-use crate::db::DB;
-use crate::market::msgs::{single_item, Item, ItemUpdate, Query, Request, Response, ToItem};
+pub use self::clearing::{ClearingOptions, CrossPolicy, PricePolicy};
+pub use self::tables::{ApiKeyRow, ApiKeyTable};
+
+use crate::db::{
+    foreign_key_violations, is_busy, unique_violation, Order, Table, UpdateOutcome, DB,
+    IN_MEMORY_PATH,
+};
+use crate::market::msgs::{
+    single_item, EntityRels, Item, ItemRecord, ItemType, ItemUpdate, LogEntry, Query, Request,
+    Response, ToItem,
+};
 use crate::market::tables::{
-    CondTable, DependTable, EntityTable, IOUTable, IdentityTable, MarketRow, MarketTable,
-    OfferTable, PredTable, PropRow, PropTable, Record, RelTable, UserTable,
+    CondTable, DependTable, EntityTable, EventRow, EventTable, IOUTable, IdentityTable, LogRow,
+    LogTable, MarketRow, MarketTable, OfferTable, PredTable, PropRow, PropTable, Record, RelTable,
+    RelTypeRow, RelTypeTable, UserTable, MARKET_VERSION, MIGRATIONS,
+};
+use crate::market::types::{
+    BookDepth, Clearing, Cond, Depend, Dollars, Entity, Offer, OfferDetails, OfferStatus, Pred,
+    Rel, Side, Timesecs, Trade, Transfer, User, UserBalance, ID, IOU,
 };
-use crate::market::types::{Cond, Depend, Entity, Pred, Rel, Timesecs, Transfer, User, ID, IOU};
+
+pub const DEFAULT_PAGE_LIMIT: u32 = 100;
+pub const MAX_PAGE_LIMIT: u32 = 1000;
+
+/// Times to retry a request that finds the database busy, by default.
+pub const DEFAULT_BUSY_RETRIES: u32 = 3;
+
+/// How long to wait before the first retry of a request that found the
+/// database busy, doubling for each retry after.
+const BUSY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// The pred arg type taking a time, given by `cond_arg_time` rather than an
+/// entity in `cond_args`.
+const TIME_ARG_TYPE: &str = "time";
+
+/// The versions of this build and of a database, for diagnosing mismatches.
+#[derive(Debug, Clone, Serialize)]
+pub struct VersionInfo {
+    pub crate_version: &'static str,
+    /// the schema version this build creates and migrates to
+    pub schema_version: u32,
+    /// the version stored in the database, or None if it isn't initialized
+    pub db_version: Option<u32>,
+}
+
+impl VersionInfo {
+    pub fn new(db_version: Option<u32>) -> VersionInfo {
+        VersionInfo {
+            crate_version: env!("CARGO_PKG_VERSION"),
+            schema_version: MARKET_VERSION,
+            db_version,
+        }
+    }
+}
 
 pub struct Market {
     db: Connection,
+    id_gen: RecordingGen,
+    max_offer_quantity: Option<u32>,
+    cross_policy: CrossPolicy,
+    busy_retries: u32,
+    request_time: Option<Timesecs>,
+    record_events: bool,
+    maintenance: bool,
+    /// the database was opened read-only, so requests that write are
+    /// refused and the rest leave no log
+    read_only: bool,
     pub info: MarketRow,
 }
 
+/// Source of ids for newly created records.
+pub trait IdGen: Send {
+    fn next_id(&mut self) -> ID;
+}
+
+/// How new ids are written. Ids are stored as they are, so changing the
+/// format leaves existing ids as they were.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IdFormat {
+    /// 32 hex digits
+    Simple,
+    /// dashed 8-4-4-4-12
+    Hyphenated,
+    /// hyphenated after "urn:uuid:"
+    Urn,
+    /// hyphenated, starting with the time like a UUIDv7 so that ids sort
+    /// in the order they were made
+    TimeOrdered,
+}
+
+impl Default for IdFormat {
+    fn default() -> Self {
+        IdFormat::Simple
+    }
+}
+
+/// Random UUID ids, the default.
+#[derive(Default)]
+pub struct UuidGen(pub IdFormat);
+
+impl IdGen for UuidGen {
+    fn next_id(&mut self) -> ID {
+        let uuid = Uuid::new_v4();
+        ID(match self.0 {
+            IdFormat::Simple => uuid.simple().to_string(),
+            IdFormat::Hyphenated => uuid.hyphenated().to_string(),
+            IdFormat::Urn => uuid.urn().to_string(),
+            IdFormat::TimeOrdered => time_ordered(&uuid, get_time()).hyphenated().to_string(),
+        })
+    }
+}
+
+/// A UUIDv7: milliseconds since 1970 in the first six bytes, then the
+/// version and the rest of the random UUID.
+fn time_ordered(random: &Uuid, time: Timespec) -> Uuid {
+    let millis = time.sec * 1000 + i64::from(time.nsec / 1_000_000);
+    let mut bytes = *random.as_bytes();
+    for (i, byte) in bytes[..6].iter_mut().enumerate() {
+        *byte = (millis >> (8 * (5 - i))) as u8;
+    }
+    bytes[6] = (bytes[6] & 0x0f) | 0x70;
+    Uuid::from_bytes(&bytes).expect("a UUID is 16 bytes")
+}
+
+/// Sequential ids, for reproducible tests.
+#[derive(Default)]
+pub struct SeqGen {
+    next: u64,
+}
+
+impl IdGen for SeqGen {
+    fn next_id(&mut self) -> ID {
+        self.next += 1;
+        ID(format!("{:032x}", self.next))
+    }
+}
+
+/// Wraps the market's IdGen to keep the ids given out during a request, so
+/// that they can be recorded with its event.
+struct RecordingGen {
+    inner: Box<dyn IdGen>,
+    issued: Vec<ID>,
+}
+
+impl RecordingGen {
+    fn new(inner: Box<dyn IdGen>) -> RecordingGen {
+        RecordingGen {
+            inner,
+            issued: Vec::new(),
+        }
+    }
+}
+
+impl IdGen for RecordingGen {
+    fn next_id(&mut self) -> ID {
+        let id = self.inner.next_id();
+        self.issued.push(id.clone());
+        id
+    }
+}
+
+/// The ids recorded with an event, given out again when it is replayed.
+struct ReplayGen(VecDeque<ID>);
+
+impl IdGen for ReplayGen {
+    fn next_id(&mut self) -> ID {
+        self.0
+            .pop_front()
+            .expect("replayed request used more ids than were recorded")
+    }
+}
+
 impl Market {
     pub fn create_new(db: Connection) -> Result<Market, Error> {
         db.create_table::<MarketTable>()?;
@@ -34,25 +202,217 @@ impl Market {
         db.create_table::<PropTable>()?;
         db.create_table::<PredTable>()?;
         db.create_table::<DependTable>()?;
+        db.create_table::<LogTable>()?;
+        db.create_table::<ApiKeyTable>()?;
+        db.create_table::<EventTable>()?;
+        db.create_table::<RelTypeTable>()?;
 
         let info = MarketRow {
-            version: 1,
+            version: MARKET_VERSION,
             creation_time: get_time(),
         };
         db.insert::<MarketTable>(&info)?;
 
-        Ok(Market { db: db, info: info })
+        Ok(Market {
+            db: db,
+            id_gen: RecordingGen::new(Box::new(UuidGen::default())),
+            max_offer_quantity: None,
+            cross_policy: CrossPolicy::default(),
+            busy_retries: DEFAULT_BUSY_RETRIES,
+            request_time: None,
+            record_events: false,
+            maintenance: false,
+            read_only: false,
+            info: info,
+        })
+    }
+
+    /// Replay the events recorded in `source` into a new in-memory market
+    /// with the same settings, and check that it ends up the same. API keys
+    /// are made outside of requests and aren't rebuilt, nor is the request log.
+    pub fn rebuild_from_events(source: Market) -> Result<Market, Error> {
+        let mut market = Market::create_new_in_memory()?;
+        market.set_max_offer_quantity(source.max_offer_quantity);
+        market.set_cross_policy(source.cross_policy);
+        market.set_record_events(true);
+        for event in source.db.select::<EventTable>().in_order()? {
+            let request: Request = serde_json::from_str(&event.event_request)?;
+            let ids: VecDeque<ID> = serde_json::from_str(&event.event_ids)?;
+            market.set_id_gen(ReplayGen(ids));
+            market.do_request_at(event.event_user, request, event.event_time)?;
+        }
+        market.set_id_gen(UuidGen::default());
+        let (a, b) = (&source.db, &market.db);
+        let same = same_rows::<UserTable>(a, b)?
+            && same_rows::<IdentityTable>(a, b)?
+            && same_rows::<IOUTable>(a, b)?
+            && same_rows::<CondTable>(a, b)?
+            && same_rows::<OfferTable>(a, b)?
+            && same_rows::<EntityTable>(a, b)?
+            && same_rows::<RelTable>(a, b)?
+            && same_rows::<PropTable>(a, b)?
+            && same_rows::<PredTable>(a, b)?
+            && same_rows::<DependTable>(a, b)?
+            && same_rows::<RelTypeTable>(a, b)?
+            && same_rows::<EventTable>(a, b)?;
+        if !same {
+            return Err(err_msg(
+                "replaying the events gives a different market; were they recorded from the start?",
+            ));
+        }
+        Ok(market)
+    }
+
+    /// A new market in a private in-memory database, opened the same way as
+    /// a file so that foreign keys are enforced. It is gone when dropped.
+    pub fn create_new_in_memory() -> Result<Market, Error> {
+        Market::create_new(DB::open_read_write(IN_MEMORY_PATH)?)
+    }
+
+    /// The schema version stored in a database, without migrating it.
+    pub fn stored_version(db: &Connection) -> Result<Option<u32>, Error> {
+        let markets = db.select::<MarketTable>();
+        if !markets.table_exists()? || markets.count()? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(markets.one()?.version))
+    }
+
+    /// A market in a database opened read-only. It has to be at the current
+    /// schema version already, as migrating it would write.
+    pub fn open_read_only(db: Connection) -> Result<Market, Error> {
+        if let Some(version) = Market::stored_version(&db)? {
+            if version != MARKET_VERSION {
+                return Err(format_err!(
+                    "schema version {} is not the current {}; open it read-write to migrate",
+                    version,
+                    MARKET_VERSION
+                ));
+            }
+        }
+        let mut market = Market::open_existing(db)?;
+        market.read_only = true;
+        Ok(market)
+    }
+
+    pub fn open_existing(mut db: Connection) -> Result<Market, Error> {
+        // anything else going wrong here is a real problem with the file
+        let markets = db.select::<MarketTable>();
+        if !markets.table_exists()? || markets.count()? == 0 {
+            return Err(err_msg("database not initialized; run `market init`"));
+        }
+        let mut info = markets.one()?;
+        if info.version > MARKET_VERSION {
+            return Err(format_err!(
+                "database version {} is newer than {}",
+                info.version,
+                MARKET_VERSION
+            ));
+        }
+        if info.version < MARKET_VERSION {
+            let tx = db.transaction()?;
+            for migration in &MIGRATIONS[(info.version - 1) as usize..] {
+                tx.execute_batch(migration)?;
+            }
+            tx.update::<MarketTable>().set_version(MARKET_VERSION)?;
+            tx.commit()?;
+            info.version = MARKET_VERSION;
+        }
+        Ok(Market {
+            db: db,
+            id_gen: RecordingGen::new(Box::new(UuidGen::default())),
+            max_offer_quantity: None,
+            cross_policy: CrossPolicy::default(),
+            busy_retries: DEFAULT_BUSY_RETRIES,
+            request_time: None,
+            record_events: false,
+            maintenance: false,
+            read_only: false,
+            info: info,
+        })
+    }
+
+    /// Checkpoint the write-ahead log, if the database has one, and close
+    /// it, so the next open has nothing to recover.
+    pub fn close(self) -> Result<(), Error> {
+        // checkpointing the log would write
+        if !self.read_only {
+            self.db
+                .query_row("PRAGMA wal_checkpoint(TRUNCATE)", &[], |_| ())?;
+        }
+        self.db.close().map_err(|(_, err)| err)?;
+        Ok(())
+    }
+
+    pub fn set_id_gen<G: IdGen + 'static>(&mut self, id_gen: G) {
+        self.id_gen.inner = Box::new(id_gen);
+    }
+
+    /// Write new random ids in this format.
+    pub fn set_id_format(&mut self, id_format: IdFormat) {
+        self.set_id_gen(UuidGen(id_format));
+    }
+
+    /// Keep every mutating request in the event table, with the ids it
+    /// generated, so that the market can be rebuilt by replaying them. This
+    /// is only complete if it was on from when the market was created.
+    pub fn set_record_events(&mut self, record_events: bool) {
+        self.record_events = record_events;
+    }
+
+    /// Retry requests that find the database locked by another connection
+    /// this many times, backing off between attempts.
+    pub fn set_busy_retries(&mut self, busy_retries: u32) {
+        self.busy_retries = busy_retries;
+    }
+
+    /// Make requests as if at this time rather than the current time.
+    pub fn set_request_time(&mut self, request_time: Option<Timesecs>) {
+        self.request_time = request_time;
+    }
+
+    /// Reject offers to buy or sell more than this many IOUs at once.
+    pub fn set_max_offer_quantity(&mut self, max_offer_quantity: Option<u32>) {
+        self.max_offer_quantity = max_offer_quantity;
     }
 
-    pub fn open_existing(db: Connection) -> Result<Market, Error> {
-        let info = db.select::<MarketTable>().one()?;
-        Ok(Market { db: db, info: info })
+    /// What to do with new offers that cross the book.
+    pub fn set_cross_policy(&mut self, cross_policy: CrossPolicy) {
+        self.cross_policy = cross_policy;
+    }
+
+    /// Whether the offer would trade straight away against the open offers
+    /// on its condition and deadline.
+    fn offer_crosses(&mut self, offer: &Offer, time: Timesecs) -> Result<bool, Error> {
+        self.expire_offers(time)?;
+        let book: Vec<Record<Offer>> = self
+            .db
+            .select::<OfferTable>()
+            .open_by_cond(&offer.offer_cond_id)?
+            .into_iter()
+            .filter(|r| r.fields.offer_cond_time == offer.offer_cond_time)
+            .collect();
+        Ok(clearing::crosses(offer, &book))
+    }
+
+    fn offer_too_large(&self, offer_details: &OfferDetails) -> bool {
+        match self.max_offer_quantity {
+            None => false,
+            Some(max) => {
+                offer_details.offer_buy_quantity.to_units() > max
+                    || offer_details.offer_sell_quantity.to_units() > max
+            }
+        }
     }
 
     pub fn select_all_user(&mut self) -> Result<Vec<Record<User>>, Error> {
         self.db.select::<UserTable>().all()
     }
 
+    pub fn select_all_identity(&mut self) -> Result<Vec<Record<types::Identity>>, Error> {
+        self.db.select::<IdentityTable>().all()
+    }
+
     pub fn select_all_iou(&mut self) -> Result<Vec<Record<IOU>>, Error> {
         self.db.select::<IOUTable>().all()
     }
@@ -88,84 +448,529 @@ impl Market {
         self.db.select::<DependTable>().all()
     }
 
+    /// Create an item, all or nothing. It gets a savepoint of its own, so
+    /// called directly it commits by itself, and called inside a request or
+    /// any other transaction it commits or rolls back along with that.
+    /// Unique constraints are left to the database, and violating one is
+    /// the client's mistake rather than a failure.
     pub fn do_create(
+        &mut self,
+        user: Option<&ID>,
+        item: Item,
+        time: Timesecs,
+    ) -> Result<Result<ID, msgs::Error>, Error> {
+        if !self.may_create(user, &item)? {
+            return Ok(Err(msgs::Error::AccessDenied));
+        }
+        self.db.execute_batch("SAVEPOINT create_item")?;
+        let result = self.insert_item(item, time);
+        match result {
+            Ok(Ok(_)) => self.db.execute_batch("RELEASE create_item")?,
+            _ => self
+                .db
+                .execute_batch("ROLLBACK TO create_item; RELEASE create_item")?,
+        }
+        result.or_else(|err| match unique_violation(&err) {
+            Some(constraint) => Ok(Err(msgs::Error::Conflict { constraint })),
+            None => Err(err),
+        })
+    }
+
+    /// The writes of creating an item, in whatever transaction is open.
+    /// Anything written before a failure is for the caller to roll back.
+    fn insert_item(
         &mut self,
         item: Item,
         time: Timesecs,
     ) -> Result<Result<ID, msgs::Error>, Error> {
+        if !item_times_in_range(&item) {
+            return Ok(Err(msgs::Error::InvalidTime));
+        }
+        if !item_amounts_in_range(&item) {
+            return Ok(Err(msgs::Error::InvalidAmount));
+        }
         match item {
             Item::User(user) => {
                 if let Some(user_name_stripped) = User::valid_user_name_stripped(&user.user_name) {
-                    if let Ok(_) = self
+                    if let Ok(r) = self
                         .db
                         .select::<UserTable>()
                         .by_user_name_stripped(&user_name_stripped)
                     {
                         // user_name must still be unique without punctuation
-                        Ok(Err(msgs::Error::CannotCreateUser))
+                        let existing = r.fields.user_name;
+                        if existing == user.user_name {
+                            Ok(Err(msgs::Error::UserNameTaken { name: existing }))
+                        } else {
+                            Ok(Err(msgs::Error::UserNameStrippedConflict {
+                                name: existing,
+                            }))
+                        }
                     } else {
-                        let record = Record::new(ID::new(), user, time);
+                        let record = Record::new(self.id_gen.next_id(), user, time);
                         self.db.insert::<UserTable>(&record)?;
                         Ok(Ok(record.id))
                     }
                 } else {
-                    Ok(Err(msgs::Error::InvalidUserName))
+                    Ok(Err(msgs::Error::InvalidUserName {
+                        name: user.user_name,
+                    }))
                 }
             }
             Item::Identity(identity) => {
+                if !identity.valid_attested_time(time) {
+                    return Ok(Err(msgs::Error::InvalidAttestedTime));
+                }
                 // FIXME validation
-                let record = Record::new(ID::new(), identity, time);
+                let record = Record::new(self.id_gen.next_id(), identity, time);
                 self.db.insert::<IdentityTable>(&record)?;
                 Ok(Ok(record.id))
             }
             Item::IOU(iou) => {
-                iou.valid()?;
-                // FIXME validation
-                let record = Record::new(ID::new(), iou, time);
-                self.db.insert::<IOUTable>(&record)?;
-                Ok(Ok(record.id))
+                if !iou.valid() {
+                    Ok(Err(msgs::Error::InvalidAmount))
+                } else if let Some(err) = self.check_iou_users(&iou)? {
+                    Ok(Err(err))
+                } else if !iou.valid_cond_time(time) {
+                    Ok(Err(msgs::Error::InvalidCondTime))
+                } else if !iou.valid_memo() {
+                    Ok(Err(msgs::Error::MemoTooLong {
+                        max_length: types::MAX_MEMO_LENGTH,
+                    }))
+                } else {
+                    // FIXME validation
+                    let record = Record::new(self.id_gen.next_id(), iou, time);
+                    self.db.insert::<IOUTable>(&record)?;
+                    Ok(Ok(record.id))
+                }
             }
             Item::Cond(cond) => {
-                // FIXME validation
-                let record = Record::new(ID::new(), cond, time);
+                if cond.cond_value.is_some() {
+                    return Ok(Err(msgs::Error::CondResolved));
+                } else if cond.cond_closed {
+                    return Ok(Err(msgs::Error::CondClosed));
+                }
+                if let Some(err) = self.check_cond_args(&cond)? {
+                    return Ok(Err(err));
+                }
+                let record = Record::new(self.id_gen.next_id(), cond, time);
                 self.db.insert::<CondTable>(&record)?;
                 Ok(Ok(record.id))
             }
             Item::Offer(offer) => {
-                if offer.offer_details.valid() {
+                if !offer.offer_details.valid() || offer.offer_status != OfferStatus::Open {
+                    Ok(Err(msgs::Error::InvalidOfferDetails))
+                } else if self.offer_too_large(&offer.offer_details) {
+                    Ok(Err(msgs::Error::OfferTooLarge))
+                } else if !offer.valid_cond_time(time) {
+                    Ok(Err(msgs::Error::InvalidCondTime))
+                } else if !offer.valid_expiry(time) {
+                    Ok(Err(msgs::Error::InvalidOfferExpiry))
+                } else if self.user_locked(&offer.offer_user)? {
+                    Ok(Err(msgs::Error::UserLocked))
+                } else if self.cond_resolved(&offer.offer_cond_id)? {
+                    Ok(Err(msgs::Error::CondResolved))
+                } else if self.cond_closed(&offer.offer_cond_id)? {
+                    Ok(Err(msgs::Error::CondClosed))
+                } else {
                     // FIXME validation
-                    let record = Record::new(ID::new(), offer, time);
+                    let crosses = self.cross_policy != CrossPolicy::Allow
+                        && self.offer_crosses(&offer, time)?;
+                    if crosses && self.cross_policy == CrossPolicy::Reject {
+                        return Ok(Err(msgs::Error::CrossedBook));
+                    }
+                    let cond_id = offer.offer_cond_id.clone();
+                    let record = Record::new(self.id_gen.next_id(), offer, time);
                     self.db.insert::<OfferTable>(&record)?;
+                    if crosses {
+                        // trading as Accept would, within both users' credit
+                        let options = ClearingOptions {
+                            check_credit: true,
+                            ..ClearingOptions::default()
+                        };
+                        self.run_clearing(&cond_id, &options, time)?;
+                    }
                     Ok(Ok(record.id))
-                } else {
-                    Ok(Err(msgs::Error::InvalidOfferDetails))
                 }
             }
             Item::Entity(entity) => {
                 // FIXME validation
-                let record = Record::new(ID::new(), entity, time);
+                let record = Record::new(self.id_gen.next_id(), entity, time);
                 self.db.insert::<EntityTable>(&record)?;
                 Ok(Ok(record.id))
             }
             Item::Rel(rel) => {
                 // FIXME validation
-                let record = Record::new(ID::new(), rel, time);
+                if !self.db.select::<RelTypeTable>().allows(&rel.rel_type)? {
+                    return Ok(Err(msgs::Error::UnknownRelType {
+                        rel_type: rel.rel_type,
+                    }));
+                }
+                let record = Record::new(self.id_gen.next_id(), rel, time);
                 self.db.insert::<RelTable>(&record)?;
                 Ok(Ok(record.id))
             }
             Item::Pred(pred) => {
                 // FIXME validation
-                let record = Record::new(ID::new(), pred, time);
+                if !pred.valid_value() {
+                    return Ok(Err(msgs::Error::InvalidPredValue {
+                        value: pred.pred_value.unwrap_or_default(),
+                    }));
+                }
+                let record = Record::new(self.id_gen.next_id(), pred, time);
                 self.db.insert::<PredTable>(&record)?;
                 Ok(Ok(record.id))
             }
             Item::Depend(depend) => {
                 // FIXME validation
-                let record = Record::new(ID::new(), depend, time);
-                self.db.insert::<DependTable>(&record)?;
-                Ok(Ok(record.id))
+                let depends = self
+                    .db
+                    .select::<DependTable>()
+                    .by_depend_type(&depend.depend_type)?;
+                // adding pred1 -> pred2 closes a cycle if pred1 is reachable from pred2
+                if depend_reaches(&depends, &depend.depend_pred2, &depend.depend_pred1) {
+                    Ok(Err(msgs::Error::DependCycle))
+                } else {
+                    let record = Record::new(self.id_gen.next_id(), depend, time);
+                    self.db.insert::<DependTable>(&record)?;
+                    Ok(Ok(record.id))
+                }
+            }
+        }
+    }
+
+    /// Create each of the offers, in a single transaction. Unless
+    /// `best_effort` is set, any offer that fails means none are created.
+    fn do_create_offers(
+        &mut self,
+        user: Option<&ID>,
+        offers: Vec<Offer>,
+        best_effort: bool,
+        time: Timesecs,
+    ) -> Result<BTreeMap<usize, Result<ID, msgs::Error>>, Error> {
+        self.db.execute_batch("SAVEPOINT offers")?;
+        let mut results = BTreeMap::new();
+        for (i, offer) in offers.into_iter().enumerate() {
+            match self.do_create(user, Item::Offer(offer), time) {
+                Ok(result) => {
+                    results.insert(i, result);
+                }
+                Err(err) => {
+                    self.db
+                        .execute_batch("ROLLBACK TO offers; RELEASE offers")?;
+                    return Err(err);
+                }
+            }
+        }
+        if !best_effort && results.values().any(Result::is_err) {
+            self.db
+                .execute_batch("ROLLBACK TO offers; RELEASE offers")?;
+            let errors = results.into_iter().filter(|(_, r)| r.is_err()).collect();
+            return Ok(errors);
+        }
+        self.db.execute_batch("RELEASE offers")?;
+        Ok(results)
+    }
+
+    /// Go through everything creating the item would, then roll it back,
+    /// so nothing is left behind either way.
+    fn do_validate(
+        &mut self,
+        user: Option<&ID>,
+        item: Item,
+        time: Timesecs,
+    ) -> Result<Result<(), msgs::Error>, Error> {
+        self.db.execute_batch("SAVEPOINT validate")?;
+        let result = self.do_create(user, item, time);
+        self.db
+            .execute_batch("ROLLBACK TO validate; RELEASE validate")?;
+        result.map(|created| created.map(|_| ()))
+    }
+
+    /// Trade against an open offer at its price, rather than waiting for
+    /// clearing. The taker buys from the offer's sell side or sells to its
+    /// buy side, and must stay within their credit limit afterwards.
+    fn do_accept(
+        &mut self,
+        taker: &ID,
+        offer_id: &ID,
+        quantity: u32,
+        side: Side,
+        time: Timesecs,
+    ) -> Result<Result<(ID, ID), msgs::Error>, Error> {
+        self.expire_offers(time)?;
+        if !has_id::<OfferTable>(&self.db, "offer_id", offer_id)? {
+            return Ok(Err(msgs::Error::NotFound {
+                id: offer_id.0.clone(),
+            }));
+        }
+        let taker_user = match self.db.select::<UserTable>().try_by_id(taker)? {
+            Some(r) => r.fields,
+            None => {
+                return Ok(Err(msgs::Error::NotFound {
+                    id: taker.0.clone(),
+                }))
+            }
+        };
+        let mut offer = self.db.select::<OfferTable>().by_id(offer_id)?.fields;
+        let offer_side = match side {
+            Side::Buy => Side::Sell,
+            Side::Sell => Side::Buy,
+        };
+        let price = offer.offer_details.price(offer_side);
+        if quantity == 0 || quantity > offer.open_quantity(offer_side) {
+            return Ok(Err(msgs::Error::InvalidAcceptQuantity));
+        } else if &offer.offer_user == taker {
+            return Ok(Err(msgs::Error::IOUSelf));
+        } else if !offer.valid_cond_time(time) {
+            return Ok(Err(msgs::Error::InvalidCondTime));
+        } else if taker_user.user_locked || self.user_locked(&offer.offer_user)? {
+            return Ok(Err(msgs::Error::UserLocked));
+        } else if self.cond_resolved(&offer.offer_cond_id)? {
+            return Ok(Err(msgs::Error::CondResolved));
+        } else if self.cond_closed(&offer.offer_cond_id)? {
+            return Ok(Err(msgs::Error::CondClosed));
+        }
+        let (buyer, seller) = match side {
+            Side::Buy => (taker, &offer.offer_user),
+            Side::Sell => (&offer.offer_user, taker),
+        };
+        let (buyer_iou, seller_iou) = types::trade_ious(
+            &offer.offer_cond_id,
+            offer.offer_cond_time,
+            buyer,
+            seller,
+            price,
+            quantity,
+        );
+        let new_ious = [buyer_iou.clone(), seller_iou.clone()];
+        if !within_credit(&self.db, &mut HashMap::new(), taker, &new_ious)? {
+            return Ok(Err(msgs::Error::CreditLimitExceeded));
+        }
+        let buyer_iou = Record::new(self.id_gen.next_id(), buyer_iou, time);
+        let seller_iou = Record::new(self.id_gen.next_id(), seller_iou, time);
+        self.db.insert::<IOUTable>(&buyer_iou)?;
+        self.db.insert::<IOUTable>(&seller_iou)?;
+        offer.fill(offer_side, quantity);
+        self.db
+            .update::<OfferTable>()
+            .fill_offer(offer_id, &offer, time)?;
+        Ok(Ok((buyer_iou.id, seller_iou.id)))
+    }
+
+    /// Delete an item, unless something still refers to it. IOUs and offers
+    /// are history rather than mistakes, so they are voided or cancelled
+    /// instead.
+    fn do_delete(&mut self, id: &ID) -> Result<Result<(), msgs::Error>, Error> {
+        let db = &self.db;
+        if has_id::<UserTable>(db, "user_id", id)? {
+            let refs = db
+                .select::<IdentityTable>()
+                .count_where("identity_user_id = ?1", &[id])?
+                + db.select::<IOUTable>()
+                    .count_where("iou_issuer = ?1 OR iou_holder = ?1", &[id])?
+                + db.select::<OfferTable>()
+                    .count_where("offer_user = ?1", &[id])?
+                + db.select::<ApiKeyTable>()
+                    .count_where("key_user = ?1", &[id])?;
+            delete_unused::<UserTable>(db, "user_id", id, refs)
+        } else if has_id::<IdentityTable>(db, "identity_id", id)? {
+            delete_unused::<IdentityTable>(db, "identity_id", id, 0)
+        } else if has_id::<CondTable>(db, "cond_id", id)? {
+            let refs = db
+                .select::<IOUTable>()
+                .count_where("iou_cond_id = ?1", &[id])?
+                + db.select::<OfferTable>().count_by_cond(id)?;
+            delete_unused::<CondTable>(db, "cond_id", id, refs)
+        } else if has_id::<EntityTable>(db, "entity_id", id)? {
+            let refs = db
+                .select::<RelTable>()
+                .count_where("rel_from = ?1 OR rel_to = ?1", &[id])?
+                + db.select::<CondTable>()
+                    .count_where("cond_arg1 = ?1 OR cond_arg2 = ?1", &[id])?
+                + db.select::<PropTable>()
+                    .count_where("entity_id = ?1", &[id])?;
+            delete_unused::<EntityTable>(db, "entity_id", id, refs)
+        } else if has_id::<RelTable>(db, "rel_id", id)? {
+            delete_unused::<RelTable>(db, "rel_id", id, 0)
+        } else if has_id::<PredTable>(db, "pred_id", id)? {
+            let refs = db
+                .select::<CondTable>()
+                .count_where("cond_pred = ?1", &[id])?
+                + db.select::<DependTable>()
+                    .count_where("depend_pred1 = ?1 OR depend_pred2 = ?1", &[id])?;
+            delete_unused::<PredTable>(db, "pred_id", id, refs)
+        } else if has_id::<DependTable>(db, "depend_id", id)? {
+            delete_unused::<DependTable>(db, "depend_id", id, 0)
+        } else if has_id::<IOUTable>(db, "iou_id", id)? || has_id::<OfferTable>(db, "offer_id", id)?
+        {
+            Ok(Err(msgs::Error::NotDeletable))
+        } else {
+            Ok(Err(msgs::Error::NotFound { id: id.0.clone() }))
+        }
+    }
+
+    fn user_locked(&self, id: &ID) -> Result<bool, Error> {
+        let r = self.db.select::<UserTable>().by_id(id)?;
+        Ok(r.fields.user_locked)
+    }
+
+    fn cond_resolved(&self, id: &ID) -> Result<bool, Error> {
+        let r = self.db.select::<CondTable>().by_id(id)?;
+        Ok(r.fields.cond_value.is_some())
+    }
+
+    fn cond_closed(&self, id: &ID) -> Result<bool, Error> {
+        let r = self.db.select::<CondTable>().by_id(id)?;
+        Ok(r.fields.cond_closed)
+    }
+
+    /// The args must be entities of the types the pred declares, in order.
+    fn check_cond_args(&self, cond: &Cond) -> Result<Option<msgs::Error>, Error> {
+        let pred = match self.db.select::<PredTable>().try_by_id(&cond.cond_pred)? {
+            Some(r) => r.fields,
+            None => {
+                return Ok(Some(msgs::Error::NotFound {
+                    id: cond.cond_pred.0.clone(),
+                }))
+            }
+        };
+        // a `time` arg is given by cond_arg_time, the rest are entities
+        let arg_types = pred.pred_args.args();
+        let time_args = arg_types.iter().filter(|t| *t == TIME_ARG_TYPE).count();
+        let entity_args = pred.arity() - time_args;
+        let given_time_args = if cond.cond_arg_time.is_some() { 1 } else { 0 };
+        if time_args != given_time_args || entity_args != cond.cond_args.len() {
+            return Ok(Some(msgs::Error::ArgCountMismatch {
+                expected: pred.arity(),
+                actual: cond.cond_args.len() + given_time_args,
+            }));
+        }
+        let entities = self.db.select::<EntityTable>();
+        let entity_arg_types = arg_types
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| *t != TIME_ARG_TYPE);
+        for (arg, (position, arg_type)) in cond.cond_args.iter().zip(entity_arg_types) {
+            let entity = match entities.try_by_id(arg)? {
+                Some(r) => r.fields,
+                None => return Ok(Some(msgs::Error::NotFound { id: arg.0.clone() })),
+            };
+            if &entity.entity_type != arg_type {
+                return Ok(Some(msgs::Error::ArgTypeMismatch {
+                    position,
+                    expected: arg_type.clone(),
+                    actual: entity.entity_type,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The issuer and holder must be distinct users that exist and are not locked.
+    fn check_iou_users(&self, iou: &IOU) -> Result<Option<msgs::Error>, Error> {
+        if iou.iou_issuer == iou.iou_holder {
+            return Ok(Some(msgs::Error::IOUSelf));
+        }
+        let users = self.db.select::<UserTable>();
+        let issuer = match users.try_by_id(&iou.iou_issuer)? {
+            Some(r) => r.fields,
+            None => {
+                return Ok(Some(msgs::Error::IssuerNotFound {
+                    id: iou.iou_issuer.clone(),
+                }))
+            }
+        };
+        let holder = match users.try_by_id(&iou.iou_holder)? {
+            Some(r) => r.fields,
+            None => {
+                return Ok(Some(msgs::Error::HolderNotFound {
+                    id: iou.iou_holder.clone(),
+                }))
+            }
+        };
+        if issuer.user_locked {
+            Ok(Some(msgs::Error::IssuerLocked {
+                id: iou.iou_issuer.clone(),
+            }))
+        } else if holder.user_locked {
+            Ok(Some(msgs::Error::HolderLocked {
+                id: iou.iou_holder.clone(),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Conditions that follow from `cond_id` holding, by transitively walking
+    /// the `implies` depends from its predicate.
+    pub fn implied_conds(&mut self, cond_id: ID) -> Result<Vec<Cond>, Error> {
+        let cond = self.db.select::<CondTable>().by_id(&cond_id)?.fields;
+        let depends = self.db.select::<DependTable>().by_depend_type("implies")?;
+        let mut implied = Vec::new();
+        let mut seen = HashSet::new();
+        let mut pending = vec![cond];
+        while let Some(cond) = pending.pop() {
+            for r in &depends {
+                if r.fields.depend_pred1 != cond.cond_pred {
+                    continue;
+                }
+                if let Some(cond_args) = self.depend_args2(&r.fields, &cond.cond_args)? {
+                    let implied_cond = Cond {
+                        cond_pred: r.fields.depend_pred2.clone(),
+                        cond_args,
+                        cond_arg_time: None,
+                        cond_value: None,
+                        cond_closed: false,
+                    };
+                    let key = (
+                        implied_cond.cond_pred.clone(),
+                        implied_cond.cond_args.clone(),
+                    );
+                    if seen.insert(key) {
+                        implied.push(implied_cond.clone());
+                        pending.push(implied_cond);
+                    }
+                }
+            }
+        }
+        Ok(implied)
+    }
+
+    /// Bind `depend_args1` to the given args and evaluate `depend_args2`,
+    /// following rels for accessors like `x.party`. Returns None if the
+    /// args don't fit or a rel is missing.
+    fn depend_args2(&self, depend: &Depend, args: &[ID]) -> Result<Option<Vec<ID>>, Error> {
+        if depend.depend_args1.len() != args.len() {
+            return Ok(None);
+        }
+        let mut vars = HashMap::new();
+        for (var, arg) in depend.depend_args1.args().iter().zip(args) {
+            if !depend.depend_vars.args().contains(var) {
+                return Ok(None);
+            }
+            vars.insert(var.as_str(), arg.clone());
+        }
+        let mut args2 = Vec::new();
+        for expr in depend.depend_args2.args() {
+            let mut path = expr.split('.');
+            let mut id = match path.next().and_then(|var| vars.get(var)) {
+                Some(id) => id.clone(),
+                None => return Ok(None),
+            };
+            for rel_type in path {
+                match self
+                    .db
+                    .select::<RelTable>()
+                    .by_rel_from_type(&id, rel_type)?
+                {
+                    Some(r) => id = r.fields.rel_to,
+                    None => return Ok(None),
+                }
             }
+            args2.push(id);
         }
+        Ok(Some(args2))
     }
 
     fn do_iou_transfer(
@@ -173,138 +978,4882 @@ impl Market {
         id: ID,
         transfer: &Transfer,
         time: Timesecs,
-    ) -> Result<HashMap<ID, Item>, Error> {
+    ) -> Result<Result<HashMap<ID, Item>, msgs::Error>, Error> {
         let mut ious = HashMap::new();
-        let tx = self.db.transaction()?;
+        let tx = self.db.savepoint()?;
         let r = tx.select::<IOUTable>().by_id(&id)?;
         let old_iou = r.fields;
-        // FIXME access control
-        transfer.valid(&old_iou)?;
-        tx.update().void_iou(&id)?;
+        if old_iou.iou_void {
+            return Ok(Err(msgs::Error::AlreadyVoid));
+        }
+        if transfer.valid(&old_iou).is_err() {
+            return Ok(Err(msgs::Error::InvalidTransfer));
+        }
+        let mut holders: Vec<&ID> = transfer.holders.keys().collect();
+        holders.sort_by(|a, b| a.0.cmp(&b.0));
+        for holder in holders {
+            if tx.select::<UserTable>().try_by_id(holder)?.is_none() {
+                return Ok(Err(msgs::Error::NotFound {
+                    id: holder.0.clone(),
+                }));
+            }
+        }
+        tx.update().void_iou(&id, time)?.updated()?;
         for new_iou in transfer.make_ious(&id, &old_iou)? {
-            let new_record = Record::new(ID::new(), new_iou, time);
+            let new_record = Record::new(self.id_gen.next_id(), new_iou, time);
             tx.insert::<IOUTable>(&new_record)?;
             ious.insert(new_record.id, new_record.fields.to_item());
         }
         tx.commit()?;
-        Ok(ious)
+        Ok(Ok(ious))
     }
 
-    fn do_iou_void(&mut self, id: &ID) -> Result<IOU, Error> {
-        let tx = self.db.transaction()?;
-        let mut r = tx.select::<IOUTable>().by_id(&id)?;
-        // FIXME access control
-        if r.fields.iou_void {
-            return Err(err_msg("IOU is already void"));
-        } else {
-            tx.update().void_iou(&id)?;
-            r.fields.iou_void = true;
-        }
-        tx.commit()?;
-        Ok(r.fields)
+    /// How much the user stands to lose on each condition, from their live
+    /// IOUs.
+    pub fn user_exposure(&mut self, user_id: &ID) -> Result<HashMap<ID, Dollars>, Error> {
+        let ious: Vec<IOU> = self
+            .db
+            .select::<IOUTable>()
+            .live_by_user(user_id)?
+            .into_iter()
+            .map(|r| r.fields)
+            .collect();
+        types::exposure(user_id, &ious)
     }
 
-    pub fn do_update(
-        &mut self,
-        id: ID,
-        item_update: ItemUpdate,
-        time: Timesecs,
-    ) -> Result<Response, Error> {
-        match item_update {
-            ItemUpdate::Offer(offer_details) => {
-                if offer_details.valid() {
-                    // FIXME access control
-                    self.db
-                        .update::<OfferTable>()
-                        .update_offer(&id, &offer_details)?;
-                    Ok(Response::Updated)
+    /// What is payable now, netted between each pair of users. Resolving a
+    /// cond replaces the IOUs that won with unconditional ones, so these
+    /// are the live unconditional IOUs.
+    pub fn settlement_report(&mut self) -> Result<Vec<(ID, ID, Dollars)>, Error> {
+        let ious: Vec<IOU> = self
+            .db
+            .select::<IOUTable>()
+            .unconditional()?
+            .into_iter()
+            .map(|r| r.fields)
+            .collect();
+        types::settlement(&ious)
+    }
+
+    /// Everything wrong with the stored records, for checking a database
+    /// after a crash. Nothing is changed.
+    pub fn integrity_problems(&self) -> Result<Vec<String>, Error> {
+        let mut problems = Vec::new();
+        for (table, rowid, parent) in foreign_key_violations(&self.db)? {
+            problems.push(format!(
+                "{} row {} refers to a missing {}",
+                table, rowid, parent
+            ));
+        }
+        let ious: HashMap<ID, IOU> = self
+            .db
+            .select::<IOUTable>()
+            .all()?
+            .into_iter()
+            .map(|r| (r.id, r.fields))
+            .collect();
+        let conds: HashSet<ID> = self
+            .db
+            .select::<CondTable>()
+            .all()?
+            .into_iter()
+            .map(|r| r.id)
+            .collect();
+        let mut splits: HashMap<&ID, Dollars> = HashMap::new();
+        let mut ids: Vec<&ID> = ious.keys().collect();
+        ids.sort_by(|a, b| a.0.cmp(&b.0));
+        for id in ids {
+            let iou = &ious[id];
+            if let Some(cond_id) = &iou.iou_cond_id {
+                if !conds.contains(cond_id) {
+                    problems.push(format!("IOU {} is on missing cond {}", id.0, cond_id.0));
+                }
+            }
+            if let Some(split) = &iou.iou_split {
+                if ious.contains_key(split) {
+                    *splits.entry(split).or_insert(Dollars::ZERO) += iou.iou_value;
                 } else {
-                    Ok(Response::Error(msgs::Error::InvalidOfferDetails))
+                    problems.push(format!(
+                        "IOU {} is split from missing IOU {}",
+                        id.0, split.0
+                    ));
                 }
             }
-            ItemUpdate::Transfer(transfer) => {
-                let items = self.do_iou_transfer(id, &transfer, time)?;
-                Ok(Response::Items(items))
+        }
+        let mut split_ids: Vec<&ID> = splits.keys().cloned().collect();
+        split_ids.sort_by(|a, b| a.0.cmp(&b.0));
+        for id in split_ids {
+            let iou = &ious[id];
+            if !iou.iou_void {
+                problems.push(format!("IOU {} was split but is not void", id.0));
             }
-            ItemUpdate::Void => {
-                let iou = self.do_iou_void(&id)?;
-                Ok(Response::Items(single_item(id, iou)))
+            if splits[id] != iou.iou_value {
+                problems.push(format!(
+                    "IOU {} is worth {} but was split into {}",
+                    id.0,
+                    iou.iou_value.to_millibucks(),
+                    splits[id].to_millibucks()
+                ));
             }
         }
+        Ok(problems)
+    }
+
+    /// Every user's unconditional debt and credit and their exposure on
+    /// each condition, in the order the users were created. Offers that
+    /// expired by `time` are left out of what open offers could add.
+    pub fn balances(&mut self, time: Timesecs) -> Result<Vec<(ID, UserBalance)>, Error> {
+        let ious: Vec<IOU> = self
+            .db
+            .select::<IOUTable>()
+            .live()?
+            .into_iter()
+            .map(|r| r.fields)
+            .collect();
+        let mut balances = types::balances(&ious)?;
+        for r in self.db.select::<OfferTable>().open(time)? {
+            let details = &r.fields.offer_details;
+            let exposure = cmp::max(
+                details.max_exposure(Side::Buy)?,
+                details.max_exposure(Side::Sell)?,
+            );
+            let balance = balances.entry(r.fields.offer_user).or_default();
+            balance.open_offers = balance.open_offers.checked_add(exposure)?;
+        }
+        let users = self.db.select::<UserTable>().all()?;
+        Ok(users
+            .into_iter()
+            .map(|r| {
+                let balance = balances.remove(&r.id).unwrap_or_default();
+                (r.id, balance)
+            })
+            .collect())
     }
 
-    pub fn do_query(&mut self, query: Query) -> Result<Response, Error> {
-        fn to_item<T: ToItem>(record: Record<T>) -> (ID, Item) {
-            (record.id, record.fields.to_item())
+    /// Each entity of the type with the rels from it, which may be none.
+    pub fn entities_with_rels(&mut self, entity_type: &str) -> Result<Vec<EntityRels>, Error> {
+        let mut rels: HashMap<ID, Vec<(ID, Rel)>> = HashMap::new();
+        for r in self.db.select::<RelTable>().from_entity_type(entity_type)? {
+            rels.entry(r.fields.rel_from.clone())
+                .or_insert_with(Vec::new)
+                .push((r.id, r.fields));
         }
+        let entities = self
+            .db
+            .select::<EntityTable>()
+            .by_entity_type(entity_type)?;
+        Ok(entities
+            .into_iter()
+            .map(|r| EntityRels {
+                rels: rels.remove(&r.id).unwrap_or_default(),
+                id: r.id,
+                entity: r.fields,
+            })
+            .collect())
+    }
+
+    /// Cancel offers that have expired. This is done lazily, before anything
+    /// that trades against open offers, rather than by a timer. Queries
+    /// leave expired offers out instead, so that they don't write.
+    pub fn expire_offers(&mut self, time: Timesecs) -> Result<usize, Error> {
+        self.db.update::<OfferTable>().expire_offers(time)
+    }
+
+    /// Open quantity at each price for a condition, across all cond_times.
+    /// Prices with nothing left on a side are left out of it, as are offers
+    /// that expired by `time`.
+    pub fn order_book(&mut self, cond_id: &ID, time: Timesecs) -> Result<BookDepth, Error> {
+        let offers = self.db.select::<OfferTable>().live_by_cond(cond_id, time)?;
+        Ok(book_depth(offers))
+    }
 
+    /// Answer a query from the rows as they were at a past time. Rows
+    /// created since are left out and IOUs voided since are live again, but
+    /// other updates aren't kept, so an offer counts in the book if it was
+    /// posted by then and is open now, with what is left of it now.
+    fn query_as_of(&mut self, query: Query, as_of: Timesecs) -> Result<Response, Error> {
+        if !as_of.in_range() {
+            return Ok(Response::Error(msgs::Error::InvalidTime));
+        }
+        let created = Timespec::from(as_of);
         match query {
             Query::AllUser => {
                 // FIXME access control
-                let items = self.select_all_user()?.into_iter().map(to_item).collect();
-                Ok(Response::Items(items))
+                let users = self.db.select::<UserTable>().created_by(created)?;
+                Ok(Response::Items(users.into_iter().map(to_item).collect()))
             }
             Query::AllIOU => {
                 // FIXME access control
-                let items = self.select_all_iou()?.into_iter().map(to_item).collect();
-                Ok(Response::Items(items))
+                let ious = self.db.select::<IOUTable>().as_of(as_of)?;
+                Ok(Response::Items(ious.into_iter().map(to_item).collect()))
             }
             Query::AllCond => {
                 // FIXME access control
-                let items = self.select_all_cond()?.into_iter().map(to_item).collect();
-                Ok(Response::Items(items))
+                let conds = self.db.select::<CondTable>().created_by(created)?;
+                Ok(Response::Items(conds.into_iter().map(to_item).collect()))
             }
             Query::AllOffer => {
                 // FIXME access control
-                let items = self
+                let offers = self.db.select::<OfferTable>().created_by(created)?;
+                Ok(Response::Items(offers.into_iter().map(to_item).collect()))
+            }
+            Query::Book(cond_id) => {
+                // FIXME access control
+                let offers = self
                     .db
                     .select::<OfferTable>()
-                    .all()?
+                    .open_by_cond(&cond_id)?
                     .into_iter()
-                    .map(to_item)
+                    .filter(|r| r.creation_time <= created)
                     .collect();
-                Ok(Response::Items(items))
-            }
-            Query::AllEntity => {
-                // FIXME access control
-                let items = self.select_all_entity()?.into_iter().map(to_item).collect();
-                Ok(Response::Items(items))
-            }
-            Query::AllRel => {
-                // FIXME access control
-                let items = self.select_all_rel()?.into_iter().map(to_item).collect();
-                Ok(Response::Items(items))
-            }
-            Query::AllPred => {
-                // FIXME access control
-                let items = self.select_all_pred()?.into_iter().map(to_item).collect();
-                Ok(Response::Items(items))
-            }
-            Query::AllDepend => {
-                // FIXME access control
-                let items = self.select_all_depend()?.into_iter().map(to_item).collect();
-                Ok(Response::Items(items))
+                Ok(Response::Book(book_depth(offers)))
             }
+            _ => Ok(Response::Error(msgs::Error::AsOfNotSupported)),
         }
     }
 
+    /// Match crossed offers on a condition, issuing a pair of IOUs for each
+    /// trade and reducing the posted quantities of the offers involved. The
+    /// options decide the price each pair of offers trades at, and how long
+    /// to keep matching before stopping with the book partly cleared.
+    ///
+    /// With `check_credit` each trade is checked against the credit of both
+    /// users, starting from their live IOUs, so clearing again later carries
+    /// on from what earlier rounds issued. A trade that doesn't fit is left
+    /// out and its offers stay open to trade with others.
+    pub fn run_clearing(
+        &mut self,
+        cond_id: &ID,
+        options: &ClearingOptions,
+        time: Timesecs,
+    ) -> Result<Clearing, Error> {
+        let tx = self.db.savepoint()?;
+        tx.update::<OfferTable>().expire_offers(time)?;
+        // offers with different deadlines are separate books
+        let mut books = BTreeMap::new();
+        for r in tx.select::<OfferTable>().open_by_cond(cond_id)? {
+            books
+                .entry(r.fields.offer_cond_time)
+                .or_insert_with(Vec::new)
+                .push(r);
+        }
+        let mut trades = Vec::new();
+        let mut complete = true;
+        let mut ledger = HashMap::new();
+        for (cond_time, mut book) in books {
+            let result = clearing::find_matches_with(&mut book, options, |buy, sell, m| {
+                if !options.check_credit {
+                    return Ok(true);
+                }
+                let (buyer, seller) = (&buy.offer_user, &sell.offer_user);
+                let (buyer_iou, seller_iou) =
+                    types::trade_ious(cond_id, cond_time, buyer, seller, m.price, m.units);
+                let new_ious = [buyer_iou, seller_iou];
+                if !within_credit(&tx, &mut ledger, buyer, &new_ious)?
+                    || !within_credit(&tx, &mut ledger, seller, &new_ious)?
+                {
+                    return Ok(false);
+                }
+                for user in &[buyer, seller] {
+                    if let Some((_, ious)) = ledger.get_mut(*user) {
+                        ious.extend_from_slice(&new_ious);
+                    }
+                }
+                Ok(true)
+            })?;
+            complete &= result.cleared;
+            let mut filled = HashSet::new();
+            for m in result.matches {
+                let buyer = &book[m.buy].fields.offer_user;
+                let seller = &book[m.sell].fields.offer_user;
+                let (buyer_iou, seller_iou) =
+                    types::trade_ious(cond_id, cond_time, buyer, seller, m.price, m.units);
+                let buyer_iou = Record::new(self.id_gen.next_id(), buyer_iou, time);
+                let seller_iou = Record::new(self.id_gen.next_id(), seller_iou, time);
+                tx.insert::<IOUTable>(&buyer_iou)?;
+                tx.insert::<IOUTable>(&seller_iou)?;
+                trades.push(Trade {
+                    cond_id: cond_id.clone(),
+                    cond_time,
+                    buyer: buyer.clone(),
+                    seller: seller.clone(),
+                    buy_offer: book[m.buy].id.clone(),
+                    sell_offer: book[m.sell].id.clone(),
+                    units: m.units,
+                    price: m.price,
+                    buyer_iou: buyer_iou.id,
+                    seller_iou: seller_iou.id,
+                });
+                filled.insert(m.buy);
+                filled.insert(m.sell);
+            }
+            for i in filled {
+                tx.update::<OfferTable>()
+                    .fill_offer(&book[i].id, &book[i].fields, time)?;
+            }
+        }
+        tx.commit()?;
+        Ok(Clearing { trades, complete })
+    }
+
+    fn do_iou_void(&mut self, id: &ID, time: Timesecs) -> Result<Result<IOU, msgs::Error>, Error> {
+        match self.db.update::<IOUTable>().void_iou(id, time)? {
+            UpdateOutcome::Updated => Ok(Ok(self.db.select::<IOUTable>().by_id(id)?.fields)),
+            UpdateOutcome::NoChange => Ok(Err(msgs::Error::AlreadyVoid)),
+            UpdateOutcome::NotFound => Ok(Err(msgs::Error::NotFound { id: id.0.clone() })),
+        }
+    }
+
+    /// End trading on a condition: open offers on it are cancelled and no
+    /// new ones are taken, so that it can be resolved. Returns how many
+    /// offers were cancelled.
+    pub fn close_cond(
+        &mut self,
+        cond_id: &ID,
+        time: Timesecs,
+    ) -> Result<Result<usize, msgs::Error>, Error> {
+        let tx = self.db.savepoint()?;
+        match tx.update::<CondTable>().close(cond_id, time)? {
+            UpdateOutcome::Updated => {}
+            UpdateOutcome::NoChange => return Ok(Err(msgs::Error::CondClosed)),
+            UpdateOutcome::NotFound => {
+                return Ok(Err(msgs::Error::NotFound {
+                    id: cond_id.0.clone(),
+                }))
+            }
+        }
+        let offers = tx.select::<OfferTable>().open_by_cond(cond_id)?;
+        for r in &offers {
+            tx.update::<OfferTable>().cancel_offer(&r.id, time)?;
+        }
+        tx.commit()?;
+        Ok(Ok(offers.len()))
+    }
+
+    /// Settle a closed condition once its outcome is known. Each live IOU on
+    /// it is voided, and the ones that pay on this outcome are replaced by an
+    /// unconditional IOU for the same value.
+    fn do_resolve(
+        &mut self,
+        cond_id: &ID,
+        cond_value: bool,
+        time: Timesecs,
+    ) -> Result<Result<HashMap<ID, Item>, msgs::Error>, Error> {
+        let mut ious = HashMap::new();
+        let tx = self.db.savepoint()?;
+        match tx
+            .update::<CondTable>()
+            .set_value(cond_id, cond_value, time)?
+        {
+            UpdateOutcome::Updated => {}
+            UpdateOutcome::NoChange => {
+                let r = tx.select::<CondTable>().by_id(cond_id)?;
+                return Ok(Err(if r.fields.cond_value.is_some() {
+                    msgs::Error::CondResolved
+                } else {
+                    msgs::Error::CondNotClosed
+                }));
+            }
+            UpdateOutcome::NotFound => {
+                return Ok(Err(msgs::Error::NotFound {
+                    id: cond_id.0.clone(),
+                }))
+            }
+        }
+        for r in tx.select::<IOUTable>().live_by_cond(cond_id)? {
+            tx.update().void_iou(&r.id, time)?.updated()?;
+            if r.fields.pays(cond_value) {
+                let new_iou = IOU {
+                    iou_cond_id: None,
+                    iou_cond_flag: false,
+                    iou_cond_time: None,
+                    iou_split: Some(r.id),
+                    ..r.fields
+                };
+                let new_record = Record::new(self.id_gen.next_id(), new_iou, time);
+                tx.insert::<IOUTable>(&new_record)?;
+                ious.insert(new_record.id, new_record.fields.to_item());
+            }
+        }
+        tx.commit()?;
+        Ok(Ok(ious))
+    }
+
+    /// Apply an update on behalf of `user`, or with no user for local
+    /// administration. Like `do_create` it is all or nothing, in a
+    /// savepoint of its own.
+    pub fn do_update(
+        &mut self,
+        user: Option<&ID>,
+        id: ID,
+        item_update: ItemUpdate,
+        time: Timesecs,
+    ) -> Result<Response, Error> {
+        self.db.execute_batch("SAVEPOINT update_item")?;
+        let result = self.apply_update(user, id, item_update, time);
+        match result {
+            Ok(Response::Error(_)) | Err(_) => self
+                .db
+                .execute_batch("ROLLBACK TO update_item; RELEASE update_item")?,
+            Ok(_) => self.db.execute_batch("RELEASE update_item")?,
+        }
+        result
+    }
+
+    /// The writes of an update, in whatever transaction is open.
+    fn apply_update(
+        &mut self,
+        user: Option<&ID>,
+        id: ID,
+        item_update: ItemUpdate,
+        time: Timesecs,
+    ) -> Result<Response, Error> {
+        if let Some(err) = self.check_update_access(user, &id, &item_update)? {
+            return Ok(Response::Error(err));
+        }
+        match item_update {
+            ItemUpdate::Offer(offer_details) => {
+                if !offer_details.valid() {
+                    Ok(Response::Error(msgs::Error::InvalidOfferDetails))
+                } else if self.offer_too_large(&offer_details) {
+                    Ok(Response::Error(msgs::Error::OfferTooLarge))
+                } else if !self.offer_owned_by(user, &id)? {
+                    Ok(Response::Error(msgs::Error::AccessDenied))
+                } else if self.offer_cond_closed(&id)? {
+                    // reposting would reopen it on a closed cond
+                    Ok(Response::Error(msgs::Error::CondClosed))
+                } else {
+                    self.db
+                        .update::<OfferTable>()
+                        .update_offer(&id, &offer_details, time)?;
+                    Ok(Response::Updated)
+                }
+            }
+            ItemUpdate::Transfer(transfer) => match self.do_iou_transfer(id, &transfer, time)? {
+                Ok(items) => Ok(Response::Items(items)),
+                Err(err) => Ok(Response::Error(err)),
+            },
+            ItemUpdate::Void => match self.do_iou_void(&id, time)? {
+                Ok(iou) => Ok(Response::Items(single_item(id, iou))),
+                Err(err) => Ok(Response::Error(err)),
+            },
+            ItemUpdate::UserLock { user_locked } => {
+                self.db
+                    .update::<UserTable>()
+                    .set_locked(&id, user_locked, time)?;
+                let r = self.db.select::<UserTable>().by_id(&id)?;
+                Ok(Response::Items(single_item(id, r.fields)))
+            }
+            ItemUpdate::CreditLimit { user_credit_limit } => {
+                if user_credit_limit < Dollars::ZERO {
+                    Ok(Response::Error(msgs::Error::InvalidCreditLimit))
+                } else {
+                    self.db
+                        .update::<UserTable>()
+                        .set_credit_limit(&id, user_credit_limit, time)?;
+                    let r = self.db.select::<UserTable>().by_id(&id)?;
+                    Ok(Response::Items(single_item(id, r.fields)))
+                }
+            }
+            ItemUpdate::DisplayName(user_display_name) => {
+                self.db
+                    .update::<UserTable>()
+                    .set_display_name(&id, &user_display_name, time)?;
+                let r = self.db.select::<UserTable>().by_id(&id)?;
+                Ok(Response::Items(single_item(id, r.fields)))
+            }
+            ItemUpdate::PredValue { pred_value } => {
+                let r = self.db.select::<PredTable>().by_id(&id)?;
+                if r.fields.pred_value_type.valid_value(&pred_value) {
+                    self.db
+                        .update::<PredTable>()
+                        .set_value(&id, &pred_value, time)?;
+                    Ok(Response::Updated)
+                } else {
+                    Ok(Response::Error(msgs::Error::InvalidPredValue {
+                        value: pred_value,
+                    }))
+                }
+            }
+            ItemUpdate::Resolve { cond_value } => match self.do_resolve(&id, cond_value, time)? {
+                Ok(items) => Ok(Response::Items(items)),
+                Err(err) => Ok(Response::Error(err)),
+            },
+            ItemUpdate::AttestIdentity {
+                time: attested_time,
+            } => {
+                if !types::plausible_attested_time(attested_time, time) {
+                    return Ok(Response::Error(msgs::Error::InvalidAttestedTime));
+                }
+                match self
+                    .db
+                    .update::<IdentityTable>()
+                    .attest(&id, attested_time, time)?
+                {
+                    UpdateOutcome::Updated => {
+                        let r = self.db.select::<IdentityTable>().by_id(&id)?;
+                        Ok(Response::Items(single_item(id, r.fields)))
+                    }
+                    UpdateOutcome::NoChange => Ok(Response::Error(msgs::Error::AlreadyVerified)),
+                    UpdateOutcome::NotFound => {
+                        Ok(Response::Error(msgs::Error::NotFound { id: id.0 }))
+                    }
+                }
+            }
+            ItemUpdate::Close => match self.close_cond(&id, time)? {
+                Ok(count) => Ok(Response::Count(count as i64)),
+                Err(err) => Ok(Response::Error(err)),
+            },
+        }
+    }
+
+    /// Raise every user's credit limit by `amount`, returning how many
+    /// users there are, or an error if the amount isn't positive.
+    pub fn increment_all_credit(
+        &mut self,
+        amount: Dollars,
+        time: Timesecs,
+    ) -> Result<Result<usize, msgs::Error>, Error> {
+        if amount <= Dollars::ZERO {
+            return Ok(Err(msgs::Error::InvalidCreditLimit));
+        }
+        let count = self
+            .db
+            .update::<UserTable>()
+            .increment_credit_limits(amount, time)?;
+        Ok(Ok(count))
+    }
+
+    fn offer_cond_closed(&self, offer_id: &ID) -> Result<bool, Error> {
+        let r = self.db.select::<OfferTable>().by_id(offer_id)?;
+        self.cond_closed(&r.fields.offer_cond_id)
+    }
+
+    /// Whether the user may create an item. Only the user themselves can
+    /// issue an IOU, make an offer or claim an identity, and only admins can
+    /// create an identity that is already verified.
+    fn may_create(&self, user: Option<&ID>, item: &Item) -> Result<bool, Error> {
+        if self.is_admin(user)? {
+            return Ok(true);
+        }
+        match item {
+            Item::IOU(iou) => Ok(Some(&iou.iou_issuer) == user),
+            Item::Offer(offer) => Ok(Some(&offer.offer_user) == user),
+            Item::Identity(identity) => {
+                Ok(Some(&identity.identity_user_id) == user && !identity.identity_verified)
+            }
+            _ => Ok(true),
+        }
+    }
+
+    /// Why the user may not make an update, if they may not. IOUs are
+    /// changed by their holders, users may name themselves, and the rest is
+    /// for admins. Offers are checked by `offer_owned_by` along with their
+    /// details.
+    fn check_update_access(
+        &self,
+        user: Option<&ID>,
+        id: &ID,
+        item_update: &ItemUpdate,
+    ) -> Result<Option<msgs::Error>, Error> {
+        if self.is_admin(user)? {
+            return Ok(None);
+        }
+        let allowed = match item_update {
+            ItemUpdate::Offer(_) => true,
+            ItemUpdate::Transfer(_) | ItemUpdate::Void => {
+                match self.db.select::<IOUTable>().try_by_id(id)? {
+                    Some(r) => Some(&r.fields.iou_holder) == user,
+                    None => return Ok(Some(msgs::Error::NotFound { id: id.0.clone() })),
+                }
+            }
+            ItemUpdate::DisplayName(_) => Some(id) == user,
+            ItemUpdate::UserLock { .. }
+            | ItemUpdate::CreditLimit { .. }
+            | ItemUpdate::Resolve { .. }
+            | ItemUpdate::Close
+            | ItemUpdate::AttestIdentity { .. }
+            | ItemUpdate::PredValue { .. } => false,
+        };
+        if allowed {
+            Ok(None)
+        } else {
+            Ok(Some(msgs::Error::AccessDenied))
+        }
+    }
+
+    /// Whether the user may do what only the market's operators can. With
+    /// no user the request comes from the command line, or from a server
+    /// run without authentication for local use, and is trusted.
+    fn is_admin(&self, user: Option<&ID>) -> Result<bool, Error> {
+        match user {
+            None => Ok(true),
+            Some(user) => self.db.select::<UserTable>().is_admin(user),
+        }
+    }
+
+    /// Let a user do what only the market's operators can, or stop them.
+    pub fn set_user_admin(
+        &mut self,
+        user_id: &ID,
+        user_admin: bool,
+        time: Timesecs,
+    ) -> Result<(), Error> {
+        self.db
+            .update::<UserTable>()
+            .set_admin(user_id, user_admin, time)
+    }
+
+    /// Whether an offer can be changed by the user, which any offer can be
+    /// when there is no user.
+    fn offer_owned_by(&self, user: Option<&ID>, offer_id: &ID) -> Result<bool, Error> {
+        match user {
+            None => Ok(true),
+            Some(user) => {
+                let r = self.db.select::<OfferTable>().by_id(offer_id)?;
+                Ok(&r.fields.offer_user == user)
+            }
+        }
+    }
+
+    /// Make a new key for the user to sign requests with.
+    pub fn create_api_key(&mut self, user_id: &ID, time: Timesecs) -> Result<ApiKeyRow, Error> {
+        // fails if there is no such user
+        self.db.select::<UserTable>().by_id(user_id)?;
+        let key = ApiKeyRow {
+            key_id: self.id_gen.next_id().0,
+            key_user: user_id.clone(),
+            key_secret: format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple()),
+            creation_time: Timespec::from(time),
+        };
+        self.db.insert::<ApiKeyTable>(&key)?;
+        Ok(key)
+    }
+
+    fn select_page<T, R>(
+        &mut self,
+        limit: Option<u32>,
+        offset: u32,
+        order: Order,
+    ) -> Result<Response, Error>
+    where
+        T: Table<TableRow = Record<R>>,
+        R: ToItem,
+    {
+        let limit = cmp::min(limit.unwrap_or(DEFAULT_PAGE_LIMIT), MAX_PAGE_LIMIT);
+        // fetch one extra row to find out if there is another page
+        let mut records = self.db.select::<T>().all_paged(limit + 1, offset, order)?;
+        let more = records.len() > limit as usize;
+        records.truncate(limit as usize);
+        let items = records.into_iter().map(to_item).collect();
+        Ok(Response::Page { items, more })
+    }
+
+    fn changed_since<T, R>(&mut self, since: Timesecs, time: Timesecs) -> Result<Response, Error>
+    where
+        T: Table<TableRow = Record<R>>,
+        R: ToItem,
+    {
+        // void rows are updated rather than deleted so they are included too
+        let records = self
+            .db
+            .select::<T>()
+            .changed_since(since)?
+            .into_iter()
+            .map(to_record)
+            .collect();
+        // the client can use our time as its next cursor to avoid clock skew
+        Ok(Response::Changed { records, time })
+    }
+
+    fn count<T: Table>(&mut self) -> Result<Response, Error> {
+        let count = self.db.select::<T>().count()?;
+        Ok(Response::Count(count))
+    }
+
+    fn select_fields<T: Table>(&mut self, fields: &[String]) -> Result<Response, Error> {
+        if let Some(field) = fields.iter().find(|f| !T::COLUMNS.contains(&f.as_str())) {
+            return Ok(Response::Error(msgs::Error::UnknownField {
+                field: field.clone(),
+            }));
+        }
+        let columns: Vec<&str> = fields.iter().map(String::as_str).collect();
+        let rows = self.db.select::<T>().columns_where(&columns, "1", &[])?;
+        let mut items = HashMap::new();
+        for row in rows {
+            let mut values = row.into_iter();
+            let id = match values.next() {
+                Some(Value::Text(id)) => ID(id),
+                _ => return Err(err_msg("expected a text id")),
+            };
+            let item = fields.iter().cloned().zip(values.map(json_value)).collect();
+            items.insert(id, item);
+        }
+        Ok(Response::Fields(items))
+    }
+
+    /// Answer a query inside a savepoint of its own. Outside of a request
+    /// that starts a deferred transaction, so whatever the query reads comes
+    /// from one snapshot even while other connections are writing.
+    pub fn do_query(&mut self, query: Query, time: Timesecs) -> Result<Response, Error> {
+        self.db.execute_batch("SAVEPOINT query")?;
+        let result = self.answer_query(query, time);
+        match result {
+            Ok(_) => self.db.execute_batch("RELEASE query")?,
+            Err(_) => self.db.execute_batch("ROLLBACK TO query; RELEASE query")?,
+        }
+        result
+    }
+
+    fn answer_query(&mut self, query: Query, time: Timesecs) -> Result<Response, Error> {
+        match &query {
+            Query::ChangedSince { since, .. } | Query::AuditLog { since, .. } => {
+                if !since.in_range() {
+                    return Ok(Response::Error(msgs::Error::InvalidTime));
+                }
+            }
+            _ => {}
+        }
+        match query {
+            Query::AllUser => {
+                // FIXME access control
+                let items = self.select_all_user()?.into_iter().map(to_item).collect();
+                Ok(Response::Items(items))
+            }
+            Query::AllIdentity => {
+                // FIXME access control
+                let items = self
+                    .select_all_identity()?
+                    .into_iter()
+                    .map(to_item)
+                    .collect();
+                Ok(Response::Items(items))
+            }
+            Query::AllIOU => {
+                // FIXME access control
+                let items = self.select_all_iou()?.into_iter().map(to_item).collect();
+                Ok(Response::Items(items))
+            }
+            Query::UnconditionalIOU => {
+                // FIXME access control
+                let items = self
+                    .db
+                    .select::<IOUTable>()
+                    .unconditional()?
+                    .into_iter()
+                    .map(to_item)
+                    .collect();
+                Ok(Response::Items(items))
+            }
+            Query::ConditionalIOU => {
+                // FIXME access control
+                let items = self
+                    .db
+                    .select::<IOUTable>()
+                    .conditional()?
+                    .into_iter()
+                    .map(to_item)
+                    .collect();
+                Ok(Response::Items(items))
+            }
+            Query::AllCond => {
+                // FIXME access control
+                let items = self.select_all_cond()?.into_iter().map(to_item).collect();
+                Ok(Response::Items(items))
+            }
+            Query::AllOffer => {
+                // FIXME access control
+                let items = self
+                    .db
+                    .select::<OfferTable>()
+                    .all()?
+                    .into_iter()
+                    .map(to_item)
+                    .collect();
+                Ok(Response::Items(items))
+            }
+            Query::AllEntity => {
+                // FIXME access control
+                let items = self.select_all_entity()?.into_iter().map(to_item).collect();
+                Ok(Response::Items(items))
+            }
+            Query::AllRel => {
+                // FIXME access control
+                let items = self.select_all_rel()?.into_iter().map(to_item).collect();
+                Ok(Response::Items(items))
+            }
+            Query::AllPred => {
+                // FIXME access control
+                let items = self.select_all_pred()?.into_iter().map(to_item).collect();
+                Ok(Response::Items(items))
+            }
+            Query::AllDepend => {
+                // FIXME access control
+                let items = self.select_all_depend()?.into_iter().map(to_item).collect();
+                Ok(Response::Items(items))
+            }
+            Query::Page {
+                item_type,
+                limit,
+                offset,
+                order,
+            } => {
+                // FIXME access control
+                match item_type {
+                    ItemType::User => self.select_page::<UserTable, User>(limit, offset, order),
+                    ItemType::IOU => self.select_page::<IOUTable, IOU>(limit, offset, order),
+                    ItemType::Cond => self.select_page::<CondTable, Cond>(limit, offset, order),
+                    ItemType::Offer => self.select_page::<OfferTable, Offer>(limit, offset, order),
+                    ItemType::Entity => {
+                        self.select_page::<EntityTable, Entity>(limit, offset, order)
+                    }
+                    ItemType::Rel => self.select_page::<RelTable, Rel>(limit, offset, order),
+                    ItemType::Pred => self.select_page::<PredTable, Pred>(limit, offset, order),
+                    ItemType::Depend => {
+                        self.select_page::<DependTable, Depend>(limit, offset, order)
+                    }
+                }
+            }
+            Query::Count(item_type) => {
+                // FIXME access control
+                match item_type {
+                    ItemType::User => self.count::<UserTable>(),
+                    ItemType::IOU => self.count::<IOUTable>(),
+                    ItemType::Cond => self.count::<CondTable>(),
+                    ItemType::Offer => self.count::<OfferTable>(),
+                    ItemType::Entity => self.count::<EntityTable>(),
+                    ItemType::Rel => self.count::<RelTable>(),
+                    ItemType::Pred => self.count::<PredTable>(),
+                    ItemType::Depend => self.count::<DependTable>(),
+                }
+            }
+            Query::Fields { item_type, fields } => {
+                // FIXME access control
+                match item_type {
+                    ItemType::User => self.select_fields::<UserTable>(&fields),
+                    ItemType::IOU => self.select_fields::<IOUTable>(&fields),
+                    ItemType::Cond => self.select_fields::<CondTable>(&fields),
+                    ItemType::Offer => self.select_fields::<OfferTable>(&fields),
+                    ItemType::Entity => self.select_fields::<EntityTable>(&fields),
+                    ItemType::Rel => self.select_fields::<RelTable>(&fields),
+                    ItemType::Pred => self.select_fields::<PredTable>(&fields),
+                    ItemType::Depend => self.select_fields::<DependTable>(&fields),
+                }
+            }
+            Query::CountOfferByCond(cond_id) => {
+                // FIXME access control
+                let count = self.db.select::<OfferTable>().count_by_cond(&cond_id)?;
+                Ok(Response::Count(count))
+            }
+            Query::CondByPred(pred_id) => {
+                // FIXME access control
+                let items = self
+                    .db
+                    .select::<CondTable>()
+                    .by_pred(&pred_id)?
+                    .into_iter()
+                    .map(to_item)
+                    .collect();
+                Ok(Response::Items(items))
+            }
+            Query::DependFor(pred_id) => {
+                // FIXME access control
+                let items = self
+                    .db
+                    .select::<DependTable>()
+                    .by_pred(&pred_id)?
+                    .into_iter()
+                    .map(to_item)
+                    .collect();
+                Ok(Response::ItemList(items))
+            }
+            Query::EntityByName(entity_name) => {
+                // FIXME access control
+                match self
+                    .db
+                    .select::<EntityTable>()
+                    .by_entity_name(&entity_name)?
+                {
+                    Some(r) => Ok(Response::Items(single_item(r.id, r.fields))),
+                    None => Ok(Response::Error(msgs::Error::NotFound { id: entity_name })),
+                }
+            }
+            Query::ChangedSince { item_type, since } => {
+                // FIXME access control
+                match item_type {
+                    ItemType::User => self.changed_since::<UserTable, User>(since, time),
+                    ItemType::IOU => self.changed_since::<IOUTable, IOU>(since, time),
+                    ItemType::Cond => self.changed_since::<CondTable, Cond>(since, time),
+                    ItemType::Offer => self.changed_since::<OfferTable, Offer>(since, time),
+                    ItemType::Entity => self.changed_since::<EntityTable, Entity>(since, time),
+                    ItemType::Rel => self.changed_since::<RelTable, Rel>(since, time),
+                    ItemType::Pred => self.changed_since::<PredTable, Pred>(since, time),
+                    ItemType::Depend => self.changed_since::<DependTable, Depend>(since, time),
+                }
+            }
+            Query::Exposure(user_id) => {
+                // FIXME access control
+                Ok(Response::Exposure(self.user_exposure(&user_id)?))
+            }
+            Query::OfferByUser(user_id) => {
+                // FIXME access control
+                let items = self
+                    .db
+                    .select::<OfferTable>()
+                    .by_user(&user_id, time)?
+                    .into_iter()
+                    .map(to_item)
+                    .collect();
+                Ok(Response::ItemList(items))
+            }
+            Query::EntitiesByProp { prop_id, value } => {
+                // FIXME access control
+                let mut items = HashMap::new();
+                for prop in self.db.select::<PropTable>().by_prop(&prop_id, &value)? {
+                    if let Some(r) = self.db.select::<EntityTable>().try_by_id(&prop.entity_id)? {
+                        items.insert(r.id, r.fields.to_item());
+                    }
+                }
+                Ok(Response::Items(items))
+            }
+            Query::EntityGraph(entity_type) => {
+                // FIXME access control
+                Ok(Response::EntityGraph(
+                    self.entities_with_rels(&entity_type)?,
+                ))
+            }
+            Query::AsOf { as_of, query } => self.query_as_of(*query, as_of),
+            Query::Multi(queries) => {
+                // the savepoint spans every query, so a writer committing
+                // part way through doesn't show up in later ones
+                let responses = queries
+                    .into_iter()
+                    .map(|query| self.answer_query(query, time))
+                    .collect::<Result<_, _>>()?;
+                Ok(Response::MultiItems(responses))
+            }
+            Query::RelTypes => {
+                let rel_types = self
+                    .db
+                    .select::<RelTypeTable>()
+                    .in_order()?
+                    .into_iter()
+                    .map(|r| r.rel_type)
+                    .collect();
+                Ok(Response::RelTypes(rel_types))
+            }
+            Query::Balances => {
+                // FIXME access control
+                Ok(Response::Balances(self.balances(time)?))
+            }
+            Query::Settlement => {
+                // FIXME access control
+                Ok(Response::Settlement(self.settlement_report()?))
+            }
+            Query::Book(cond_id) => {
+                // FIXME access control
+                Ok(Response::Book(self.order_book(&cond_id, time)?))
+            }
+            Query::AuditLog { since, limit } => {
+                // FIXME access control
+                let limit = cmp::min(limit.unwrap_or(DEFAULT_PAGE_LIMIT), MAX_PAGE_LIMIT);
+                let entries = self
+                    .db
+                    .select::<LogTable>()
+                    .since(since, limit)?
+                    .into_iter()
+                    .map(|r| LogEntry {
+                        time: Timesecs::from(r.log_time),
+                        user: r.log_user,
+                        request: r.log_request,
+                        status: r.log_status,
+                    })
+                    .collect();
+                Ok(Response::AuditLog(entries))
+            }
+        }
+    }
+
+    /// Process a request and record it in the audit log. The log entry is
+    /// written in the same transaction as any changes the request makes, so
+    /// a request that fails with an error leaves neither behind.
+    ///
+    /// If the database is busy the whole request is rolled back and tried
+    /// again, up to the configured number of retries. Nothing outside the
+    /// database is changed until it commits, so this is always safe.
     pub fn do_request(&mut self, request: Request) -> Result<Response, Error> {
-        let time = Timesecs::now();
+        self.do_request_as(None, request)
+    }
+
+    /// Process a request on behalf of an authenticated user.
+    pub fn do_request_as(&mut self, user: Option<ID>, request: Request) -> Result<Response, Error> {
+        let time = self.request_time.unwrap_or_else(Timesecs::now);
+        self.do_request_at(user, request, time)
+    }
+
+    /// Process a request as if it were made at the given time, which is
+    /// used for creation and update times and written to the log.
+    pub fn do_request_at(
+        &mut self,
+        user: Option<ID>,
+        request: Request,
+        time: Timesecs,
+    ) -> Result<Response, Error> {
+        if self.maintenance && request.is_mutation() {
+            // turned away before the database is touched at all
+            return Ok(Response::Error(msgs::Error::MaintenanceMode));
+        }
+        if self.read_only && request.writes() {
+            // rather than failing in SQLite with an internal error
+            return Ok(Response::Error(msgs::Error::ReadOnly));
+        }
+        let log_request = serde_json::to_string(&request)?;
+        let mut backoff = BUSY_BACKOFF;
+        let mut retries = 0;
+        loop {
+            match self.try_request(user.as_ref(), request.clone(), &log_request, time) {
+                Err(ref err) if is_busy(err) && retries < self.busy_retries => {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn try_request(
+        &mut self,
+        user: Option<&ID>,
+        request: Request,
+        log_request: &str,
+        time: Timesecs,
+    ) -> Result<Response, Error> {
+        let record_event = self.record_events && request.is_mutation() && !self.read_only;
+        self.id_gen.issued.clear();
+        // a savepoint rather than a transaction, as requests use their own
+        self.db.execute_batch("SAVEPOINT request")?;
+        // a panic is rolled back like any other error, so one bad request
+        // can't leave the savepoint open or take the server down with it
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.dispatch(user, request, time).and_then(|response| {
+                if self.read_only {
+                    // there is nowhere to keep a log
+                    return Ok(response);
+                }
+                let log = LogRow {
+                    log_time: Timespec::from(time),
+                    log_user: user.cloned(),
+                    log_request: String::from(log_request),
+                    log_status: response.status(),
+                };
+                self.db.insert::<LogTable>(&log)?;
+                if record_event {
+                    let event = EventRow {
+                        event_time: time,
+                        event_user: user.cloned(),
+                        event_request: String::from(log_request),
+                        event_ids: serde_json::to_string(&self.id_gen.issued)?,
+                    };
+                    self.db.insert::<EventTable>(&event)?;
+                }
+                Ok(response)
+            })
+        }))
+        .unwrap_or_else(|cause| Err(format_err!("request panicked: {}", panic_message(&*cause))));
+        let result = match result {
+            // releasing the outermost savepoint commits, which can find the
+            // database busy, and the request must not stay open if it does
+            Ok(response) => self
+                .db
+                .execute_batch("RELEASE request")
+                .map(|_| response)
+                .map_err(Error::from),
+            Err(err) => Err(err),
+        };
+        if result.is_err() {
+            self.db
+                .execute_batch("ROLLBACK TO request; RELEASE request")?;
+        }
+        result
+    }
+
+    fn dispatch(
+        &mut self,
+        user: Option<&ID>,
+        request: Request,
+        time: Timesecs,
+    ) -> Result<Response, Error> {
         match request {
-            Request::Create(item) => match self.do_create(item, time)? {
+            Request::Create(item) => match self.do_create(user, item, time)? {
                 Ok(id) => Ok(Response::Created(id)),
                 Err(err) => Ok(Response::Error(err)),
             },
-            Request::Update { id, item_update } => self.do_update(id, item_update, time),
-            Request::Query(query) => self.do_query(query),
+            Request::CreateOffers {
+                offers,
+                best_effort,
+            } => {
+                let results = self.do_create_offers(user, offers, best_effort, time)?;
+                Ok(Response::CreatedOffers(results))
+            }
+            Request::CreateEcho(item) => {
+                // items are stored just as they were given
+                match self.do_create(user, item.clone(), time)? {
+                    Ok(id) => Ok(Response::CreatedItem {
+                        id,
+                        item,
+                        creation_time: time,
+                    }),
+                    Err(err) => Ok(Response::Error(err)),
+                }
+            }
+            Request::Validate(item) => match self.do_validate(user, item, time)? {
+                Ok(()) => Ok(Response::Updated),
+                Err(err) => Ok(Response::Error(err)),
+            },
+            Request::IncrementCredit { amount } => {
+                if !self.is_admin(user)? {
+                    return Ok(Response::Error(msgs::Error::AccessDenied));
+                }
+                match self.increment_all_credit(amount, time)? {
+                    Ok(count) => Ok(Response::Count(count as i64)),
+                    Err(err) => Ok(Response::Error(err)),
+                }
+            }
+            Request::Update { id, item_update } => self.do_update(user, id, item_update, time),
+            Request::Accept {
+                user: taker,
+                offer_id,
+                quantity,
+                side,
+            } => {
+                if user.map_or(false, |user| user != &taker) {
+                    return Ok(Response::Error(msgs::Error::AccessDenied));
+                }
+                match self.do_accept(&taker, &offer_id, quantity, side, time)? {
+                    Ok((buyer_iou, seller_iou)) => Ok(Response::Accepted {
+                        buyer_iou,
+                        seller_iou,
+                    }),
+                    Err(err) => Ok(Response::Error(err)),
+                }
+            }
+            Request::Clear {
+                cond_id,
+                max_iterations,
+                check_credit,
+            } => {
+                if !self.is_admin(user)? {
+                    return Ok(Response::Error(msgs::Error::AccessDenied));
+                }
+                if !has_id::<CondTable>(&self.db, "cond_id", &cond_id)? {
+                    return Ok(Response::Error(msgs::Error::NotFound { id: cond_id.0 }));
+                }
+                let mut options = ClearingOptions::default();
+                if let Some(max_iterations) = max_iterations {
+                    options.max_iterations = max_iterations;
+                }
+                options.check_credit = check_credit;
+                let clearing = self.run_clearing(&cond_id, &options, time)?;
+                Ok(Response::Traded {
+                    trades: clearing.trades,
+                    complete: clearing.complete,
+                })
+            }
+            Request::Delete { id } => {
+                if !self.is_admin(user)? {
+                    return Ok(Response::Error(msgs::Error::AccessDenied));
+                }
+                match self.do_delete(&id)? {
+                    Ok(()) => Ok(Response::Updated),
+                    Err(err) => Ok(Response::Error(err)),
+                }
+            }
+            Request::AddRelType { rel_type } => {
+                if !self.is_admin(user)? {
+                    return Ok(Response::Error(msgs::Error::AccessDenied));
+                }
+                // adding a type twice is harmless
+                if !self.db.select::<RelTypeTable>().has(&rel_type)? {
+                    let row = RelTypeRow {
+                        rel_type,
+                        creation_time: Timespec::from(time),
+                    };
+                    self.db.insert::<RelTypeTable>(&row)?;
+                }
+                Ok(Response::Updated)
+            }
+            Request::Maintenance { enabled } => {
+                if !self.is_admin(user)? {
+                    return Ok(Response::Error(msgs::Error::AccessDenied));
+                }
+                self.maintenance = enabled;
+                Ok(Response::Updated)
+            }
+            Request::Query(query) => self.do_query(query, time),
+        }
+    }
+}
+
+/// Whether every time in an item is one that can be stored.
+fn item_times_in_range(item: &Item) -> bool {
+    let times = match item {
+        Item::Identity(identity) => vec![Some(identity.identity_attested_time)],
+        Item::IOU(iou) => vec![iou.iou_cond_time],
+        Item::Cond(cond) => vec![cond.cond_arg_time],
+        Item::Offer(offer) => vec![offer.offer_cond_time, offer.offer_expiry],
+        _ => vec![],
+    };
+    times.into_iter().flatten().all(Timesecs::in_range)
+}
+
+fn item_amounts_in_range(item: &Item) -> bool {
+    let amounts = match item {
+        Item::User(user) => vec![user.user_credit_limit],
+        Item::IOU(iou) => vec![iou.iou_value],
+        Item::Offer(offer) => vec![
+            offer.offer_details.offer_buy_price,
+            offer.offer_details.offer_sell_price,
+        ],
+        _ => vec![],
+    };
+    amounts.into_iter().all(Dollars::in_range)
+}
+
+/// Whether two databases have the same rows in a table, in the same order.
+fn same_rows<T: Table>(a: &Connection, b: &Connection) -> Result<bool, Error>
+where
+    T::TableRow: fmt::Debug,
+{
+    let rows =
+        |db: &Connection| -> Result<String, Error> { Ok(format!("{:?}", db.select::<T>().all()?)) };
+    Ok(rows(a)? == rows(b)?)
+}
+
+fn panic_message(cause: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = cause.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = cause.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown cause"
+    }
+}
+
+fn has_id<T: Table>(db: &Connection, id_column: &str, id: &ID) -> Result<bool, Error> {
+    let query = format!("{} = ?1", id_column);
+    Ok(db.select::<T>().count_where(&query, &[id])? > 0)
+}
+
+fn delete_unused<T: Table>(
+    db: &Connection,
+    id_column: &str,
+    id: &ID,
+    refs: i64,
+) -> Result<Result<(), msgs::Error>, Error> {
+    if refs > 0 {
+        return Ok(Err(msgs::Error::InUse));
+    }
+    let query = format!("{} = ?1", id_column);
+    db.update::<T>().delete_one(&query, &[id])?;
+    Ok(Ok(()))
+}
+
+/// Whether the user's exposure stays within their credit limit with the new
+/// IOUs on top of the live ones they hold or issued. Their limit and live
+/// IOUs are loaded into the ledger the first time, for the caller to add
+/// to as it issues more.
+fn within_credit(
+    db: &Connection,
+    ledger: &mut HashMap<ID, (Dollars, Vec<IOU>)>,
+    user: &ID,
+    new_ious: &[IOU],
+) -> Result<bool, Error> {
+    if !ledger.contains_key(user) {
+        let limit = db
+            .select::<UserTable>()
+            .by_id(user)?
+            .fields
+            .user_credit_limit;
+        let ious = db
+            .select::<IOUTable>()
+            .live_by_user(user)?
+            .into_iter()
+            .map(|r| r.fields)
+            .collect();
+        ledger.insert(user.clone(), (limit, ious));
+    }
+    let (limit, ious) = &ledger[user];
+    let mut ious = ious.clone();
+    ious.extend_from_slice(new_ious);
+    let exposure = Dollars::checked_sum(types::exposure(user, &ious)?.values().cloned())?;
+    Ok(exposure <= *limit)
+}
+
+/// Open quantity at each price over the offers. Prices with nothing left
+/// on a side are left out of it.
+fn book_depth(offers: Vec<Record<Offer>>) -> BookDepth {
+    let mut book = BookDepth::default();
+    for r in offers {
+        let offer = r.fields;
+        let buy_quantity = offer.open_quantity(Side::Buy);
+        if buy_quantity > 0 {
+            *book
+                .bids
+                .entry(offer.offer_details.offer_buy_price)
+                .or_insert(0) += buy_quantity;
+        }
+        let sell_quantity = offer.open_quantity(Side::Sell);
+        if sell_quantity > 0 {
+            *book
+                .asks
+                .entry(offer.offer_details.offer_sell_price)
+                .or_insert(0) += sell_quantity;
+        }
+    }
+    book
+}
+
+/// A column value as JSON, for queries that ask for fields rather than items.
+fn json_value(value: Value) -> serde_json::Value {
+    match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Integer(i) => serde_json::Value::from(i),
+        Value::Real(f) => serde_json::Value::from(f),
+        Value::Text(s) => serde_json::Value::from(s),
+        Value::Blob(b) => serde_json::Value::from(b),
+    }
+}
+
+fn to_item<T: ToItem>(record: Record<T>) -> (ID, Item) {
+    (record.id, record.fields.to_item())
+}
+
+fn to_record<T: ToItem>(record: Record<T>) -> (ID, ItemRecord) {
+    let item_record = ItemRecord {
+        item: record.fields.to_item(),
+        creation_time: Timesecs::from(record.creation_time),
+        updated_time: Timesecs::from(record.updated_time),
+    };
+    (record.id, item_record)
+}
+
+/// Whether `to` can be reached from `from` by following depend edges.
+fn depend_reaches(depends: &[Record<Depend>], from: &ID, to: &ID) -> bool {
+    let mut seen = HashSet::new();
+    let mut pending = vec![from];
+    while let Some(pred) = pending.pop() {
+        if pred == to {
+            return true;
+        }
+        if seen.insert(pred) {
+            for r in depends {
+                if &r.fields.depend_pred1 == pred {
+                    pending.push(&r.fields.depend_pred2);
+                }
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+fn test_market() -> Market {
+    Market::create_new_in_memory().unwrap()
+}
+
+#[cfg(test)]
+fn create_item(market: &mut Market, item: Item) -> ID {
+    match market.do_request(Request::Create(item)).unwrap() {
+        Response::Created(id) => id,
+        _ => panic!("expected ID!"),
+    }
+}
+
+#[cfg(test)]
+fn create_user(market: &mut Market, user_name: &str) -> ID {
+    let user = User {
+        user_name: String::from(user_name),
+        user_locked: false,
+        user_credit_limit: Dollars::ZERO,
+        user_display_name: None,
+    };
+    create_item(market, Item::User(user))
+}
+
+#[cfg(test)]
+fn set_user_locked(market: &mut Market, id: &ID, user_locked: bool) {
+    let request = Request::Update {
+        id: id.clone(),
+        item_update: ItemUpdate::UserLock { user_locked },
+    };
+    match market.do_request(request).unwrap() {
+        Response::Items(_) => {}
+        _ => panic!("expected Items!"),
+    }
+}
+
+#[cfg(test)]
+fn set_credit_limit(market: &mut Market, id: &ID, millibucks: i64) {
+    let request = Request::Update {
+        id: id.clone(),
+        item_update: ItemUpdate::CreditLimit {
+            user_credit_limit: Dollars::from_millibucks(millibucks),
+        },
+    };
+    match market.do_request(request).unwrap() {
+        Response::Items(_) => {}
+        _ => panic!("expected Items!"),
+    }
+}
+
+#[cfg(test)]
+fn new_iou(issuer: &ID, holder: &ID) -> IOU {
+    IOU {
+        iou_issuer: issuer.clone(),
+        iou_holder: holder.clone(),
+        iou_value: types::Dollars::from_millibucks(1000),
+        iou_cond_id: None,
+        iou_cond_flag: false,
+        iou_cond_time: None,
+        iou_split: None,
+        iou_void: false,
+        iou_memo: None,
+    }
+}
+
+#[cfg(test)]
+fn create_error(market: &mut Market, item: Item) -> Option<msgs::Error> {
+    match market.do_request(Request::Create(item)).unwrap() {
+        Response::Created(_) => None,
+        Response::Error(err) => Some(err),
+        _ => panic!("expected ID!"),
+    }
+}
+
+#[test]
+fn id_formats() {
+    let mut market = test_market();
+    let simple = create_user(&mut market, "simple");
+    assert_eq!(simple.0.len(), 32);
+    for &(id_format, prefix, len) in &[
+        (IdFormat::Simple, "", 32),
+        (IdFormat::Hyphenated, "", 36),
+        (IdFormat::Urn, "urn:uuid:", 45),
+        (IdFormat::TimeOrdered, "", 36),
+    ] {
+        market.set_id_format(id_format);
+        let user_name = format!("{:?}", id_format);
+        let id = create_user(&mut market, &user_name);
+        assert!(id.0.starts_with(prefix), "{}", id.0);
+        assert_eq!(id.0.len(), len);
+        assert!(Uuid::parse_str(&id.0).is_ok());
+        let stored = market.db.select::<UserTable>().by_id(&id).unwrap();
+        assert_eq!(stored.id, id);
+        assert_eq!(stored.fields.user_name, user_name);
+        // ids made before the format changed still work as they are
+        let iou = Item::IOU(new_iou(&simple, &id));
+        assert_eq!(create_error(&mut market, iou), None);
+    }
+}
+
+#[test]
+fn time_ordered_ids() {
+    let random = Uuid::new_v4();
+    let early = time_ordered(&random, Timespec::new(1_500_000_000, 0));
+    let late = time_ordered(&random, Timespec::new(1_500_000_000, 1_000_000));
+    assert!(early.hyphenated().to_string() < late.hyphenated().to_string());
+    assert!(early
+        .hyphenated()
+        .to_string()
+        .starts_with("015d3ef7-9800-7"));
+    assert_eq!(early.get_version_num(), 7);
+    assert_eq!(&early.as_bytes()[7..], &random.as_bytes()[7..]);
+}
+
+#[test]
+fn user_name_conflicts() {
+    let mut market = test_market();
+    create_user(&mut market, "MrFoo");
+    let user = |user_name: &str| {
+        Item::User(User {
+            user_name: String::from(user_name),
+            user_locked: false,
+            user_credit_limit: Dollars::ZERO,
+            user_display_name: None,
+        })
+    };
+    let taken = msgs::Error::UserNameTaken {
+        name: String::from("MrFoo"),
+    };
+    let conflict = msgs::Error::UserNameStrippedConflict {
+        name: String::from("MrFoo"),
+    };
+    assert_eq!(create_error(&mut market, user("MrFoo")), Some(taken));
+    assert_eq!(create_error(&mut market, user("Mr.Foo")), Some(conflict));
+    let conflict = msgs::Error::UserNameStrippedConflict {
+        name: String::from("MrFoo"),
+    };
+    assert_eq!(create_error(&mut market, user("mr_foo")), Some(conflict));
+    assert_eq!(create_error(&mut market, user("Mr.Foo2")), None);
+}
+
+#[cfg(test)]
+fn create_offers(market: &mut Market, offers: Vec<Offer>, best_effort: bool) -> Vec<(usize, bool)> {
+    let request = Request::CreateOffers {
+        offers,
+        best_effort,
+    };
+    match market.do_request(request).unwrap() {
+        Response::CreatedOffers(results) => results
+            .into_iter()
+            .map(|(i, result)| (i, result.is_ok()))
+            .collect(),
+        _ => panic!("expected CreatedOffers!"),
+    }
+}
+
+#[test]
+fn offer_quantity_checked() {
+    let mut market = test_market();
+    market.set_max_offer_quantity(Some(1000));
+    let foo = create_user(&mut market, "foo");
+    let cond_id = create_cond(&mut market);
+    let offer = |buy_quantity, sell_quantity| {
+        Item::Offer(Offer {
+            offer_user: foo.clone(),
+            offer_cond_id: cond_id.clone(),
+            offer_cond_time: None,
+            offer_details: types::OfferDetails {
+                offer_buy_price: Dollars::from_millibucks(400),
+                offer_sell_price: Dollars::from_millibucks(600),
+                offer_buy_quantity: types::Quantity::from_units(buy_quantity),
+                offer_sell_quantity: types::Quantity::from_units(sell_quantity),
+            },
+            offer_status: OfferStatus::Open,
+            offer_expiry: None,
+        })
+    };
+    assert_eq!(
+        create_error(&mut market, offer(0, 0)),
+        Some(msgs::Error::InvalidOfferDetails)
+    );
+    assert_eq!(
+        create_error(&mut market, offer(10, 1001)),
+        Some(msgs::Error::OfferTooLarge)
+    );
+    let offer_id = create_item(&mut market, offer(1000, 0));
+    let update = |buy_quantity| Request::Update {
+        id: offer_id.clone(),
+        item_update: ItemUpdate::Offer(types::OfferDetails {
+            offer_buy_price: Dollars::from_millibucks(400),
+            offer_sell_price: Dollars::from_millibucks(600),
+            offer_buy_quantity: types::Quantity::from_units(buy_quantity),
+            offer_sell_quantity: types::Quantity::ZERO,
+        }),
+    };
+    match market.do_request(update(5000)).unwrap() {
+        Response::Error(err) => assert_eq!(err, msgs::Error::OfferTooLarge),
+        _ => panic!("expected Error!"),
+    }
+    match market.do_request(update(500)).unwrap() {
+        Response::Updated => {}
+        _ => panic!("expected Updated!"),
+    }
+}
+
+#[test]
+fn bulk_offers() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let cond1 = create_cond(&mut market);
+    let pred = Pred {
+        pred_name: String::from("other"),
+        pred_args: types::ArgList::from(""),
+        pred_value: None,
+        pred_value_type: types::PredValueType::Bool,
+    };
+    let pred_id = create_item(&mut market, Item::Pred(pred));
+    let cond2 = create_item(
+        &mut market,
+        Item::Cond(Cond {
+            cond_pred: pred_id,
+            cond_args: vec![],
+            cond_arg_time: None,
+            cond_value: None,
+            cond_closed: false,
+        }),
+    );
+    let offer = |cond_id: &ID, buy_price: i64| Offer {
+        offer_user: foo.clone(),
+        offer_cond_id: cond_id.clone(),
+        offer_cond_time: None,
+        offer_details: types::OfferDetails {
+            offer_buy_price: Dollars::from_millibucks(buy_price),
+            offer_sell_price: Dollars::from_millibucks(600),
+            offer_buy_quantity: types::Quantity::from_units(10),
+            offer_sell_quantity: types::Quantity::from_units(10),
+        },
+        offer_status: OfferStatus::Open,
+        offer_expiry: None,
+    };
+    let count = |market: &mut Market| market.db.select::<OfferTable>().count().unwrap();
+    // the second offer's buy price is above its sell price
+    let offers = vec![offer(&cond1, 400), offer(&cond2, 700), offer(&cond2, 500)];
+    assert_eq!(
+        create_offers(&mut market, offers.clone(), false),
+        vec![(1, false)]
+    );
+    assert_eq!(count(&mut market), 0);
+    assert_eq!(
+        create_offers(&mut market, offers, true),
+        vec![(0, true), (1, false), (2, true)]
+    );
+    assert_eq!(count(&mut market), 2);
+    let offers = vec![offer(&cond1, 300), offer(&cond2, 300)];
+    assert_eq!(
+        create_offers(&mut market, offers, false),
+        vec![(0, true), (1, true)]
+    );
+    assert_eq!(count(&mut market), 4);
+}
+
+#[test]
+fn create_echo() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let request = Request::CreateEcho(Item::IOU(new_iou(&foo, &bar)));
+    match market.do_request(request).unwrap() {
+        Response::CreatedItem {
+            id,
+            item: Item::IOU(iou),
+            creation_time,
+        } => {
+            let r = market.db.select::<IOUTable>().by_id(&id).unwrap();
+            assert_eq!(Timesecs::from(r.creation_time), creation_time);
+            assert_eq!(iou.iou_issuer, foo);
+            assert_eq!(iou.iou_value, r.fields.iou_value);
+            assert!(!iou.iou_void);
+        }
+        _ => panic!("expected CreatedItem!"),
+    }
+    let request = Request::CreateEcho(Item::IOU(new_iou(&foo, &foo)));
+    match market.do_request(request).unwrap() {
+        Response::Error(err) => assert_eq!(err, msgs::Error::IOUSelf),
+        _ => panic!("expected Error!"),
+    }
+}
+
+#[test]
+fn validate_only() {
+    let mut market = test_market();
+    let user = |user_name: &str| {
+        Item::User(User {
+            user_name: String::from(user_name),
+            user_locked: false,
+            user_credit_limit: Dollars::ZERO,
+            user_display_name: None,
+        })
+    };
+    let validate =
+        |market: &mut Market, item| match market.do_request(Request::Validate(item)).unwrap() {
+            Response::Updated => None,
+            Response::Error(err) => Some(err),
+            _ => panic!("expected Updated or Error!"),
+        };
+    assert_eq!(validate(&mut market, user("foo")), None);
+    assert_eq!(market.db.select::<UserTable>().count().unwrap(), 0);
+    let foo = create_user(&mut market, "foo");
+    assert_eq!(
+        validate(&mut market, user("foo")),
+        Some(msgs::Error::UserNameTaken {
+            name: String::from("foo")
+        })
+    );
+    assert_eq!(
+        validate(&mut market, user("no spaces")),
+        Some(msgs::Error::InvalidUserName {
+            name: String::from("no spaces")
+        })
+    );
+    let cond_id = create_cond(&mut market);
+    let offer = |buy_quantity| {
+        Item::Offer(Offer {
+            offer_user: foo.clone(),
+            offer_cond_id: cond_id.clone(),
+            offer_cond_time: None,
+            offer_details: types::OfferDetails {
+                offer_buy_price: Dollars::from_millibucks(400),
+                offer_sell_price: Dollars::from_millibucks(600),
+                offer_buy_quantity: types::Quantity::from_units(buy_quantity),
+                offer_sell_quantity: types::Quantity::ZERO,
+            },
+            offer_status: OfferStatus::Open,
+            offer_expiry: None,
+        })
+    };
+    assert_eq!(
+        validate(&mut market, offer(0)),
+        Some(msgs::Error::InvalidOfferDetails)
+    );
+    assert_eq!(validate(&mut market, offer(10)), None);
+    assert_eq!(market.db.select::<UserTable>().count().unwrap(), 1);
+    assert_eq!(market.db.select::<OfferTable>().count().unwrap(), 0);
+}
+
+#[test]
+fn iou_users_checked() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let nobody = ID(String::from("nobody"));
+    let iou = |issuer: &ID, holder: &ID| Item::IOU(new_iou(issuer, holder));
+    assert_eq!(
+        create_error(&mut market, iou(&foo, &foo)),
+        Some(msgs::Error::IOUSelf)
+    );
+    assert_eq!(
+        create_error(&mut market, iou(&nobody, &bar)),
+        Some(msgs::Error::IssuerNotFound { id: nobody.clone() })
+    );
+    assert_eq!(
+        create_error(&mut market, iou(&foo, &nobody)),
+        Some(msgs::Error::HolderNotFound { id: nobody.clone() })
+    );
+    assert_eq!(create_error(&mut market, iou(&foo, &bar)), None);
+    set_user_locked(&mut market, &bar, true);
+    assert_eq!(
+        create_error(&mut market, iou(&foo, &bar)),
+        Some(msgs::Error::HolderLocked { id: bar.clone() })
+    );
+    assert_eq!(
+        create_error(&mut market, iou(&bar, &foo)),
+        Some(msgs::Error::IssuerLocked { id: bar.clone() })
+    );
+}
+
+#[test]
+fn transfer_to_unknown_user() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let iou_id = create_item(&mut market, Item::IOU(new_iou(&foo, &bar)));
+    let mut holders = HashMap::new();
+    holders.insert(foo.clone(), types::Dollars::from_millibucks(500));
+    holders.insert(
+        ID(String::from("nobody")),
+        types::Dollars::from_millibucks(500),
+    );
+    let request = Request::Update {
+        id: iou_id.clone(),
+        item_update: ItemUpdate::Transfer(Transfer { holders }),
+    };
+    match market.do_request(request).unwrap() {
+        Response::Error(err) => assert_eq!(
+            err,
+            msgs::Error::NotFound {
+                id: String::from("nobody")
+            }
+        ),
+        _ => panic!("expected NotFound"),
+    }
+    // nothing was written
+    assert_eq!(market.db.select::<IOUTable>().count().unwrap(), 1);
+    assert!(
+        !market
+            .db
+            .select::<IOUTable>()
+            .by_id(&iou_id)
+            .unwrap()
+            .fields
+            .iou_void
+    );
+}
+
+#[test]
+fn integrity_check() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let iou_id = create_item(&mut market, Item::IOU(new_iou(&foo, &bar)));
+    let old_iou = market
+        .db
+        .select::<IOUTable>()
+        .by_id(&iou_id)
+        .unwrap()
+        .fields;
+    let request = Request::Update {
+        id: iou_id.clone(),
+        item_update: ItemUpdate::Transfer(Transfer::partial(
+            &old_iou,
+            foo.clone(),
+            types::Dollars::from_millibucks(300),
+        )),
+    };
+    match market.do_request(request).unwrap() {
+        Response::Items(_) => (),
+        _ => panic!("expected Items!"),
+    }
+    assert!(market.integrity_problems().unwrap().is_empty());
+    // turn foreign keys off so that bad rows can be written
+    market
+        .db
+        .execute_batch("PRAGMA foreign_keys = OFF")
+        .unwrap();
+    market
+        .db
+        .execute("UPDATE iou SET iou_value = 1 WHERE iou_id = ?1", &[&iou_id])
+        .unwrap();
+    market
+        .db
+        .execute(
+            "UPDATE iou SET iou_cond_id = 'nocond' WHERE iou_split = ?1 AND iou_holder = ?2",
+            &[&iou_id, &bar],
+        )
+        .unwrap();
+    let problems = market.integrity_problems().unwrap();
+    assert_eq!(problems.len(), 3, "{:?}", problems);
+    assert!(problems[0].starts_with("iou row"));
+    assert!(problems[1].ends_with("is on missing cond nocond"));
+    assert!(problems[2].contains("is worth 1 but was split into 1000"));
+}
+
+#[test]
+fn multi_query_snapshot() {
+    use std::env;
+    use std::fs;
+    use std::process;
+    let path = env::temp_dir().join(format!("market-multi-{}.db", process::id()));
+    let _ = fs::remove_file(&path);
+    let mut market = Market::create_new(Connection::open(&path).unwrap()).unwrap();
+    // readers don't hold writers up in WAL mode, so nothing but the
+    // snapshot keeps their commits out of the Multi
+    market
+        .db
+        .query_row("PRAGMA journal_mode = WAL", &[], |_| ())
+        .unwrap();
+    // logging the query waits for the writer if it committed in between
+    market.set_busy_retries(10);
+    let writer_path = path.clone();
+    let writer = thread::spawn(move || {
+        let mut writer = Market::open_existing(Connection::open(&writer_path).unwrap()).unwrap();
+        writer.set_busy_retries(10);
+        for i in 0..200 {
+            create_user(&mut writer, &format!("user{}", i));
+        }
+    });
+    let query = Request::Query(Query::Multi(vec![Query::Count(ItemType::User); 50]));
+    let counts: Vec<i64> = match market.do_request(query).unwrap() {
+        Response::MultiItems(responses) => responses
+            .into_iter()
+            .map(|response| match response {
+                Response::Count(count) => count,
+                _ => panic!("expected Count!"),
+            })
+            .collect(),
+        _ => panic!("expected MultiItems!"),
+    };
+    writer.join().unwrap();
+    assert_eq!(counts.len(), 50);
+    assert!(counts.iter().all(|&count| count == counts[0]));
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn maintenance_mode() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let maintenance = |market: &mut Market, enabled| match market
+        .do_request(Request::Maintenance { enabled })
+        .unwrap()
+    {
+        Response::Updated => {}
+        _ => panic!("expected Updated!"),
+    };
+    maintenance(&mut market, true);
+    let bar = User {
+        user_name: String::from("bar"),
+        user_locked: false,
+        user_credit_limit: Dollars::ZERO,
+        user_display_name: None,
+    };
+    assert_eq!(
+        create_error(&mut market, Item::User(bar.clone())),
+        Some(msgs::Error::MaintenanceMode)
+    );
+    let lock = Request::Update {
+        id: foo.clone(),
+        item_update: ItemUpdate::UserLock { user_locked: true },
+    };
+    match market.do_request(lock).unwrap() {
+        Response::Error(err) => assert_eq!(err, msgs::Error::MaintenanceMode),
+        _ => panic!("expected MaintenanceMode!"),
+    }
+    match market.do_request(Request::Query(Query::AllUser)).unwrap() {
+        Response::Items(items) => assert_eq!(items.len(), 1),
+        _ => panic!("expected Items!"),
+    }
+    maintenance(&mut market, false);
+    assert_eq!(create_error(&mut market, Item::User(bar)), None);
+    // only admins can switch it
+    let enable = Request::Maintenance { enabled: true };
+    match market.do_request_as(Some(foo.clone()), enable).unwrap() {
+        Response::Error(err) => assert_eq!(err, msgs::Error::AccessDenied),
+        _ => panic!("expected AccessDenied!"),
+    }
+    market
+        .set_user_admin(&foo, true, Timesecs::from(100))
+        .unwrap();
+    let enable = Request::Maintenance { enabled: true };
+    match market.do_request_as(Some(foo), enable).unwrap() {
+        Response::Updated => {}
+        _ => panic!("expected Updated!"),
+    }
+}
+
+#[test]
+fn query_snapshot() {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::mpsc;
+    let path = env::temp_dir().join(format!("market-snapshot-{}.db", process::id()));
+    let _ = fs::remove_file(&path);
+    let mut market = Market::create_new(Connection::open(&path).unwrap()).unwrap();
+    market
+        .db
+        .query_row("PRAGMA journal_mode = WAL", &[], |_| ())
+        .unwrap();
+    let writer_path = path.clone();
+    let (done, writer_done) = mpsc::channel();
+    let writer = thread::spawn(move || {
+        let mut writer = Market::open_existing(Connection::open(&writer_path).unwrap()).unwrap();
+        writer.set_busy_retries(10);
+        for i in 0..100 {
+            create_user(&mut writer, &format!("user{}", i));
+        }
+        done.send(()).unwrap();
+    });
+    // each user is committed along with its log entry, so the two tables
+    // only disagree if a query sees half of a write
+    let query = Query::Multi(vec![
+        Query::Count(ItemType::User),
+        Query::AuditLog {
+            since: Timesecs::from(0),
+            limit: Some(MAX_PAGE_LIMIT),
+        },
+    ]);
+    loop {
+        let finished = writer_done.try_recv().is_ok();
+        let responses = match market.do_query(query.clone(), Timesecs::now()).unwrap() {
+            Response::MultiItems(responses) => responses,
+            _ => panic!("expected MultiItems!"),
+        };
+        match (&responses[0], &responses[1]) {
+            (Response::Count(users), Response::AuditLog(entries)) => {
+                assert_eq!(*users, entries.len() as i64);
+                if finished {
+                    assert_eq!(*users, 100);
+                    break;
+                }
+            }
+            _ => panic!("expected Count and AuditLog!"),
+        }
+    }
+    writer.join().unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn busy_retry() {
+    use std::env;
+    use std::fs;
+    use std::process;
+    let path = env::temp_dir().join(format!("market-busy-{}.db", process::id()));
+    let _ = fs::remove_file(&path);
+    let mut market = Market::create_new(Connection::open(&path).unwrap()).unwrap();
+    market.db.busy_timeout(Duration::from_millis(0)).unwrap();
+    let other = Connection::open(&path).unwrap();
+    other.execute_batch("BEGIN EXCLUSIVE").unwrap();
+    market.set_busy_retries(0);
+    let user = |user_name: &str| {
+        Request::Create(Item::User(User {
+            user_name: String::from(user_name),
+            user_locked: false,
+            user_credit_limit: Dollars::ZERO,
+            user_display_name: None,
+        }))
+    };
+    match market.do_request(user("foo")) {
+        Err(err) => assert!(is_busy(&err)),
+        Ok(_) => panic!("expected busy!"),
+    }
+    // the other connection lets go while the request is backing off
+    market.set_busy_retries(5);
+    let unlock = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(25));
+        other.execute_batch("COMMIT").unwrap();
+    });
+    match market.do_request(user("foo")).unwrap() {
+        Response::Created(_) => {}
+        _ => panic!("expected Created!"),
+    }
+    unlock.join().unwrap();
+    assert_eq!(market.db.select::<UserTable>().count().unwrap(), 1);
+    assert_eq!(market.db.select::<LogTable>().count().unwrap(), 1);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn busy_release() {
+    use std::env;
+    use std::fs;
+    use std::process;
+    let path = env::temp_dir().join(format!("market-release-{}.db", process::id()));
+    let _ = fs::remove_file(&path);
+    let mut market = Market::create_new(Connection::open(&path).unwrap()).unwrap();
+    market.db.busy_timeout(Duration::from_millis(0)).unwrap();
+    market.set_busy_retries(0);
+    // a reader in the middle of a transaction lets the request write, but
+    // not commit when it releases its savepoint
+    let other = Connection::open(&path).unwrap();
+    other
+        .execute_batch("BEGIN; SELECT count(*) FROM user;")
+        .unwrap();
+    match market.do_request(Request::Create(Item::User(User {
+        user_name: String::from("foo"),
+        user_locked: false,
+        user_credit_limit: Dollars::ZERO,
+        user_display_name: None,
+    }))) {
+        Err(err) => assert!(is_busy(&err)),
+        Ok(_) => panic!("expected busy!"),
+    }
+    other.execute_batch("COMMIT").unwrap();
+    // the failed request was rolled back rather than left open, so the next
+    // one commits by itself
+    create_user(&mut market, "bar");
+    let users: i64 = other
+        .query_row("SELECT count(*) FROM user", &[], |row| row.get(0))
+        .unwrap();
+    assert_eq!(users, 1);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn user_lock_toggle() {
+    let mut market = test_market();
+    let user_id = create_user(&mut market, "foo");
+    set_user_locked(&mut market, &user_id, true);
+    assert!(market.user_locked(&user_id).unwrap());
+    set_user_locked(&mut market, &user_id, false);
+    assert!(!market.user_locked(&user_id).unwrap());
+}
+
+#[test]
+fn credit_limits() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let grant = |market: &mut Market, amount| match market
+        .do_request(Request::IncrementCredit {
+            amount: Dollars::from_millibucks(amount),
+        })
+        .unwrap()
+    {
+        Response::Count(count) => Ok(count),
+        Response::Error(err) => Err(err),
+        _ => panic!("expected Count!"),
+    };
+    assert_eq!(grant(&mut market, 1000), Ok(2));
+    assert_eq!(grant(&mut market, 500), Ok(2));
+    assert_eq!(grant(&mut market, 0), Err(msgs::Error::InvalidCreditLimit));
+    let set = |market: &mut Market, amount| {
+        market
+            .do_update(
+                None,
+                bar.clone(),
+                ItemUpdate::CreditLimit {
+                    user_credit_limit: Dollars::from_millibucks(amount),
+                },
+                Timesecs::from(100),
+            )
+            .unwrap()
+    };
+    match set(&mut market, -1) {
+        Response::Error(err) => assert_eq!(err, msgs::Error::InvalidCreditLimit),
+        _ => panic!("expected Error!"),
+    }
+    match set(&mut market, 200) {
+        Response::Items(_) => {}
+        _ => panic!("expected Items!"),
+    }
+    let limit = |market: &mut Market, user_id| {
+        let r = market.db.select::<UserTable>().by_id(user_id).unwrap();
+        r.fields.user_credit_limit.to_millibucks()
+    };
+    assert_eq!(limit(&mut market, &foo), 1500);
+    assert_eq!(limit(&mut market, &bar), 200);
+}
+
+#[test]
+fn user_lock_enforced() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let pred = Pred {
+        pred_name: String::from("pred"),
+        pred_args: types::ArgList::from(""),
+        pred_value: None,
+        pred_value_type: types::PredValueType::Bool,
+    };
+    let pred_id = create_item(&mut market, Item::Pred(pred));
+    let cond = Cond {
+        cond_pred: pred_id,
+        cond_args: vec![],
+        cond_arg_time: None,
+        cond_value: None,
+        cond_closed: false,
+    };
+    let cond_id = create_item(&mut market, Item::Cond(cond));
+    set_user_locked(&mut market, &foo, true);
+    match market
+        .do_request(Request::Create(Item::IOU(new_iou(&foo, &bar))))
+        .unwrap()
+    {
+        Response::Error(msgs::Error::IssuerLocked { .. }) => {}
+        _ => panic!("expected IssuerLocked"),
+    }
+    let offer = Item::Offer(Offer {
+        offer_user: foo,
+        offer_cond_id: cond_id,
+        offer_cond_time: None,
+        offer_details: types::OfferDetails {
+            offer_buy_price: types::Dollars::from_millibucks(400),
+            offer_sell_price: types::Dollars::from_millibucks(600),
+            offer_buy_quantity: types::Quantity::from_units(10),
+            offer_sell_quantity: types::Quantity::from_units(10),
+        },
+        offer_status: OfferStatus::Open,
+        offer_expiry: None,
+    });
+    match market.do_request(Request::Create(offer)).unwrap() {
+        Response::Error(msgs::Error::UserLocked) => {}
+        _ => panic!("expected UserLocked"),
+    }
+}
+
+#[test]
+fn changed_since() {
+    let mut market = test_market();
+    let user = |user_name: &str| {
+        Item::User(User {
+            user_name: String::from(user_name),
+            user_locked: false,
+            user_credit_limit: Dollars::ZERO,
+            user_display_name: None,
+        })
+    };
+    let foo = market
+        .do_create(None, user("foo"), Timesecs::from(100))
+        .unwrap()
+        .unwrap();
+    market
+        .do_create(None, user("bar"), Timesecs::from(200))
+        .unwrap()
+        .unwrap();
+    let lock = ItemUpdate::UserLock { user_locked: true };
+    market
+        .do_update(None, foo.clone(), lock, Timesecs::from(300))
+        .unwrap();
+    let query = Query::ChangedSince {
+        item_type: ItemType::User,
+        since: Timesecs::from(250),
+    };
+    match market.do_query(query, Timesecs::from(400)).unwrap() {
+        Response::Changed { records, time } => {
+            assert_eq!(records.len(), 1);
+            assert_eq!(records[&foo].creation_time, Timesecs::from(100));
+            assert_eq!(records[&foo].updated_time, Timesecs::from(300));
+            assert_eq!(time, Timesecs::from(400));
+        }
+        _ => panic!("expected Changed!"),
+    }
+}
+
+#[test]
+fn response_times_are_secs() {
+    let mut market = test_market();
+    let foo = market
+        .do_create(
+            None,
+            Item::User(User {
+                user_name: String::from("foo"),
+                user_locked: false,
+                user_credit_limit: Dollars::ZERO,
+                user_display_name: None,
+            }),
+            Timesecs::from(100),
+        )
+        .unwrap()
+        .unwrap();
+    let query = Query::ChangedSince {
+        item_type: ItemType::User,
+        since: Timesecs::from(0),
+    };
+    let response = market.do_query(query, Timesecs::from(400)).unwrap();
+    let json = serde_json::to_value(&response).unwrap();
+    let changed = &json["Changed"];
+    assert_eq!(changed["time"], 400);
+    assert_eq!(changed["records"][&foo.0]["creation_time"], 100);
+    assert_eq!(changed["records"][&foo.0]["updated_time"], 100);
+}
+
+#[test]
+fn as_of_queries() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let cond_id = create_cond(&mut market);
+    let offer = |user: &ID, buy_quantity| {
+        Item::Offer(Offer {
+            offer_user: user.clone(),
+            offer_cond_id: cond_id.clone(),
+            offer_cond_time: None,
+            offer_details: types::OfferDetails {
+                offer_buy_price: Dollars::from_millibucks(400),
+                offer_sell_price: Dollars::from_millibucks(600),
+                offer_buy_quantity: types::Quantity::from_units(buy_quantity),
+                offer_sell_quantity: types::Quantity::ZERO,
+            },
+            offer_status: OfferStatus::Open,
+            offer_expiry: None,
+        })
+    };
+    let first = offer(&foo, 10);
+    let second = offer(&bar, 5);
+    market
+        .do_create(None, first, Timesecs::from(100))
+        .unwrap()
+        .unwrap();
+    market
+        .do_create(None, second, Timesecs::from(300))
+        .unwrap()
+        .unwrap();
+    let iou = Item::IOU(new_iou(&foo, &bar));
+    let iou_id = market
+        .do_create(None, iou, Timesecs::from(100))
+        .unwrap()
+        .unwrap();
+    market
+        .do_update(None, iou_id.clone(), ItemUpdate::Void, Timesecs::from(300))
+        .unwrap();
+    let as_of = |market: &mut Market, as_of, query| {
+        let query = Query::AsOf {
+            as_of: Timesecs::from(as_of),
+            query: Box::new(query),
+        };
+        market.do_query(query, Timesecs::from(400)).unwrap()
+    };
+    let bids = |response| match response {
+        Response::Book(book) => book.bids.values().cloned().collect::<Vec<u32>>(),
+        _ => panic!("expected Book!"),
+    };
+    // the book before the second offer was posted
+    assert_eq!(
+        bids(as_of(&mut market, 200, Query::Book(cond_id.clone()))),
+        vec![10]
+    );
+    assert_eq!(
+        bids(as_of(&mut market, 300, Query::Book(cond_id.clone()))),
+        vec![15]
+    );
+    let iou_void = |response| match response {
+        Response::Items(items) => match items.get(&iou_id) {
+            Some(Item::IOU(iou)) => Some(iou.iou_void),
+            Some(_) => panic!("expected IOU!"),
+            None => None,
+        },
+        _ => panic!("expected Items!"),
+    };
+    assert_eq!(iou_void(as_of(&mut market, 50, Query::AllIOU)), None);
+    assert_eq!(
+        iou_void(as_of(&mut market, 200, Query::AllIOU)),
+        Some(false)
+    );
+    assert_eq!(iou_void(as_of(&mut market, 300, Query::AllIOU)), Some(true));
+    match as_of(&mut market, 200, Query::Balances) {
+        Response::Error(err) => assert_eq!(err, msgs::Error::AsOfNotSupported),
+        _ => panic!("expected AsOfNotSupported!"),
+    }
+}
+
+#[test]
+fn iou_memo() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let memo = |memo: String| {
+        Item::IOU(IOU {
+            iou_memo: Some(memo),
+            ..new_iou(&foo, &bar)
+        })
+    };
+    assert_eq!(
+        create_error(&mut market, memo("x".repeat(types::MAX_MEMO_LENGTH + 1))),
+        Some(msgs::Error::MemoTooLong {
+            max_length: types::MAX_MEMO_LENGTH
+        })
+    );
+    // the limit is on characters rather than bytes
+    create_item(&mut market, memo("é".repeat(types::MAX_MEMO_LENGTH)));
+    let iou_id = create_item(&mut market, memo(String::from("bet on game 3")));
+    let old_iou = market
+        .db
+        .select::<IOUTable>()
+        .by_id(&iou_id)
+        .unwrap()
+        .fields;
+    assert_eq!(old_iou.iou_memo.as_ref().unwrap(), "bet on game 3");
+    let request = Request::Update {
+        id: iou_id,
+        item_update: ItemUpdate::Transfer(Transfer::partial(
+            &old_iou,
+            foo.clone(),
+            Dollars::from_millibucks(300),
+        )),
+    };
+    let items = match market.do_request(request).unwrap() {
+        Response::Items(items) => items,
+        _ => panic!("expected Items!"),
+    };
+    assert_eq!(items.len(), 2);
+    for item in items.values() {
+        match item {
+            Item::IOU(iou) => assert_eq!(iou.iou_memo, old_iou.iou_memo),
+            _ => panic!("expected IOU!"),
+        }
+    }
+}
+
+#[test]
+fn void_outcomes() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let iou = Item::IOU(new_iou(&foo, &bar));
+    let iou_id = market
+        .do_create(None, iou, Timesecs::from(100))
+        .unwrap()
+        .unwrap();
+    let void = |market: &mut Market, id: &ID| {
+        market
+            .do_update(None, id.clone(), ItemUpdate::Void, Timesecs::from(200))
+            .unwrap()
+            .status()
+    };
+    assert_eq!(void(&mut market, &iou_id), "ok");
+    assert_eq!(void(&mut market, &iou_id), "AlreadyVoid");
+    assert_eq!(
+        void(&mut market, &ID(String::from("nosuchiou"))),
+        "NotFound { id: \"nosuchiou\" }"
+    );
+    let outcome = market
+        .db
+        .update::<IOUTable>()
+        .void_iou(&iou_id, Timesecs::from(300))
+        .unwrap();
+    assert_eq!(outcome, UpdateOutcome::NoChange);
+    assert!(outcome.updated().is_err());
+}
+
+#[test]
+fn error_codes() {
+    let json = |err| serde_json::to_value(&Response::Error(err)).unwrap();
+    assert_eq!(
+        json(msgs::Error::NotFound {
+            id: String::from("foo")
+        }),
+        serde_json::json!({"Error": {"code": "not_found", "message": "foo not found", "id": "foo"}})
+    );
+    assert_eq!(
+        json(msgs::Error::ArgCountMismatch {
+            expected: 2,
+            actual: 1
+        }),
+        serde_json::json!({"Error": {
+            "code": "arg_count_mismatch",
+            "message": "expected 2 args but got 1",
+            "expected": 2,
+            "actual": 1,
+        }})
+    );
+    assert_eq!(
+        json(msgs::Error::CondResolved),
+        serde_json::json!({"Error": {"code": "cond_resolved", "message": "cond is already resolved"}})
+    );
+}
+
+#[test]
+fn field_selection() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let fields = |market: &mut Market, item_type: ItemType, fields: &[&str]| {
+        let query = Query::Fields {
+            item_type,
+            fields: fields.iter().map(|f| String::from(*f)).collect(),
+        };
+        market.do_query(query, Timesecs::from(100)).unwrap()
+    };
+    let users = match fields(&mut market, ItemType::User, &["user_name", "user_locked"]) {
+        Response::Fields(users) => users,
+        _ => panic!("expected Fields!"),
+    };
+    assert_eq!(users.len(), 2);
+    assert_eq!(users[&foo]["user_name"], "foo");
+    assert_eq!(users[&bar]["user_name"], "bar");
+    assert_eq!(users[&bar]["user_locked"], 0);
+    assert_eq!(users[&foo].len(), 2);
+    match fields(&mut market, ItemType::Cond, &[]) {
+        Response::Fields(conds) => assert!(conds.is_empty()),
+        _ => panic!("expected Fields!"),
+    }
+    for bad in &["user_name_stripped", "user_name FROM user; --", "iou_value"] {
+        assert_eq!(
+            fields(&mut market, ItemType::User, &["user_name", bad]).status(),
+            format!("UnknownField {{ field: {:?} }}", bad)
+        );
+    }
+    assert!(market
+        .db
+        .select::<UserTable>()
+        .columns_where(&["creation_time"], "1", &[])
+        .is_err());
+}
+
+#[test]
+fn changed_since_void() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let iou = Item::IOU(new_iou(&foo, &bar));
+    let iou_id = market
+        .do_create(None, iou, Timesecs::from(100))
+        .unwrap()
+        .unwrap();
+    market
+        .do_update(None, iou_id.clone(), ItemUpdate::Void, Timesecs::from(200))
+        .unwrap();
+    let query = Query::ChangedSince {
+        item_type: ItemType::IOU,
+        since: Timesecs::from(200),
+    };
+    match market.do_query(query, Timesecs::from(300)).unwrap() {
+        Response::Changed { records, .. } => match &records[&iou_id].item {
+            Item::IOU(iou) => assert!(iou.iou_void),
+            _ => panic!("expected IOU!"),
+        },
+        _ => panic!("expected Changed!"),
+    }
+}
+
+#[cfg(test)]
+fn create_v1_table<T: Table>(db: &Connection) {
+    // version 1 tables were the same apart from the columns and checks added
+    // since
+    let lines: Vec<String> = T::CREATE_TABLE
+        .lines()
+        .filter(|line| {
+            !line.contains("updated_time")
+                && !line.contains("offer_status")
+                && !line.contains("cond_value")
+                && !line.contains("pred_value_type")
+                && !line.contains("user_credit_limit")
+                && !line.contains("user_display_name")
+                && !line.contains("user_admin")
+                && !line.contains("offer_expiry")
+                && !line.contains("cond_arg_time")
+                && !line.contains("iou_void_time")
+                && !line.contains("iou_memo")
+                && !line.contains("cond_closed")
+                && !line.contains("identity_verified")
+                && !line.trim_start().starts_with("CHECK(")
+                && !line.trim_start().starts_with("AND ")
+        })
+        .map(|line| line.replace(" CHECK(iou_value >= 0)", ""))
+        .collect();
+    let create_table = lines.join("\n").replace(",\n        )", "\n        )");
+    db.execute(&create_table, &[]).unwrap();
+}
+
+#[test]
+fn migrate_v1() {
+    let db = Connection::open_in_memory().unwrap();
+    create_v1_table::<MarketTable>(&db);
+    create_v1_table::<UserTable>(&db);
+    create_v1_table::<IdentityTable>(&db);
+    create_v1_table::<IOUTable>(&db);
+    create_v1_table::<CondTable>(&db);
+    create_v1_table::<OfferTable>(&db);
+    create_v1_table::<EntityTable>(&db);
+    create_v1_table::<RelTable>(&db);
+    create_v1_table::<PropTable>(&db);
+    create_v1_table::<PredTable>(&db);
+    create_v1_table::<DependTable>(&db);
+    db.execute(
+        "INSERT INTO market (version, creation_time) VALUES (1, '')",
+        &[],
+    )
+    .unwrap();
+    db.execute(
+        "INSERT INTO user (user_id, user_name, user_name_stripped, user_locked, creation_time)
+        VALUES ('foo', 'foo', 'foo', 0, ?1)",
+        &[&get_time()],
+    )
+    .unwrap();
+    let market = Market::open_existing(db).unwrap();
+    assert_eq!(market.info.version, MARKET_VERSION);
+    let r = market
+        .db
+        .select::<UserTable>()
+        .by_id(&ID(String::from("foo")))
+        .unwrap();
+    assert_eq!(r.updated_time, r.creation_time);
+    assert_eq!(market.db.select::<LogTable>().count().unwrap(), 0);
+}
+
+#[test]
+fn open_uninitialized() {
+    use std::env;
+    use std::fs;
+    use std::process;
+    let not_initialized = |db| match Market::open_existing(db) {
+        Err(err) => err.to_string().contains("market init"),
+        Ok(_) => false,
+    };
+    let path = env::temp_dir().join(format!("market-empty-{}.db", process::id()));
+    let _ = fs::remove_file(&path);
+    assert!(not_initialized(Connection::open(&path).unwrap()));
+    fs::remove_file(&path).unwrap();
+    // a market table without its row is no better
+    let db = Connection::open_in_memory().unwrap();
+    db.create_table::<MarketTable>().unwrap();
+    assert!(not_initialized(db));
+}
+
+#[test]
+fn in_memory_foreign_keys() {
+    let mut market = test_market();
+    let alice = create_user(&mut market, "alice");
+    let nobody = ID(String::from("nobody"));
+    let now = get_time();
+    let record = |id: &str, iou: IOU| Record {
+        id: ID(String::from(id)),
+        fields: iou,
+        creation_time: now,
+        updated_time: now,
+    };
+    // each test market is its own database
+    assert_eq!(test_market().db.select::<UserTable>().count().unwrap(), 0);
+    assert!(market
+        .db
+        .insert::<IOUTable>(&record("bad", new_iou(&alice, &nobody)))
+        .is_err());
+    let bob = create_user(&mut market, "bob");
+    market
+        .db
+        .insert::<IOUTable>(&record("good", new_iou(&alice, &bob)))
+        .unwrap();
+}
+
+#[test]
+fn storage_checks() {
+    let mut market = test_market();
+    let alice = create_user(&mut market, "alice");
+    let bob = create_user(&mut market, "bob");
+    let cond = create_cond(&mut market);
+    let insert_offer = |db: &Connection, id: &str, buy_price: i64, sell_price: i64| {
+        db.execute(
+            "INSERT INTO offer (offer_id, offer_user, offer_cond_id, offer_buy_price,
+                offer_sell_price, offer_buy_quantity, offer_sell_quantity, offer_status,
+                creation_time, updated_time)
+            VALUES (?1, ?2, ?3, ?4, ?5, 1, 1, 'open', '', '')",
+            &[&id, &alice, &cond, &buy_price, &sell_price],
+        )
+    };
+    let insert_iou = |db: &Connection, id: &str, value: i64| {
+        db.execute(
+            "INSERT INTO iou (iou_id, iou_issuer, iou_holder, iou_value, iou_cond_flag,
+                creation_time, updated_time)
+            VALUES (?1, ?2, ?3, ?4, 0, '', '')",
+            &[&id, &alice, &bob, &value],
+        )
+    };
+    // the same invariants as OfferDetails::valid, behind the validation
+    assert!(insert_offer(&market.db, "inverted", 600, 400).is_err());
+    assert!(insert_offer(&market.db, "crossed", 500, 500).is_err());
+    assert!(insert_offer(&market.db, "negative", -100, 400).is_err());
+    assert!(insert_offer(&market.db, "overpriced", 600, 1100).is_err());
+    assert!(insert_iou(&market.db, "negative", -1000).is_err());
+    insert_offer(&market.db, "good", 0, 1000).unwrap();
+    insert_iou(&market.db, "good", 1000).unwrap();
+    // and migrated databases get them too, keeping their rows
+    let db = Connection::open_in_memory().unwrap();
+    db.execute("PRAGMA foreign_keys = ON", &[]).unwrap();
+    create_v1_table::<MarketTable>(&db);
+    create_v1_table::<UserTable>(&db);
+    create_v1_table::<IOUTable>(&db);
+    create_v1_table::<CondTable>(&db);
+    create_v1_table::<OfferTable>(&db);
+    create_v1_table::<PredTable>(&db);
+    db.execute_batch(
+        "INSERT INTO market (version, creation_time) VALUES (1, '');
+        INSERT INTO user (user_id, user_name, user_name_stripped, user_locked, creation_time)
+            VALUES ('alice', 'alice', 'alice', 0, ''), ('bob', 'bob', 'bob', 0, '');
+        INSERT INTO iou (iou_id, iou_issuer, iou_holder, iou_value, iou_cond_flag,
+                iou_split, creation_time)
+            VALUES ('old', 'alice', 'bob', 1000, 0, NULL, ''),
+                ('split', 'alice', 'bob', 500, 0, 'old', '');",
+    )
+    .unwrap();
+    let migrated = Market::open_existing(db).unwrap();
+    assert_eq!(migrated.db.select::<IOUTable>().count().unwrap(), 2);
+    assert!(insert_iou(&migrated.db, "negative", -1000).is_err());
+}
+
+#[test]
+fn stored_version() {
+    let db = Connection::open_in_memory().unwrap();
+    assert_eq!(Market::stored_version(&db).unwrap(), None);
+    let market = Market::create_new(db).unwrap();
+    assert_eq!(
+        Market::stored_version(&market.db).unwrap(),
+        Some(MARKET_VERSION)
+    );
+    market
+        .db
+        .update::<MarketTable>()
+        .set_version(MARKET_VERSION - 1)
+        .unwrap();
+    // read as it is, not as it would be after migrating
+    assert_eq!(
+        Market::stored_version(&market.db).unwrap(),
+        Some(MARKET_VERSION - 1)
+    );
+    let info = VersionInfo::new(Some(MARKET_VERSION - 1));
+    assert_eq!(info.crate_version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.schema_version, MARKET_VERSION);
+}
+
+#[cfg(test)]
+fn index_names<T: Table>(db: &Connection) -> Vec<String> {
+    let query = format!("PRAGMA index_list({})", T::TABLE_NAME);
+    let mut stmt = db.prepare(&query).unwrap();
+    let names = stmt
+        .query_map(&[], |r| r.get::<_, String>("name"))
+        .unwrap()
+        .map(|name| name.unwrap())
+        .filter(|name| name.ends_with("_index"))
+        .collect();
+    names
+}
+
+#[test]
+fn indexes_created() {
+    let new_market = test_market();
+    let db = Connection::open_in_memory().unwrap();
+    create_v1_table::<MarketTable>(&db);
+    create_v1_table::<UserTable>(&db);
+    create_v1_table::<IdentityTable>(&db);
+    create_v1_table::<IOUTable>(&db);
+    create_v1_table::<CondTable>(&db);
+    create_v1_table::<OfferTable>(&db);
+    create_v1_table::<EntityTable>(&db);
+    create_v1_table::<RelTable>(&db);
+    create_v1_table::<PropTable>(&db);
+    create_v1_table::<PredTable>(&db);
+    create_v1_table::<DependTable>(&db);
+    db.execute(
+        "INSERT INTO market (version, creation_time) VALUES (1, '')",
+        &[],
+    )
+    .unwrap();
+    let migrated = Market::open_existing(db).unwrap();
+    // new and migrated databases end up with the same indexes
+    for market in &[new_market, migrated] {
+        let mut iou_indexes = index_names::<IOUTable>(&market.db);
+        iou_indexes.sort();
+        assert_eq!(
+            iou_indexes,
+            vec!["iou_cond_id_index", "iou_holder_index", "iou_issuer_index"]
+        );
+        assert_eq!(
+            index_names::<OfferTable>(&market.db),
+            vec!["offer_cond_id_index"]
+        );
+        assert_eq!(
+            index_names::<EntityTable>(&market.db),
+            vec!["entity_type_index"]
+        );
+        assert_eq!(index_names::<RelTable>(&market.db), vec!["rel_to_index"]);
+        assert_eq!(
+            index_names::<PropTable>(&market.db),
+            vec!["prop_value_index"]
+        );
+    }
+}
+
+#[test]
+fn audit_log() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    set_user_locked(&mut market, &foo, true);
+    let bar = create_user(&mut market, "bar");
+    assert_eq!(
+        create_error(&mut market, Item::IOU(new_iou(&foo, &bar))),
+        Some(msgs::Error::IssuerLocked { id: foo.clone() })
+    );
+    // voiding an IOU that doesn't exist fails, and isn't logged
+    let void = Request::Update {
+        id: ID(String::from("nothing")),
+        item_update: ItemUpdate::Void,
+    };
+    assert!(market.do_request(void).is_err());
+    let query = Query::AuditLog {
+        since: Timesecs::from(0),
+        limit: Some(3),
+    };
+    match market.do_request(Request::Query(query)).unwrap() {
+        Response::AuditLog(entries) => {
+            let statuses: Vec<&str> = entries.iter().map(|e| e.status.as_str()).collect();
+            assert_eq!(statuses, vec!["ok", "ok", "ok"]);
+            assert!(entries[0].request.contains("\"foo\""));
+            assert!(entries[1].request.contains("UserLock"));
+        }
+        _ => panic!("expected AuditLog!"),
+    }
+    let query = Query::AuditLog {
+        since: Timesecs::from(0),
+        limit: None,
+    };
+    match market.do_request(Request::Query(query)).unwrap() {
+        Response::AuditLog(entries) => {
+            let statuses: Vec<&str> = entries.iter().map(|e| e.status.as_str()).collect();
+            assert_eq!(statuses, vec!["ok", "ok", "ok", "IssuerLocked", "ok"]);
+        }
+        _ => panic!("expected AuditLog!"),
+    }
+}
+
+#[cfg(test)]
+fn create_offer(
+    market: &mut Market,
+    user: &ID,
+    cond_id: &ID,
+    buy: (i64, u32),
+    sell: (i64, u32),
+) -> ID {
+    let offer = Offer {
+        offer_user: user.clone(),
+        offer_cond_id: cond_id.clone(),
+        offer_cond_time: None,
+        offer_details: types::OfferDetails {
+            offer_buy_price: Dollars::from_millibucks(buy.0),
+            offer_sell_price: Dollars::from_millibucks(sell.0),
+            offer_buy_quantity: types::Quantity::from_units(buy.1),
+            offer_sell_quantity: types::Quantity::from_units(sell.1),
+        },
+        offer_status: OfferStatus::Open,
+        offer_expiry: None,
+    };
+    create_item(market, Item::Offer(offer))
+}
+
+#[test]
+fn offer_owner_checked() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let cond_id = create_cond(&mut market);
+    let offer_id = create_offer(&mut market, &foo, &cond_id, (400, 10), (600, 10));
+    let update = || Request::Update {
+        id: offer_id.clone(),
+        item_update: ItemUpdate::Offer(types::OfferDetails {
+            offer_buy_price: Dollars::from_millibucks(450),
+            offer_sell_price: Dollars::from_millibucks(550),
+            offer_buy_quantity: types::Quantity::from_units(10),
+            offer_sell_quantity: types::Quantity::from_units(10),
+        }),
+    };
+    match market.do_request_as(Some(bar.clone()), update()).unwrap() {
+        Response::Error(err) => assert_eq!(err, msgs::Error::AccessDenied),
+        _ => panic!("expected Error!"),
+    }
+    match market.do_request_as(Some(foo.clone()), update()).unwrap() {
+        Response::Updated => {}
+        _ => panic!("expected Updated!"),
+    }
+    let log = market
+        .db
+        .select::<LogTable>()
+        .since(Timesecs::from(0), 10)
+        .unwrap();
+    let users: Vec<Option<ID>> = log.into_iter().map(|r| r.log_user).collect();
+    assert_eq!(&users[users.len() - 2..], &[Some(bar), Some(foo)]);
+}
+
+#[test]
+fn api_keys() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let key = market.create_api_key(&foo, Timesecs::from(100)).unwrap();
+    let other = market.create_api_key(&foo, Timesecs::from(100)).unwrap();
+    assert_ne!(key.key_secret, other.key_secret);
+    let r = market
+        .db
+        .select::<ApiKeyTable>()
+        .by_key_id(&key.key_id)
+        .unwrap()
+        .unwrap();
+    assert_eq!(r.key_user, foo);
+    assert_eq!(r.key_secret, key.key_secret);
+    assert!(market
+        .db
+        .select::<ApiKeyTable>()
+        .by_key_id("nothing")
+        .unwrap()
+        .is_none());
+    // keys can only be made for users that exist
+    assert!(market
+        .create_api_key(&ID(String::from("nobody")), Timesecs::from(100))
+        .is_err());
+}
+
+#[cfg(test)]
+fn create_cond(market: &mut Market) -> ID {
+    let pred = Pred {
+        pred_name: String::from("pred"),
+        pred_args: types::ArgList::from(""),
+        pred_value: None,
+        pred_value_type: types::PredValueType::Bool,
+    };
+    let pred_id = create_item(market, Item::Pred(pred));
+    let cond = Cond {
+        cond_pred: pred_id,
+        cond_args: vec![],
+        cond_arg_time: None,
+        cond_value: None,
+        cond_closed: false,
+    };
+    create_item(market, Item::Cond(cond))
+}
+
+#[test]
+fn offer_units_exposure() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let cond_id = create_cond(&mut market);
+    // 100 units bought at 45 cents risk $45, 50 sold at 60 cents risk $20
+    let offer_id = create_offer(&mut market, &foo, &cond_id, (450, 100), (600, 50));
+    let open_offers = |market: &mut Market, user: &ID| {
+        market
+            .balances(Timesecs::now())
+            .unwrap()
+            .into_iter()
+            .find(|(id, _)| id == user)
+            .unwrap()
+            .1
+            .open_offers
+    };
+    assert_eq!(
+        open_offers(&mut market, &foo),
+        Dollars::from_millibucks(45000)
+    );
+    let credit = Request::Update {
+        id: bar.clone(),
+        item_update: ItemUpdate::CreditLimit {
+            user_credit_limit: Dollars::from_millibucks(100_000),
+        },
+    };
+    market.do_request(credit).unwrap();
+    let accept = Request::Accept {
+        user: bar.clone(),
+        offer_id,
+        quantity: 100,
+        side: Side::Sell,
+    };
+    let (buyer_iou, seller_iou) = match market.do_request(accept).unwrap() {
+        Response::Accepted {
+            buyer_iou,
+            seller_iou,
+        } => (buyer_iou, seller_iou),
+        _ => panic!("expected Accepted!"),
+    };
+    let iou_value = |market: &mut Market, id: &ID| {
+        market
+            .db
+            .select::<IOUTable>()
+            .by_id(id)
+            .unwrap()
+            .fields
+            .iou_value
+    };
+    assert_eq!(
+        iou_value(&mut market, &buyer_iou),
+        Dollars::from_millibucks(45000)
+    );
+    assert_eq!(
+        iou_value(&mut market, &seller_iou),
+        Dollars::from_millibucks(55000)
+    );
+    assert_eq!(
+        open_offers(&mut market, &foo),
+        Dollars::from_millibucks(20000)
+    );
+}
+
+#[test]
+fn accept_offer() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let cond_id = create_cond(&mut market);
+    let offer_id = create_offer(&mut market, &foo, &cond_id, (400, 10), (600, 10));
+    let accept = |user: &ID, quantity, side| Request::Accept {
+        user: user.clone(),
+        offer_id: offer_id.clone(),
+        quantity,
+        side,
+    };
+    let error = |market: &mut Market, request| match market.do_request(request).unwrap() {
+        Response::Error(err) => err,
+        _ => panic!("expected Error!"),
+    };
+    // buying 5 risks $3 if the cond is false
+    assert_eq!(
+        error(&mut market, accept(&bar, 5, Side::Buy)),
+        msgs::Error::CreditLimitExceeded
+    );
+    let credit = Request::Update {
+        id: bar.clone(),
+        item_update: ItemUpdate::CreditLimit {
+            user_credit_limit: Dollars::from_millibucks(3000),
+        },
+    };
+    market.do_request(credit).unwrap();
+    assert_eq!(
+        error(&mut market, accept(&bar, 11, Side::Buy)),
+        msgs::Error::InvalidAcceptQuantity
+    );
+    assert_eq!(
+        error(&mut market, accept(&bar, 0, Side::Buy)),
+        msgs::Error::InvalidAcceptQuantity
+    );
+    assert_eq!(
+        error(&mut market, accept(&foo, 5, Side::Buy)),
+        msgs::Error::IOUSelf
+    );
+    match market
+        .do_request_as(Some(foo.clone()), accept(&bar, 5, Side::Buy))
+        .unwrap()
+    {
+        Response::Error(err) => assert_eq!(err, msgs::Error::AccessDenied),
+        _ => panic!("expected AccessDenied"),
+    }
+    let (buyer_iou, seller_iou) = match market.do_request(accept(&bar, 5, Side::Buy)).unwrap() {
+        Response::Accepted {
+            buyer_iou,
+            seller_iou,
+        } => (buyer_iou, seller_iou),
+        _ => panic!("expected Accepted!"),
+    };
+    let ious = market.db.select::<IOUTable>();
+    let buyer_iou = ious.by_id(&buyer_iou).unwrap().fields;
+    assert_eq!((&buyer_iou.iou_issuer, &buyer_iou.iou_holder), (&bar, &foo));
+    assert_eq!(buyer_iou.iou_value, Dollars::from_millibucks(3000));
+    assert!(!buyer_iou.iou_cond_flag);
+    let seller_iou = ious.by_id(&seller_iou).unwrap().fields;
+    assert_eq!(
+        (&seller_iou.iou_issuer, &seller_iou.iou_holder),
+        (&foo, &bar)
+    );
+    assert_eq!(seller_iou.iou_value, Dollars::from_millibucks(2000));
+    assert!(seller_iou.iou_cond_flag);
+    let offer = market.db.select::<OfferTable>().by_id(&offer_id).unwrap();
+    assert_eq!(offer.fields.offer_details.offer_sell_quantity.to_units(), 5);
+    assert_eq!(offer.fields.offer_details.offer_buy_quantity.to_units(), 10);
+    // selling back offsets the position, so it fits in the same limit
+    match market.do_request(accept(&bar, 5, Side::Sell)).unwrap() {
+        Response::Accepted { .. } => (),
+        _ => panic!("expected Accepted!"),
+    }
+    let exposure = market.user_exposure(&bar).unwrap();
+    assert_eq!(exposure[&cond_id], Dollars::from_millibucks(1000));
+}
+
+#[test]
+fn ious_by_condition() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let cond_id = create_cond(&mut market);
+    let plain = create_item(&mut market, Item::IOU(new_iou(&foo, &bar)));
+    let mut iou = new_iou(&foo, &bar);
+    iou.iou_cond_id = Some(cond_id.clone());
+    let conditional = create_item(&mut market, Item::IOU(iou));
+    let void = create_item(&mut market, Item::IOU(new_iou(&bar, &foo)));
+    market
+        .do_request(Request::Update {
+            id: void,
+            item_update: ItemUpdate::Void,
+        })
+        .unwrap();
+    let query = |market: &mut Market, query| match market.do_query(query, Timesecs::now()) {
+        Ok(Response::Items(items)) => items,
+        _ => panic!("expected Items!"),
+    };
+    // the NULL cond column comes back as None
+    let items = query(&mut market, Query::UnconditionalIOU);
+    assert_eq!(items.len(), 1);
+    match &items[&plain] {
+        Item::IOU(iou) => assert_eq!(iou.iou_cond_id, None),
+        _ => panic!("expected IOU!"),
+    }
+    let items = query(&mut market, Query::ConditionalIOU);
+    assert_eq!(items.len(), 1);
+    match &items[&conditional] {
+        Item::IOU(iou) => assert_eq!(iou.iou_cond_id, Some(cond_id)),
+        _ => panic!("expected IOU!"),
+    }
+}
+
+#[test]
+fn resolve_cond() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let cond_id = create_cond(&mut market);
+    let cond_iou = |cond_flag| {
+        let mut iou = new_iou(&foo, &bar);
+        iou.iou_cond_id = Some(cond_id.clone());
+        iou.iou_cond_flag = cond_flag;
+        iou
+    };
+    let if_iou = create_item(&mut market, Item::IOU(cond_iou(true)));
+    let not_iou = create_item(&mut market, Item::IOU(cond_iou(false)));
+    let offer = create_offer(&mut market, &foo, &cond_id, (400, 10), (600, 10));
+    let close = Request::Update {
+        id: cond_id.clone(),
+        item_update: ItemUpdate::Close,
+    };
+    match market.do_request(close).unwrap() {
+        Response::Count(count) => assert_eq!(count, 1),
+        _ => panic!("expected Count!"),
+    }
+    let resolve = Request::Update {
+        id: cond_id.clone(),
+        item_update: ItemUpdate::Resolve { cond_value: true },
+    };
+    let items = match market.do_request(resolve.clone()).unwrap() {
+        Response::Items(items) => items,
+        _ => panic!("expected Items!"),
+    };
+    // the NOT IOU is void, the IF IOU is replaced by an unconditional one
+    let ious = market.db.select::<IOUTable>();
+    assert!(ious.by_id(&not_iou).unwrap().fields.iou_void);
+    assert!(ious.by_id(&if_iou).unwrap().fields.iou_void);
+    assert_eq!(items.len(), 1);
+    match items.values().next().unwrap() {
+        Item::IOU(iou) => {
+            assert_eq!(iou.iou_cond_id, None);
+            assert_eq!(iou.iou_split, Some(if_iou));
+            assert_eq!(iou.iou_value, Dollars::from_millibucks(1000));
+            assert!(!iou.iou_void);
+        }
+        _ => panic!("expected IOU!"),
+    }
+    let r = market
+        .db
+        .select::<OfferTable>()
+        .one_where("offer_id = ?1", &[&offer])
+        .unwrap();
+    assert_eq!(r.fields.offer_status, OfferStatus::Cancelled);
+    // a condition only resolves once, and takes no new offers after
+    match market.do_request(resolve).unwrap() {
+        Response::Error(err) => assert_eq!(err, msgs::Error::CondResolved),
+        _ => panic!("expected Error!"),
+    }
+    let offer = Offer {
+        offer_user: foo.clone(),
+        offer_cond_id: cond_id.clone(),
+        offer_cond_time: None,
+        offer_details: types::OfferDetails {
+            offer_buy_price: Dollars::from_millibucks(400),
+            offer_sell_price: Dollars::from_millibucks(600),
+            offer_buy_quantity: types::Quantity::from_units(1),
+            offer_sell_quantity: types::Quantity::from_units(1),
+        },
+        offer_status: OfferStatus::Open,
+        offer_expiry: None,
+    };
+    assert_eq!(
+        create_error(&mut market, Item::Offer(offer)),
+        Some(msgs::Error::CondResolved)
+    );
+}
+
+#[test]
+fn resolve_cond_false() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let cond_id = create_cond(&mut market);
+    let mut iou = new_iou(&foo, &bar);
+    iou.iou_cond_id = Some(cond_id.clone());
+    iou.iou_cond_flag = false;
+    let not_iou = create_item(&mut market, Item::IOU(iou));
+    market
+        .close_cond(&cond_id, Timesecs::now())
+        .unwrap()
+        .unwrap();
+    let items = market
+        .do_resolve(&cond_id, false, Timesecs::now())
+        .unwrap()
+        .unwrap();
+    match items.values().next().unwrap() {
+        Item::IOU(iou) => assert_eq!(iou.iou_split, Some(not_iou)),
+        _ => panic!("expected IOU!"),
+    }
+}
+
+#[test]
+fn close_then_resolve() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let cond_id = create_cond(&mut market);
+    let mut iou = new_iou(&foo, &bar);
+    iou.iou_cond_id = Some(cond_id.clone());
+    iou.iou_cond_flag = true;
+    let if_iou = create_item(&mut market, Item::IOU(iou));
+    let offer = create_offer(&mut market, &foo, &cond_id, (400, 10), (600, 10));
+    let time = Timesecs::now();
+    // an open cond can't be resolved, and is left as it was
+    assert_eq!(
+        market.do_resolve(&cond_id, true, time).unwrap().err(),
+        Some(msgs::Error::CondNotClosed)
+    );
+    let ious = market.db.select::<IOUTable>();
+    assert!(!ious.by_id(&if_iou).unwrap().fields.iou_void);
+    // closing cancels the open offers, once
+    assert_eq!(market.close_cond(&cond_id, time).unwrap(), Ok(1));
+    let offer_status = |market: &Market| {
+        market
+            .db
+            .select::<OfferTable>()
+            .by_id(&offer)
+            .unwrap()
+            .fields
+            .offer_status
+    };
+    assert_eq!(offer_status(&market), OfferStatus::Cancelled);
+    assert_eq!(
+        market.close_cond(&cond_id, time).unwrap(),
+        Err(msgs::Error::CondClosed)
+    );
+    let nocond = ID(String::from("nocond"));
+    assert_eq!(
+        market.close_cond(&nocond, time).unwrap(),
+        Err(msgs::Error::NotFound {
+            id: String::from("nocond")
+        })
+    );
+    // no new offers, and no reposting old ones
+    let details = types::OfferDetails {
+        offer_buy_price: Dollars::from_millibucks(400),
+        offer_sell_price: Dollars::from_millibucks(600),
+        offer_buy_quantity: types::Quantity::from_units(1),
+        offer_sell_quantity: types::Quantity::from_units(1),
+    };
+    let new_offer = Offer {
+        offer_user: bar.clone(),
+        offer_cond_id: cond_id.clone(),
+        offer_cond_time: None,
+        offer_details: details.clone(),
+        offer_status: OfferStatus::Open,
+        offer_expiry: None,
+    };
+    assert_eq!(
+        create_error(&mut market, Item::Offer(new_offer)),
+        Some(msgs::Error::CondClosed)
+    );
+    let repost = Request::Update {
+        id: offer.clone(),
+        item_update: ItemUpdate::Offer(details),
+    };
+    match market.do_request(repost).unwrap() {
+        Response::Error(err) => assert_eq!(err, msgs::Error::CondClosed),
+        _ => panic!("expected Error!"),
+    }
+    assert_eq!(offer_status(&market), OfferStatus::Cancelled);
+    // and then it resolves as before
+    let items = market.do_resolve(&cond_id, true, time).unwrap().unwrap();
+    assert_eq!(items.len(), 1);
+    let ious = market.db.select::<IOUTable>();
+    assert!(ious.by_id(&if_iou).unwrap().fields.iou_void);
+    assert_eq!(
+        market.do_resolve(&cond_id, true, time).unwrap().err(),
+        Some(msgs::Error::CondResolved)
+    );
+}
+
+#[test]
+fn pred_value_checked() {
+    let mut market = test_market();
+    let pred = |pred_value: Option<&str>| Pred {
+        pred_name: String::from("CO2 passes 500ppm"),
+        pred_args: types::ArgList::from(""),
+        pred_value: pred_value.map(String::from),
+        pred_value_type: types::PredValueType::Date,
+    };
+    assert_eq!(
+        create_error(&mut market, Item::Pred(pred(Some("true")))),
+        Some(msgs::Error::InvalidPredValue {
+            value: String::from("true")
+        })
+    );
+    let pred_id = create_item(&mut market, Item::Pred(pred(None)));
+    let set_value = |market: &mut Market, pred_value: &str| {
+        let request = Request::Update {
+            id: pred_id.clone(),
+            item_update: ItemUpdate::PredValue {
+                pred_value: String::from(pred_value),
+            },
+        };
+        match market.do_request(request).unwrap() {
+            Response::Updated => None,
+            Response::Error(err) => Some(err),
+            _ => panic!("expected Updated!"),
+        }
+    };
+    assert_eq!(
+        set_value(&mut market, "512"),
+        Some(msgs::Error::InvalidPredValue {
+            value: String::from("512")
+        })
+    );
+    assert_eq!(set_value(&mut market, "2031-06-01 00:00:00"), None);
+    let r = market.db.select::<PredTable>().by_id(&pred_id).unwrap();
+    assert_eq!(r.fields.pred_value_type, types::PredValueType::Date);
+    assert_eq!(r.fields.pred_value.unwrap(), "2031-06-01 00:00:00");
+}
+
+#[test]
+fn cond_by_pred() {
+    let mut market = test_market();
+    let mut create = |item| create_item(&mut market, item);
+    let pred = |pred_name: &str| {
+        Item::Pred(Pred {
+            pred_name: String::from(pred_name),
+            pred_args: types::ArgList::from("person"),
+            pred_value: None,
+            pred_value_type: types::PredValueType::Bool,
+        })
+    };
+    let person = |entity_name: &str| {
+        Item::Entity(Entity {
+            entity_name: String::from(entity_name),
+            entity_type: String::from("person"),
+        })
+    };
+    let wins = create(pred("Candidate wins 2020 election"));
+    let runs = create(pred("Candidate runs in 2020 election"));
+    let alice = create(person("Alice"));
+    let bob = create(person("Bob"));
+    let cond = |pred_id: &ID, arg: &ID| {
+        Item::Cond(Cond {
+            cond_pred: pred_id.clone(),
+            cond_args: vec![arg.clone()],
+            cond_arg_time: None,
+            cond_value: None,
+            cond_closed: false,
+        })
+    };
+    let alice_wins = create(cond(&wins, &alice));
+    let bob_wins = create(cond(&wins, &bob));
+    create(cond(&runs, &alice));
+    match market
+        .do_query(Query::CondByPred(wins), Timesecs::now())
+        .unwrap()
+    {
+        Response::Items(items) => {
+            let mut ids: Vec<&ID> = items.keys().collect();
+            ids.sort_by(|a, b| a.0.cmp(&b.0));
+            let mut expected = vec![&alice_wins, &bob_wins];
+            expected.sort_by(|a, b| a.0.cmp(&b.0));
+            assert_eq!(ids, expected);
+        }
+        _ => panic!("expected Items!"),
+    }
+}
+
+#[test]
+fn order_book_depth() {
+    let mut market = test_market();
+    let alice = create_user(&mut market, "alice");
+    let bob = create_user(&mut market, "bob");
+    let cond_id = create_cond(&mut market);
+    create_offer(&mut market, &alice, &cond_id, (400, 10), (600, 5));
+    create_offer(&mut market, &bob, &cond_id, (400, 20), (700, 0));
+    create_offer(&mut market, &bob, &cond_id, (300, 1), (600, 2));
+    match market
+        .do_query(Query::Book(cond_id), Timesecs::now())
+        .unwrap()
+    {
+        Response::Book(book) => {
+            let bids: Vec<(i64, u32)> = book
+                .bids
+                .iter()
+                .map(|(p, q)| (p.to_millibucks(), *q))
+                .collect();
+            assert_eq!(bids, vec![(300, 1), (400, 30)]);
+            let asks: Vec<(i64, u32)> = book
+                .asks
+                .iter()
+                .map(|(p, q)| (p.to_millibucks(), *q))
+                .collect();
+            assert_eq!(asks, vec![(600, 7)]);
+        }
+        _ => panic!("expected Book!"),
+    }
+}
+
+#[test]
+fn clearing_fills_offers() {
+    let mut market = test_market();
+    let alice = create_user(&mut market, "alice");
+    let bob = create_user(&mut market, "bob");
+    let cond_id = create_cond(&mut market);
+    let alice_offer = create_offer(&mut market, &alice, &cond_id, (500, 100), (600, 0));
+    let bob_offer = create_offer(&mut market, &bob, &cond_id, (300, 0), (400, 40));
+    let clearing = market
+        .run_clearing(&cond_id, &ClearingOptions::default(), Timesecs::now())
+        .unwrap();
+    assert!(clearing.complete);
+    let trades = clearing.trades;
+    assert_eq!(trades.len(), 1);
+    assert_eq!(trades[0].units, 40);
+    assert_eq!(trades[0].price, Dollars::from_millibucks(450));
+    let buyer_iou = market
+        .db
+        .select::<IOUTable>()
+        .by_id(&trades[0].buyer_iou)
+        .unwrap();
+    assert_eq!(buyer_iou.fields.iou_issuer, alice);
+    assert_eq!(
+        buyer_iou.fields.iou_value,
+        Dollars::from_millibucks(450 * 40)
+    );
+    assert!(!buyer_iou.fields.iou_cond_flag);
+    let seller_iou = market
+        .db
+        .select::<IOUTable>()
+        .by_id(&trades[0].seller_iou)
+        .unwrap();
+    assert_eq!(seller_iou.fields.iou_issuer, bob);
+    assert_eq!(
+        seller_iou.fields.iou_value,
+        Dollars::from_millibucks(550 * 40)
+    );
+    assert!(seller_iou.fields.iou_cond_flag);
+    // alice's offer stays open with what's left, bob's is used up
+    let offers = market.db.select::<OfferTable>();
+    let alice_offer = offers.one_where("offer_id = ?1", &[&alice_offer]).unwrap();
+    assert_eq!(
+        alice_offer
+            .fields
+            .offer_details
+            .offer_buy_quantity
+            .to_units(),
+        60
+    );
+    assert_eq!(alice_offer.fields.offer_status, OfferStatus::Open);
+    let bob_offer = offers.one_where("offer_id = ?1", &[&bob_offer]).unwrap();
+    assert_eq!(bob_offer.fields.offer_status, OfferStatus::Filled);
+    let exposure = |market: &mut Market, user: &ID| match market
+        .do_query(Query::Exposure(user.clone()), Timesecs::now())
+        .unwrap()
+    {
+        Response::Exposure(exposure) => exposure[&cond_id],
+        _ => panic!("expected Exposure!"),
+    };
+    assert_eq!(
+        exposure(&mut market, &alice),
+        Dollars::from_millibucks(450 * 40)
+    );
+    assert_eq!(
+        exposure(&mut market, &bob),
+        Dollars::from_millibucks(550 * 40)
+    );
+    // nothing left to match
+    assert!(market
+        .run_clearing(&cond_id, &ClearingOptions::default(), Timesecs::now())
+        .unwrap()
+        .trades
+        .is_empty());
+}
+
+#[test]
+fn rebuild_from_events() {
+    let mut market = test_market();
+    market.set_record_events(true);
+    let alice = create_user(&mut market, "alice");
+    let bob = create_user(&mut market, "bob");
+    let cond_id = create_cond(&mut market);
+    create_offer(&mut market, &alice, &cond_id, (500, 100), (600, 0));
+    create_offer(&mut market, &bob, &cond_id, (300, 0), (400, 40));
+    let request = Request::Clear {
+        cond_id,
+        max_iterations: None,
+        check_credit: false,
+    };
+    market.do_request(request).unwrap();
+    set_user_locked(&mut market, &bob, true);
+    // queries aren't events
+    market.do_request(Request::Query(Query::AllIOU)).unwrap();
+    assert_eq!(market.db.select::<EventTable>().count().unwrap(), 8);
+    let rebuilt = Market::rebuild_from_events(market).unwrap();
+    assert_eq!(rebuilt.db.select::<IOUTable>().count().unwrap(), 2);
+    assert!(
+        rebuilt
+            .db
+            .select::<UserTable>()
+            .by_id(&bob)
+            .unwrap()
+            .fields
+            .user_locked
+    );
+    // a market that didn't record from the start can't be rebuilt
+    let mut market = test_market();
+    create_user(&mut market, "carol");
+    market.set_record_events(true);
+    create_user(&mut market, "dave");
+    assert!(Market::rebuild_from_events(market).is_err());
+    // offers that matched when they were made match again
+    let mut market = test_market();
+    market.set_cross_policy(CrossPolicy::Match);
+    market.set_record_events(true);
+    let alice = create_user(&mut market, "alice");
+    let bob = create_user(&mut market, "bob");
+    set_credit_limit(&mut market, &alice, 100_000);
+    set_credit_limit(&mut market, &bob, 100_000);
+    let cond_id = create_cond(&mut market);
+    create_offer(&mut market, &alice, &cond_id, (500, 100), (600, 0));
+    create_offer(&mut market, &bob, &cond_id, (300, 0), (400, 40));
+    assert_eq!(market.db.select::<IOUTable>().count().unwrap(), 2);
+    let rebuilt = Market::rebuild_from_events(market).unwrap();
+    assert_eq!(rebuilt.db.select::<IOUTable>().count().unwrap(), 2);
+}
+
+#[test]
+fn crossing_offer_policy() {
+    let setup = |cross_policy| {
+        let mut market = test_market();
+        market.set_cross_policy(cross_policy);
+        let alice = create_user(&mut market, "alice");
+        let bob = create_user(&mut market, "bob");
+        set_credit_limit(&mut market, &alice, 100_000);
+        set_credit_limit(&mut market, &bob, 100_000);
+        let cond_id = create_cond(&mut market);
+        create_offer(&mut market, &alice, &cond_id, (500, 100), (600, 0));
+        (market, bob, cond_id)
+    };
+    let crossing = |bob: &ID, cond_id: &ID| {
+        Item::Offer(Offer {
+            offer_user: bob.clone(),
+            offer_cond_id: cond_id.clone(),
+            offer_cond_time: None,
+            offer_details: types::OfferDetails {
+                offer_buy_price: Dollars::from_millibucks(300),
+                offer_sell_price: Dollars::from_millibucks(400),
+                offer_buy_quantity: types::Quantity::ZERO,
+                offer_sell_quantity: types::Quantity::from_units(40),
+            },
+            offer_status: OfferStatus::Open,
+            offer_expiry: None,
+        })
+    };
+    let (mut market, bob, cond_id) = setup(CrossPolicy::Reject);
+    assert_eq!(
+        create_error(&mut market, crossing(&bob, &cond_id)),
+        Some(msgs::Error::CrossedBook)
+    );
+    // one that doesn't cross is still fine
+    create_offer(&mut market, &bob, &cond_id, (300, 0), (550, 40));
+    assert_eq!(market.db.select::<IOUTable>().count().unwrap(), 0);
+    let (mut market, bob, cond_id) = setup(CrossPolicy::Match);
+    let offer_id = create_item(&mut market, crossing(&bob, &cond_id));
+    let offer = market.db.select::<OfferTable>().by_id(&offer_id).unwrap();
+    assert_eq!(offer.fields.offer_status, OfferStatus::Filled);
+    assert_eq!(market.db.select::<IOUTable>().count().unwrap(), 2);
+    let (mut market, bob, cond_id) = setup(CrossPolicy::Allow);
+    create_item(&mut market, crossing(&bob, &cond_id));
+    assert_eq!(market.db.select::<IOUTable>().count().unwrap(), 0);
+    // a match past either user's credit limit doesn't trade
+    let (mut market, bob, cond_id) = setup(CrossPolicy::Match);
+    set_credit_limit(&mut market, &bob, 1000);
+    let offer_id = create_item(&mut market, crossing(&bob, &cond_id));
+    let offer = market.db.select::<OfferTable>().by_id(&offer_id).unwrap();
+    assert_eq!(offer.fields.offer_status, OfferStatus::Open);
+    assert_eq!(market.db.select::<IOUTable>().count().unwrap(), 0);
+}
+
+#[test]
+fn create_all_or_nothing() {
+    let mut market = test_market();
+    market.set_cross_policy(CrossPolicy::Match);
+    let alice = create_user(&mut market, "alice");
+    let bob = create_user(&mut market, "bob");
+    set_credit_limit(&mut market, &alice, 100_000);
+    set_credit_limit(&mut market, &bob, 100_000);
+    let cond_id = create_cond(&mut market);
+    create_offer(&mut market, &alice, &cond_id, (500, 100), (600, 0));
+    let crossing = Item::Offer(Offer {
+        offer_user: bob,
+        offer_cond_id: cond_id,
+        offer_cond_time: None,
+        offer_details: types::OfferDetails {
+            offer_buy_price: Dollars::from_millibucks(300),
+            offer_sell_price: Dollars::from_millibucks(400),
+            offer_buy_quantity: types::Quantity::ZERO,
+            offer_sell_quantity: types::Quantity::from_units(40),
+        },
+        offer_status: OfferStatus::Open,
+        offer_expiry: None,
+    });
+    let count = |market: &mut Market| {
+        (
+            market.db.select::<OfferTable>().count().unwrap(),
+            market.db.select::<IOUTable>().count().unwrap(),
+        )
+    };
+    // the offer is written before clearing it fails
+    market
+        .db
+        .execute_batch(
+            "CREATE TEMP TRIGGER no_ious BEFORE INSERT ON iou
+            BEGIN SELECT RAISE(ABORT, 'no ious'); END",
+        )
+        .unwrap();
+    assert!(market
+        .do_create(None, crossing.clone(), Timesecs::now())
+        .is_err());
+    assert_eq!(count(&mut market), (1, 0));
+    // a create that succeeds inside a transaction goes with the transaction
+    market
+        .db
+        .execute_batch("DROP TRIGGER no_ious; BEGIN")
+        .unwrap();
+    assert!(market
+        .do_create(None, crossing, Timesecs::now())
+        .unwrap()
+        .is_ok());
+    assert_eq!(count(&mut market), (2, 2));
+    market.db.execute_batch("ROLLBACK").unwrap();
+    assert_eq!(count(&mut market), (1, 0));
+}
+
+#[test]
+fn clear_request() {
+    let mut market = test_market();
+    let alice = create_user(&mut market, "alice");
+    let bob = create_user(&mut market, "bob");
+    let cond_id = create_cond(&mut market);
+    create_offer(&mut market, &alice, &cond_id, (500, 100), (600, 0));
+    create_offer(&mut market, &bob, &cond_id, (300, 0), (400, 40));
+    let trades = match market
+        .do_request(Request::Clear {
+            cond_id: cond_id.clone(),
+            max_iterations: None,
+            check_credit: false,
+        })
+        .unwrap()
+    {
+        Response::Traded { trades, complete } => {
+            assert!(complete);
+            trades
+        }
+        _ => panic!("expected Traded!"),
+    };
+    assert_eq!(trades.len(), 1);
+    assert_eq!(trades[0].buyer, alice);
+    assert_eq!(trades[0].seller, bob);
+    // clients can read the trades back
+    let response = Response::Traded {
+        trades,
+        complete: true,
+    };
+    let json = serde_json::to_value(&response).unwrap();
+    let trades: Vec<Trade> = serde_json::from_value(json["Traded"]["trades"].clone()).unwrap();
+    assert_eq!(trades[0].units, 40);
+    assert_eq!(trades[0].price, Dollars::from_millibucks(450));
+    let nobody = ID(String::from("nobody"));
+    match market
+        .do_request(Request::Clear {
+            cond_id: nobody,
+            max_iterations: None,
+            check_credit: false,
+        })
+        .unwrap()
+    {
+        Response::Error(msgs::Error::NotFound { .. }) => (),
+        _ => panic!("expected NotFound!"),
+    }
+}
+
+#[test]
+fn clearing_rounds_credit() {
+    let mut market = test_market();
+    let alice = create_user(&mut market, "alice");
+    let bob = create_user(&mut market, "bob");
+    let carol = create_user(&mut market, "carol");
+    let set_limit = |market: &mut Market, user: &ID, millibucks| {
+        let request = Request::Update {
+            id: user.clone(),
+            item_update: ItemUpdate::CreditLimit {
+                user_credit_limit: Dollars::from_millibucks(millibucks),
+            },
+        };
+        assert_eq!(market.do_request(request).unwrap().status(), "ok");
+    };
+    let clear = |market: &mut Market, cond_id: &ID| match market
+        .do_request(Request::Clear {
+            cond_id: cond_id.clone(),
+            max_iterations: None,
+            check_credit: true,
+        })
+        .unwrap()
+    {
+        Response::Traded { trades, .. } => trades,
+        _ => panic!("expected Traded!"),
+    };
+    // each trade is 10 at $0.50, risking $5 on either side
+    set_limit(&mut market, &alice, 7500);
+    set_limit(&mut market, &bob, 5000);
+    set_limit(&mut market, &carol, 5000);
+    let cond_id = create_cond(&mut market);
+    let alice_offer = create_offer(&mut market, &alice, &cond_id, (600, 20), (700, 0));
+    create_offer(&mut market, &bob, &cond_id, (300, 0), (400, 10));
+    let trades = clear(&mut market, &cond_id);
+    assert_eq!(trades.len(), 1);
+    assert_eq!(trades[0].seller, bob);
+    assert_eq!(
+        market.user_exposure(&alice).unwrap()[&cond_id],
+        Dollars::from_millibucks(5000)
+    );
+    // on its own this would fit alice's limit, but not with the first round
+    create_offer(&mut market, &carol, &cond_id, (300, 0), (400, 10));
+    assert!(clear(&mut market, &cond_id).is_empty());
+    let open_quantity = |market: &mut Market| {
+        market
+            .db
+            .select::<OfferTable>()
+            .by_id(&alice_offer)
+            .unwrap()
+            .fields
+            .open_quantity(Side::Buy)
+    };
+    assert_eq!(open_quantity(&mut market), 10);
+    set_limit(&mut market, &alice, 10000);
+    let trades = clear(&mut market, &cond_id);
+    assert_eq!(trades.len(), 1);
+    assert_eq!(trades[0].seller, carol);
+    assert_eq!(open_quantity(&mut market), 0);
+    assert_eq!(
+        market.user_exposure(&alice).unwrap()[&cond_id],
+        Dollars::from_millibucks(10000)
+    );
+}
+
+#[test]
+fn seq_ids() {
+    let mut market = test_market();
+    market.set_id_gen(SeqGen::default());
+    for n in 1..3 {
+        let user = Item::User(User {
+            user_name: format!("user{}", n),
+            user_locked: false,
+            user_credit_limit: Dollars::ZERO,
+            user_display_name: None,
+        });
+        match market.do_request(Request::Create(user)).unwrap() {
+            Response::Created(id) => assert_eq!(id, ID(format!("{:032x}", n))),
+            _ => panic!("expected ID!"),
+        }
+    }
+}
+
+#[test]
+fn offers_expire() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let cond_id = create_cond(&mut market);
+    let now = Timesecs::now();
+    let offer = |offer_expiry: i64| {
+        Item::Offer(Offer {
+            offer_user: foo.clone(),
+            offer_cond_id: cond_id.clone(),
+            offer_cond_time: None,
+            offer_details: types::OfferDetails {
+                offer_buy_price: Dollars::from_millibucks(400),
+                offer_sell_price: Dollars::from_millibucks(600),
+                offer_buy_quantity: types::Quantity::from_units(10),
+                offer_sell_quantity: types::Quantity::from_units(10),
+            },
+            offer_status: OfferStatus::Open,
+            offer_expiry: Some(Timesecs::from(i64::from(now) + offer_expiry)),
+        })
+    };
+    assert_eq!(
+        create_error(&mut market, offer(-10)),
+        Some(msgs::Error::InvalidOfferExpiry)
+    );
+    let offer_id = create_item(&mut market, offer(100));
+    let book = |market: &mut Market, time: i64| match market
+        .do_query(
+            Query::Book(cond_id.clone()),
+            Timesecs::from(i64::from(now) + time),
+        )
+        .unwrap()
+    {
+        Response::Book(book) => book.bids.len(),
+        _ => panic!("expected Book!"),
+    };
+    assert_eq!(book(&mut market, 50), 1);
+    assert_eq!(book(&mut market, 100), 0);
+    // the query leaves it out without writing
+    let status = |market: &mut Market| {
+        let r = market.db.select::<OfferTable>().by_id(&offer_id).unwrap();
+        r.fields.offer_status
+    };
+    assert_eq!(status(&mut market), OfferStatus::Open);
+    let later = Timesecs::from(i64::from(now) + 100);
+    assert_eq!(market.expire_offers(later).unwrap(), 1);
+    assert_eq!(status(&mut market), OfferStatus::Cancelled);
+}
+
+#[test]
+fn offers_by_user() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let cond1 = create_cond(&mut market);
+    let cond2 = create_cond(&mut market);
+    let foo_high = create_offer(&mut market, &foo, &cond1, (500, 10), (600, 10));
+    let foo_low = create_offer(&mut market, &foo, &cond1, (300, 10), (700, 10));
+    let foo_other = create_offer(&mut market, &foo, &cond2, (100, 10), (900, 10));
+    create_offer(&mut market, &bar, &cond1, (400, 10), (650, 10));
+    create_offer(&mut market, &bar, &cond2, (200, 10), (800, 10));
+    let foo_cancelled = create_offer(&mut market, &foo, &cond2, (150, 10), (850, 10));
+    market
+        .db
+        .update::<OfferTable>()
+        .cancel_offer(&foo_cancelled, Timesecs::from(100))
+        .unwrap();
+    let ids = match market
+        .do_query(Query::OfferByUser(foo.clone()), Timesecs::now())
+        .unwrap()
+    {
+        Response::ItemList(items) => items.into_iter().map(|(id, _)| id).collect::<Vec<ID>>(),
+        _ => panic!("expected ItemList!"),
+    };
+    let mut expected = vec![(&cond1, foo_low), (&cond1, foo_high), (&cond2, foo_other)];
+    // sorted by cond and then price, so the cond ids decide which comes first
+    expected.sort_by(|a, b| (a.0).0.cmp(&(b.0).0));
+    let expected: Vec<ID> = expected.into_iter().map(|(_, id)| id).collect();
+    assert_eq!(ids, expected);
+}
+
+#[test]
+fn entity_graph() {
+    let mut market = test_market();
+    let entity = |market: &mut Market, entity_name: &str, entity_type: &str| {
+        create_item(
+            market,
+            Item::Entity(Entity {
+                entity_name: String::from(entity_name),
+                entity_type: String::from(entity_type),
+            }),
+        )
+    };
+    let trump = entity(&mut market, "Donald Trump", "person");
+    let clinton = entity(&mut market, "Hillary Clinton", "person");
+    let kanye = entity(&mut market, "Kanye West", "person");
+    let repub = entity(&mut market, "Republican Party", "party");
+    let democ = entity(&mut market, "Democratic Party", "party");
+    for (from, to) in &[(&trump, &repub), (&clinton, &democ)] {
+        create_item(
+            &mut market,
+            Item::Rel(Rel {
+                rel_type: String::from("party"),
+                rel_from: (*from).clone(),
+                rel_to: (*to).clone(),
+            }),
+        );
+    }
+    let graph = match market
+        .do_query(Query::EntityGraph(String::from("person")), Timesecs::now())
+        .unwrap()
+    {
+        Response::EntityGraph(graph) => graph,
+        _ => panic!("expected EntityGraph!"),
+    };
+    let ids: Vec<&ID> = graph.iter().map(|e| &e.id).collect();
+    assert_eq!(ids, vec![&trump, &clinton, &kanye]);
+    assert_eq!(graph[0].rels.len(), 1);
+    assert_eq!(graph[0].rels[0].1.rel_to, repub);
+    assert_eq!(graph[1].rels[0].1.rel_to, democ);
+    // entities without rels are still there
+    assert!(graph[2].rels.is_empty());
+    let parties = market.entities_with_rels("party").unwrap();
+    assert_eq!(parties.len(), 2);
+    assert!(parties.iter().all(|e| e.rels.is_empty()));
+}
+
+#[test]
+fn unique_conflicts() {
+    let mut market = test_market();
+    let entity = Item::Entity(Entity {
+        entity_name: String::from("Donald Trump"),
+        entity_type: String::from("person"),
+    });
+    create_item(&mut market, entity.clone());
+    assert_eq!(
+        create_error(&mut market, entity),
+        Some(msgs::Error::Conflict {
+            constraint: String::from("entity.entity_name")
+        })
+    );
+    // user names are checked before they get to the database, but the
+    // constraint is still there underneath
+    let foo = create_user(&mut market, "foo");
+    let mut user = market.db.select::<UserTable>().by_id(&foo).unwrap();
+    user.id = ID(String::from("other"));
+    let err = market.db.insert::<UserTable>(&user).unwrap_err();
+    assert_eq!(unique_violation(&err), Some(String::from("user.user_name")));
+    assert_eq!(unique_violation(&format_err!("something else")), None);
+}
+
+#[test]
+fn rel_type_registry() {
+    let mut market = test_market();
+    let entity = |market: &mut Market, entity_name: &str| {
+        create_item(
+            market,
+            Item::Entity(Entity {
+                entity_name: String::from(entity_name),
+                entity_type: String::from("person"),
+            }),
+        )
+    };
+    let trump = entity(&mut market, "Donald Trump");
+    let repub = entity(&mut market, "Republican Party");
+    let rel = |rel_type: &str| {
+        Item::Rel(Rel {
+            rel_type: String::from(rel_type),
+            rel_from: trump.clone(),
+            rel_to: repub.clone(),
+        })
+    };
+    let rel_types =
+        |market: &mut Market| match market.do_request(Request::Query(Query::RelTypes)).unwrap() {
+            Response::RelTypes(rel_types) => rel_types,
+            _ => panic!("expected RelTypes!"),
+        };
+    // any type goes until some are registered
+    assert_eq!(create_error(&mut market, rel("Party")), None);
+    assert!(rel_types(&mut market).is_empty());
+    for rel_type in &["party", "spouse", "party"] {
+        let request = Request::AddRelType {
+            rel_type: String::from(*rel_type),
+        };
+        assert_eq!(market.do_request(request).unwrap().status(), "ok");
+    }
+    assert_eq!(rel_types(&mut market), vec!["party", "spouse"]);
+    assert_eq!(create_error(&mut market, rel("party")), None);
+    assert_eq!(
+        create_error(&mut market, rel("Party")),
+        Some(msgs::Error::UnknownRelType {
+            rel_type: String::from("Party")
+        })
+    );
+}
+
+#[test]
+fn delete_unreferenced() {
+    let mut market = test_market();
+    let delete = |market: &mut Market, id: &ID| {
+        market
+            .do_request(Request::Delete { id: id.clone() })
+            .unwrap()
+            .status()
+    };
+    let entity = |market: &mut Market, entity_name: &str| {
+        create_item(
+            market,
+            Item::Entity(Entity {
+                entity_name: String::from(entity_name),
+                entity_type: String::from("person"),
+            }),
+        )
+    };
+    let trump = entity(&mut market, "Donald Trump");
+    let repub = entity(&mut market, "Republican Party");
+    let typo = entity(&mut market, "Donlad Trump");
+    let rel = create_item(
+        &mut market,
+        Item::Rel(Rel {
+            rel_type: String::from("party"),
+            rel_from: trump.clone(),
+            rel_to: repub.clone(),
+        }),
+    );
+    // both ends of the rel are in use
+    assert_eq!(delete(&mut market, &trump), "InUse");
+    assert_eq!(delete(&mut market, &repub), "InUse");
+    assert_eq!(delete(&mut market, &typo), "ok");
+    assert_eq!(market.db.select::<EntityTable>().count().unwrap(), 2);
+    assert_eq!(
+        delete(&mut market, &typo),
+        format!("NotFound {{ id: {:?} }}", typo.0)
+    );
+    // once the rel is gone so is the reference
+    assert_eq!(delete(&mut market, &rel), "ok");
+    assert_eq!(delete(&mut market, &repub), "ok");
+
+    let cond = create_cond(&mut market);
+    let pred = market
+        .db
+        .select::<CondTable>()
+        .by_id(&cond)
+        .unwrap()
+        .fields
+        .cond_pred;
+    assert_eq!(delete(&mut market, &pred), "InUse");
+
+    let alice = create_user(&mut market, "alice");
+    let bob = create_user(&mut market, "bob");
+    let carol = create_user(&mut market, "carol");
+    let iou = create_item(&mut market, Item::IOU(new_iou(&alice, &bob)));
+    assert_eq!(delete(&mut market, &alice), "InUse");
+    assert_eq!(delete(&mut market, &bob), "InUse");
+    assert_eq!(delete(&mut market, &iou), "NotDeletable");
+    assert_eq!(delete(&mut market, &carol), "ok");
+    assert!(market
+        .db
+        .select::<UserTable>()
+        .try_by_id(&carol)
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn times_out_of_range() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let cond_id = create_cond(&mut market);
+    let iou = |iou_cond_time: i64| {
+        Item::IOU(IOU {
+            iou_cond_id: Some(cond_id.clone()),
+            iou_cond_time: Some(Timesecs::from(iou_cond_time)),
+            ..new_iou(&foo, &bar)
+        })
+    };
+    assert_eq!(
+        create_error(&mut market, iou(i64::MAX)),
+        Some(msgs::Error::InvalidTime)
+    );
+    assert_eq!(
+        create_error(&mut market, iou(-1)),
+        Some(msgs::Error::InvalidTime)
+    );
+    assert_eq!(
+        create_error(&mut market, iou(i64::from(types::MAX_TIME))),
+        None
+    );
+    let mut offer = Offer {
+        offer_user: foo.clone(),
+        offer_cond_id: cond_id.clone(),
+        offer_cond_time: None,
+        offer_details: types::OfferDetails {
+            offer_buy_price: Dollars::from_millibucks(400),
+            offer_sell_price: Dollars::from_millibucks(600),
+            offer_buy_quantity: types::Quantity::from_units(10),
+            offer_sell_quantity: types::Quantity::from_units(10),
+        },
+        offer_status: OfferStatus::Open,
+        offer_expiry: Some(Timesecs::from(i64::MAX)),
+    };
+    assert_eq!(
+        create_error(&mut market, Item::Offer(offer.clone())),
+        Some(msgs::Error::InvalidTime)
+    );
+    offer.offer_expiry = None;
+    offer.offer_cond_time = Some(Timesecs::from(i64::MAX));
+    assert_eq!(
+        create_error(&mut market, Item::Offer(offer)),
+        Some(msgs::Error::InvalidTime)
+    );
+    let query = Query::ChangedSince {
+        item_type: ItemType::IOU,
+        since: Timesecs::from(i64::MAX),
+    };
+    match market.do_request(Request::Query(query)).unwrap() {
+        Response::Error(msgs::Error::InvalidTime) => (),
+        _ => panic!("expected InvalidTime!"),
+    }
+}
+
+#[test]
+fn amounts_out_of_range() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let iou = |iou_value: Dollars| {
+        Item::IOU(IOU {
+            iou_value,
+            ..new_iou(&foo, &bar)
+        })
+    };
+    assert_eq!(
+        create_error(&mut market, iou(Dollars::from_millibucks(i64::MAX))),
+        Some(msgs::Error::InvalidAmount)
+    );
+    assert_eq!(create_error(&mut market, iou(types::MAX_DOLLARS)), None);
+    let user = Item::User(User {
+        user_name: String::from("baz"),
+        user_locked: false,
+        user_credit_limit: Dollars::from_millibucks(i64::MIN),
+        user_display_name: None,
+    });
+    assert_eq!(
+        create_error(&mut market, user),
+        Some(msgs::Error::InvalidAmount)
+    );
+    let cond_id = create_cond(&mut market);
+    let offer = Item::Offer(Offer {
+        offer_user: foo.clone(),
+        offer_cond_id: cond_id,
+        offer_cond_time: None,
+        offer_details: types::OfferDetails {
+            offer_buy_price: Dollars::from_millibucks(i64::MIN),
+            offer_sell_price: Dollars::from_millibucks(600),
+            offer_buy_quantity: types::Quantity::from_units(10),
+            offer_sell_quantity: types::Quantity::ZERO,
+        },
+        offer_status: OfferStatus::Open,
+        offer_expiry: None,
+    });
+    assert_eq!(
+        create_error(&mut market, offer),
+        Some(msgs::Error::InvalidAmount)
+    );
+    // many of the largest IOUs still add up
+    for _ in 1..100 {
+        create_item(&mut market, iou(types::MAX_DOLLARS));
+    }
+    let balances: HashMap<ID, UserBalance> = market
+        .balances(Timesecs::from(100))
+        .unwrap()
+        .into_iter()
+        .collect();
+    assert_eq!(
+        balances[&bar].credit.to_millibucks(),
+        100 * types::MAX_DOLLARS.to_millibucks()
+    );
+    let settlement = market.settlement_report().unwrap();
+    assert_eq!(
+        settlement,
+        vec![(
+            foo.clone(),
+            bar.clone(),
+            Dollars::from_millibucks(100 * types::MAX_DOLLARS.to_millibucks())
+        )]
+    );
+}
+
+#[test]
+fn iou_values_checked() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    for millibucks in &[0, -1000] {
+        let iou = IOU {
+            iou_value: Dollars::from_millibucks(*millibucks),
+            ..new_iou(&foo, &bar)
+        };
+        assert_eq!(
+            create_error(&mut market, Item::IOU(iou)),
+            Some(msgs::Error::InvalidAmount)
+        );
+    }
+    let iou_id = create_item(&mut market, Item::IOU(new_iou(&foo, &bar)));
+    let transfer = |market: &mut Market, holders: &[(&ID, i64)]| {
+        let holders = holders
+            .iter()
+            .map(|(id, millibucks)| ((*id).clone(), Dollars::from_millibucks(*millibucks)))
+            .collect();
+        let request = Request::Update {
+            id: iou_id.clone(),
+            item_update: ItemUpdate::Transfer(Transfer { holders }),
+        };
+        match market.do_request(request).unwrap() {
+            Response::Items(_) => None,
+            Response::Error(err) => Some(err),
+            _ => panic!("expected Items or Error!"),
+        }
+    };
+    assert_eq!(
+        transfer(&mut market, &[(&foo, 0), (&bar, 1000)]),
+        Some(msgs::Error::InvalidTransfer)
+    );
+    assert_eq!(
+        transfer(&mut market, &[(&foo, -500), (&bar, 1500)]),
+        Some(msgs::Error::InvalidTransfer)
+    );
+    assert_eq!(
+        transfer(&mut market, &[(&bar, 900)]),
+        Some(msgs::Error::InvalidTransfer)
+    );
+    assert_eq!(transfer(&mut market, &[(&foo, 500), (&bar, 500)]), None);
+    // the old IOU is void once it has been transferred
+    assert_eq!(
+        transfer(&mut market, &[(&bar, 1000)]),
+        Some(msgs::Error::AlreadyVoid)
+    );
+}
+
+#[test]
+fn request_at_time() {
+    let mut market = test_market();
+    let mut create_at = |user_name: &str, time: &str| {
+        let time = Timesecs::parse_datetime(time).unwrap();
+        let item = Item::User(User {
+            user_name: String::from(user_name),
+            user_locked: false,
+            user_credit_limit: Dollars::ZERO,
+            user_display_name: None,
+        });
+        match market
+            .do_request_at(None, Request::Create(item), time)
+            .unwrap()
+        {
+            Response::Created(id) => (id, Timespec::from(time)),
+            _ => panic!("expected ID!"),
+        }
+    };
+    let (alice, alice_time) = create_at("alice", "2018-01-01 00:00:00");
+    let (bob, bob_time) = create_at("bob", "2018-06-01 12:00:00");
+    let users = market.db.select::<UserTable>();
+    assert_eq!(users.by_id(&alice).unwrap().creation_time, alice_time);
+    assert_eq!(users.by_id(&bob).unwrap().creation_time, bob_time);
+    let log = market
+        .db
+        .select::<LogTable>()
+        .since(Timesecs::from(0), 10)
+        .unwrap();
+    let log_times: Vec<Timespec> = log.iter().map(|r| r.log_time).collect();
+    assert_eq!(log_times, vec![alice_time, bob_time]);
+}
+
+#[cfg(test)]
+struct PanicGen;
+
+#[cfg(test)]
+impl IdGen for PanicGen {
+    fn next_id(&mut self) -> ID {
+        panic!("no ids left")
+    }
+}
+
+#[test]
+fn request_panic_is_error() {
+    let mut market = test_market();
+    create_user(&mut market, "alice");
+    // panics whatever the build, unlike overflowing arithmetic
+    market.set_id_gen(PanicGen);
+    let user = Request::Create(Item::User(User {
+        user_name: String::from("bob"),
+        user_locked: false,
+        user_credit_limit: Dollars::ZERO,
+        user_display_name: None,
+    }));
+    match market.do_request(user) {
+        Err(err) => assert!(err.to_string().contains("no ids left")),
+        Ok(_) => panic!("expected an error!"),
+    }
+    market.set_id_gen(UuidGen::default());
+    // the request savepoint was rolled back, so later requests still commit
+    let carol = create_user(&mut market, "carol");
+    market
+        .db
+        .execute_batch("SAVEPOINT check; ROLLBACK")
+        .unwrap();
+    assert!(market
+        .db
+        .select::<UserTable>()
+        .try_by_id(&carol)
+        .unwrap()
+        .is_some());
+}
+
+#[test]
+fn cond_arg_types() {
+    let mut market = test_market();
+    let entity = |market: &mut Market, entity_name: &str, entity_type: &str| {
+        create_item(
+            market,
+            Item::Entity(Entity {
+                entity_name: String::from(entity_name),
+                entity_type: String::from(entity_type),
+            }),
+        )
+    };
+    let trump = entity(&mut market, "Donald Trump", "person");
+    let repub = entity(&mut market, "Republican Party", "party");
+    let candidate2020 = create_item(
+        &mut market,
+        Item::Pred(Pred {
+            pred_name: String::from("Candidate wins 2020 election"),
+            pred_args: types::ArgList::from("person"),
+            pred_value: None,
+            pred_value_type: types::PredValueType::Bool,
+        }),
+    );
+    let cond = |cond_args: Vec<&ID>| {
+        Item::Cond(Cond {
+            cond_pred: candidate2020.clone(),
+            cond_args: cond_args.into_iter().cloned().collect(),
+            cond_arg_time: None,
+            cond_value: None,
+            cond_closed: false,
+        })
+    };
+    assert_eq!(create_error(&mut market, cond(vec![&trump])), None);
+    assert_eq!(
+        create_error(&mut market, cond(vec![&repub])),
+        Some(msgs::Error::ArgTypeMismatch {
+            position: 0,
+            expected: String::from("person"),
+            actual: String::from("party"),
+        })
+    );
+    assert_eq!(
+        create_error(&mut market, cond(vec![&trump, &repub])),
+        Some(msgs::Error::ArgCountMismatch {
+            expected: 1,
+            actual: 2,
+        })
+    );
+    let nobody = ID(String::from("nobody"));
+    assert_eq!(
+        create_error(&mut market, cond(vec![&nobody])),
+        Some(msgs::Error::NotFound {
+            id: String::from("nobody")
+        })
+    );
+    let co2_by = create_item(
+        &mut market,
+        Item::Pred(Pred {
+            pred_name: String::from("Atmospheric CO2 levels pass 500ppm"),
+            pred_args: types::ArgList::from("time"),
+            pred_value: None,
+            pred_value_type: types::PredValueType::Bool,
+        }),
+    );
+    let deadline = |cond_arg_time: Option<Timesecs>| {
+        Item::Cond(Cond {
+            cond_pred: co2_by.clone(),
+            cond_args: Vec::new(),
+            cond_arg_time,
+            cond_value: None,
+            cond_closed: false,
+        })
+    };
+    assert_eq!(
+        create_error(&mut market, deadline(None)),
+        Some(msgs::Error::ArgCountMismatch {
+            expected: 1,
+            actual: 0,
+        })
+    );
+    let cond_id = create_item(&mut market, deadline(Some(Timesecs::from(1000))));
+    let cond = market.db.select::<CondTable>().by_id(&cond_id).unwrap();
+    assert_eq!(cond.fields.cond_arg_time, Some(Timesecs::from(1000)));
+}
+
+#[test]
+fn implied_party_wins() {
+    let mut market = test_market();
+    let mut create = |item| match market.do_request(Request::Create(item)).unwrap() {
+        Response::Created(id) => id,
+        _ => panic!("expected ID!"),
+    };
+    let trump = create(Item::Entity(Entity {
+        entity_name: String::from("Donald Trump"),
+        entity_type: String::from("person"),
+    }));
+    let repub = create(Item::Entity(Entity {
+        entity_name: String::from("Republican Party"),
+        entity_type: String::from("party"),
+    }));
+    create(Item::Rel(Rel {
+        rel_type: String::from("party"),
+        rel_from: trump.clone(),
+        rel_to: repub.clone(),
+    }));
+    let candidate2020 = create(Item::Pred(Pred {
+        pred_name: String::from("Candidate wins 2020 election"),
+        pred_args: types::ArgList::from("person"),
+        pred_value: None,
+        pred_value_type: types::PredValueType::Bool,
+    }));
+    let party2020 = create(Item::Pred(Pred {
+        pred_name: String::from("Party wins 2020 election"),
+        pred_args: types::ArgList::from("party"),
+        pred_value: None,
+        pred_value_type: types::PredValueType::Bool,
+    }));
+    create(Item::Depend(Depend {
+        depend_type: String::from("implies"),
+        depend_pred1: candidate2020.clone(),
+        depend_pred2: party2020.clone(),
+        depend_vars: types::ArgList::from("x"),
+        depend_args1: types::ArgList::from("x"),
+        depend_args2: types::ArgList::from("x.party"),
+    }));
+    let trump_elected = create(Item::Cond(Cond {
+        cond_pred: candidate2020,
+        cond_args: vec![trump],
+        cond_arg_time: None,
+        cond_value: None,
+        cond_closed: false,
+    }));
+    let implied = market.implied_conds(trump_elected).unwrap();
+    assert_eq!(implied.len(), 1);
+    assert_eq!(implied[0].cond_pred, party2020);
+    assert_eq!(implied[0].cond_args, vec![repub]);
+}
+
+#[test]
+fn depend_cycle() {
+    let mut market = test_market();
+    let mut preds = Vec::new();
+    for name in &["a", "b", "c"] {
+        let pred = Item::Pred(Pred {
+            pred_name: String::from(*name),
+            pred_args: types::ArgList::from("x"),
+            pred_value: None,
+            pred_value_type: types::PredValueType::Bool,
+        });
+        match market.do_request(Request::Create(pred)).unwrap() {
+            Response::Created(id) => preds.push(id),
+            _ => panic!("expected ID!"),
+        }
+    }
+    let depend = |depend_type: &str, pred1: &ID, pred2: &ID| {
+        Request::Create(Item::Depend(Depend {
+            depend_type: String::from(depend_type),
+            depend_pred1: pred1.clone(),
+            depend_pred2: pred2.clone(),
+            depend_vars: types::ArgList::from("x"),
+            depend_args1: types::ArgList::from("x"),
+            depend_args2: types::ArgList::from("x"),
+        }))
+    };
+    let mut create = |request| match market.do_request(request).unwrap() {
+        Response::Created(_) => Ok(()),
+        Response::Error(err) => Err(err),
+        _ => panic!("expected ID!"),
+    };
+    assert!(create(depend("implies", &preds[0], &preds[1])).is_ok());
+    assert!(create(depend("implies", &preds[1], &preds[2])).is_ok());
+    match create(depend("implies", &preds[2], &preds[0])) {
+        Err(msgs::Error::DependCycle) => {}
+        _ => panic!("expected DependCycle"),
+    }
+    match create(depend("implies", &preds[1], &preds[1])) {
+        Err(msgs::Error::DependCycle) => {}
+        _ => panic!("expected DependCycle"),
+    }
+    // cycles are only checked within the same depend_type
+    assert!(create(depend("requires", &preds[2], &preds[0])).is_ok());
+}
+
+#[test]
+fn depend_for_pred() {
+    let mut market = test_market();
+    let pred = |pred_name: &str, pred_args: &str| {
+        Item::Pred(Pred {
+            pred_name: String::from(pred_name),
+            pred_args: types::ArgList::from(pred_args),
+            pred_value: None,
+            pred_value_type: types::PredValueType::Bool,
+        })
+    };
+    let nominee2020 = create_item(
+        &mut market,
+        pred("Party nominee for 2020 election", "party,person"),
+    );
+    let candidate2020 = create_item(&mut market, pred("Candidate wins 2020 election", "person"));
+    let party2020 = create_item(&mut market, pred("Party wins 2020 election", "party"));
+    let depend = |depend_type: &str, pred2: &ID, depend_args2: &str| {
+        Item::Depend(Depend {
+            depend_type: String::from(depend_type),
+            depend_pred1: candidate2020.clone(),
+            depend_pred2: pred2.clone(),
+            depend_vars: types::ArgList::from("x"),
+            depend_args1: types::ArgList::from("x"),
+            depend_args2: types::ArgList::from(depend_args2),
+        })
+    };
+    let requires = create_item(&mut market, depend("requires", &nominee2020, "x.party, x"));
+    let implies = create_item(&mut market, depend("implies", &party2020, "x.party"));
+    let depend_for = |market: &mut Market, pred_id: &ID| {
+        let query = Query::DependFor(pred_id.clone());
+        match market.do_query(query, Timesecs::from(100)).unwrap() {
+            Response::ItemList(items) => items.into_iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            _ => panic!("expected ItemList!"),
+        }
+    };
+    assert_eq!(
+        depend_for(&mut market, &candidate2020),
+        vec![requires.clone(), implies.clone()]
+    );
+    assert_eq!(depend_for(&mut market, &nominee2020), vec![requires]);
+    assert_eq!(depend_for(&mut market, &party2020), vec![implies]);
+    assert!(depend_for(&mut market, &ID(String::from("nopred"))).is_empty());
+}
+
+#[test]
+fn all_identity() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let identity = create_item(
+        &mut market,
+        Item::Identity(types::Identity {
+            identity_user_id: foo.clone(),
+            identity_service: String::from("tumblr"),
+            identity_account_name: String::from("mr--foo"),
+            identity_attested_time: Timesecs::from(0),
+            identity_verified: false,
+        }),
+    );
+    match market
+        .do_request(Request::Query(Query::AllIdentity))
+        .unwrap()
+    {
+        Response::Items(items) => {
+            assert_eq!(items.len(), 1);
+            match &items[&identity] {
+                Item::Identity(identity) => {
+                    assert_eq!(identity.identity_user_id, foo);
+                    assert_eq!(identity.identity_account_name, "mr--foo");
+                }
+                _ => panic!("expected Identity!"),
+            }
+        }
+        _ => panic!("expected Items!"),
+    }
+}
+
+#[test]
+fn attest_identity() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let identity = |service: &str, attested_time, verified| {
+        Item::Identity(types::Identity {
+            identity_user_id: foo.clone(),
+            identity_service: String::from(service),
+            identity_account_name: String::from("mr--foo"),
+            identity_attested_time: Timesecs::from(attested_time),
+            identity_verified: verified,
+        })
+    };
+    let future = 4_000_000_000;
+    assert_eq!(
+        create_error(&mut market, identity("tumblr", 0, true)),
+        Some(msgs::Error::InvalidAttestedTime)
+    );
+    assert_eq!(
+        create_error(&mut market, identity("tumblr", future, false)),
+        Some(msgs::Error::InvalidAttestedTime)
+    );
+    let tumblr = create_item(&mut market, identity("tumblr", 0, false));
+    let attest = |id: &ID, time| Request::Update {
+        id: id.clone(),
+        item_update: ItemUpdate::AttestIdentity {
+            time: Timesecs::from(time),
+        },
+    };
+    let error = |market: &mut Market, request| match market.do_request(request).unwrap() {
+        Response::Error(err) => err,
+        _ => panic!("expected Error!"),
+    };
+    assert_eq!(
+        error(&mut market, attest(&tumblr, 0)),
+        msgs::Error::InvalidAttestedTime
+    );
+    assert_eq!(
+        error(&mut market, attest(&tumblr, future)),
+        msgs::Error::InvalidAttestedTime
+    );
+    match market.do_request(attest(&tumblr, 1000)).unwrap() {
+        Response::Items(items) => match &items[&tumblr] {
+            Item::Identity(identity) => {
+                assert!(identity.identity_verified);
+                assert_eq!(identity.identity_attested_time, Timesecs::from(1000));
+            }
+            _ => panic!("expected Identity!"),
+        },
+        _ => panic!("expected Items!"),
+    }
+    // verified once, and then it stays as it was attested
+    assert_eq!(
+        error(&mut market, attest(&tumblr, 2000)),
+        msgs::Error::AlreadyVerified
+    );
+    let r = market.db.select::<IdentityTable>().by_id(&tumblr).unwrap();
+    assert_eq!(r.fields.identity_attested_time, Timesecs::from(1000));
+    assert_eq!(
+        error(&mut market, attest(&ID(String::from("nobody")), 1000)),
+        msgs::Error::NotFound {
+            id: String::from("nobody")
+        }
+    );
+}
+
+#[test]
+fn entities_by_prop() {
+    let mut market = test_market();
+    let entity = |market: &mut Market, entity_name: &str| {
+        create_item(
+            market,
+            Item::Entity(Entity {
+                entity_name: String::from(entity_name),
+                entity_type: String::from("person"),
+            }),
+        )
+    };
+    let harris = entity(&mut market, "Kamala Harris");
+    let newsom = entity(&mut market, "Gavin Newsom");
+    let abbott = entity(&mut market, "Greg Abbott");
+    let now = get_time();
+    for (entity_id, prop_id, prop_value) in &[
+        (&harris, "state", "CA"),
+        (&newsom, "state", "CA"),
+        (&abbott, "state", "TX"),
+        (&abbott, "home", "CA"),
+    ] {
+        let prop = PropRow {
+            entity_id: (*entity_id).clone(),
+            prop_id: String::from(*prop_id),
+            prop_value: String::from(*prop_value),
+            creation_time: now,
+        };
+        market.db.insert::<PropTable>(&prop).unwrap();
+    }
+    let props = market
+        .db
+        .select::<PropTable>()
+        .by_prop("state", "CA")
+        .unwrap();
+    assert_eq!(props.len(), 2);
+    let by_prop = |market: &mut Market, prop_id: &str, value: &str| {
+        let query = Query::EntitiesByProp {
+            prop_id: String::from(prop_id),
+            value: String::from(value),
+        };
+        match market.do_query(query, Timesecs::now()).unwrap() {
+            Response::Items(items) => items.into_iter().map(|(id, _)| id).collect::<HashSet<_>>(),
+            _ => panic!("expected Items!"),
+        }
+    };
+    let california: HashSet<ID> = vec![harris, newsom].into_iter().collect();
+    assert_eq!(by_prop(&mut market, "state", "CA"), california);
+    let texas: HashSet<ID> = vec![abbott].into_iter().collect();
+    assert_eq!(by_prop(&mut market, "state", "TX"), texas);
+    assert!(by_prop(&mut market, "state", "NY").is_empty());
+}
+
+#[test]
+fn user_display_names() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let user = |user_name: &str, user_display_name: &str| {
+        Item::User(User {
+            user_name: String::from(user_name),
+            user_locked: false,
+            user_credit_limit: Dollars::ZERO,
+            user_display_name: Some(String::from(user_display_name)),
+        })
+    };
+    let bar = create_item(&mut market, user("bar", "Sam"));
+    let rename = ItemUpdate::DisplayName(String::from("Sam"));
+    match market
+        .do_update(None, foo.clone(), rename, Timesecs::from(100))
+        .unwrap()
+    {
+        Response::Items(items) => match &items[&foo] {
+            Item::User(user) => assert_eq!(user.user_display_name, Some(String::from("Sam"))),
+            _ => panic!("expected User!"),
+        },
+        _ => panic!("expected Items!"),
+    }
+    let taken = msgs::Error::UserNameTaken {
+        name: String::from("foo"),
+    };
+    assert_eq!(create_error(&mut market, user("foo", "Alex")), Some(taken));
+    match market.do_query(Query::AllUser, Timesecs::now()).unwrap() {
+        Response::Items(items) => {
+            assert_eq!(items.len(), 2);
+            for id in &[foo, bar] {
+                match &items[id] {
+                    Item::User(user) => {
+                        assert_eq!(user.user_display_name, Some(String::from("Sam")))
+                    }
+                    _ => panic!("expected User!"),
+                }
+            }
+        }
+        _ => panic!("expected Items!"),
+    }
+}
+
+#[test]
+fn read_only_queries() {
+    use std::env;
+    use std::fs;
+    use std::process;
+    let path = env::temp_dir().join(format!("market-read-only-log-{}.db", process::id()));
+    let _ = fs::remove_file(&path);
+    let mut market = Market::create_new(DB::open_read_write(&path).unwrap()).unwrap();
+    create_user(&mut market, "foo");
+    market.close().unwrap();
+    // as for `market status`, which can't write the request log
+    let mut market = Market::open_read_only(DB::open_read_only(&path).unwrap()).unwrap();
+    match market.do_request(Request::Query(Query::AllUser)).unwrap() {
+        Response::Items(items) => assert_eq!(items.len(), 1),
+        _ => panic!("expected Items!"),
+    }
+    let user = Request::Create(Item::User(User {
+        user_name: String::from("bar"),
+        user_locked: false,
+        user_credit_limit: Dollars::ZERO,
+        user_display_name: None,
+    }));
+    match market.do_request(user).unwrap() {
+        Response::Error(err) => assert_eq!(err, msgs::Error::ReadOnly),
+        _ => panic!("expected ReadOnly!"),
+    }
+    market.close().unwrap();
+    let market = Market::open_existing(DB::open_read_write(&path).unwrap()).unwrap();
+    assert_eq!(market.db.select::<LogTable>().count().unwrap(), 1);
+    drop(market);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn update_access_checked() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let iou_id = create_item(&mut market, Item::IOU(new_iou(&foo, &bar)));
+    let denied = |market: &mut Market, user: &ID, request: Request| match market
+        .do_request_as(Some(user.clone()), request)
+        .unwrap()
+    {
+        Response::Error(msgs::Error::AccessDenied) => true,
+        _ => false,
+    };
+    let update = |id: &ID, item_update: ItemUpdate| Request::Update {
+        id: id.clone(),
+        item_update,
+    };
+    // the issuer can't void what they owe, but the holder can forgive it
+    assert!(denied(&mut market, &foo, update(&iou_id, ItemUpdate::Void)));
+    assert!(!denied(
+        &mut market,
+        &bar,
+        update(&iou_id, ItemUpdate::Void)
+    ));
+    let name = |name: &str| ItemUpdate::DisplayName(String::from(name));
+    assert!(denied(&mut market, &foo, update(&bar, name("Bar"))));
+    assert!(!denied(&mut market, &bar, update(&bar, name("Bar"))));
+    let limit = || ItemUpdate::CreditLimit {
+        user_credit_limit: Dollars::from_millibucks(1000),
+    };
+    assert!(denied(&mut market, &foo, update(&bar, limit())));
+    // an IOU that doesn't exist is just not found
+    let missing = ID(String::from("nothing"));
+    match market
+        .do_request_as(Some(foo.clone()), update(&missing, ItemUpdate::Void))
+        .unwrap()
+    {
+        Response::Error(err) => assert_eq!(err, msgs::Error::NotFound { id: missing.0 }),
+        _ => panic!("expected NotFound!"),
+    }
+    let delete = || Request::Delete { id: bar.clone() };
+    assert!(denied(&mut market, &foo, delete()));
+    let cond_id = create_cond(&mut market);
+    let clear = || Request::Clear {
+        cond_id: cond_id.clone(),
+        max_iterations: None,
+        check_credit: false,
+    };
+    assert!(denied(&mut market, &foo, clear()));
+    let add_rel_type = || Request::AddRelType {
+        rel_type: String::from("spouse"),
+    };
+    assert!(denied(&mut market, &foo, add_rel_type()));
+    market
+        .set_user_admin(&foo, true, Timesecs::from(100))
+        .unwrap();
+    assert!(!denied(&mut market, &foo, update(&bar, limit())));
+    assert!(!denied(&mut market, &foo, update(&bar, name("Mr Bar"))));
+    assert!(!denied(&mut market, &foo, clear()));
+    assert!(!denied(&mut market, &foo, add_rel_type()));
+    market
+        .set_user_admin(&foo, false, Timesecs::from(200))
+        .unwrap();
+    assert!(denied(&mut market, &foo, update(&bar, limit())));
+    // as from the command line
+    match market.do_request(update(&bar, limit())).unwrap() {
+        Response::Items(_) => {}
+        _ => panic!("expected Items!"),
+    }
+}
+
+#[test]
+fn create_access_checked() {
+    let mut market = test_market();
+    let foo = create_user(&mut market, "foo");
+    let bar = create_user(&mut market, "bar");
+    let cond_id = create_cond(&mut market);
+    let create_as = |market: &mut Market, user: &ID, item: Item| match market
+        .do_request_as(Some(user.clone()), Request::Create(item))
+        .unwrap()
+    {
+        Response::Created(_) => None,
+        Response::Error(err) => Some(err),
+        _ => panic!("expected Created or Error!"),
+    };
+    // bar can't make foo owe them, but can owe foo
+    let iou = |issuer: &ID, holder: &ID| Item::IOU(new_iou(issuer, holder));
+    assert_eq!(
+        create_as(&mut market, &bar, iou(&foo, &bar)),
+        Some(msgs::Error::AccessDenied)
+    );
+    assert_eq!(create_as(&mut market, &bar, iou(&bar, &foo)), None);
+    let offer = |offer_user: &ID| Offer {
+        offer_user: offer_user.clone(),
+        offer_cond_id: cond_id.clone(),
+        offer_cond_time: None,
+        offer_details: types::OfferDetails {
+            offer_buy_price: Dollars::from_millibucks(400),
+            offer_sell_price: Dollars::from_millibucks(600),
+            offer_buy_quantity: types::Quantity::from_units(10),
+            offer_sell_quantity: types::Quantity::ZERO,
+        },
+        offer_status: OfferStatus::Open,
+        offer_expiry: None,
+    };
+    assert_eq!(
+        create_as(&mut market, &bar, Item::Offer(offer(&foo))),
+        Some(msgs::Error::AccessDenied)
+    );
+    assert_eq!(create_as(&mut market, &bar, Item::Offer(offer(&bar))), None);
+    let offers = Request::CreateOffers {
+        offers: vec![offer(&foo)],
+        best_effort: false,
+    };
+    match market.do_request_as(Some(bar.clone()), offers).unwrap() {
+        Response::CreatedOffers(results) => {
+            assert_eq!(results[&0], Err(msgs::Error::AccessDenied))
         }
+        _ => panic!("expected CreatedOffers!"),
+    }
+    let validate = Request::Validate(iou(&foo, &bar));
+    match market.do_request_as(Some(bar.clone()), validate).unwrap() {
+        Response::Error(err) => assert_eq!(err, msgs::Error::AccessDenied),
+        _ => panic!("expected AccessDenied!"),
     }
+    let identity = |identity_user_id: &ID, identity_verified| {
+        Item::Identity(types::Identity {
+            identity_user_id: identity_user_id.clone(),
+            identity_service: String::from("tumblr"),
+            identity_account_name: String::from("mr--bar"),
+            identity_attested_time: Timesecs::now(),
+            identity_verified,
+        })
+    };
+    assert_eq!(
+        create_as(&mut market, &bar, identity(&foo, false)),
+        Some(msgs::Error::AccessDenied)
+    );
+    // only AttestIdentity, or an admin, can verify one
+    assert_eq!(
+        create_as(&mut market, &bar, identity(&bar, true)),
+        Some(msgs::Error::AccessDenied)
+    );
+    assert_eq!(create_as(&mut market, &bar, identity(&bar, false)), None);
+    market
+        .set_user_admin(&foo, true, Timesecs::from(100))
+        .unwrap();
+    assert_eq!(create_as(&mut market, &foo, identity(&foo, true)), None);
+    assert_eq!(create_as(&mut market, &foo, iou(&bar, &foo)), None);
 }
 
-impl ID {
-    fn new() -> ID {
-        ID(Uuid::new_v4().simple().to_string())
+#[test]
+fn clearing_passes_over_credit() {
+    let mut market = test_market();
+    let alice = create_user(&mut market, "alice");
+    let bob = create_user(&mut market, "bob");
+    let carol = create_user(&mut market, "carol");
+    for (user, millibucks) in &[(&alice, 10000), (&carol, 5000)] {
+        let request = Request::Update {
+            id: (*user).clone(),
+            item_update: ItemUpdate::CreditLimit {
+                user_credit_limit: Dollars::from_millibucks(*millibucks),
+            },
+        };
+        assert_eq!(market.do_request(request).unwrap().status(), "ok");
+    }
+    let cond_id = create_cond(&mut market);
+    create_offer(&mut market, &alice, &cond_id, (500, 10), (900, 0));
+    // bob asks less but has no credit, so carol sells instead
+    let bob_offer = create_offer(&mut market, &bob, &cond_id, (100, 0), (400, 10));
+    create_offer(&mut market, &carol, &cond_id, (100, 0), (450, 10));
+    let request = Request::Clear {
+        cond_id: cond_id.clone(),
+        max_iterations: None,
+        check_credit: true,
+    };
+    match market.do_request(request).unwrap() {
+        Response::Traded { trades, complete } => {
+            assert!(complete);
+            assert_eq!(trades.len(), 1);
+            assert_eq!(trades[0].seller, carol);
+            assert_eq!(trades[0].units, 10);
+        }
+        _ => panic!("expected Traded!"),
     }
+    let bob_offer = market.db.select::<OfferTable>().by_id(&bob_offer).unwrap();
+    assert_eq!(bob_offer.fields.open_quantity(Side::Sell), 10);
 }
 
 // vi: ts=8 sts=4 et
@@ -1,17 +1,71 @@
-use std::collections::HashMap;
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_json;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 
+use crate::db::Order;
 use crate::market::types::{
-    Cond, Depend, Entity, Identity, Offer, OfferDetails, Pred, Rel, Transfer, User, ID, IOU,
+    BookDepth, Cond, Depend, Dollars, Entity, Identity, Offer, OfferDetails, Pred, Rel, Side,
+    Timesecs, Trade, Transfer, User, UserBalance, ID, IOU,
 };
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Request {
     Create(Item),
-    Update { id: ID, item_update: ItemUpdate },
+    /// like Create, but responds with the stored item
+    CreateEcho(Item),
+    /// create many offers at once, all or nothing unless best_effort is set
+    CreateOffers {
+        offers: Vec<Offer>,
+        #[serde(default)]
+        best_effort: bool,
+    },
+    /// check that Create would succeed, without creating anything
+    Validate(Item),
+    /// raise every user's credit limit by the amount
+    IncrementCredit {
+        amount: Dollars,
+    },
+    Update {
+        id: ID,
+        item_update: ItemUpdate,
+    },
+    /// trade against an open offer straight away, buying from its sell
+    /// side or selling to its buy side
+    Accept {
+        user: ID,
+        offer_id: ID,
+        quantity: u32,
+        side: Side,
+    },
+    /// match crossed offers on a condition with the default price policy
+    Clear {
+        cond_id: ID,
+        /// rounds of matching per book, if not the default
+        #[serde(default)]
+        max_iterations: Option<usize>,
+        /// leave out trades that would take a user past their credit limit
+        #[serde(default)]
+        check_credit: bool,
+    },
+    /// delete an item that nothing else refers to
+    Delete {
+        id: ID,
+    },
+    /// while enabled every mutating request is rejected, so that the
+    /// database can be migrated or settled with queries still answered
+    Maintenance {
+        enabled: bool,
+    },
+    /// allow rels of this type; once any are added, rels of other types
+    /// are rejected
+    AddRelType {
+        rel_type: String,
+    },
     Query(Query),
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Item {
     User(User),
@@ -25,41 +79,540 @@ pub enum Item {
     Depend(Depend),
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ItemUpdate {
     Offer(OfferDetails),
     Transfer(Transfer),
     Void,
+    UserLock {
+        user_locked: bool,
+    },
+    CreditLimit {
+        user_credit_limit: Dollars,
+    },
+    /// set the name shown for a user, which unlike the user name need not
+    /// be unique
+    DisplayName(String),
+    Resolve {
+        cond_value: bool,
+    },
+    Close,
+    /// verify an identity, as confirmed at `time`
+    AttestIdentity {
+        time: Timesecs,
+    },
+    PredValue {
+        pred_value: String,
+    },
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Query {
     AllUser,
+    AllIdentity,
     AllIOU,
+    UnconditionalIOU,
+    ConditionalIOU,
     AllCond,
     AllOffer,
     AllEntity,
     AllRel,
     AllPred,
     AllDepend,
+    Page {
+        item_type: ItemType,
+        #[serde(default)]
+        limit: Option<u32>,
+        #[serde(default)]
+        offset: u32,
+        #[serde(default)]
+        order: Order,
+    },
+    Count(ItemType),
+    CountOfferByCond(ID),
+    EntityByName(String),
+    ChangedSince {
+        item_type: ItemType,
+        since: Timesecs,
+    },
+    AuditLog {
+        since: Timesecs,
+        #[serde(default)]
+        limit: Option<u32>,
+    },
+    Book(ID),
+    Exposure(ID),
+    Balances,
+    Settlement,
+    CondByPred(ID),
+    /// the depends on either side of a predicate
+    DependFor(ID),
+    EntityGraph(String),
+    /// the entities with a prop of this value
+    EntitiesByProp {
+        prop_id: String,
+        value: String,
+    },
+    OfferByUser(ID),
+    RelTypes,
+    /// only the named fields of every item of the type, as they are stored
+    Fields {
+        item_type: ItemType,
+        fields: Vec<String>,
+    },
+    /// each of the queries in turn, all against the same snapshot
+    Multi(Vec<Query>),
+    /// a query answered as it would have been at a past time, which works
+    /// for AllUser, AllIOU, AllCond, AllOffer and Book
+    AsOf {
+        as_of: Timesecs,
+        query: Box<Query>,
+    },
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ItemType {
+    User,
+    IOU,
+    Cond,
+    Offer,
+    Entity,
+    Rel,
+    Pred,
+    Depend,
+}
+
+/// Why the market refused a request. Clients get a stable `code` for each
+/// variant and a `message` for people, along with its fields.
+#[derive(Debug, PartialEq)]
 pub enum Error {
-    InvalidUserName,
-    CannotCreateUser,
+    InvalidUserName {
+        name: String,
+    },
+    /// the user who already has the name
+    UserNameTaken {
+        name: String,
+    },
+    /// the user whose name is the same once stripped
+    UserNameStrippedConflict {
+        name: String,
+    },
     InvalidOfferDetails,
+    OfferTooLarge,
+    CrossedBook,
+    InvalidCondTime,
+    MemoTooLong {
+        max_length: usize,
+    },
+    InvalidTime,
+    InvalidAmount,
+    /// the new values aren't all positive, or don't add up to the old one
+    InvalidTransfer,
+    AsOfNotSupported,
+    InvalidOfferExpiry,
+    DependCycle,
+    UnknownRelType {
+        rel_type: String,
+    },
+    UnknownField {
+        field: String,
+    },
+    ArgCountMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    ArgTypeMismatch {
+        position: usize,
+        expected: String,
+        actual: String,
+    },
+    /// the id, or name, that nothing has
+    NotFound {
+        id: String,
+    },
+    UserLocked,
+    IOUSelf,
+    IssuerNotFound {
+        id: ID,
+    },
+    HolderNotFound {
+        id: ID,
+    },
+    IssuerLocked {
+        id: ID,
+    },
+    HolderLocked {
+        id: ID,
+    },
+    CondResolved,
+    CondClosed,
+    CondNotClosed,
+    AlreadyVoid,
+    InvalidAttestedTime,
+    AlreadyVerified,
+    InvalidPredValue {
+        value: String,
+    },
+    InvalidCreditLimit,
+    InvalidAcceptQuantity,
+    CreditLimitExceeded,
+    AccessDenied,
+    InUse,
+    NotDeletable,
+    /// the item would duplicate one that already exists
+    Conflict {
+        constraint: String,
+    },
+    MaintenanceMode,
+    /// the server only answers queries
+    ReadOnly,
+    /// the market failed in a way only its log explains
+    RequestFailed,
+    /// the request nests too deep or has too long a list to be worth doing
+    RequestTooComplex {
+        max_depth: usize,
+        max_elements: usize,
+    },
 }
 
 #[derive(Serialize)]
 pub enum Response {
     Created(ID),
+    CreatedItem {
+        id: ID,
+        item: Item,
+        creation_time: Timesecs,
+    },
+    /// the result for each offer by its index in the request; if nothing was
+    /// created only the errors are included
+    CreatedOffers(BTreeMap<usize, Result<ID, Error>>),
     Updated,
+    /// the IOUs settling an accepted offer
+    Accepted {
+        buyer_iou: ID,
+        seller_iou: ID,
+    },
     Items(HashMap<ID, Item>),
+    /// items in an order that matters, such as a user's offers by condition
+    ItemList(Vec<(ID, Item)>),
+    Page {
+        items: Vec<(ID, Item)>,
+        more: bool,
+    },
+    Count(i64),
+    Changed {
+        records: HashMap<ID, ItemRecord>,
+        time: Timesecs,
+    },
+    AuditLog(Vec<LogEntry>),
+    Book(BookDepth),
+    Exposure(HashMap<ID, Dollars>),
+    Balances(Vec<(ID, UserBalance)>),
+    /// (payer, payee, amount)
+    Settlement(Vec<(ID, ID, Dollars)>),
+    /// the trades made by clearing; if it stopped at the iteration cap
+    /// before it was complete offers may still cross, and clearing again
+    /// carries on
+    Traded {
+        trades: Vec<Trade>,
+        complete: bool,
+    },
+    EntityGraph(Vec<EntityRels>),
+    /// the allowed rel types, or none if any type is
+    RelTypes(Vec<String>),
+    /// the requested fields of each item, with flags as 0 or 1 and
+    /// amounts in millibucks
+    Fields(HashMap<ID, BTreeMap<String, serde_json::Value>>),
+    /// the response to each query of a Multi, in order
+    MultiItems(Vec<Response>),
     Error(Error),
 }
 
+#[derive(Serialize)]
+pub struct ItemRecord {
+    pub item: Item,
+    pub creation_time: Timesecs,
+    pub updated_time: Timesecs,
+}
+
+/// An entity along with the rels from it.
+#[derive(Serialize)]
+pub struct EntityRels {
+    pub id: ID,
+    pub entity: Entity,
+    pub rels: Vec<(ID, Rel)>,
+}
+
+#[derive(Serialize)]
+pub struct LogEntry {
+    pub time: Timesecs,
+    pub user: Option<ID>,
+    pub request: String,
+    pub status: String,
+}
+
+/// How deep queries can nest in a request, counting the outermost.
+pub const MAX_REQUEST_DEPTH: usize = 4;
+
+/// The longest list a request can have, such as the queries of a Multi or
+/// the offers of a CreateOffers.
+pub const MAX_REQUEST_ELEMENTS: usize = 1000;
+
+fn too_complex() -> Error {
+    Error::RequestTooComplex {
+        max_depth: MAX_REQUEST_DEPTH,
+        max_elements: MAX_REQUEST_ELEMENTS,
+    }
+}
+
+fn check_elements(count: usize) -> Result<(), Error> {
+    if count > MAX_REQUEST_ELEMENTS {
+        Err(too_complex())
+    } else {
+        Ok(())
+    }
+}
+
+impl Request {
+    /// Check the request is within the depth and length limits, so a
+    /// server can turn it away before doing any of it.
+    pub fn check_complexity(&self) -> Result<(), Error> {
+        match self {
+            Request::CreateOffers { offers, .. } => check_elements(offers.len()),
+            Request::Query(query) => query.check_complexity(1),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_mutation(&self) -> bool {
+        match self {
+            Request::Create(_) => true,
+            Request::CreateEcho(_) => true,
+            Request::CreateOffers { .. } => true,
+            Request::Validate(_) => false,
+            Request::IncrementCredit { .. } => true,
+            Request::Update { .. } => true,
+            Request::Accept { .. } => true,
+            Request::Clear { .. } => true,
+            Request::Delete { .. } => true,
+            // changes nothing in the database, and has to get through
+            // maintenance mode to end it
+            Request::Maintenance { .. } => false,
+            Request::AddRelType { .. } => true,
+            Request::Query(_) => false,
+        }
+    }
+
+    /// Whether a read-only market has to turn the request away. Validate
+    /// is among them, as it writes in a savepoint that it then rolls back.
+    pub fn writes(&self) -> bool {
+        match self {
+            Request::Validate(_) => true,
+            _ => self.is_mutation(),
+        }
+    }
+}
+
+impl Query {
+    fn check_complexity(&self, depth: usize) -> Result<(), Error> {
+        if depth > MAX_REQUEST_DEPTH {
+            return Err(too_complex());
+        }
+        match self {
+            Query::Multi(queries) => {
+                check_elements(queries.len())?;
+                for query in queries {
+                    query.check_complexity(depth + 1)?;
+                }
+                Ok(())
+            }
+            Query::AsOf { query, .. } => query.check_complexity(depth + 1),
+            Query::Fields { fields, .. } => check_elements(fields.len()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidUserName { .. } => "invalid_user_name",
+            Error::UserNameTaken { .. } => "user_name_taken",
+            Error::UserNameStrippedConflict { .. } => "user_name_stripped_conflict",
+            Error::InvalidOfferDetails => "invalid_offer_details",
+            Error::OfferTooLarge => "offer_too_large",
+            Error::CrossedBook => "crossed_book",
+            Error::InvalidCondTime => "invalid_cond_time",
+            Error::MemoTooLong { .. } => "memo_too_long",
+            Error::InvalidTime => "invalid_time",
+            Error::InvalidAmount => "invalid_amount",
+            Error::InvalidTransfer => "invalid_transfer",
+            Error::AsOfNotSupported => "as_of_not_supported",
+            Error::InvalidOfferExpiry => "invalid_offer_expiry",
+            Error::DependCycle => "depend_cycle",
+            Error::UnknownRelType { .. } => "unknown_rel_type",
+            Error::UnknownField { .. } => "unknown_field",
+            Error::ArgCountMismatch { .. } => "arg_count_mismatch",
+            Error::ArgTypeMismatch { .. } => "arg_type_mismatch",
+            Error::NotFound { .. } => "not_found",
+            Error::UserLocked => "user_locked",
+            Error::IOUSelf => "iou_self",
+            Error::IssuerNotFound { .. } => "issuer_not_found",
+            Error::HolderNotFound { .. } => "holder_not_found",
+            Error::IssuerLocked { .. } => "issuer_locked",
+            Error::HolderLocked { .. } => "holder_locked",
+            Error::CondResolved => "cond_resolved",
+            Error::CondClosed => "cond_closed",
+            Error::CondNotClosed => "cond_not_closed",
+            Error::AlreadyVoid => "already_void",
+            Error::InvalidAttestedTime => "invalid_attested_time",
+            Error::AlreadyVerified => "already_verified",
+            Error::InvalidPredValue { .. } => "invalid_pred_value",
+            Error::InvalidCreditLimit => "invalid_credit_limit",
+            Error::InvalidAcceptQuantity => "invalid_accept_quantity",
+            Error::CreditLimitExceeded => "credit_limit_exceeded",
+            Error::AccessDenied => "access_denied",
+            Error::InUse => "in_use",
+            Error::NotDeletable => "not_deletable",
+            Error::Conflict { .. } => "conflict",
+            Error::MaintenanceMode => "maintenance_mode",
+            Error::ReadOnly => "read_only",
+            Error::RequestFailed => "request_failed",
+            Error::RequestTooComplex { .. } => "request_too_complex",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidUserName { name } => write!(f, "invalid user name {:?}", name),
+            Error::UserNameTaken { name } => write!(f, "user name {:?} is taken", name),
+            Error::UserNameStrippedConflict { name } => {
+                write!(f, "user name is too much like {:?}", name)
+            }
+            Error::InvalidOfferDetails => write!(f, "invalid offer prices or quantities"),
+            Error::OfferTooLarge => write!(f, "offer quantity is over the limit"),
+            Error::CrossedBook => write!(f, "offer would cross the book"),
+            Error::InvalidCondTime => write!(f, "invalid cond time"),
+            Error::MemoTooLong { max_length } => {
+                write!(f, "memo is longer than {} characters", max_length)
+            }
+            Error::InvalidTime => write!(f, "time is out of range"),
+            Error::InvalidAmount => write!(f, "amount is out of range"),
+            Error::InvalidTransfer => write!(f, "transfer doesn't add up to the IOU's value"),
+            Error::AsOfNotSupported => write!(f, "query can't be asked as of a past time"),
+            Error::InvalidOfferExpiry => write!(f, "offer expiry is not in the future"),
+            Error::DependCycle => write!(f, "depend would make a cycle"),
+            Error::UnknownRelType { rel_type } => write!(f, "unknown rel type {:?}", rel_type),
+            Error::UnknownField { field } => write!(f, "unknown field {:?}", field),
+            Error::ArgCountMismatch { expected, actual } => {
+                write!(f, "expected {} args but got {}", expected, actual)
+            }
+            Error::ArgTypeMismatch {
+                position,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "arg {} should be a {} but is a {}",
+                position, expected, actual
+            ),
+            Error::NotFound { id } => write!(f, "{} not found", id),
+            Error::UserLocked => write!(f, "user is locked"),
+            Error::IOUSelf => write!(f, "users can't trade with themselves"),
+            Error::IssuerNotFound { id } => write!(f, "issuer {} not found", id.0),
+            Error::HolderNotFound { id } => write!(f, "holder {} not found", id.0),
+            Error::IssuerLocked { id } => write!(f, "issuer {} is locked", id.0),
+            Error::HolderLocked { id } => write!(f, "holder {} is locked", id.0),
+            Error::CondResolved => write!(f, "cond is already resolved"),
+            Error::CondClosed => write!(f, "cond is closed to new offers"),
+            Error::CondNotClosed => write!(f, "cond must be closed before it is resolved"),
+            Error::AlreadyVoid => write!(f, "IOU is already void"),
+            Error::InvalidAttestedTime => {
+                write!(f, "attested time must be set and not in the future")
+            }
+            Error::AlreadyVerified => write!(f, "identity is already verified"),
+            Error::InvalidPredValue { value } => write!(f, "invalid pred value {:?}", value),
+            Error::InvalidCreditLimit => write!(f, "credit limit can't be negative"),
+            Error::InvalidAcceptQuantity => write!(f, "quantity is not open on the offer"),
+            Error::CreditLimitExceeded => write!(f, "credit limit exceeded"),
+            Error::AccessDenied => write!(f, "access denied"),
+            Error::InUse => write!(f, "item is still referred to"),
+            Error::NotDeletable => write!(f, "item can't be deleted"),
+            Error::Conflict { constraint } => write!(f, "conflicts with {}", constraint),
+            Error::MaintenanceMode => write!(f, "market is in maintenance mode"),
+            Error::ReadOnly => write!(f, "market is read-only"),
+            Error::RequestFailed => write!(f, "request failed"),
+            Error::RequestTooComplex {
+                max_depth,
+                max_elements,
+            } => write!(
+                f,
+                "request nests more than {} deep or has more than {} in a list",
+                max_depth, max_elements
+            ),
+        }
+    }
+}
+
+/// The code and message, then the fields of the variant if it has any.
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("code", self.code())?;
+        map.serialize_entry("message", &self.to_string())?;
+        match self {
+            Error::InvalidUserName { name }
+            | Error::UserNameTaken { name }
+            | Error::UserNameStrippedConflict { name } => map.serialize_entry("name", name)?,
+            Error::UnknownRelType { rel_type } => map.serialize_entry("rel_type", rel_type)?,
+            Error::UnknownField { field } => map.serialize_entry("field", field)?,
+            Error::MemoTooLong { max_length } => map.serialize_entry("max_length", max_length)?,
+            Error::ArgCountMismatch { expected, actual } => {
+                map.serialize_entry("expected", expected)?;
+                map.serialize_entry("actual", actual)?;
+            }
+            Error::ArgTypeMismatch {
+                position,
+                expected,
+                actual,
+            } => {
+                map.serialize_entry("position", position)?;
+                map.serialize_entry("expected", expected)?;
+                map.serialize_entry("actual", actual)?;
+            }
+            Error::NotFound { id } => map.serialize_entry("id", id)?,
+            Error::IssuerNotFound { id }
+            | Error::HolderNotFound { id }
+            | Error::IssuerLocked { id }
+            | Error::HolderLocked { id } => map.serialize_entry("id", id)?,
+            Error::InvalidPredValue { value } => map.serialize_entry("value", value)?,
+            Error::Conflict { constraint } => map.serialize_entry("constraint", constraint)?,
+            Error::RequestTooComplex {
+                max_depth,
+                max_elements,
+            } => {
+                map.serialize_entry("max_depth", max_depth)?;
+                map.serialize_entry("max_elements", max_elements)?;
+            }
+            _ => {}
+        }
+        map.end()
+    }
+}
+
+impl Response {
+    /// "ok", or the error a request was rejected with.
+    pub fn status(&self) -> String {
+        match self {
+            Response::Error(err) => format!("{:?}", err),
+            _ => String::from("ok"),
+        }
+    }
+}
+
 pub fn single_item<T: ToItem>(id: ID, t: T) -> HashMap<ID, Item> {
     let mut items = HashMap::new();
     items.insert(id, t.to_item());
@@ -76,6 +629,12 @@ impl ToItem for User {
     }
 }
 
+impl ToItem for Identity {
+    fn to_item(self) -> Item {
+        Item::Identity(self)
+    }
+}
+
 impl ToItem for IOU {
     fn to_item(self) -> Item {
         Item::IOU(self)
@@ -1,16 +1,139 @@
 use failure::{err_msg, Error};
+use std::collections::HashSet;
 use time::Timespec;
 
 use rusqlite;
-use rusqlite::types::{FromSql, ToSql, ToSqlOutput, Value, ValueRef};
+use rusqlite::types::{FromSql, FromSqlError, ToSql, ToSqlOutput, Value, ValueRef};
 use rusqlite::Row;
 
-use crate::db::{Select, Table, Update};
+use crate::db::{Select, Table, Update, UpdateOutcome};
 use crate::market::types::{
-    ArgList, Cond, Depend, Dollars, Entity, Identity, Offer, OfferDetails, Pred, Rel, Timesecs,
-    User, ID, IOU,
+    ArgList, Cond, Depend, Dollars, Entity, Identity, Offer, OfferDetails, OfferStatus, Pred,
+    PredValueType, Quantity, Rel, Timesecs, User, ID, IOU,
 };
 
+/// The schema version written by `Market::create_new`.
+pub const MARKET_VERSION: u32 = 21;
+
+/// SQL to upgrade the schema from version N to N + 1, starting at version 1.
+pub const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE user ADD COLUMN updated_time TEXT NOT NULL DEFAULT '';
+    ALTER TABLE identity ADD COLUMN updated_time TEXT NOT NULL DEFAULT '';
+    ALTER TABLE iou ADD COLUMN updated_time TEXT NOT NULL DEFAULT '';
+    ALTER TABLE cond ADD COLUMN updated_time TEXT NOT NULL DEFAULT '';
+    ALTER TABLE offer ADD COLUMN updated_time TEXT NOT NULL DEFAULT '';
+    ALTER TABLE entity ADD COLUMN updated_time TEXT NOT NULL DEFAULT '';
+    ALTER TABLE rel ADD COLUMN updated_time TEXT NOT NULL DEFAULT '';
+    ALTER TABLE pred ADD COLUMN updated_time TEXT NOT NULL DEFAULT '';
+    ALTER TABLE depend ADD COLUMN updated_time TEXT NOT NULL DEFAULT '';
+    UPDATE user SET updated_time = creation_time;
+    UPDATE identity SET updated_time = creation_time;
+    UPDATE iou SET updated_time = creation_time;
+    UPDATE cond SET updated_time = creation_time;
+    UPDATE offer SET updated_time = creation_time;
+    UPDATE entity SET updated_time = creation_time;
+    UPDATE rel SET updated_time = creation_time;
+    UPDATE pred SET updated_time = creation_time;
+    UPDATE depend SET updated_time = creation_time;",
+    "ALTER TABLE offer ADD COLUMN offer_status TEXT NOT NULL DEFAULT 'open';",
+    "CREATE TABLE request_log (
+            log_time        TEXT NOT NULL,
+            log_user        TEXT,
+            log_request     TEXT NOT NULL,
+            log_status      TEXT NOT NULL
+        );",
+    "ALTER TABLE cond ADD COLUMN cond_value BOOLEAN;",
+    "ALTER TABLE pred ADD COLUMN pred_value_type TEXT NOT NULL DEFAULT 'bool';",
+    "CREATE TABLE api_key (
+            key_id          TEXT NOT NULL PRIMARY KEY,
+            key_user        TEXT NOT NULL,
+            key_secret      TEXT NOT NULL,
+            creation_time   TEXT NOT NULL,
+            FOREIGN KEY(key_user) REFERENCES user(user_id)
+        );",
+    "ALTER TABLE user ADD COLUMN user_credit_limit INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE offer ADD COLUMN offer_expiry INTEGER;",
+    "CREATE INDEX iou_issuer_index ON iou(iou_issuer);
+    CREATE INDEX iou_holder_index ON iou(iou_holder);
+    CREATE INDEX iou_cond_id_index ON iou(iou_cond_id);
+    CREATE INDEX offer_cond_id_index ON offer(offer_cond_id);
+    CREATE INDEX entity_type_index ON entity(entity_type);
+    CREATE INDEX rel_to_index ON rel(rel_to);",
+    "ALTER TABLE cond ADD COLUMN cond_arg_time INTEGER;",
+    "CREATE TABLE event (
+            event_time      INTEGER NOT NULL,
+            event_user      TEXT,
+            event_request   TEXT NOT NULL,
+            event_ids       TEXT NOT NULL
+        );",
+    "CREATE TABLE rel_type (
+            rel_type        TEXT NOT NULL PRIMARY KEY,
+            creation_time   TEXT NOT NULL
+        );",
+    "ALTER TABLE iou ADD COLUMN iou_void_time INTEGER;",
+    "ALTER TABLE iou ADD COLUMN iou_memo TEXT;",
+    "ALTER TABLE iou RENAME TO iou_old;
+    CREATE TABLE iou (
+            iou_id          TEXT NOT NULL PRIMARY KEY,
+            iou_issuer      TEXT NOT NULL REFERENCES user(user_id),
+            iou_holder      TEXT NOT NULL REFERENCES user(user_id),
+            iou_value       INTEGER NOT NULL CHECK(iou_value >= 0),
+            iou_cond_id     TEXT REFERENCES cond(cond_id),
+            iou_cond_flag   INTEGER NOT NULL,
+            iou_cond_time   INTEGER,
+            iou_split       TEXT REFERENCES iou(iou_id),
+            iou_void        BOOLEAN,
+            iou_void_time   INTEGER,
+            iou_memo        TEXT,
+            creation_time   TEXT NOT NULL,
+            updated_time    TEXT NOT NULL
+        );
+    INSERT INTO iou (iou_id, iou_issuer, iou_holder, iou_value, iou_cond_id, iou_cond_flag,
+            iou_cond_time, iou_split, iou_void, iou_void_time, iou_memo, creation_time,
+            updated_time)
+        SELECT iou_id, iou_issuer, iou_holder, iou_value, iou_cond_id, iou_cond_flag,
+            iou_cond_time, iou_split, iou_void, iou_void_time, iou_memo, creation_time,
+            updated_time
+        FROM iou_old;
+    DROP TABLE iou_old;
+    CREATE INDEX iou_issuer_index ON iou(iou_issuer);
+    CREATE INDEX iou_holder_index ON iou(iou_holder);
+    CREATE INDEX iou_cond_id_index ON iou(iou_cond_id);
+    ALTER TABLE offer RENAME TO offer_old;
+    CREATE TABLE offer (
+            offer_id            TEXT NOT NULL PRIMARY KEY,
+            offer_user          TEXT NOT NULL REFERENCES user(user_id),
+            offer_cond_id       TEXT NOT NULL REFERENCES cond(cond_id),
+            offer_cond_time     INTEGER,
+            offer_buy_price     INTEGER NOT NULL,
+            offer_sell_price    INTEGER NOT NULL,
+            offer_buy_quantity    INTEGER NOT NULL,
+            offer_sell_quantity   INTEGER NOT NULL,
+            offer_status        TEXT NOT NULL,
+            offer_expiry        INTEGER,
+            creation_time       TEXT NOT NULL,
+            updated_time        TEXT NOT NULL,
+            UNIQUE(offer_user, offer_cond_id, offer_cond_time),
+            CHECK(0 <= offer_buy_price AND offer_buy_price < offer_sell_price
+                AND offer_sell_price <= 1000)
+        );
+    INSERT INTO offer (offer_id, offer_user, offer_cond_id, offer_cond_time, offer_buy_price,
+            offer_sell_price, offer_buy_quantity, offer_sell_quantity, offer_status,
+            offer_expiry, creation_time, updated_time)
+        SELECT offer_id, offer_user, offer_cond_id, offer_cond_time, offer_buy_price,
+            offer_sell_price, offer_buy_quantity, offer_sell_quantity, offer_status,
+            offer_expiry, creation_time, updated_time
+        FROM offer_old;
+    DROP TABLE offer_old;
+    CREATE INDEX offer_cond_id_index ON offer(offer_cond_id);",
+    "ALTER TABLE cond ADD COLUMN cond_closed BOOLEAN NOT NULL DEFAULT 0;
+    UPDATE cond SET cond_closed = 1 WHERE cond_value IS NOT NULL;",
+    "ALTER TABLE identity ADD COLUMN identity_verified BOOLEAN NOT NULL DEFAULT 0;",
+    "CREATE INDEX prop_value_index ON prop(prop_id, prop_value);",
+    "ALTER TABLE user ADD COLUMN user_display_name TEXT;",
+    "ALTER TABLE user ADD COLUMN user_admin BOOLEAN NOT NULL DEFAULT 0;",
+];
+
 pub struct MarketTable {}
 pub struct UserTable {}
 pub struct IdentityTable {}
@@ -22,6 +145,10 @@ pub struct RelTable {}
 pub struct PropTable {}
 pub struct PredTable {}
 pub struct DependTable {}
+pub struct LogTable {}
+pub struct ApiKeyTable {}
+pub struct EventTable {}
+pub struct RelTypeTable {}
 
 #[derive(Debug)]
 pub struct MarketRow {
@@ -68,6 +195,47 @@ impl FromSql for Dollars {
     }
 }
 
+impl ToSql for Quantity {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput> {
+        Ok(ToSqlOutput::Owned(Value::Integer(i64::from(
+            self.to_units(),
+        ))))
+    }
+}
+
+impl FromSql for Quantity {
+    fn column_result(value: ValueRef) -> rusqlite::types::FromSqlResult<Self> {
+        let units: u32 = FromSql::column_result(value)?;
+        Ok(Quantity::from_units(units))
+    }
+}
+
+impl ToSql for OfferStatus {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput> {
+        ToSql::to_sql(self.as_str())
+    }
+}
+
+impl FromSql for OfferStatus {
+    fn column_result(value: ValueRef) -> rusqlite::types::FromSqlResult<Self> {
+        let s: String = FromSql::column_result(value)?;
+        OfferStatus::from_name(&s).ok_or(FromSqlError::InvalidType)
+    }
+}
+
+impl ToSql for PredValueType {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput> {
+        ToSql::to_sql(self.as_str())
+    }
+}
+
+impl FromSql for PredValueType {
+    fn column_result(value: ValueRef) -> rusqlite::types::FromSqlResult<Self> {
+        let s: String = FromSql::column_result(value)?;
+        PredValueType::from_name(&s).ok_or(FromSqlError::InvalidType)
+    }
+}
+
 impl ToSql for ArgList {
     fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput> {
         Ok(ToSqlOutput::Owned(Value::Text(String::from(self))))
@@ -86,6 +254,7 @@ pub struct Record<T> {
     pub id: ID,
     pub fields: T,
     pub creation_time: Timespec,
+    pub updated_time: Timespec,
 }
 
 impl<T> Record<T> {
@@ -94,6 +263,7 @@ impl<T> Record<T> {
             id,
             fields,
             creation_time: Timespec::from(creation_time),
+            updated_time: Timespec::from(creation_time),
         }
     }
 }
@@ -106,6 +276,44 @@ pub struct PropRow {
     pub creation_time: Timespec,
 }
 
+/// A request the server processed, kept for auditing.
+#[derive(Debug)]
+pub struct LogRow {
+    pub log_time: Timespec,
+    pub log_user: Option<ID>,
+    pub log_request: String,
+    pub log_status: String,
+}
+
+/// A mutating request as it was made, with the ids it generated, so that
+/// replaying the events in order rebuilds the market.
+#[derive(Debug)]
+pub struct EventRow {
+    pub event_time: Timesecs,
+    pub event_user: Option<ID>,
+    /// the request as JSON
+    pub event_request: String,
+    /// the ids given to new records, in order, as a JSON list
+    pub event_ids: String,
+}
+
+/// A rel type that rels may be created with. Once there are any, no other
+/// types are allowed.
+#[derive(Debug)]
+pub struct RelTypeRow {
+    pub rel_type: String,
+    pub creation_time: Timespec,
+}
+
+/// A secret a user signs requests with, looked up by its key id.
+#[derive(Debug)]
+pub struct ApiKeyRow {
+    pub key_id: String,
+    pub key_user: ID,
+    pub key_secret: String,
+    pub creation_time: Timespec,
+}
+
 impl Table for MarketTable {
     type TableRow = MarketRow;
 
@@ -134,44 +342,81 @@ impl Table for MarketTable {
     }
 }
 
+impl<'a> Update<'a, MarketTable> {
+    pub fn set_version(&self, version: u32) -> Result<(), Error> {
+        self.update_one("version = ?1", &[&version])
+    }
+}
+
+impl<'a, T, R> Select<'a, T>
+where
+    T: Table<TableRow = Record<R>>,
+{
+    pub fn changed_since(&self, since: Timesecs) -> Result<Vec<Record<R>>, Error> {
+        self.all_where("updated_time >= ?1", &[&Timespec::from(since)])
+    }
+}
+
 impl Table for UserTable {
     type TableRow = Record<User>;
 
     const TABLE_NAME: &'static str = "user";
 
+    const ID_COLUMN: &'static str = "user_id";
+
+    const COLUMNS: &'static [&'static str] = &[
+        "user_name",
+        "user_locked",
+        "user_credit_limit",
+        "user_display_name",
+    ];
+
     const CREATE_TABLE: &'static str = "CREATE TABLE user (
             user_id             TEXT NOT NULL PRIMARY KEY,
             user_name           TEXT NOT NULL UNIQUE,
             user_name_stripped  TEXT NOT NULL UNIQUE,
             user_locked         BOOLEAN,
-            creation_time       TEXT NOT NULL
+            user_credit_limit   INTEGER NOT NULL,
+            user_display_name   TEXT,
+            user_admin          BOOLEAN NOT NULL DEFAULT 0,
+            creation_time       TEXT NOT NULL,
+            updated_time        TEXT NOT NULL
         )";
 
     fn from_row(r: &Row) -> Result<Self::TableRow, Error> {
         let user_id = r.get_checked("user_id")?;
         let user_name = r.get_checked("user_name")?;
         let user_locked = r.get_checked("user_locked")?;
+        let user_credit_limit = r.get_checked("user_credit_limit")?;
+        let user_display_name = r.get_checked("user_display_name")?;
         let creation_time = r.get_checked("creation_time")?;
+        let updated_time = r.get_checked("updated_time")?;
         Ok(Record {
             id: user_id,
             fields: User {
                 user_name,
                 user_locked,
+                user_credit_limit,
+                user_display_name,
             },
             creation_time,
+            updated_time,
         })
     }
 
     fn do_insert(table: &Update<Self>, r: &Self::TableRow) -> Result<(), Error> {
         table.insert(
-            "(user_id, user_name, user_name_stripped, user_locked, creation_time)
-            VALUES (?1, ?2, ?3, ?4, ?5)",
+            "(user_id, user_name, user_name_stripped, user_locked, user_credit_limit, user_display_name, creation_time, updated_time)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
             &[
                 &r.id,
                 &r.fields.user_name,
                 &User::user_name_stripped(&r.fields.user_name),
                 &r.fields.user_locked,
+                &r.fields.user_credit_limit,
+                &r.fields.user_display_name,
                 &r.creation_time,
+                &r.updated_time,
             ],
         )
     }
@@ -183,6 +428,21 @@ impl<'a> Select<'a, UserTable> {
     }
 }
 
+impl<'a> Select<'a, UserTable> {
+    /// Whether the user is one of the market's operators. This is kept
+    /// out of `User` so that it can't be set by creating one.
+    pub fn is_admin(&self, id: &ID) -> Result<bool, Error> {
+        Ok(self.count_where("user_id = ?1 AND user_admin", &[id])? > 0)
+    }
+}
+
+impl<'a> Select<'a, UserTable> {
+    pub fn try_by_id(&self, id: &ID) -> Result<Option<Record<User>>, Error> {
+        let mut users = self.all_where("user_id = ?1", &[id])?;
+        Ok(users.pop())
+    }
+}
+
 impl<'a> Select<'a, UserTable> {
     pub fn by_user_name(&self, user_name: &str) -> Result<Record<User>, Error> {
         self.one_where("user_name = ?1", &[&user_name])
@@ -195,6 +455,55 @@ impl<'a> Select<'a, UserTable> {
     }
 }
 
+impl<'a> Update<'a, UserTable> {
+    pub fn set_locked(&self, id: &ID, user_locked: bool, time: Timesecs) -> Result<(), Error> {
+        self.update_one(
+            "user_locked = ?2, updated_time = ?3 WHERE user_id = ?1",
+            &[id, &user_locked, &Timespec::from(time)],
+        )
+    }
+
+    pub fn set_credit_limit(
+        &self,
+        id: &ID,
+        user_credit_limit: Dollars,
+        time: Timesecs,
+    ) -> Result<(), Error> {
+        self.update_one(
+            "user_credit_limit = ?2, updated_time = ?3 WHERE user_id = ?1",
+            &[id, &user_credit_limit, &Timespec::from(time)],
+        )
+    }
+
+    pub fn set_admin(&self, id: &ID, user_admin: bool, time: Timesecs) -> Result<(), Error> {
+        self.update_one(
+            "user_admin = ?2, updated_time = ?3 WHERE user_id = ?1",
+            &[id, &user_admin, &Timespec::from(time)],
+        )
+    }
+
+    pub fn set_display_name(
+        &self,
+        id: &ID,
+        user_display_name: &str,
+        time: Timesecs,
+    ) -> Result<(), Error> {
+        self.update_one(
+            "user_display_name = ?2, updated_time = ?3 WHERE user_id = ?1",
+            &[id, &user_display_name, &Timespec::from(time)],
+        )
+    }
+
+    /// Raise every user's credit limit by the same amount, returning how
+    /// many users there were.
+    pub fn increment_credit_limits(&self, amount: Dollars, time: Timesecs) -> Result<usize, Error> {
+        self.update_count(
+            "user_credit_limit = user_credit_limit + ?1, updated_time = ?2",
+            &[&amount, &Timespec::from(time)],
+        )
+    }
+}
+
 impl Table for IdentityTable {
     type TableRow = Record<Identity>;
 
@@ -206,7 +515,9 @@ impl Table for IdentityTable {
             identity_service        TEXT NOT NULL,
             identity_account_name   TEXT NOT NULL,
             identity_attested_time  INTEGER NOT NULL,
+            identity_verified       BOOLEAN NOT NULL,
             creation_time           TEXT NOT NULL,
+            updated_time            TEXT NOT NULL,
             UNIQUE(identity_user_id, identity_service)
         )";
 
@@ -216,7 +527,9 @@ impl Table for IdentityTable {
         let identity_service = r.get_checked("identity_service")?;
         let identity_account_name = r.get_checked("identity_account_name")?;
         let identity_attested_time = r.get_checked("identity_attested_time")?;
+        let identity_verified = r.get_checked("identity_verified")?;
         let creation_time = r.get_checked("creation_time")?;
+        let updated_time = r.get_checked("updated_time")?;
         Ok(Record {
             id: identity_id,
             fields: Identity {
@@ -224,45 +537,96 @@ impl Table for IdentityTable {
                 identity_service,
                 identity_account_name,
                 identity_attested_time,
+                identity_verified,
             },
             creation_time,
+            updated_time,
         })
     }
 
     fn do_insert(table: &Update<Self>, r: &Self::TableRow) -> Result<(), Error> {
         table.insert(
-            "(identity_id, identity_user_id, identity_service, identity_account_name, identity_attested_time, creation_time)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            "(identity_id, identity_user_id, identity_service, identity_account_name, identity_attested_time, identity_verified, creation_time, updated_time)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
             &[
                 &r.id,
                 &r.fields.identity_user_id,
                 &r.fields.identity_service,
                 &r.fields.identity_account_name,
                 &r.fields.identity_attested_time,
+                &r.fields.identity_verified,
                 &r.creation_time,
+                &r.updated_time,
             ],
         )
     }
 }
 
+impl<'a> Select<'a, IdentityTable> {
+    pub fn by_id(&self, id: &ID) -> Result<Record<Identity>, Error> {
+        self.one_where("identity_id = ?1", &[id])
+    }
+}
+
+impl<'a> Update<'a, IdentityTable> {
+    /// Verify the identity as attested at `attested_time`, unless it is
+    /// already verified.
+    pub fn attest(
+        &self,
+        id: &ID,
+        attested_time: Timesecs,
+        time: Timesecs,
+    ) -> Result<UpdateOutcome, Error> {
+        self.update_by_id(
+            "identity_id",
+            "identity_attested_time = ?2, identity_verified = 1, updated_time = ?3
+            WHERE identity_id = ?1 AND NOT identity_verified",
+            &[id, &attested_time, &Timespec::from(time)],
+        )
+    }
+}
+
 impl Table for IOUTable {
     type TableRow = Record<IOU>;
 
     const TABLE_NAME: &'static str = "iou";
 
+    const ID_COLUMN: &'static str = "iou_id";
+
+    const COLUMNS: &'static [&'static str] = &[
+        "iou_issuer",
+        "iou_holder",
+        "iou_value",
+        "iou_cond_id",
+        "iou_cond_flag",
+        "iou_cond_time",
+        "iou_split",
+        "iou_void",
+        "iou_memo",
+    ];
+
     const CREATE_TABLE: &'static str = "CREATE TABLE iou (
             iou_id          TEXT NOT NULL PRIMARY KEY,
             iou_issuer      TEXT NOT NULL REFERENCES user(user_id),
             iou_holder      TEXT NOT NULL REFERENCES user(user_id),
-            iou_value       INTEGER NOT NULL,
+            iou_value       INTEGER NOT NULL CHECK(iou_value >= 0),
             iou_cond_id     TEXT REFERENCES cond(cond_id),
             iou_cond_flag   INTEGER NOT NULL,
             iou_cond_time   INTEGER,
             iou_split       TEXT REFERENCES iou(iou_id),
             iou_void        BOOLEAN,
-            creation_time   TEXT NOT NULL
+            iou_void_time   INTEGER,
+            iou_memo        TEXT,
+            creation_time   TEXT NOT NULL,
+            updated_time    TEXT NOT NULL
         )";
 
+    const CREATE_INDEXES: &'static [&'static str] = &[
+        "CREATE INDEX iou_issuer_index ON iou(iou_issuer)",
+        "CREATE INDEX iou_holder_index ON iou(iou_holder)",
+        "CREATE INDEX iou_cond_id_index ON iou(iou_cond_id)",
+    ];
+
     fn from_row(r: &Row) -> Result<Self::TableRow, Error> {
         let iou_id = r.get_checked("iou_id")?;
         let iou_issuer = r.get_checked("iou_issuer")?;
@@ -273,7 +637,9 @@ impl Table for IOUTable {
         let iou_cond_time = r.get_checked("iou_cond_time")?;
         let iou_split = r.get_checked("iou_split")?;
         let iou_void = r.get_checked("iou_void")?;
+        let iou_memo = r.get_checked("iou_memo")?;
         let creation_time = r.get_checked("creation_time")?;
+        let updated_time = r.get_checked("updated_time")?;
         Ok(Record {
             id: iou_id,
             fields: IOU {
@@ -285,15 +651,17 @@ impl Table for IOUTable {
                 iou_cond_time,
                 iou_split,
                 iou_void,
+                iou_memo,
             },
             creation_time,
+            updated_time,
         })
     }
 
     fn do_insert(table: &Update<Self>, r: &Self::TableRow) -> Result<(), Error> {
         table.insert(
-            "(iou_id, iou_issuer, iou_holder, iou_value, iou_cond_id, iou_cond_flag, iou_cond_time, iou_split, iou_void, creation_time)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            "(iou_id, iou_issuer, iou_holder, iou_value, iou_cond_id, iou_cond_flag, iou_cond_time, iou_split, iou_void, iou_memo, creation_time, updated_time)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
             &[
                 &r.id,
                 &r.fields.iou_issuer,
@@ -304,7 +672,9 @@ impl Table for IOUTable {
                 &r.fields.iou_cond_time,
                 &r.fields.iou_split,
                 &r.fields.iou_void,
-                &r.creation_time
+                &r.fields.iou_memo,
+                &r.creation_time,
+                &r.updated_time
             ])
     }
 }
@@ -315,9 +685,70 @@ impl<'a> Select<'a, IOUTable> {
     }
 }
 
+impl<'a> Select<'a, IOUTable> {
+    pub fn try_by_id(&self, id: &ID) -> Result<Option<Record<IOU>>, Error> {
+        let mut ious = self.all_where("iou_id = ?1", &[id])?;
+        Ok(ious.pop())
+    }
+}
+
 impl<'a> Update<'a, IOUTable> {
-    pub fn void_iou(&self, id: &ID) -> Result<(), Error> {
-        self.update_one("iou_void = 1 WHERE iou_id = ?1 AND iou_void = 0", &[id])
+    /// Void the IOU, unless it is already void.
+    pub fn void_iou(&self, id: &ID, time: Timesecs) -> Result<UpdateOutcome, Error> {
+        self.update_by_id(
+            "iou_id",
+            "iou_void = 1, iou_void_time = ?3, updated_time = ?2
+            WHERE iou_id = ?1 AND iou_void = 0",
+            &[id, &Timespec::from(time), &time],
+        )
+    }
+}
+
+impl<'a> Select<'a, IOUTable> {
+    pub fn live(&self) -> Result<Vec<Record<IOU>>, Error> {
+        self.all_where("iou_void = 0", &[])
+    }
+
+    pub fn live_by_cond(&self, cond_id: &ID) -> Result<Vec<Record<IOU>>, Error> {
+        self.all_where("iou_cond_id = ?1 AND iou_void = 0", &[cond_id])
+    }
+
+    pub fn live_by_user(&self, user_id: &ID) -> Result<Vec<Record<IOU>>, Error> {
+        self.all_where(
+            "(iou_issuer = ?1 OR iou_holder = ?1) AND iou_void = 0",
+            &[user_id],
+        )
+    }
+
+    /// Live IOUs that don't depend on any cond, so are payable now.
+    pub fn unconditional(&self) -> Result<Vec<Record<IOU>>, Error> {
+        self.all_where("iou_cond_id IS NULL AND iou_void = 0", &[])
+    }
+
+    /// Live IOUs still waiting on their cond.
+    pub fn conditional(&self) -> Result<Vec<Record<IOU>>, Error> {
+        self.all_where("iou_cond_id IS NOT NULL AND iou_void = 0", &[])
+    }
+
+    /// IOUs as they were at the time: those created since are left out and
+    /// those voided since are live. IOUs voided before void times were kept
+    /// stay void.
+    pub fn as_of(&self, time: Timesecs) -> Result<Vec<Record<IOU>>, Error> {
+        let mut ious = self.created_by(Timespec::from(time))?;
+        let voided_since: HashSet<ID> = self
+            .all_where(
+                "creation_time <= ?1 AND iou_void_time > ?2",
+                &[&Timespec::from(time), &time],
+            )?
+            .into_iter()
+            .map(|r| r.id)
+            .collect();
+        for r in &mut ious {
+            if voided_since.contains(&r.id) {
+                r.fields.iou_void = false;
+            }
+        }
+        Ok(ious)
     }
 }
 
@@ -326,12 +757,27 @@ impl Table for CondTable {
 
     const TABLE_NAME: &'static str = "cond";
 
+    const ID_COLUMN: &'static str = "cond_id";
+
+    const COLUMNS: &'static [&'static str] = &[
+        "cond_pred",
+        "cond_arg1",
+        "cond_arg2",
+        "cond_arg_time",
+        "cond_value",
+        "cond_closed",
+    ];
+
     const CREATE_TABLE: &'static str = "CREATE TABLE cond (
             cond_id         TEXT NOT NULL PRIMARY KEY,
             cond_pred       TEXT NOT NULL REFERENCES pred(pred_id),
             cond_arg1       TEXT REFERENCES entity(entity_id),
             cond_arg2       TEXT REFERENCES entity(entity_id),
-            creation_time   TEXT NOT NULL
+            cond_arg_time   INTEGER,
+            cond_value      BOOLEAN,
+            cond_closed     BOOLEAN NOT NULL,
+            creation_time   TEXT NOT NULL,
+            updated_time    TEXT NOT NULL
         )";
 
     fn from_row(r: &Row) -> Result<Self::TableRow, Error> {
@@ -339,7 +785,11 @@ impl Table for CondTable {
         let cond_pred = r.get_checked("cond_pred")?;
         let cond_arg1 = r.get_checked("cond_arg1")?;
         let cond_arg2 = r.get_checked("cond_arg2")?;
+        let cond_arg_time = r.get_checked("cond_arg_time")?;
+        let cond_value = r.get_checked("cond_value")?;
+        let cond_closed = r.get_checked("cond_closed")?;
         let creation_time = r.get_checked("creation_time")?;
+        let updated_time = r.get_checked("updated_time")?;
         let mut cond_args = Vec::new();
         if let Some(arg1) = cond_arg1 {
             cond_args.push(arg1);
@@ -352,8 +802,12 @@ impl Table for CondTable {
             fields: Cond {
                 cond_pred,
                 cond_args,
+                cond_arg_time,
+                cond_value,
+                cond_closed,
             },
             creation_time,
+            updated_time,
         })
     }
 
@@ -371,14 +825,18 @@ impl Table for CondTable {
                 None
             };
             table.insert(
-                "(cond_id, cond_pred, cond_arg1, cond_arg2, creation_time)
-                VALUES (?1, ?2, ?3, ?4, ?5)",
+                "(cond_id, cond_pred, cond_arg1, cond_arg2, cond_arg_time, cond_value, cond_closed, creation_time, updated_time)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
                 &[
                     &r.id,
                     &r.fields.cond_pred,
                     &cond_arg1,
                     &cond_arg2,
+                    &r.fields.cond_arg_time,
+                    &r.fields.cond_value,
+                    &r.fields.cond_closed,
                     &r.creation_time,
+                    &r.updated_time,
                 ],
             )
         } else {
@@ -390,11 +848,63 @@ impl Table for CondTable {
     }
 }
 
+impl<'a> Select<'a, CondTable> {
+    pub fn by_id(&self, id: &ID) -> Result<Record<Cond>, Error> {
+        self.one_where("cond_id = ?1", &[id])
+    }
+}
+
+impl<'a> Select<'a, CondTable> {
+    pub fn by_pred(&self, pred_id: &ID) -> Result<Vec<Record<Cond>>, Error> {
+        self.all_where("cond_pred = ?1", &[pred_id])
+    }
+}
+
+impl<'a> Update<'a, CondTable> {
+    /// Close the cond to new offers, unless it is already closed.
+    pub fn close(&self, id: &ID, time: Timesecs) -> Result<UpdateOutcome, Error> {
+        self.update_by_id(
+            "cond_id",
+            "cond_closed = 1, updated_time = ?2 WHERE cond_id = ?1 AND NOT cond_closed",
+            &[id, &Timespec::from(time)],
+        )
+    }
+
+    /// Resolve the cond if it is closed, unless it is already resolved.
+    pub fn set_value(
+        &self,
+        id: &ID,
+        cond_value: bool,
+        time: Timesecs,
+    ) -> Result<UpdateOutcome, Error> {
+        self.update_by_id(
+            "cond_id",
+            "cond_value = ?2, updated_time = ?3
+            WHERE cond_id = ?1 AND cond_closed AND cond_value IS NULL",
+            &[id, &cond_value, &Timespec::from(time)],
+        )
+    }
+}
+
 impl Table for OfferTable {
     type TableRow = Record<Offer>;
 
     const TABLE_NAME: &'static str = "offer";
 
+    const ID_COLUMN: &'static str = "offer_id";
+
+    const COLUMNS: &'static [&'static str] = &[
+        "offer_user",
+        "offer_cond_id",
+        "offer_cond_time",
+        "offer_buy_price",
+        "offer_sell_price",
+        "offer_buy_quantity",
+        "offer_sell_quantity",
+        "offer_status",
+        "offer_expiry",
+    ];
+
     const CREATE_TABLE: &'static str = "CREATE TABLE offer (
             offer_id            TEXT NOT NULL PRIMARY KEY,
             offer_user          TEXT NOT NULL REFERENCES user(user_id),
@@ -404,10 +914,18 @@ impl Table for OfferTable {
             offer_sell_price    INTEGER NOT NULL,
             offer_buy_quantity    INTEGER NOT NULL,
             offer_sell_quantity   INTEGER NOT NULL,
+            offer_status        TEXT NOT NULL,
+            offer_expiry        INTEGER,
             creation_time       TEXT NOT NULL,
-            UNIQUE(offer_user, offer_cond_id, offer_cond_time)
+            updated_time        TEXT NOT NULL,
+            UNIQUE(offer_user, offer_cond_id, offer_cond_time),
+            CHECK(0 <= offer_buy_price AND offer_buy_price < offer_sell_price
+                AND offer_sell_price <= 1000)
         )";
 
+    const CREATE_INDEXES: &'static [&'static str] =
+        &["CREATE INDEX offer_cond_id_index ON offer(offer_cond_id)"];
+
     fn from_row(r: &Row) -> Result<Self::TableRow, Error> {
         let offer_id = r.get_checked("offer_id")?;
         let offer_user = r.get_checked("offer_user")?;
@@ -417,7 +935,10 @@ impl Table for OfferTable {
         let offer_sell_price = r.get_checked("offer_sell_price")?;
         let offer_buy_quantity = r.get_checked("offer_buy_quantity")?;
         let offer_sell_quantity = r.get_checked("offer_sell_quantity")?;
+        let offer_status = r.get_checked("offer_status")?;
+        let offer_expiry = r.get_checked("offer_expiry")?;
         let creation_time = r.get_checked("creation_time")?;
+        let updated_time = r.get_checked("updated_time")?;
         Ok(Record {
             id: offer_id,
             fields: Offer {
@@ -430,15 +951,18 @@ impl Table for OfferTable {
                     offer_buy_quantity,
                     offer_sell_quantity,
                 },
+                offer_status,
+                offer_expiry,
             },
             creation_time,
+            updated_time,
         })
     }
 
     fn do_insert(table: &Update<Self>, r: &Self::TableRow) -> Result<(), Error> {
         table.insert(
-            "(offer_id, offer_user, offer_cond_id, offer_cond_time, offer_buy_price, offer_sell_price, offer_buy_quantity, offer_sell_quantity, creation_time)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            "(offer_id, offer_user, offer_cond_id, offer_cond_time, offer_buy_price, offer_sell_price, offer_buy_quantity, offer_sell_quantity, offer_status, offer_expiry, creation_time, updated_time)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
             &[
                 &r.id,
                 &r.fields.offer_user,
@@ -448,16 +972,68 @@ impl Table for OfferTable {
                 &r.fields.offer_details.offer_sell_price,
                 &r.fields.offer_details.offer_buy_quantity,
                 &r.fields.offer_details.offer_sell_quantity,
-                &r.creation_time
+                &r.fields.offer_status,
+                &r.fields.offer_expiry,
+                &r.creation_time,
+                &r.updated_time
             ])
     }
 }
 
+impl<'a> Select<'a, OfferTable> {
+    pub fn count_by_cond(&self, cond_id: &ID) -> Result<i64, Error> {
+        self.count_where("offer_cond_id = ?1", &[cond_id])
+    }
+
+    pub fn by_id(&self, id: &ID) -> Result<Record<Offer>, Error> {
+        self.one_where("offer_id = ?1", &[id])
+    }
+
+    /// The user's open offers that haven't expired by `time`, by condition
+    /// and then price.
+    pub fn by_user(&self, user_id: &ID, time: Timesecs) -> Result<Vec<Record<Offer>>, Error> {
+        self.all_where(
+            "offer_user = ?1 AND offer_status = ?2
+            AND (offer_expiry IS NULL OR offer_expiry > ?3)
+            ORDER BY offer_cond_id, offer_buy_price, offer_sell_price, rowid",
+            &[user_id, &OfferStatus::Open, &time],
+        )
+    }
+
+    /// Open offers that haven't expired by `time`.
+    pub fn open(&self, time: Timesecs) -> Result<Vec<Record<Offer>>, Error> {
+        self.all_where(
+            "offer_status = ?1 AND (offer_expiry IS NULL OR offer_expiry > ?2)",
+            &[&OfferStatus::Open, &time],
+        )
+    }
+
+    /// Like `open_by_cond`, but leaving out offers that expired by `time`
+    /// without cancelling them, for queries.
+    pub fn live_by_cond(&self, cond_id: &ID, time: Timesecs) -> Result<Vec<Record<Offer>>, Error> {
+        self.all_where(
+            "offer_cond_id = ?1 AND offer_status = ?2
+            AND (offer_expiry IS NULL OR offer_expiry > ?3)
+            ORDER BY creation_time, rowid",
+            &[cond_id, &OfferStatus::Open, &time],
+        )
+    }
+
+    pub fn open_by_cond(&self, cond_id: &ID) -> Result<Vec<Record<Offer>>, Error> {
+        self.all_where(
+            "offer_cond_id = ?1 AND offer_status = ?2 ORDER BY creation_time, rowid",
+            &[cond_id, &OfferStatus::Open],
+        )
+    }
+}
+
 impl<'a> Update<'a, OfferTable> {
-    pub fn update_offer(&self, id: &ID, offer: &OfferDetails) -> Result<(), Error> {
+    pub fn update_offer(&self, id: &ID, offer: &OfferDetails, time: Timesecs) -> Result<(), Error> {
+        // updating an offer reposts it even if it was filled
         self.update_one(
             "offer_buy_price = ?2, offer_sell_price = ?3,
-            offer_buy_quantity = ?4, offer_sell_quantity = ?5
+            offer_buy_quantity = ?4, offer_sell_quantity = ?5,
+            offer_status = ?6, updated_time = ?7
             WHERE offer_id = ?1",
             &[
                 id,
@@ -465,6 +1041,46 @@ impl<'a> Update<'a, OfferTable> {
                 &offer.offer_sell_price,
                 &offer.offer_buy_quantity,
                 &offer.offer_sell_quantity,
+                &OfferStatus::Open,
+                &Timespec::from(time),
+            ],
+        )
+    }
+
+    pub fn fill_offer(&self, id: &ID, offer: &Offer, time: Timesecs) -> Result<(), Error> {
+        self.update_one(
+            "offer_buy_quantity = ?2, offer_sell_quantity = ?3,
+            offer_status = ?4, updated_time = ?5
+            WHERE offer_id = ?1",
+            &[
+                id,
+                &offer.offer_details.offer_buy_quantity,
+                &offer.offer_details.offer_sell_quantity,
+                &offer.offer_status,
+                &Timespec::from(time),
+            ],
+        )
+    }
+}
+
+impl<'a> Update<'a, OfferTable> {
+    pub fn cancel_offer(&self, id: &ID, time: Timesecs) -> Result<(), Error> {
+        self.update_one(
+            "offer_status = ?2, updated_time = ?3 WHERE offer_id = ?1",
+            &[id, &OfferStatus::Cancelled, &Timespec::from(time)],
+        )
+    }
+
+    /// Cancel open offers that expired by `time`, returning how many.
+    pub fn expire_offers(&self, time: Timesecs) -> Result<usize, Error> {
+        self.update_count(
+            "offer_status = ?1, updated_time = ?2
+            WHERE offer_status = ?3 AND offer_expiry <= ?4",
+            &[
+                &OfferStatus::Cancelled,
+                &Timespec::from(time),
+                &OfferStatus::Open,
+                &time,
             ],
         )
     }
@@ -475,18 +1091,27 @@ impl Table for EntityTable {
 
     const TABLE_NAME: &'static str = "entity";
 
+    const ID_COLUMN: &'static str = "entity_id";
+
+    const COLUMNS: &'static [&'static str] = &["entity_name", "entity_type"];
+
     const CREATE_TABLE: &'static str = "CREATE TABLE entity (
             entity_id       TEXT NOT NULL PRIMARY KEY,
             entity_name     TEXT NOT NULL UNIQUE,
             entity_type     TEXT NOT NULL,
-            creation_time   TEXT NOT NULL
+            creation_time   TEXT NOT NULL,
+            updated_time    TEXT NOT NULL
         )";
 
+    const CREATE_INDEXES: &'static [&'static str] =
+        &["CREATE INDEX entity_type_index ON entity(entity_type)"];
+
     fn from_row(r: &Row) -> Result<Self::TableRow, Error> {
         let entity_id = r.get_checked("entity_id")?;
         let entity_name = r.get_checked("entity_name")?;
         let entity_type = r.get_checked("entity_type")?;
         let creation_time = r.get_checked("creation_time")?;
+        let updated_time = r.get_checked("updated_time")?;
         Ok(Record {
             id: entity_id,
             fields: Entity {
@@ -494,49 +1119,72 @@ impl Table for EntityTable {
                 entity_type,
             },
             creation_time,
+            updated_time,
         })
     }
 
     fn do_insert(table: &Update<Self>, r: &Self::TableRow) -> Result<(), Error> {
         table.insert(
-            "(entity_id, entity_name, entity_type, creation_time)
-            VALUES (?1, ?2, ?3, ?4)",
+            "(entity_id, entity_name, entity_type, creation_time, updated_time)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
             &[
                 &r.id,
                 &r.fields.entity_name,
                 &r.fields.entity_type,
                 &r.creation_time,
+                &r.updated_time,
             ],
         )
     }
 }
 
 impl<'a> Select<'a, EntityTable> {
+    pub fn try_by_id(&self, id: &ID) -> Result<Option<Record<Entity>>, Error> {
+        let mut entities = self.all_where("entity_id = ?1", &[id])?;
+        Ok(entities.pop())
+    }
+
     pub fn by_entity_type(&self, entity_type: &str) -> Result<Vec<Record<Entity>>, Error> {
         self.all_where("entity_type = ?1", &[&entity_type])
     }
 }
 
+impl<'a> Select<'a, EntityTable> {
+    pub fn by_entity_name(&self, entity_name: &str) -> Result<Option<Record<Entity>>, Error> {
+        // UNIQUE(entity_name) so there is at most one
+        let mut entities = self.all_where("entity_name = ?1", &[&entity_name])?;
+        Ok(entities.pop())
+    }
+}
+
 impl Table for RelTable {
     type TableRow = Record<Rel>;
 
     const TABLE_NAME: &'static str = "rel";
 
+    const ID_COLUMN: &'static str = "rel_id";
+
+    const COLUMNS: &'static [&'static str] = &["rel_type", "rel_from", "rel_to"];
+
     const CREATE_TABLE: &'static str = "CREATE TABLE rel (
             rel_id          TEXT NOT NULL PRIMARY KEY,
             rel_type        TEXT NOT NULL,
             rel_from        TEXT NOT NULL REFERENCES entity(entity_id),
             rel_to          TEXT_NOT_NULL REFERENCES entity(entity_id),
             creation_time   TEXT NOT NULL,
+            updated_time    TEXT NOT NULL,
             UNIQUE(rel_from, rel_type)
         )";
 
+    const CREATE_INDEXES: &'static [&'static str] = &["CREATE INDEX rel_to_index ON rel(rel_to)"];
+
     fn from_row(r: &Row) -> Result<Self::TableRow, Error> {
         let rel_id = r.get_checked("rel_id")?;
         let rel_type = r.get_checked("rel_type")?;
         let rel_from = r.get_checked("rel_from")?;
         let rel_to = r.get_checked("rel_to")?;
         let creation_time = r.get_checked("creation_time")?;
+        let updated_time = r.get_checked("updated_time")?;
         Ok(Record {
             id: rel_id,
             fields: Rel {
@@ -545,24 +1193,46 @@ impl Table for RelTable {
                 rel_to,
             },
             creation_time,
+            updated_time,
         })
     }
 
     fn do_insert(table: &Update<Self>, r: &Self::TableRow) -> Result<(), Error> {
         table.insert(
-            "(rel_id, rel_type, rel_from, rel_to, creation_time)
-            VALUES (?1, ?2, ?3, ?4, ?5)",
+            "(rel_id, rel_type, rel_from, rel_to, creation_time, updated_time)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
             &[
                 &r.id,
                 &r.fields.rel_type,
                 &r.fields.rel_from,
                 &r.fields.rel_to,
                 &r.creation_time,
+                &r.updated_time,
             ],
         )
     }
 }
 
+impl<'a> Select<'a, RelTable> {
+    pub fn by_rel_from_type(
+        &self,
+        rel_from: &ID,
+        rel_type: &str,
+    ) -> Result<Option<Record<Rel>>, Error> {
+        // UNIQUE(rel_from, rel_type) so there is at most one
+        let mut rels = self.all_where("rel_from = ?1 AND rel_type = ?2", &[rel_from, &rel_type])?;
+        Ok(rels.pop())
+    }
+
+    /// Rels from any entity of the type.
+    pub fn from_entity_type(&self, entity_type: &str) -> Result<Vec<Record<Rel>>, Error> {
+        self.all_where(
+            "rel_from IN (SELECT entity_id FROM entity WHERE entity_type = ?1) ORDER BY rowid",
+            &[&entity_type],
+        )
+    }
+}
+
 impl Table for PropTable {
     type TableRow = PropRow;
 
@@ -576,6 +1246,9 @@ impl Table for PropTable {
             PRIMARY KEY(entity_id, prop_id)
         )";
 
+    const CREATE_INDEXES: &'static [&'static str] =
+        &["CREATE INDEX prop_value_index ON prop(prop_id, prop_value)"];
+
     fn from_row(r: &Row) -> Result<Self::TableRow, Error> {
         let entity_id = r.get_checked("entity_id")?;
         let prop_id = r.get_checked("prop_id")?;
@@ -598,17 +1271,34 @@ impl Table for PropTable {
     }
 }
 
+impl<'a> Select<'a, PropTable> {
+    /// The props with this id and value, one per entity.
+    pub fn by_prop(&self, prop_id: &str, prop_value: &str) -> Result<Vec<PropRow>, Error> {
+        self.all_where(
+            "prop_id = ?1 AND prop_value = ?2 ORDER BY entity_id",
+            &[&prop_id, &prop_value],
+        )
+    }
+}
+
 impl Table for PredTable {
     type TableRow = Record<Pred>;
 
     const TABLE_NAME: &'static str = "pred";
 
+    const ID_COLUMN: &'static str = "pred_id";
+
+    const COLUMNS: &'static [&'static str] =
+        &["pred_name", "pred_args", "pred_value", "pred_value_type"];
+
     const CREATE_TABLE: &'static str = "CREATE TABLE pred (
             pred_id         TEXT NOT NULL PRIMARY KEY,
             pred_name       TEXT NOT NULL UNIQUE,
             pred_args       TEXT NOT NULL,
             pred_value      TEXT,
-            creation_time   TEXT NOT NULL
+            pred_value_type TEXT NOT NULL,
+            creation_time   TEXT NOT NULL,
+            updated_time    TEXT NOT NULL
         )";
 
     fn from_row(r: &Row) -> Result<Self::TableRow, Error> {
@@ -616,38 +1306,75 @@ impl Table for PredTable {
         let pred_name = r.get_checked("pred_name")?;
         let pred_args = r.get_checked("pred_args")?;
         let pred_value = r.get_checked("pred_value")?;
+        let pred_value_type = r.get_checked("pred_value_type")?;
         let creation_time = r.get_checked("creation_time")?;
+        let updated_time = r.get_checked("updated_time")?;
         Ok(Record {
             id: pred_id,
             fields: Pred {
                 pred_name,
                 pred_args,
                 pred_value,
+                pred_value_type,
             },
             creation_time,
+            updated_time,
         })
     }
 
     fn do_insert(table: &Update<Self>, r: &Self::TableRow) -> Result<(), Error> {
         table.insert(
-            "(pred_id, pred_name, pred_args, pred_value, creation_time)
-            VALUES (?1, ?2, ?3, ?4, ?5)",
+            "(pred_id, pred_name, pred_args, pred_value, pred_value_type, creation_time, updated_time)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
             &[
                 &r.id,
                 &r.fields.pred_name,
                 &r.fields.pred_args,
                 &r.fields.pred_value,
+                &r.fields.pred_value_type,
                 &r.creation_time,
+                &r.updated_time,
             ],
         )
     }
 }
 
+impl<'a> Select<'a, PredTable> {
+    pub fn by_id(&self, id: &ID) -> Result<Record<Pred>, Error> {
+        self.one_where("pred_id = ?1", &[id])
+    }
+
+    pub fn try_by_id(&self, id: &ID) -> Result<Option<Record<Pred>>, Error> {
+        let mut preds = self.all_where("pred_id = ?1", &[id])?;
+        Ok(preds.pop())
+    }
+}
+
+impl<'a> Update<'a, PredTable> {
+    pub fn set_value(&self, id: &ID, pred_value: &str, time: Timesecs) -> Result<(), Error> {
+        self.update_one(
+            "pred_value = ?2, updated_time = ?3 WHERE pred_id = ?1",
+            &[id, &pred_value, &Timespec::from(time)],
+        )
+    }
+}
+
 impl Table for DependTable {
     type TableRow = Record<Depend>;
 
     const TABLE_NAME: &'static str = "depend";
 
+    const ID_COLUMN: &'static str = "depend_id";
+
+    const COLUMNS: &'static [&'static str] = &[
+        "depend_type",
+        "depend_pred1",
+        "depend_pred2",
+        "depend_vars",
+        "depend_args1",
+        "depend_args2",
+    ];
+
     const CREATE_TABLE: &'static str = "CREATE TABLE depend (
             depend_id       TEXT NOT NULL PRIMARY KEY,
             depend_type     TEXT NOT NULL,
@@ -657,6 +1384,7 @@ impl Table for DependTable {
             depend_args1    TEXT NOT NULL,
             depend_args2    TEXT NOT NULL,
             creation_time   TEXT NOT NULL,
+            updated_time    TEXT NOT NULL,
             UNIQUE(depend_type, depend_pred1, depend_pred2)
         )";
 
@@ -669,6 +1397,7 @@ impl Table for DependTable {
         let depend_args1 = r.get_checked("depend_args1")?;
         let depend_args2 = r.get_checked("depend_args2")?;
         let creation_time = r.get_checked("creation_time")?;
+        let updated_time = r.get_checked("updated_time")?;
         Ok(Record {
             id: depend_id,
             fields: Depend {
@@ -680,13 +1409,14 @@ impl Table for DependTable {
                 depend_args2,
             },
             creation_time,
+            updated_time,
         })
     }
 
     fn do_insert(table: &Update<Self>, r: &Self::TableRow) -> Result<(), Error> {
         table.insert(
-            "(depend_id, depend_type, depend_pred1, depend_pred2, depend_vars, depend_args1, depend_args2, creation_time)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            "(depend_id, depend_type, depend_pred1, depend_pred2, depend_vars, depend_args1, depend_args2, creation_time, updated_time)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
             &[
                 &r.id,
                 &r.fields.depend_type,
@@ -695,9 +1425,191 @@ impl Table for DependTable {
                 &r.fields.depend_vars,
                 &r.fields.depend_args1,
                 &r.fields.depend_args2,
-                &r.creation_time
+                &r.creation_time,
+                &r.updated_time
             ])
     }
 }
 
+impl<'a> Select<'a, DependTable> {
+    pub fn by_depend_type(&self, depend_type: &str) -> Result<Vec<Record<Depend>>, Error> {
+        self.all_where("depend_type = ?1", &[&depend_type])
+    }
+
+    /// Depends from or to the predicate, in the order they were created.
+    pub fn by_pred(&self, pred_id: &ID) -> Result<Vec<Record<Depend>>, Error> {
+        self.all_where(
+            "depend_pred1 = ?1 OR depend_pred2 = ?1 ORDER BY rowid",
+            &[pred_id],
+        )
+    }
+}
+
+impl Table for LogTable {
+    type TableRow = LogRow;
+
+    const TABLE_NAME: &'static str = "request_log";
+
+    const CREATE_TABLE: &'static str = "CREATE TABLE request_log (
+            log_time        TEXT NOT NULL,
+            log_user        TEXT,
+            log_request     TEXT NOT NULL,
+            log_status      TEXT NOT NULL
+        )";
+
+    fn from_row(r: &Row) -> Result<LogRow, Error> {
+        let log_time = r.get_checked("log_time")?;
+        let log_user = r.get_checked("log_user")?;
+        let log_request = r.get_checked("log_request")?;
+        let log_status = r.get_checked("log_status")?;
+        Ok(LogRow {
+            log_time,
+            log_user,
+            log_request,
+            log_status,
+        })
+    }
+
+    fn do_insert(table: &Update<Self>, r: &Self::TableRow) -> Result<(), Error> {
+        table.insert(
+            "(log_time, log_user, log_request, log_status)
+            VALUES (?1, ?2, ?3, ?4)",
+            &[&r.log_time, &r.log_user, &r.log_request, &r.log_status],
+        )
+    }
+}
+
+impl<'a> Select<'a, LogTable> {
+    pub fn since(&self, since: Timesecs, limit: u32) -> Result<Vec<LogRow>, Error> {
+        self.all_where(
+            "log_time >= ?1 ORDER BY rowid LIMIT ?2",
+            &[&Timespec::from(since), &limit],
+        )
+    }
+}
+
+impl Table for ApiKeyTable {
+    type TableRow = ApiKeyRow;
+
+    const TABLE_NAME: &'static str = "api_key";
+
+    const CREATE_TABLE: &'static str = "CREATE TABLE api_key (
+            key_id          TEXT NOT NULL PRIMARY KEY,
+            key_user        TEXT NOT NULL,
+            key_secret      TEXT NOT NULL,
+            creation_time   TEXT NOT NULL,
+            FOREIGN KEY(key_user) REFERENCES user(user_id)
+        )";
+
+    fn from_row(r: &Row) -> Result<ApiKeyRow, Error> {
+        let key_id = r.get_checked("key_id")?;
+        let key_user = r.get_checked("key_user")?;
+        let key_secret = r.get_checked("key_secret")?;
+        let creation_time = r.get_checked("creation_time")?;
+        Ok(ApiKeyRow {
+            key_id,
+            key_user,
+            key_secret,
+            creation_time,
+        })
+    }
+
+    fn do_insert(table: &Update<Self>, r: &Self::TableRow) -> Result<(), Error> {
+        table.insert(
+            "(key_id, key_user, key_secret, creation_time)
+            VALUES (?1, ?2, ?3, ?4)",
+            &[&r.key_id, &r.key_user, &r.key_secret, &r.creation_time],
+        )
+    }
+}
+
+impl Table for EventTable {
+    type TableRow = EventRow;
+
+    const TABLE_NAME: &'static str = "event";
+
+    const CREATE_TABLE: &'static str = "CREATE TABLE event (
+            event_time      INTEGER NOT NULL,
+            event_user      TEXT,
+            event_request   TEXT NOT NULL,
+            event_ids       TEXT NOT NULL
+        )";
+
+    fn from_row(r: &Row) -> Result<EventRow, Error> {
+        let event_time = r.get_checked("event_time")?;
+        let event_user = r.get_checked("event_user")?;
+        let event_request = r.get_checked("event_request")?;
+        let event_ids = r.get_checked("event_ids")?;
+        Ok(EventRow {
+            event_time,
+            event_user,
+            event_request,
+            event_ids,
+        })
+    }
+
+    fn do_insert(table: &Update<Self>, r: &Self::TableRow) -> Result<(), Error> {
+        table.insert(
+            "(event_time, event_user, event_request, event_ids)
+            VALUES (?1, ?2, ?3, ?4)",
+            &[&r.event_time, &r.event_user, &r.event_request, &r.event_ids],
+        )
+    }
+}
+
+impl<'a> Select<'a, EventTable> {
+    pub fn in_order(&self) -> Result<Vec<EventRow>, Error> {
+        self.all_where("1 ORDER BY rowid", &[])
+    }
+}
+
+impl Table for RelTypeTable {
+    type TableRow = RelTypeRow;
+
+    const TABLE_NAME: &'static str = "rel_type";
+
+    const CREATE_TABLE: &'static str = "CREATE TABLE rel_type (
+            rel_type        TEXT NOT NULL PRIMARY KEY,
+            creation_time   TEXT NOT NULL
+        )";
+
+    fn from_row(r: &Row) -> Result<RelTypeRow, Error> {
+        let rel_type = r.get_checked("rel_type")?;
+        let creation_time = r.get_checked("creation_time")?;
+        Ok(RelTypeRow {
+            rel_type,
+            creation_time,
+        })
+    }
+
+    fn do_insert(table: &Update<Self>, r: &Self::TableRow) -> Result<(), Error> {
+        table.insert(
+            "(rel_type, creation_time) VALUES (?1, ?2)",
+            &[&r.rel_type, &r.creation_time],
+        )
+    }
+}
+
+impl<'a> Select<'a, RelTypeTable> {
+    pub fn in_order(&self) -> Result<Vec<RelTypeRow>, Error> {
+        self.all_where("1 ORDER BY rel_type", &[])
+    }
+
+    pub fn has(&self, rel_type: &str) -> Result<bool, Error> {
+        Ok(self.count_where("rel_type = ?1", &[&rel_type])? > 0)
+    }
+
+    /// Whether rels may have the type: it is registered, or no types are.
+    pub fn allows(&self, rel_type: &str) -> Result<bool, Error> {
+        Ok(self.count()? == 0 || self.has(rel_type)?)
+    }
+}
+
+impl<'a> Select<'a, ApiKeyTable> {
+    pub fn by_key_id(&self, key_id: &str) -> Result<Option<ApiKeyRow>, Error> {
+        let rows = self.all_where("key_id = ?1", &[&key_id])?;
+        Ok(rows.into_iter().next())
+    }
+}
+
 // vi: ts=8 sts=4 et
@@ -1,35 +1,56 @@
-use failure::{err_msg, Error};
-use std::collections::HashMap;
+use failure::{err_msg, format_err, Error};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::iter::Sum;
 use std::ops::{Add, AddAssign, Sub, SubAssign};
+use std::str::FromStr;
 use time::get_time;
 use time::{strptime, Timespec};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct ID(pub String);
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+/// A number of contract units on one side of an offer
+pub struct Quantity(u32);
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 /// measured in millidollars
 pub struct Dollars(i64);
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 /// UNIX time, seconds since 1970
+///
+/// Times in responses are always given as this, which serializes as a bare
+/// number, and never as a `Timespec`, which would be a `{sec, nsec}` object.
+/// Stored records keep `Timespec` and are converted at the response boundary.
 pub struct Timesecs(i64);
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ArgList(Vec<String>);
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub user_name: String,
     pub user_locked: bool,
+    /// how much the user may owe, set by the market operator
+    #[serde(default)]
+    pub user_credit_limit: Dollars,
+    /// a name to show for the user, which need not be unique
+    #[serde(default)]
+    pub user_display_name: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Identity {
     pub identity_user_id: ID,
     pub identity_service: String,
     pub identity_account_name: String,
     pub identity_attested_time: Timesecs,
+    /// set once the account is confirmed to be the user's, at the attested
+    /// time
+    #[serde(default)]
+    pub identity_verified: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -42,56 +63,149 @@ pub struct IOU {
     pub iou_cond_time: Option<Timesecs>,
     pub iou_split: Option<ID>,
     pub iou_void: bool,
+    /// what the IOU is for, kept by the IOUs it is split into
+    #[serde(default)]
+    pub iou_memo: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Splits an IOU between new holders, whose values must add up to the whole
+/// IOU. To hand over only part of it the current holder keeps the rest by
+/// being one of the holders, which `Transfer::partial` does for you.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transfer {
     pub holders: HashMap<ID, Dollars>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Cond {
     pub cond_pred: ID,
     pub cond_args: Vec<ID>,
+    /// the value of a `time` arg, which isn't an entity
+    #[serde(default)]
+    pub cond_arg_time: Option<Timesecs>,
+    #[serde(default)]
+    pub cond_value: Option<bool>,
+    /// closed to new offers, and ready to be resolved
+    #[serde(default)]
+    pub cond_closed: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// If `offer_cond_time` is set the condition must hold by that deadline, and
+/// the offer only matches offers on the same condition with the same deadline.
 pub struct Offer {
     pub offer_user: ID,
     pub offer_cond_id: ID,
     pub offer_cond_time: Option<Timesecs>,
     pub offer_details: OfferDetails,
+    #[serde(default)]
+    pub offer_status: OfferStatus,
+    /// when the offer is cancelled if it hasn't been filled, if ever
+    #[serde(default)]
+    pub offer_expiry: Option<Timesecs>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OfferStatus {
+    Open,
+    Filled,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+/// Quantities are in contract units, each paying a dollar if the condition
+/// comes out true. Buying a unit at a price risks the price, and selling one
+/// risks the rest of the dollar.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OfferDetails {
     pub offer_buy_price: Dollars,
     pub offer_sell_price: Dollars,
-    pub offer_buy_quantity: u32,
-    pub offer_sell_quantity: u32,
+    pub offer_buy_quantity: Quantity,
+    pub offer_sell_quantity: Quantity,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A match between two offers, settled by a pair of conditional IOUs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Trade {
+    pub cond_id: ID,
+    pub cond_time: Option<Timesecs>,
+    pub buyer: ID,
+    pub seller: ID,
+    pub buy_offer: ID,
+    pub sell_offer: ID,
+    pub units: u32,
+    pub price: Dollars,
+    pub buyer_iou: ID,
+    pub seller_iou: ID,
+}
+
+/// The trades made by clearing a condition, and whether every book on it
+/// was cleared or clearing stopped at the iteration cap.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Clearing {
+    pub trades: Vec<Trade>,
+    pub complete: bool,
+}
+
+/// Total open quantity at each price on either side of a condition.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BookDepth {
+    pub bids: BTreeMap<Dollars, u32>,
+    pub asks: BTreeMap<Dollars, u32>,
+}
+
+/// What a user owes and is owed, unconditionally and on each condition.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UserBalance {
+    /// unconditional IOUs the user issued
+    pub debt: Dollars,
+    /// unconditional IOUs the user holds
+    pub credit: Dollars,
+    /// the most the user could lose on each condition, as for `exposure`
+    pub exposure: HashMap<ID, Dollars>,
+    /// the most the user's open offers could add to that if they were
+    /// filled, taking the larger side of each
+    #[serde(default)]
+    pub open_offers: Dollars,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Entity {
     pub entity_name: String,
     pub entity_type: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Rel {
     pub rel_type: String,
     pub rel_from: ID,
     pub rel_to: ID,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Pred {
     pub pred_name: String,
     pub pred_args: ArgList,
     pub pred_value: Option<String>,
+    #[serde(default)]
+    pub pred_value_type: PredValueType,
+}
+
+/// The kind of value a predicate resolves to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PredValueType {
+    Bool,
+    /// when it became true, as "%Y-%m-%d %H:%M:%S"
+    Date,
+    Scalar,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Depend {
     pub depend_type: String,
     pub depend_pred1: ID,
@@ -116,37 +230,397 @@ impl User {
     }
 
     fn valid_user_name_char(c: char) -> bool {
-        c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.'
+        c.is_alphanumeric() || c == '_' || c == '-' || c == '.'
     }
 
+    /// The user name without punctuation and lowercased, which must be
+    /// unique. Letters and digits from any script are kept.
     pub fn user_name_stripped(user_name: &str) -> String {
-        let mut user_name_stripped: String = user_name
+        user_name
             .chars()
-            .filter(char::is_ascii_alphanumeric)
-            .collect();
-        user_name_stripped.make_ascii_lowercase();
-        user_name_stripped
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+            .collect()
     }
 }
 
 impl OfferDetails {
+    /// Prices must be in order within a dollar, and there must be something
+    /// to buy or sell.
     pub fn valid(&self) -> bool {
         Dollars::ZERO <= self.offer_buy_price
             && self.offer_buy_price < self.offer_sell_price
             && self.offer_sell_price <= Dollars::ONE
+            && (self.offer_buy_quantity > Quantity::ZERO
+                || self.offer_sell_quantity > Quantity::ZERO)
+    }
+
+    pub fn price(&self, side: Side) -> Dollars {
+        match side {
+            Side::Buy => self.offer_buy_price,
+            Side::Sell => self.offer_sell_price,
+        }
+    }
+
+    /// Units offered on one side.
+    pub fn quantity(&self, side: Side) -> Quantity {
+        match side {
+            Side::Buy => self.offer_buy_quantity,
+            Side::Sell => self.offer_sell_quantity,
+        }
+    }
+
+    /// The most one side could lose if it were filled at its quoted price,
+    /// the same as the IOU its owner would issue in `trade_ious`.
+    pub fn max_exposure(&self, side: Side) -> Result<Dollars, Error> {
+        self.quantity(side)
+            .max_exposure(side.unit_cost(self.price(side)))
+    }
+}
+
+impl Side {
+    /// What one unit at the price risks: the price for the buyer, who loses
+    /// if the condition is false, and the rest of the dollar for the seller.
+    pub fn unit_cost(self, price: Dollars) -> Dollars {
+        match self {
+            Side::Buy => price,
+            Side::Sell => Dollars::ONE - price,
+        }
+    }
+}
+
+impl Identity {
+    /// The attested time can't be in the future, and a verified identity
+    /// must have one.
+    pub fn valid_attested_time(&self, time: Timesecs) -> bool {
+        if self.identity_verified {
+            plausible_attested_time(self.identity_attested_time, time)
+        } else {
+            self.identity_attested_time <= time
+        }
+    }
+}
+
+/// Whether an identity could have been attested at `attested_time`, given
+/// that it is now `time`.
+pub fn plausible_attested_time(attested_time: Timesecs, time: Timesecs) -> bool {
+    Timesecs::from(0) < attested_time && attested_time <= time
+}
+
+impl Offer {
+    pub fn valid_cond_time(&self, time: Timesecs) -> bool {
+        match self.offer_cond_time {
+            None => true,
+            Some(cond_time) => cond_time > time,
+        }
+    }
+
+    /// An offer can't be made that has already expired.
+    pub fn valid_expiry(&self, time: Timesecs) -> bool {
+        match self.offer_expiry {
+            None => true,
+            Some(expiry) => expiry > time,
+        }
+    }
+
+    /// Units still available to match on one side of the offer, as the
+    /// matching engine counts them.
+    pub fn open_quantity(&self, side: Side) -> u32 {
+        if self.offer_status != OfferStatus::Open {
+            return 0;
+        }
+        self.offer_details.quantity(side).to_units()
+    }
+
+    /// Take `units` off one side, marking the offer filled once both sides
+    /// are exhausted.
+    pub fn fill(&mut self, side: Side, units: u32) {
+        let details = &mut self.offer_details;
+        let quantity = match side {
+            Side::Buy => &mut details.offer_buy_quantity,
+            Side::Sell => &mut details.offer_sell_quantity,
+        };
+        *quantity = Quantity::from_units(quantity.to_units() - units);
+        if details.offer_buy_quantity == Quantity::ZERO
+            && details.offer_sell_quantity == Quantity::ZERO
+        {
+            self.offer_status = OfferStatus::Filled;
+        }
+    }
+}
+
+impl Default for OfferStatus {
+    fn default() -> Self {
+        OfferStatus::Open
+    }
+}
+
+impl OfferStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OfferStatus::Open => "open",
+            OfferStatus::Filled => "filled",
+            OfferStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn from_name(s: &str) -> Option<OfferStatus> {
+        match s {
+            "open" => Some(OfferStatus::Open),
+            "filled" => Some(OfferStatus::Filled),
+            "cancelled" => Some(OfferStatus::Cancelled),
+            _ => None,
+        }
+    }
+}
+
+impl Default for PredValueType {
+    fn default() -> Self {
+        PredValueType::Bool
+    }
+}
+
+impl PredValueType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PredValueType::Bool => "bool",
+            PredValueType::Date => "date",
+            PredValueType::Scalar => "scalar",
+        }
+    }
+
+    pub fn from_name(s: &str) -> Option<PredValueType> {
+        match s {
+            "bool" => Some(PredValueType::Bool),
+            "date" => Some(PredValueType::Date),
+            "scalar" => Some(PredValueType::Scalar),
+            _ => None,
+        }
+    }
+
+    pub fn valid_value(&self, value: &str) -> bool {
+        match self {
+            PredValueType::Bool => value == "true" || value == "false",
+            PredValueType::Date => Timesecs::parse_datetime(value).is_ok(),
+            PredValueType::Scalar => value.parse::<f64>().map_or(false, f64::is_finite),
+        }
+    }
+}
+
+impl Pred {
+    /// The number of args a cond on this pred takes, counting a time arg.
+    pub fn arity(&self) -> usize {
+        self.pred_args.len()
+    }
+
+    pub fn valid_value(&self) -> bool {
+        match &self.pred_value {
+            None => true,
+            Some(value) => self.pred_value_type.valid_value(value),
+        }
     }
 }
 
 impl IOU {
-    pub fn valid(&self) -> Result<(), Error> {
-        if self.iou_value <= Dollars::ZERO {
-            return Err(err_msg("IOU value must be positive"));
+    /// The value must be positive.
+    pub fn valid(&self) -> bool {
+        self.iou_value > Dollars::ZERO
+    }
+
+    pub fn valid_memo(&self) -> bool {
+        match &self.iou_memo {
+            None => true,
+            Some(memo) => memo.chars().count() <= MAX_MEMO_LENGTH,
+        }
+    }
+
+    pub fn valid_cond_time(&self, time: Timesecs) -> bool {
+        match self.iou_cond_time {
+            None => true,
+            Some(cond_time) => self.iou_cond_id.is_some() && cond_time > time,
+        }
+    }
+
+    /// Whether the IOU still pays once its condition resolves to `cond_value`.
+    /// A true `iou_cond_flag` pays if the condition is true, and a false one
+    /// pays if it is false.
+    pub fn pays(&self, cond_value: bool) -> bool {
+        self.iou_cond_flag == cond_value
+    }
+}
+
+/// The pair of IOUs settling a trade: the buyer pays the price if the
+/// condition is false and the seller pays the rest of the dollar if it is
+/// true.
+pub fn trade_ious(
+    cond_id: &ID,
+    cond_time: Option<Timesecs>,
+    buyer: &ID,
+    seller: &ID,
+    price: Dollars,
+    units: u32,
+) -> (IOU, IOU) {
+    let buyer_iou = IOU {
+        iou_issuer: buyer.clone(),
+        iou_holder: seller.clone(),
+        iou_value: Side::Buy.unit_cost(price).times(units),
+        iou_cond_id: Some(cond_id.clone()),
+        iou_cond_flag: false,
+        iou_cond_time: cond_time,
+        iou_split: None,
+        iou_void: false,
+        iou_memo: None,
+    };
+    let seller_iou = IOU {
+        iou_issuer: seller.clone(),
+        iou_holder: buyer.clone(),
+        iou_value: Side::Sell.unit_cost(price).times(units),
+        iou_cond_id: Some(cond_id.clone()),
+        iou_cond_flag: true,
+        iou_cond_time: cond_time,
+        iou_split: None,
+        iou_void: false,
+        iou_memo: None,
+    };
+    (buyer_iou, seller_iou)
+}
+
+/// The most a user could lose on each condition they hold or issued live
+/// IOUs on: their net position if it comes out true and if it comes out
+/// false, taking the worse of the two. Unconditional IOUs don't depend on
+/// any condition and are left out.
+pub fn exposure(user: &ID, ious: &[IOU]) -> Result<HashMap<ID, Dollars>, Error> {
+    let mut positions = Positions::new();
+    for iou in ious {
+        let cond_id = match &iou.iou_cond_id {
+            Some(cond_id) if !iou.iou_void => cond_id,
+            _ => continue,
+        };
+        if &iou.iou_holder == user {
+            add_position(&mut positions, cond_id, iou.iou_cond_flag, iou.iou_value)?;
+        } else if &iou.iou_issuer == user {
+            add_position(
+                &mut positions,
+                cond_id,
+                iou.iou_cond_flag,
+                Dollars::ZERO - iou.iou_value,
+            )?;
         }
-        Ok(())
     }
+    worst_case(positions)
+}
+
+/// The balance of every user with live IOUs, in one pass over them.
+pub fn balances(ious: &[IOU]) -> Result<HashMap<ID, UserBalance>, Error> {
+    let mut balances: HashMap<ID, UserBalance> = HashMap::new();
+    let mut positions: HashMap<&ID, Positions> = HashMap::new();
+    for iou in ious.iter().filter(|iou| !iou.iou_void) {
+        match &iou.iou_cond_id {
+            None => {
+                let holder = balances.entry(iou.iou_holder.clone()).or_default();
+                holder.credit = holder.credit.checked_add(iou.iou_value)?;
+                let issuer = balances.entry(iou.iou_issuer.clone()).or_default();
+                issuer.debt = issuer.debt.checked_add(iou.iou_value)?;
+            }
+            Some(cond_id) => {
+                add_position(
+                    positions.entry(&iou.iou_holder).or_default(),
+                    cond_id,
+                    iou.iou_cond_flag,
+                    iou.iou_value,
+                )?;
+                add_position(
+                    positions.entry(&iou.iou_issuer).or_default(),
+                    cond_id,
+                    iou.iou_cond_flag,
+                    Dollars::ZERO - iou.iou_value,
+                )?;
+            }
+        }
+    }
+    for (user, positions) in positions {
+        balances.entry(user.clone()).or_default().exposure = worst_case(positions)?;
+    }
+    Ok(balances)
+}
+
+/// Who pays whom on unconditional IOUs, as (payer, payee, amount) with the
+/// IOUs between each pair of users netted against each other. Pairs that
+/// cancel out are left out.
+pub fn settlement(ious: &[IOU]) -> Result<Vec<(ID, ID, Dollars)>, Error> {
+    // keyed by the pair in id order, positive if the first owes the second
+    let mut net: HashMap<(&ID, &ID), Dollars> = HashMap::new();
+    for iou in ious
+        .iter()
+        .filter(|iou| !iou.iou_void && iou.iou_cond_id.is_none())
+    {
+        if iou.iou_issuer.0 < iou.iou_holder.0 {
+            let amount = net.entry((&iou.iou_issuer, &iou.iou_holder)).or_default();
+            *amount = amount.checked_add(iou.iou_value)?;
+        } else {
+            let amount = net.entry((&iou.iou_holder, &iou.iou_issuer)).or_default();
+            *amount = amount.checked_sub(iou.iou_value)?;
+        }
+    }
+    let mut payments: Vec<(ID, ID, Dollars)> = net
+        .into_iter()
+        .filter_map(|((first, second), amount)| {
+            if amount > Dollars::ZERO {
+                Some((first.clone(), second.clone(), amount))
+            } else if amount < Dollars::ZERO {
+                Some((second.clone(), first.clone(), amount.abs()))
+            } else {
+                None
+            }
+        })
+        .collect();
+    payments.sort_by(|a, b| (&(a.0).0, &(a.1).0).cmp(&(&(b.0).0, &(b.1).0)));
+    Ok(payments)
+}
+
+/// Net position on each cond if it is (false, true).
+type Positions<'a> = HashMap<&'a ID, (Dollars, Dollars)>;
+
+fn add_position<'a>(
+    positions: &mut Positions<'a>,
+    cond_id: &'a ID,
+    cond_flag: bool,
+    value: Dollars,
+) -> Result<(), Error> {
+    let position = positions
+        .entry(cond_id)
+        .or_insert((Dollars::ZERO, Dollars::ZERO));
+    if cond_flag {
+        position.1 = position.1.checked_add(value)?;
+    } else {
+        position.0 = position.0.checked_add(value)?;
+    }
+    Ok(())
+}
+
+fn worst_case(positions: Positions) -> Result<HashMap<ID, Dollars>, Error> {
+    positions
+        .into_iter()
+        .map(|(cond_id, (if_false, if_true))| {
+            let worst = cmp::min(cmp::min(if_false, if_true), Dollars::ZERO);
+            Ok((cond_id.clone(), Dollars::ZERO.checked_sub(worst)?))
+        })
+        .collect()
 }
 
 impl Transfer {
+    /// Transfer `amount` of the IOU to `to`, leaving the remainder with its
+    /// current holder. Check it with `valid` like any other transfer.
+    pub fn partial(old_iou: &IOU, to: ID, amount: Dollars) -> Transfer {
+        let mut holders = HashMap::new();
+        let remainder = old_iou.iou_value - amount;
+        if remainder != Dollars::ZERO {
+            holders.insert(old_iou.iou_holder.clone(), remainder);
+        }
+        *holders.entry(to).or_insert(Dollars::ZERO) += amount;
+        Transfer { holders }
+    }
+
     pub fn valid(&self, old_iou: &IOU) -> Result<(), Error> {
         if old_iou.iou_void {
             return Err(err_msg("transfer IOU cannot be void"));
@@ -179,6 +653,7 @@ impl Transfer {
                 iou_cond_time: old_iou.iou_cond_time,
                 iou_split: Some(old_id.clone()),
                 iou_void: *user_id == old_iou.iou_issuer,
+                iou_memo: old_iou.iou_memo.clone(),
             };
             ious.push(new_iou);
         }
@@ -197,6 +672,149 @@ impl Dollars {
     pub fn to_millibucks(&self) -> i64 {
         self.0
     }
+
+    /// The sum, or an error rather than a wrapped total if it doesn't fit.
+    pub fn checked_add(self, other: Dollars) -> Result<Dollars, Error> {
+        self.0
+            .checked_add(other.0)
+            .map(Dollars)
+            .ok_or_else(|| format_err!("{} + {} millibucks overflows", self.0, other.0))
+    }
+
+    /// The difference, or an error rather than a wrapped one.
+    pub fn checked_sub(self, other: Dollars) -> Result<Dollars, Error> {
+        self.0
+            .checked_sub(other.0)
+            .map(Dollars)
+            .ok_or_else(|| format_err!("{} - {} millibucks overflows", self.0, other.0))
+    }
+
+    /// Add up the amounts, or an error if the total doesn't fit.
+    pub fn checked_sum<I: IntoIterator<Item = Dollars>>(amounts: I) -> Result<Dollars, Error> {
+        amounts
+            .into_iter()
+            .try_fold(Dollars::ZERO, Dollars::checked_add)
+    }
+
+    /// Whether the amount is no more than `MAX_DOLLARS` either way, so that
+    /// many of them can be added up. Amounts from clients must be.
+    pub fn in_range(self) -> bool {
+        -MAX_DOLLARS.0 <= self.0 && self <= MAX_DOLLARS
+    }
+
+    /// Parse an amount in dollars with up to three decimal places, such as
+    /// "3.40" or "-0.005".
+    pub fn parse(s: &str) -> Result<Dollars, Error> {
+        let invalid = || format_err!("invalid amount {:?}", s);
+        let (negative, digits) = if s.starts_with('-') {
+            (true, &s[1..])
+        } else {
+            (false, s)
+        };
+        let (whole, fraction) = match digits.find('.') {
+            Some(point) => (&digits[..point], &digits[point + 1..]),
+            None => (digits, ""),
+        };
+        if (whole.is_empty() && fraction.is_empty())
+            || fraction.len() > 3
+            || !whole
+                .chars()
+                .chain(fraction.chars())
+                .all(|c| c.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+        let whole: i64 = if whole.is_empty() { 0 } else { whole.parse()? };
+        let fraction: i64 = if fraction.is_empty() {
+            0
+        } else {
+            format!("{:0<3}", fraction).parse()?
+        };
+        let m = whole
+            .checked_mul(1000)
+            .and_then(|m| m.checked_add(fraction))
+            .ok_or_else(invalid)?;
+        Ok(Dollars(if negative { -m } else { m }))
+    }
+
+    /// Halfway between two prices, rounding down.
+    pub fn midpoint(a: Dollars, b: Dollars) -> Dollars {
+        Dollars((a.0 + b.0) / 2)
+    }
+
+    pub fn times(&self, units: u32) -> Dollars {
+        Dollars(self.0 * i64::from(units))
+    }
+
+    pub fn abs(self) -> Dollars {
+        Dollars(self.0.abs())
+    }
+
+    /// In dollars as a float, for charts and stats rather than accounts.
+    pub fn as_f64_dollars(&self) -> f64 {
+        self.0 as f64 / 1000.0
+    }
+
+    /// In whole cents, rounding to the nearest cent and half a cent away
+    /// from zero, for systems that count in cents.
+    // nothing in the market counts in cents itself
+    #[allow(dead_code)]
+    pub fn to_cents_rounded(&self) -> i64 {
+        let cents = self.0 / 10;
+        match self.0 % 10 {
+            r if r >= 5 => cents + 1,
+            r if r <= -5 => cents - 1,
+            _ => cents,
+        }
+    }
+}
+
+impl From<Dollars> for f64 {
+    fn from(d: Dollars) -> f64 {
+        d.as_f64_dollars()
+    }
+}
+
+impl Quantity {
+    pub const ZERO: Self = Quantity(0);
+
+    pub fn from_units(units: u32) -> Self {
+        Quantity(units)
+    }
+
+    pub fn to_units(self) -> u32 {
+        self.0
+    }
+
+    /// What this many units would cost at `unit_cost` each, or an error
+    /// rather than a wrapped total if it doesn't fit.
+    pub fn max_exposure(self, unit_cost: Dollars) -> Result<Dollars, Error> {
+        unit_cost
+            .0
+            .checked_mul(i64::from(self.0))
+            .map(Dollars)
+            .ok_or_else(|| format_err!("{} units at {} millibucks overflows", self.0, unit_cost.0))
+    }
+}
+
+impl FromStr for Dollars {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Dollars, Error> {
+        Dollars::parse(s)
+    }
+}
+
+impl Sum for Dollars {
+    fn sum<I: Iterator<Item = Dollars>>(iter: I) -> Dollars {
+        iter.fold(Dollars::ZERO, Add::add)
+    }
+}
+
+impl<'a> Sum<&'a Dollars> for Dollars {
+    fn sum<I: Iterator<Item = &'a Dollars>>(iter: I) -> Dollars {
+        iter.cloned().sum()
+    }
 }
 
 impl Add for Dollars {
@@ -233,6 +851,12 @@ impl From<Timesecs> for Timespec {
     }
 }
 
+impl From<Timespec> for Timesecs {
+    fn from(t: Timespec) -> Timesecs {
+        Timesecs(t.sec)
+    }
+}
+
 impl<'a> From<Timesecs> for i64 {
     fn from(t: Timesecs) -> i64 {
         t.0
@@ -245,6 +869,23 @@ impl From<i64> for Timesecs {
     }
 }
 
+/// The start of 2000, before which no operation time is plausible.
+pub const MIN_OPERATION_TIME: Timesecs = Timesecs(946_684_800);
+
+/// How far ahead of the clock an operation time can be, in seconds.
+pub const MAX_OPERATION_AHEAD: i64 = 24 * 60 * 60;
+
+/// The end of 9999. Stored timestamps are text that only sorts by time with
+/// four digit years, and far larger times can't be formatted at all.
+pub const MAX_TIME: Timesecs = Timesecs(253_402_300_799);
+
+/// A billion dollars, the most any one amount can be. Millions of them
+/// still add up without overflowing.
+pub const MAX_DOLLARS: Dollars = Dollars(1_000_000_000_000);
+
+/// The longest memo an IOU can have, in characters.
+pub const MAX_MEMO_LENGTH: usize = 200;
+
 impl Timesecs {
     pub fn now() -> Timesecs {
         Timesecs::from(get_time().sec)
@@ -255,6 +896,59 @@ impl Timesecs {
             strptime(s, "%Y-%m-%d %H:%M:%S")?.to_timespec().sec,
         ))
     }
+
+    /// The start of a day given as "2020-01-01", and nothing more.
+    pub fn parse_date(s: &str) -> Result<Timesecs, Error> {
+        let tm = strptime(s, "%Y-%m-%d")?;
+        // strptime stops at the end of the format, ignoring anything after
+        if tm.strftime("%Y-%m-%d")?.to_string() != s {
+            return Err(format_err!("invalid date {:?}", s));
+        }
+        Ok(Timesecs::from(tm.to_timespec().sec))
+    }
+
+    /// Whether the time is between 1970 and `MAX_TIME`, so that it can be
+    /// stored and compared with others. Times from clients must be.
+    pub fn in_range(self) -> bool {
+        self.0 >= 0 && self <= MAX_TIME
+    }
+
+    /// Check that a time someone supplied for an operation is after 2000
+    /// and not more than a day ahead of `now`, so that a typo isn't stored.
+    pub fn check_operation_time(self, now: Timesecs) -> Result<Timesecs, Error> {
+        if self < MIN_OPERATION_TIME {
+            Err(err_msg("time is before the year 2000"))
+        } else if self.0 > now.0 + MAX_OPERATION_AHEAD {
+            Err(err_msg("time is more than a day in the future"))
+        } else {
+            Ok(self)
+        }
+    }
+}
+
+/// A date and time as for `parse_datetime`, or a date alone.
+impl FromStr for Timesecs {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Timesecs, Error> {
+        Timesecs::parse_datetime(s)
+            .or_else(|_| Timesecs::parse_date(s))
+            .map_err(|_| format_err!("invalid time {:?}", s))
+    }
+}
+
+impl ArgList {
+    pub fn args(&self) -> &[String] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 }
 
 impl<'a> From<&'a ArgList> for String {
@@ -293,6 +987,29 @@ fn token_list_two() {
     assert_eq!(ArgList::from(",y").0.len(), 2);
 }
 
+#[test]
+fn token_list_len() {
+    assert_eq!(ArgList::from("").len(), 0);
+    assert!(ArgList::from(" \n\t ").is_empty());
+    assert_eq!(ArgList::from("x").len(), 1);
+    assert!(!ArgList::from("x").is_empty());
+    assert_eq!(ArgList::from("x,").len(), 2);
+}
+
+#[test]
+fn pred_arity() {
+    let pred = |pred_args| Pred {
+        pred_name: String::from("pred"),
+        pred_args: ArgList::from(pred_args),
+        pred_value: None,
+        pred_value_type: PredValueType::default(),
+    };
+    assert_eq!(pred("party,person").arity(), 2);
+    assert_eq!(pred("").arity(), 0);
+    assert!(ArgList::from("").is_empty());
+    assert!(!ArgList::from("party").is_empty());
+}
+
 #[test]
 fn dollars_ord() {
     assert!(Dollars::from_millibucks(1) > Dollars::ZERO);
@@ -300,6 +1017,324 @@ fn dollars_ord() {
     assert!(Dollars::from_millibucks(0) == Dollars::ZERO);
 }
 
+#[test]
+fn cond_time_in_future() {
+    let now = Timesecs::from(1000);
+    let mut offer = Offer {
+        offer_user: ID(String::from("user")),
+        offer_cond_id: ID(String::from("cond")),
+        offer_cond_time: None,
+        offer_details: OfferDetails {
+            offer_buy_price: Dollars::from_millibucks(400),
+            offer_sell_price: Dollars::from_millibucks(600),
+            offer_buy_quantity: Quantity::from_units(10),
+            offer_sell_quantity: Quantity::from_units(10),
+        },
+        offer_status: OfferStatus::Open,
+        offer_expiry: None,
+    };
+    assert!(offer.valid_cond_time(now));
+    offer.offer_cond_time = Some(Timesecs::from(1001));
+    assert!(offer.valid_cond_time(now));
+    offer.offer_cond_time = Some(now);
+    assert!(!offer.valid_cond_time(now));
+}
+
+#[cfg(test)]
+fn transfer_iou(cond_id: Option<ID>) -> IOU {
+    IOU {
+        iou_issuer: ID(String::from("issuer")),
+        iou_holder: ID(String::from("holder")),
+        iou_value: Dollars::from_millibucks(1000),
+        iou_cond_time: cond_id.as_ref().map(|_| Timesecs::from(2000)),
+        iou_cond_id: cond_id,
+        iou_cond_flag: true,
+        iou_split: None,
+        iou_void: false,
+        iou_memo: None,
+    }
+}
+
+#[cfg(test)]
+fn transfer_to(holders: &[(&str, i64)]) -> Transfer {
+    Transfer {
+        holders: holders
+            .iter()
+            .map(|(h, v)| (ID(String::from(*h)), Dollars::from_millibucks(*v)))
+            .collect(),
+    }
+}
+
+#[test]
+fn transfer_unconditional() {
+    let old_id = ID(String::from("old"));
+    let old_iou = transfer_iou(None);
+    let transfer = transfer_to(&[("a", 600), ("b", 400)]);
+    assert!(transfer.valid(&old_iou).is_ok());
+    for iou in transfer.make_ious(&old_id, &old_iou).unwrap() {
+        assert_eq!(iou.iou_issuer, old_iou.iou_issuer);
+        assert_eq!(iou.iou_cond_id, None);
+        assert_eq!(iou.iou_cond_time, None);
+        assert_eq!(iou.iou_split, Some(old_id.clone()));
+        assert!(!iou.iou_void);
+    }
+}
+
+#[test]
+fn transfer_conditional() {
+    let old_id = ID(String::from("old"));
+    let old_iou = transfer_iou(Some(ID(String::from("cond"))));
+    let transfer = transfer_to(&[("a", 600), ("b", 400)]);
+    assert!(transfer.valid(&old_iou).is_ok());
+    for iou in transfer.make_ious(&old_id, &old_iou).unwrap() {
+        assert_eq!(iou.iou_cond_id, old_iou.iou_cond_id);
+        assert_eq!(iou.iou_cond_flag, old_iou.iou_cond_flag);
+        assert_eq!(iou.iou_cond_time, old_iou.iou_cond_time);
+        assert!(!iou.iou_void);
+    }
+}
+
+#[test]
+fn transfer_to_issuer_is_void() {
+    let old_id = ID(String::from("old"));
+    let old_iou = transfer_iou(Some(ID(String::from("cond"))));
+    let transfer = transfer_to(&[("issuer", 300), ("a", 700)]);
+    assert!(transfer.valid(&old_iou).is_ok());
+    for iou in transfer.make_ious(&old_id, &old_iou).unwrap() {
+        assert_eq!(iou.iou_void, iou.iou_holder == old_iou.iou_issuer);
+    }
+}
+
+#[test]
+fn transfer_partial() {
+    let old_id = ID(String::from("old"));
+    let old_iou = transfer_iou(None);
+    let transfer = Transfer::partial(
+        &old_iou,
+        ID(String::from("a")),
+        Dollars::from_millibucks(300),
+    );
+    assert!(transfer.valid(&old_iou).is_ok());
+    let mut ious = transfer.make_ious(&old_id, &old_iou).unwrap();
+    ious.sort_by(|a, b| a.iou_holder.0.cmp(&b.iou_holder.0));
+    assert_eq!(ious.len(), 2);
+    assert_eq!(ious[0].iou_holder, ID(String::from("a")));
+    assert_eq!(ious[0].iou_value, Dollars::from_millibucks(300));
+    assert_eq!(ious[1].iou_holder, old_iou.iou_holder);
+    assert_eq!(ious[1].iou_value, Dollars::from_millibucks(700));
+    // the whole IOU leaves nothing behind, and more than it is refused
+    let all = Transfer::partial(&old_iou, ID(String::from("a")), old_iou.iou_value);
+    assert_eq!(all.holders.len(), 1);
+    assert!(all.valid(&old_iou).is_ok());
+    let too_much = Transfer::partial(
+        &old_iou,
+        ID(String::from("a")),
+        Dollars::from_millibucks(1100),
+    );
+    assert!(too_much.valid(&old_iou).is_err());
+    let to_holder = Transfer::partial(
+        &old_iou,
+        old_iou.iou_holder.clone(),
+        Dollars::from_millibucks(300),
+    );
+    assert_eq!(to_holder.holders[&old_iou.iou_holder], old_iou.iou_value);
+}
+
+#[test]
+fn transfer_value_positive() {
+    let old_iou = transfer_iou(None);
+    assert!(transfer_to(&[("a", 1000), ("b", 0)])
+        .valid(&old_iou)
+        .is_err());
+    assert!(transfer_to(&[("a", 1100), ("b", -100)])
+        .valid(&old_iou)
+        .is_err());
+    assert!(transfer_to(&[("a", 900)]).valid(&old_iou).is_err());
+    assert!(transfer_to(&[("a", 1000)]).valid(&old_iou).is_ok());
+}
+
+#[test]
+fn offer_quantity_valid() {
+    let mut details = OfferDetails {
+        offer_buy_price: Dollars::from_millibucks(400),
+        offer_sell_price: Dollars::from_millibucks(600),
+        offer_buy_quantity: Quantity::ZERO,
+        offer_sell_quantity: Quantity::ZERO,
+    };
+    assert!(!details.valid());
+    details.offer_sell_quantity = Quantity::from_units(1);
+    assert!(details.valid());
+    details.offer_sell_quantity = Quantity::ZERO;
+    details.offer_buy_quantity = Quantity::from_units(1);
+    assert!(details.valid());
+}
+
+#[test]
+fn pred_value_types() {
+    assert!(PredValueType::Bool.valid_value("true"));
+    assert!(!PredValueType::Bool.valid_value("yes"));
+    assert!(PredValueType::Date.valid_value("2030-01-01 00:00:00"));
+    assert!(!PredValueType::Date.valid_value("500"));
+    assert!(PredValueType::Scalar.valid_value("412.5"));
+    assert!(!PredValueType::Scalar.valid_value("NaN"));
+    assert!(!PredValueType::Scalar.valid_value("2030-01-01 00:00:00"));
+}
+
+#[test]
+fn exposure_after_trade() {
+    // alice buys a $1 IOU from bob at 45c, then sells 2 back at 60c
+    let alice = ID(String::from("alice"));
+    let bob = ID(String::from("bob"));
+    let cond_id = ID(String::from("cond"));
+    let iou = |issuer: &ID, holder: &ID, value: i64, cond_flag: bool| IOU {
+        iou_issuer: issuer.clone(),
+        iou_holder: holder.clone(),
+        iou_value: Dollars::from_millibucks(value),
+        iou_cond_id: Some(cond_id.clone()),
+        iou_cond_flag: cond_flag,
+        iou_cond_time: None,
+        iou_split: None,
+        iou_void: false,
+        iou_memo: None,
+    };
+    let mut ious = vec![iou(&alice, &bob, 450, false), iou(&bob, &alice, 550, true)];
+    let exposure_of =
+        |user: &ID, ious: &[IOU]| exposure(user, ious).unwrap()[&cond_id].to_millibucks();
+    assert_eq!(exposure_of(&alice, &ious), 450);
+    assert_eq!(exposure_of(&bob, &ious), 550);
+    ious.push(iou(&bob, &alice, 1200, false));
+    ious.push(iou(&alice, &bob, 800, true));
+    // alice is now short one: -250 if true and +750 if false
+    assert_eq!(exposure_of(&alice, &ious), 250);
+    assert_eq!(exposure_of(&bob, &ious), 750);
+    // void and unconditional IOUs don't count
+    ious[2].iou_void = true;
+    ious.push(IOU {
+        iou_cond_id: None,
+        ..iou(&alice, &bob, 5000, false)
+    });
+    assert_eq!(exposure_of(&alice, &ious), 450);
+    assert_eq!(exposure(&alice, &ious).unwrap().len(), 1);
+    assert_eq!(
+        exposure(&ID(String::from("carol")), &ious).unwrap().len(),
+        0
+    );
+}
+
+#[test]
+fn balances_match_exposure() {
+    let alice = ID(String::from("alice"));
+    let bob = ID(String::from("bob"));
+    let carol = ID(String::from("carol"));
+    let cond1 = ID(String::from("cond1"));
+    let cond2 = ID(String::from("cond2"));
+    let iou = |issuer: &ID, holder: &ID, value: i64, cond: Option<&ID>, cond_flag: bool| IOU {
+        iou_issuer: issuer.clone(),
+        iou_holder: holder.clone(),
+        iou_value: Dollars::from_millibucks(value),
+        iou_cond_id: cond.cloned(),
+        iou_cond_flag: cond_flag,
+        iou_cond_time: None,
+        iou_split: None,
+        iou_void: false,
+        iou_memo: None,
+    };
+    let mut ious = vec![
+        iou(&alice, &bob, 450, Some(&cond1), false),
+        iou(&bob, &alice, 550, Some(&cond1), true),
+        iou(&carol, &alice, 300, Some(&cond2), true),
+        iou(&alice, &carol, 700, Some(&cond2), false),
+        iou(&alice, &bob, 2000, None, false),
+        iou(&carol, &bob, 500, None, false),
+        iou(&carol, &alice, 9000, None, false),
+    ];
+    ious[6].iou_void = true;
+    let balances = balances(&ious).unwrap();
+    for user in &[&alice, &bob, &carol] {
+        assert_eq!(balances[*user].exposure, exposure(user, &ious).unwrap());
+    }
+    assert_eq!(balances[&alice].debt.to_millibucks(), 2000);
+    assert_eq!(balances[&alice].credit.to_millibucks(), 0);
+    assert_eq!(balances[&bob].credit.to_millibucks(), 2500);
+    assert_eq!(balances[&carol].debt.to_millibucks(), 500);
+    assert_eq!(balances[&alice].exposure.len(), 2);
+    assert_eq!(balances[&bob].exposure.len(), 1);
+}
+
+#[test]
+fn settlement_nets_pairs() {
+    let alice = ID(String::from("alice"));
+    let bob = ID(String::from("bob"));
+    let carol = ID(String::from("carol"));
+    let iou = |issuer: &ID, holder: &ID, value: i64| IOU {
+        iou_issuer: issuer.clone(),
+        iou_holder: holder.clone(),
+        iou_value: Dollars::from_millibucks(value),
+        iou_cond_id: None,
+        iou_cond_flag: false,
+        iou_cond_time: None,
+        iou_split: None,
+        iou_void: false,
+        iou_memo: None,
+    };
+    let mut ious = vec![
+        iou(&alice, &bob, 1000),
+        iou(&bob, &alice, 400),
+        iou(&carol, &alice, 700),
+        iou(&bob, &carol, 250),
+        iou(&carol, &bob, 250),
+        iou(&alice, &carol, 5000),
+        iou(&bob, &alice, 3000),
+    ];
+    ious[5].iou_void = true;
+    ious[6].iou_cond_id = Some(ID(String::from("cond")));
+    let payments = settlement(&ious).unwrap();
+    let payments: Vec<(&str, &str, i64)> = payments
+        .iter()
+        .map(|(payer, payee, amount)| (&payer.0[..], &payee.0[..], amount.to_millibucks()))
+        .collect();
+    // bob and carol are square, so owe each other nothing
+    assert_eq!(
+        payments,
+        vec![("alice", "bob", 600), ("carol", "alice", 700)]
+    );
+}
+
+#[test]
+fn operation_time_checked() {
+    let now = Timesecs::parse_datetime("2018-06-01 12:00:00").unwrap();
+    let check = |s| Timesecs::parse_datetime(s).and_then(|t| t.check_operation_time(now));
+    assert!(check("2018-06-01 12:00:00").is_ok());
+    assert!(check("2001-01-01 00:00:00").is_ok());
+    assert!(check("2018-06-02 11:00:00").is_ok());
+    assert!(check("2018-06-03 12:00:00").is_err());
+    assert!(check("1969-12-31 00:00:00").is_err());
+    assert!(check("1999-12-31 23:59:59").is_err());
+    assert!(check("9999-01-01 00:00:00").is_err());
+}
+
+#[test]
+fn offer_expiry_valid() {
+    let offer = |offer_expiry: Option<i64>| Offer {
+        offer_user: ID(String::from("foo")),
+        offer_cond_id: ID(String::from("cond")),
+        offer_cond_time: None,
+        offer_details: OfferDetails {
+            offer_buy_price: Dollars::from_millibucks(400),
+            offer_sell_price: Dollars::from_millibucks(600),
+            offer_buy_quantity: Quantity::from_units(10),
+            offer_sell_quantity: Quantity::from_units(10),
+        },
+        offer_status: OfferStatus::Open,
+        offer_expiry: offer_expiry.map(Timesecs::from),
+    };
+    let now = Timesecs::from(1000);
+    assert!(offer(None).valid_expiry(now));
+    assert!(offer(Some(1001)).valid_expiry(now));
+    assert!(!offer(Some(1000)).valid_expiry(now));
+    assert!(!offer(Some(10)).valid_expiry(now));
+}
+
 #[test]
 fn user_name_stripped1() {
     assert_eq!(User::user_name_stripped("abcdef"), "abcdef");
@@ -307,4 +1342,224 @@ fn user_name_stripped1() {
     assert_eq!(User::user_name_stripped(" abc.123 "), "abc123");
 }
 
+#[test]
+fn user_name_stripped_unicode() {
+    assert_eq!(User::user_name_stripped("José.Álvarez"), "joséálvarez");
+    assert_eq!(User::user_name_stripped("Иван_Петров"), "иванпетров");
+    assert_eq!(User::user_name_stripped("山田-太郎"), "山田太郎");
+    assert_eq!(User::user_name_stripped("ΣΟΦΊΑ"), "σοφία");
+}
+
+#[test]
+fn valid_user_name_unicode() {
+    assert_eq!(
+        User::valid_user_name_stripped("Zoë"),
+        Some(String::from("zoë"))
+    );
+    assert_eq!(
+        User::valid_user_name_stripped("Ελένη"),
+        Some(String::from("ελένη"))
+    );
+    assert_eq!(User::valid_user_name_stripped("zoë smith"), None);
+    assert_eq!(User::valid_user_name_stripped("-._"), None);
+    assert_eq!(User::valid_user_name_stripped("zoë☃"), None);
+}
+
+#[test]
+fn offer_max_exposure() {
+    let details = OfferDetails {
+        offer_buy_price: Dollars::from_millibucks(450),
+        offer_sell_price: Dollars::from_millibucks(550),
+        offer_buy_quantity: Quantity::from_units(100),
+        offer_sell_quantity: Quantity::from_units(100),
+    };
+    assert_eq!(
+        details.max_exposure(Side::Buy).unwrap(),
+        Dollars::from_millibucks(45000)
+    );
+    assert_eq!(
+        details.max_exposure(Side::Sell).unwrap(),
+        Dollars::from_millibucks(45000)
+    );
+    let cond_id = ID(String::from("cond"));
+    let (buyer_iou, seller_iou) = trade_ious(
+        &cond_id,
+        None,
+        &ID(String::from("foo")),
+        &ID(String::from("bar")),
+        details.offer_buy_price,
+        details.offer_buy_quantity.to_units(),
+    );
+    assert_eq!(
+        buyer_iou.iou_value,
+        details.max_exposure(Side::Buy).unwrap()
+    );
+    assert_eq!(seller_iou.iou_value, Dollars::from_millibucks(55000));
+}
+
+#[test]
+fn quantity_max_exposure() {
+    let most = Quantity::from_units(u32::max_value());
+    assert_eq!(
+        Quantity::ZERO.max_exposure(Dollars::ONE).unwrap(),
+        Dollars::ZERO
+    );
+    // the largest offer at the highest price still fits
+    assert_eq!(
+        most.max_exposure(Dollars::ONE).unwrap(),
+        Dollars::from_millibucks(1000 * i64::from(u32::max_value()))
+    );
+    let huge = Dollars::from_millibucks(i64::max_value() / 2 + 1);
+    assert_eq!(Quantity::from_units(1).max_exposure(huge).unwrap(), huge);
+    assert!(Quantity::from_units(2).max_exposure(huge).is_err());
+    assert!(most.max_exposure(huge).is_err());
+    // quantities are bare numbers on the wire, like prices
+    assert_eq!(serde_json::to_string(&most).unwrap(), "4294967295");
+    assert_eq!(
+        serde_json::from_str::<Quantity>("40").unwrap(),
+        Quantity::from_units(40)
+    );
+}
+
+#[test]
+fn dollars_conversions() {
+    let cents = |m| Dollars::from_millibucks(m).to_cents_rounded();
+    assert_eq!(cents(0), 0);
+    assert_eq!(cents(1), 0);
+    assert_eq!(cents(4), 0);
+    assert_eq!(cents(5), 1);
+    assert_eq!(cents(9), 1);
+    assert_eq!(cents(1004), 100);
+    assert_eq!(cents(1005), 101);
+    assert_eq!(cents(-1), 0);
+    assert_eq!(cents(-4), 0);
+    assert_eq!(cents(-5), -1);
+    assert_eq!(cents(-1005), -101);
+    assert_eq!(Dollars::from_millibucks(1).as_f64_dollars(), 0.001);
+    assert_eq!(f64::from(Dollars::from_millibucks(-2500)), -2.5);
+    assert_eq!(f64::from(Dollars::ONE), 1.0);
+}
+
+#[test]
+fn dollars_sum_abs() {
+    let values: Vec<Dollars> = [250, -1000, 400, 0]
+        .iter()
+        .map(|&m| Dollars::from_millibucks(m))
+        .collect();
+    assert_eq!(
+        values.iter().sum::<Dollars>(),
+        Dollars::from_millibucks(-350)
+    );
+    assert_eq!(
+        values.iter().map(|d| d.abs()).sum::<Dollars>(),
+        Dollars::from_millibucks(1650)
+    );
+    assert_eq!(
+        Vec::<Dollars>::new().into_iter().sum::<Dollars>(),
+        Dollars::ZERO
+    );
+    assert_eq!(
+        Dollars::from_millibucks(-5).abs(),
+        Dollars::from_millibucks(5)
+    );
+    assert_eq!(values.iter().max(), Some(&Dollars::from_millibucks(400)));
+}
+
+#[test]
+fn cond_flag_pays() {
+    let mut iou = transfer_iou(Some(ID(String::from("cond"))));
+    iou.iou_cond_flag = true;
+    assert!(iou.pays(true));
+    assert!(!iou.pays(false));
+    iou.iou_cond_flag = false;
+    assert!(!iou.pays(true));
+    assert!(iou.pays(false));
+}
+
+#[test]
+fn identity_attested_time() {
+    let now = Timesecs::from(1_000_000);
+    let identity = |attested_time, verified| Identity {
+        identity_user_id: ID(String::from("foo")),
+        identity_service: String::from("tumblr"),
+        identity_account_name: String::from("mr--foo"),
+        identity_attested_time: Timesecs::from(attested_time),
+        identity_verified: verified,
+    };
+    // unverified identities needn't have been attested yet
+    assert!(identity(0, false).valid_attested_time(now));
+    assert!(!identity(0, true).valid_attested_time(now));
+    assert!(identity(1_000_000, true).valid_attested_time(now));
+    // and nothing is attested in the future
+    assert!(!identity(1_000_001, true).valid_attested_time(now));
+    assert!(!identity(1_000_001, false).valid_attested_time(now));
+}
+
+#[test]
+fn dollars_from_str() {
+    let parse = |s: &str| s.parse::<Dollars>().map(|d| d.to_millibucks()).ok();
+    assert_eq!(parse("3.40"), Some(3400));
+    assert_eq!(parse("3"), Some(3000));
+    assert_eq!(parse("0.005"), Some(5));
+    assert_eq!(parse(".5"), Some(500));
+    assert_eq!(parse("-12.25"), Some(-12250));
+    assert_eq!(parse(""), None);
+    assert_eq!(parse("-"), None);
+    assert_eq!(parse("."), None);
+    assert_eq!(parse("1.2345"), None);
+    assert_eq!(parse("+1"), None);
+    assert_eq!(parse("1,000"), None);
+    assert_eq!(parse("99999999999999999999"), None);
+}
+
+#[test]
+fn timesecs_from_str() {
+    let parse = |s: &str| s.parse::<Timesecs>().map(i64::from).ok();
+    assert_eq!(parse("2020-01-01"), Some(1_577_836_800));
+    assert_eq!(parse("2020-01-01 00:00:30"), Some(1_577_836_830));
+    assert_eq!(parse("2020-01-01T00:00:30"), None);
+    assert_eq!(parse("yesterday"), None);
+    assert!("soon".parse::<Timesecs>().is_err());
+}
+
+#[test]
+fn dollars_overflow_checked() {
+    let most = Dollars::from_millibucks(i64::MAX);
+    assert_eq!(
+        Dollars::ONE.checked_add(Dollars::ONE).unwrap(),
+        Dollars::from_millibucks(2000)
+    );
+    assert!(most.checked_add(Dollars::from_millibucks(1)).is_err());
+    assert!(Dollars::from_millibucks(i64::MIN)
+        .checked_sub(Dollars::from_millibucks(1))
+        .is_err());
+    assert_eq!(
+        Dollars::checked_sum(vec![Dollars::ONE; 3]).unwrap(),
+        Dollars::from_millibucks(3000)
+    );
+    assert!(Dollars::checked_sum(vec![most, Dollars::ONE]).is_err());
+    // IOUs that add up past i64 are an error, not a wrapped total
+    let alice = ID(String::from("alice"));
+    let bob = ID(String::from("bob"));
+    let cond_id = ID(String::from("cond"));
+    let iou = |iou_cond_id: Option<ID>| IOU {
+        iou_issuer: alice.clone(),
+        iou_holder: bob.clone(),
+        iou_value: Dollars::from_millibucks(i64::MAX / 2 + 1),
+        iou_cond_id,
+        iou_cond_flag: true,
+        iou_cond_time: None,
+        iou_split: None,
+        iou_void: false,
+        iou_memo: None,
+    };
+    let unconditional = vec![iou(None), iou(None)];
+    assert!(balances(&unconditional).is_err());
+    assert!(settlement(&unconditional).is_err());
+    let conditional = vec![iou(Some(cond_id.clone())), iou(Some(cond_id))];
+    assert!(balances(&conditional).is_err());
+    assert!(exposure(&alice, &conditional).is_err());
+    assert!(balances(&unconditional[..1]).is_ok());
+}
+
 // vi: ts=8 sts=4 et
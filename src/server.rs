@@ -1,122 +1,1249 @@
 use failure::{err_msg, Error};
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
 use std::str;
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use futures;
 use futures::future::Future;
 use futures::sync::oneshot;
+use tokio_timer::Timeout;
+use tokio_uds::UnixListener;
 
+use hmac::{Hmac, Mac};
+use log::Level;
+use rusqlite::Connection;
+use sha2::Sha256;
+
+use rmp_serde;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_json;
 
 use actix;
+use actix::{
+    Actor, ActorContext, Addr, AsyncContext, Context, Handler, Message, Recipient, StreamHandler,
+};
 use actix_web::error;
+use actix_web::http::header;
 use actix_web::server;
+use actix_web::ws;
 use actix_web::{App, AsyncResponder, FutureResponse, HttpMessage, HttpRequest, HttpResponse};
 
-use crate::market::{self, Market};
+use crate::db::DB;
+use crate::market::msgs::{Item, ItemUpdate, Request, Response};
+use crate::market::types::{Timesecs, Trade, ID};
+use crate::market::ApiKeyTable;
+use crate::market::{self, Market, VersionInfo};
 
 type ResponseFuture = futures::sync::oneshot::Sender<market::msgs::Response>;
 
+const UNIX_SCHEME: &str = "unix:";
+
+/// Where the server listens for connections.
+#[derive(Debug, PartialEq)]
+pub enum BindAddress {
+    /// `host:port`
+    Tcp(String),
+    /// `unix:PATH`, for a reverse proxy on the same host
+    Unix(PathBuf),
+}
+
+impl BindAddress {
+    pub fn parse(addr: &str) -> BindAddress {
+        if addr.starts_with(UNIX_SCHEME) {
+            BindAddress::Unix(PathBuf::from(&addr[UNIX_SCHEME.len()..]))
+        } else {
+            BindAddress::Tcp(String::from(addr))
+        }
+    }
+}
+
+pub struct ServerOptions {
+    /// how long to wait for the market to respond to a request
+    pub request_timeout: Duration,
+    /// requests per second allowed from each client address
+    pub rate_limit: f64,
+    /// requests a client can make at once before being limited
+    pub rate_burst: u32,
+    /// database to check request signatures against, or None to accept
+    /// unsigned requests
+    pub api_keys: Option<String>,
+    /// largest request body accepted, in bytes
+    pub max_body_size: usize,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            request_timeout: Duration::from_secs(30),
+            rate_limit: 100.0,
+            rate_burst: 200,
+            api_keys: None,
+            max_body_size: 256 * 1024,
+        }
+    }
+}
+
 struct AppState {
     channel: Arc<Mutex<mpsc::Sender<(AppMsg, ResponseFuture)>>>,
+    feed: Addr<FeedHub>,
+    request_timeout: Duration,
+    limiter: Arc<Mutex<RateLimiter>>,
+    api_keys: Option<Arc<Mutex<Connection>>>,
+    nonces: Arc<Mutex<Nonces>>,
+    max_body_size: usize,
+    version: VersionInfo,
+}
+
+/// Clients with full buckets are forgotten once there are this many.
+const MAX_BUCKETS: usize = 10000;
+
+/// Token bucket for one client address.
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+struct RateLimiter {
+    rate: f64,
+    burst: u32,
+    buckets: HashMap<Option<IpAddr>, Bucket>,
+}
+
+impl RateLimiter {
+    fn new(rate: f64, burst: u32) -> Self {
+        RateLimiter {
+            rate,
+            burst,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Take a token from the client's bucket, or false if it is empty.
+    fn allow(&mut self, client: Option<IpAddr>, now: Instant) -> bool {
+        let (rate, burst) = (self.rate, f64::from(self.burst));
+        if self.buckets.len() >= MAX_BUCKETS {
+            self.buckets
+                .retain(|_, b| b.tokens + secs(now - b.last) * rate < burst);
+        }
+        let bucket = self.buckets.entry(client).or_insert(Bucket {
+            tokens: burst,
+            last: now,
+        });
+        bucket.tokens = (bucket.tokens + secs(now - bucket.last) * rate).min(burst);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9
+}
+
+/// The scheme clients put before `KEYID:TIME:NONCE:SIGNATURE` in the
+/// Authorization header. The signature is the hex HMAC-SHA256 of
+/// `TIME:NONCE:` and then the request body, where TIME is in UNIX seconds
+/// and NONCE is any string without a colon that the key hasn't used yet.
+/// The /ws upgrade has no body, so only `TIME:NONCE:` is signed for it.
+const AUTH_SCHEME: &str = "HMAC-SHA256 ";
+
+/// How far the time of a signed request can be from the server's, in
+/// seconds. Older requests are refused, so nonces are only kept this long.
+const MAX_SIGNATURE_AGE: i64 = 5 * 60;
+
+/// The nonces of recent signed requests, so that none can be replayed.
+#[derive(Default)]
+struct Nonces {
+    seen: HashMap<(String, String), i64>,
+}
+
+impl Nonces {
+    /// Note a nonce the key signed at `time`, or false if it already did.
+    fn first_use(&mut self, key_id: &str, nonce: &str, time: i64, now: i64) -> bool {
+        self.seen.retain(|_, t| *t >= now - MAX_SIGNATURE_AGE);
+        self.seen
+            .insert((String::from(key_id), String::from(nonce)), time)
+            .is_none()
+    }
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// The user whose key signed the body, or None if the signature is
+/// missing or doesn't match, or the request is stale or a replay.
+fn authenticate(
+    db: &Connection,
+    nonces: &mut Nonces,
+    auth: Option<&str>,
+    body: &[u8],
+    now: Timesecs,
+) -> Result<Option<ID>, Error> {
+    let (key_id, time, nonce, signature) = match auth
+        .filter(|auth| auth.starts_with(AUTH_SCHEME))
+        .and_then(|auth| {
+            let mut parts = auth[AUTH_SCHEME.len()..].splitn(4, ':');
+            Some((
+                parts.next()?,
+                parts.next()?.parse::<i64>().ok()?,
+                parts.next()?,
+                from_hex(parts.next()?)?,
+            ))
+        }) {
+        Some(parsed) => parsed,
+        None => return Ok(None),
+    };
+    let key = match db.select::<ApiKeyTable>().by_key_id(key_id)? {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    let mut mac = Hmac::<Sha256>::new_varkey(key.key_secret.as_bytes())
+        .map_err(|_| err_msg("invalid key secret"))?;
+    mac.input(format!("{}:{}:", time, nonce).as_bytes());
+    mac.input(body);
+    // verify compares in constant time
+    if mac.verify(&signature).is_err() {
+        return Ok(None);
+    }
+    // only once it is signed, so that forgeries aren't remembered
+    let now = i64::from(now);
+    if time < now - MAX_SIGNATURE_AGE
+        || time > now + MAX_SIGNATURE_AGE
+        || !nonces.first_use(key_id, nonce, time, now)
+    {
+        return Ok(None);
+    }
+    Ok(Some(key.key_user))
 }
 
 enum AppMsg {
-    Request(market::msgs::Request),
-    //FIXME Shutdown,
+    /// A request on behalf of the user who signed it, if any.
+    Request {
+        user: Option<ID>,
+        request: market::msgs::Request,
+    },
+    /// Close the market and stop the worker.
+    Shutdown,
 }
 
 #[derive(Debug)]
 enum AppError {
-    Canceled, // FIXME
+    /// the market failed to produce a response
+    Canceled,
+    Timeout,
+    RateLimited,
+    Unauthorized,
+    Database(Error),
+    PayloadTooLarge,
     Payload(error::PayloadError),
     Json(serde_json::Error),
+    MsgPackDecode(rmp_serde::decode::Error),
+    MsgPackEncode(rmp_serde::encode::Error),
     Utf8(str::Utf8Error),
 }
 
+/// The JSON body of an error response, so clients can tell errors apart
+/// without parsing the detail.
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    error: &'static str,
+    detail: String,
+    /// where in the request body a JSON parse error was found
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    column: Option<usize>,
+}
+
+impl AppError {
+    fn code(&self) -> &'static str {
+        match self {
+            AppError::Canceled => "canceled",
+            AppError::Timeout => "timeout",
+            AppError::RateLimited => "rate_limited",
+            AppError::Unauthorized => "unauthorized",
+            AppError::Database(_) => "database",
+            AppError::PayloadTooLarge => "payload_too_large",
+            AppError::Payload(_) => "invalid_payload",
+            AppError::Json(_) => "invalid_json",
+            AppError::MsgPackDecode(_) => "invalid_msgpack",
+            AppError::MsgPackEncode(_) => "msgpack_encode",
+            AppError::Utf8(_) => "invalid_utf8",
+        }
+    }
+
+    fn to_body(&self) -> ErrorBody {
+        let (detail, line, column) = match self {
+            AppError::Database(err) => (err.to_string(), None, None),
+            AppError::Payload(err) => (err.to_string(), None, None),
+            AppError::Json(err) => (err.to_string(), Some(err.line()), Some(err.column())),
+            AppError::MsgPackDecode(err) => (err.to_string(), None, None),
+            AppError::MsgPackEncode(err) => (err.to_string(), None, None),
+            AppError::Utf8(err) => (err.to_string(), None, None),
+            _ => (format!("{:?}", self), None, None),
+        };
+        ErrorBody {
+            error: self.code(),
+            detail,
+            line,
+            column,
+        }
+    }
+}
+
 fn make_error(err: AppError) -> HttpResponse {
-    HttpResponse::BadRequest().body(format!("{:?}", err))
+    let body = err.to_body();
+    match err {
+        AppError::Timeout => HttpResponse::GatewayTimeout().json(body),
+        AppError::RateLimited => HttpResponse::TooManyRequests().json(body),
+        AppError::PayloadTooLarge => HttpResponse::PayloadTooLarge().json(body),
+        AppError::Unauthorized => HttpResponse::Unauthorized().json(body),
+        AppError::Canceled | AppError::Database(_) => {
+            HttpResponse::InternalServerError().json(body)
+        }
+        _ => HttpResponse::BadRequest().json(body),
+    }
 }
 
-fn make_ok(str: String) -> HttpResponse {
-    HttpResponse::Ok().body(str)
+fn wait_reply(
+    on_reply: oneshot::Receiver<market::msgs::Response>,
+    timeout: Duration,
+) -> impl Future<Item = market::msgs::Response, Error = AppError> {
+    // if this times out the receiver is dropped and the late reply discarded
+    Timeout::new(on_reply, timeout).map_err(|e| {
+        if e.is_elapsed() {
+            AppError::Timeout
+        } else {
+            AppError::Canceled
+        }
+    })
+}
+
+fn payload_error(err: error::PayloadError) -> AppError {
+    match err {
+        error::PayloadError::Overflow => AppError::PayloadTooLarge,
+        err => AppError::Payload(err),
+    }
+}
+
+fn make_ok(body: Vec<u8>, encoding: Encoding) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(encoding.content_type())
+        .body(body)
+}
+
+/// The market's response to a request. Errors are mostly answered like
+/// anything else, but a conflict with existing data gets its own status.
+fn make_reply(response: &Response, body: Vec<u8>, encoding: Encoding) -> HttpResponse {
+    match response {
+        Response::Error(market::msgs::Error::Conflict { .. }) => HttpResponse::Conflict()
+            .content_type(encoding.content_type())
+            .body(body),
+        Response::Error(market::msgs::Error::RequestFailed) => HttpResponse::InternalServerError()
+            .content_type(encoding.content_type())
+            .body(body),
+        _ => make_ok(body, encoding),
+    }
+}
+
+const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+/// How a request or response body is encoded. JSON unless the client asks
+/// for msgpack.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Json,
+    MsgPack,
+}
+
+impl Encoding {
+    /// From a Content-Type or Accept header.
+    fn from_header(value: Option<&str>) -> Encoding {
+        let is_msgpack = |media_type: &str| {
+            media_type.split(';').next().map(str::trim) == Some(MSGPACK_CONTENT_TYPE)
+        };
+        match value {
+            Some(value) if value.split(',').any(is_msgpack) => Encoding::MsgPack,
+            _ => Encoding::Json,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::MsgPack => MSGPACK_CONTENT_TYPE,
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, body: &[u8]) -> Result<T, AppError> {
+        match self {
+            Encoding::Json => {
+                let body_str = str::from_utf8(body).map_err(|e| AppError::Utf8(e))?;
+                serde_json::from_str(body_str).map_err(|e| AppError::Json(e))
+            }
+            Encoding::MsgPack => {
+                rmp_serde::from_slice(body).map_err(|e| AppError::MsgPackDecode(e))
+            }
+        }
+    }
+
+    fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, AppError> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(|e| AppError::Json(e)),
+            // with field names, as items are tagged by a field
+            Encoding::MsgPack => {
+                rmp_serde::to_vec_named(value).map_err(|e| AppError::MsgPackEncode(e))
+            }
+        }
+    }
 }
 
 fn handle_post(req: &HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let client = req.peer_addr().map(|addr| addr.ip());
+    if !req
+        .state()
+        .limiter
+        .lock()
+        .unwrap()
+        .allow(client, Instant::now())
+    {
+        return futures::future::ok::<_, error::Error>(make_error(AppError::RateLimited))
+            .responder();
+    }
     let tx = req.state().channel.lock().unwrap().clone();
-    // req.payload().concat2() gives denial of service on big payloads
+    let timeout = req.state().request_timeout;
+    let api_keys = req.state().api_keys.clone();
+    let nonces = req.state().nonces.clone();
+    let max_body_size = req.state().max_body_size;
+    let auth = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|auth| auth.to_str().ok())
+        .map(String::from);
+    let header_encoding = |name| {
+        Encoding::from_header(
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok()),
+        )
+    };
+    let request_encoding = header_encoding(header::CONTENT_TYPE);
+    let response_encoding = header_encoding(header::ACCEPT);
+    // req.payload().concat2() gives denial of service on big payloads, so
+    // the body is limited and anything bigger is refused before parsing
     req.body()
-        .map_err(|e| AppError::Payload(e))
-        .and_then(|b| {
-            let req_str = match str::from_utf8(&b) {
-                Ok(req_str) => req_str,
-                Err(utf8_error) => return Err(AppError::Utf8(utf8_error)),
+        .limit(max_body_size)
+        .map_err(payload_error)
+        .and_then(move |b| {
+            // the signature is checked before the request goes to the market
+            let user = match api_keys {
+                None => None,
+                Some(db) => {
+                    match authenticate(
+                        &db.lock().unwrap(),
+                        &mut nonces.lock().unwrap(),
+                        auth.as_ref().map(String::as_str),
+                        &b,
+                        Timesecs::now(),
+                    ) {
+                        Ok(Some(user)) => Some(user),
+                        Ok(None) => return Err(AppError::Unauthorized),
+                        Err(err) => return Err(AppError::Database(err)),
+                    }
+                }
             };
-            serde_json::from_str::<market::msgs::Request>(req_str)
-                .map_err(|e| AppError::Json(e))
-                .map(|market_req| AppMsg::Request(market_req))
+            request_encoding
+                .decode::<market::msgs::Request>(&b)
+                .map(|request| AppMsg::Request { user, request })
         })
         .map(move |msg| {
             let (reply, on_reply) = oneshot::channel::<market::msgs::Response>();
             futures::future::result(tx.send((msg, reply)))
                 .map_err(|_| AppError::Canceled)
-                .and_then(|_| {
-                    on_reply
-                        .map_err(|_| AppError::Canceled)
-                        .and_then(|market_reply| {
-                            serde_json::to_string(&market_reply).map_err(|e| AppError::Json(e))
-                        })
+                .and_then(move |_| {
+                    wait_reply(on_reply, timeout).and_then(move |market_reply| {
+                        response_encoding
+                            .encode(&market_reply)
+                            .map(|body| make_reply(&market_reply, body, response_encoding))
+                    })
                 })
         })
         .flatten()
         .then(|r| match r {
-            Ok(s) => Ok(make_ok(s)),
+            Ok(response) => Ok(response),
             Err(e) => Ok(make_error(e)),
         })
         .responder()
 }
 
+/// A market event serialized as JSON, pushed to every websocket client.
+#[derive(Clone)]
+struct FeedEvent(String);
+
+impl Message for FeedEvent {
+    type Result = ();
+}
+
+struct Subscribe(Recipient<FeedEvent>);
+
+impl Message for Subscribe {
+    type Result = ();
+}
+
+#[derive(Default)]
+struct FeedHub {
+    sockets: Vec<Recipient<FeedEvent>>,
+}
+
+impl Actor for FeedHub {
+    type Context = Context<Self>;
+}
+
+impl Handler<Subscribe> for FeedHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _ctx: &mut Context<Self>) {
+        self.sockets.push(msg.0);
+    }
+}
+
+impl Handler<FeedEvent> for FeedHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: FeedEvent, _ctx: &mut Context<Self>) {
+        // sockets that have closed fail to send and are dropped
+        self.sockets
+            .retain(|socket| socket.do_send(msg.clone()).is_ok());
+    }
+}
+
+struct FeedSocket;
+
+impl Actor for FeedSocket {
+    type Context = ws::WebsocketContext<Self, AppState>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let recipient = ctx.address().recipient();
+        ctx.state().feed.do_send(Subscribe(recipient));
+    }
+}
+
+impl Handler<FeedEvent> for FeedSocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: FeedEvent, ctx: &mut Self::Context) {
+        ctx.text(msg.0);
+    }
+}
+
+impl StreamHandler<ws::Message, ws::ProtocolError> for FeedSocket {
+    fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
+        match msg {
+            ws::Message::Ping(msg) => ctx.pong(&msg),
+            ws::Message::Close(_) => ctx.stop(),
+            _ => {}
+        }
+    }
+}
+
+fn handle_version(req: &HttpRequest<AppState>) -> HttpResponse {
+    let encoding = Encoding::from_header(
+        req.headers()
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok()),
+    );
+    match encoding.encode(&req.state().version) {
+        Ok(body) => make_ok(body, encoding),
+        Err(err) => make_error(err),
+    }
+}
+
+fn handle_ws(req: &HttpRequest<AppState>) -> Result<HttpResponse, error::Error> {
+    // the feed shows every change, so it is kept from anyone who couldn't
+    // make a request
+    if let Some(db) = &req.state().api_keys {
+        let auth = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|auth| auth.to_str().ok());
+        match authenticate(
+            &db.lock().unwrap(),
+            &mut req.state().nonces.lock().unwrap(),
+            auth,
+            &[],
+            Timesecs::now(),
+        ) {
+            Ok(Some(_)) => {}
+            Ok(None) => return Ok(make_error(AppError::Unauthorized)),
+            Err(err) => return Ok(make_error(AppError::Database(err))),
+        }
+    }
+    ws::start(req, FeedSocket)
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum FeedMessage {
+    Created {
+        id: ID,
+        item: Item,
+    },
+    Updated {
+        id: ID,
+        update: ItemUpdate,
+        items: Option<HashMap<ID, Item>>,
+    },
+    Deleted {
+        id: ID,
+    },
+    Traded {
+        trades: Vec<Trade>,
+    },
+}
+
+fn feed_messages(req: Request, response: &Response) -> Vec<FeedMessage> {
+    match (req, response) {
+        (Request::Create(item), Response::Created(id)) => vec![FeedMessage::Created {
+            id: id.clone(),
+            item,
+        }],
+        (Request::CreateEcho(_), Response::CreatedItem { id, item, .. }) => {
+            vec![FeedMessage::Created {
+                id: id.clone(),
+                item: item.clone(),
+            }]
+        }
+        (Request::CreateOffers { offers, .. }, Response::CreatedOffers(results)) => offers
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, offer)| match results.get(&i) {
+                Some(Ok(id)) => Some(FeedMessage::Created {
+                    id: id.clone(),
+                    item: Item::Offer(offer),
+                }),
+                _ => None,
+            })
+            .collect(),
+        (Request::Update { id, item_update }, Response::Updated) => vec![FeedMessage::Updated {
+            id,
+            update: item_update,
+            items: None,
+        }],
+        (Request::Update { id, item_update }, Response::Items(items)) => {
+            vec![FeedMessage::Updated {
+                id,
+                update: item_update,
+                items: Some(items.clone()),
+            }]
+        }
+        (Request::Delete { id }, Response::Updated) => vec![FeedMessage::Deleted { id }],
+        (_, Response::Traded { trades, .. }) if !trades.is_empty() => vec![FeedMessage::Traded {
+            trades: trades.clone(),
+        }],
+        _ => vec![],
+    }
+}
+
 fn work_thread(
     mut market: Market,
     rx: mpsc::Receiver<(AppMsg, ResponseFuture)>,
+    feed: Addr<FeedHub>,
 ) -> Result<(), Error> {
     loop {
         let (msg, reply) = rx.recv()?;
         match msg {
-            AppMsg::Request(req) => {
-                let response = market.do_request(req)?;
-                match reply.send(response) {
-                    Ok(()) => {}
-                    Err(_req) => return Err(err_msg("http thread not responding")),
+            AppMsg::Request { user, request: req } => {
+                // turned away before it is logged or done at all
+                if let Err(err) = req.check_complexity() {
+                    info!("request from {:?} rejected: {}", user, err);
+                    let _ = reply.send(Response::Error(err));
+                    continue;
+                }
+                if log_enabled!(Level::Info) {
+                    // the Authorization header never gets this far
+                    match serde_json::to_string(&req) {
+                        Ok(json) => info!("request from {:?}: {}", user, json),
+                        Err(err) => {
+                            // nothing has been done yet, so turning it away is safe
+                            error!("request from {:?} can't be logged: {}", user, err);
+                            let _ = reply.send(Response::Error(market::msgs::Error::RequestFailed));
+                            continue;
+                        }
+                    }
+                }
+                // queries don't change anything so they aren't sent to the feed
+                let feed_req = if req.is_mutation() {
+                    Some(req.clone())
+                } else {
+                    None
+                };
+                let response = match market.do_request_as(user, req) {
+                    Ok(response) => response,
+                    Err(err) => {
+                        // this request alone fails, and the client is told so
+                        error!("request failed: {}", err);
+                        let _ = reply.send(Response::Error(market::msgs::Error::RequestFailed));
+                        continue;
+                    }
+                };
+                info!("response: {}", response.status());
+                if let Some(feed_req) = feed_req {
+                    for message in feed_messages(feed_req, &response) {
+                        // the market has already committed, so the client still gets its response
+                        match serde_json::to_string(&message) {
+                            Ok(json) => feed.do_send(FeedEvent(json)),
+                            Err(err) => error!("feed message dropped: {}", err),
+                        }
+                    }
                 }
+                // the request may have timed out, in which case nobody is waiting
+                let _ = reply.send(response);
             }
+            AppMsg::Shutdown => {
+                info!("shutting down");
+                return market.close();
+            }
+        }
+    }
+}
+
+/// The thread the market runs on. However the server stops, dropping this
+/// shuts the market down and waits for it, so the database is left closed
+/// rather than with a thread still holding it.
+struct Worker {
+    channel: Arc<Mutex<mpsc::Sender<(AppMsg, ResponseFuture)>>>,
+    thread: Option<thread::JoinHandle<Result<(), Error>>>,
+}
+
+impl Worker {
+    fn start(market: Market, feed: Addr<FeedHub>) -> Worker {
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn(move || work_thread(market, rx, feed));
+        Worker {
+            channel: Arc::new(Mutex::new(tx)),
+            thread: Some(thread),
+        }
+    }
+
+    /// Shut down and wait for the worker, with how it ended.
+    fn join(mut self) -> Result<(), Error> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<(), Error> {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+        // if the worker already stopped on an error nobody gets this
+        let (reply, _) = oneshot::channel();
+        let _ = self.channel.lock().unwrap().send((AppMsg::Shutdown, reply));
+        match thread.join() {
+            Ok(res) => res,
+            Err(_) => Err(err_msg("could not join thread")),
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if let Err(err) = self.shutdown() {
+            error!("worker failed: {}", err);
         }
     }
 }
 
-pub fn run_server(market: Market, addr_str: &str) -> Result<(), Error> {
+/// A socket left behind by a server that stopped would make binding fail,
+/// so it is removed first. Anything else at the path is left alone.
+fn remove_stale_socket(path: &Path) -> Result<(), Error> {
+    match fs::symlink_metadata(path) {
+        Ok(ref metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+pub fn run_server(market: Market, addr: &BindAddress, options: ServerOptions) -> Result<(), Error> {
     let sys = actix::System::new("market");
 
-    let (tx, rx) = mpsc::channel();
-    let thread_handle = thread::spawn(move || work_thread(market, rx));
-    let arc_mutex_tx = Arc::new(Mutex::new(tx));
+    let feed = FeedHub::default().start();
+    let work_feed = feed.clone();
+    let version = VersionInfo::new(Some(market.info.version));
+
+    let worker = Worker::start(market, work_feed);
+    let arc_mutex_tx = worker.channel.clone();
+    let limiter = Arc::new(Mutex::new(RateLimiter::new(
+        options.rate_limit,
+        options.rate_burst,
+    )));
+    // a separate connection so checking keys doesn't wait on the market
+    let api_keys = match &options.api_keys {
+        Some(db_filename) => Some(Arc::new(Mutex::new(DB::open_read_only(Path::new(
+            db_filename,
+        ))?))),
+        None => None,
+    };
+    let nonces = Arc::new(Mutex::new(Nonces::default()));
 
-    let _ = server::new(move || {
+    let app_server = server::new(move || {
         App::with_state(AppState {
             channel: arc_mutex_tx.clone(),
+            feed: feed.clone(),
+            request_timeout: options.request_timeout,
+            limiter: limiter.clone(),
+            api_keys: api_keys.clone(),
+            nonces: nonces.clone(),
+            max_body_size: options.max_body_size,
+            version: version.clone(),
         })
         .resource("/", |r| r.post().a(handle_post))
+        .resource("/version", |r| r.get().f(handle_version))
+        .resource("/ws", |r| r.get().f(handle_ws))
     })
-    .bind(addr_str)?
-    .start();
+    // SIGINT and SIGTERM stop the system once connections are done, so
+    // that the worker below is shut down in order rather than left running
+    .system_exit();
+    match addr {
+        BindAddress::Tcp(addr_str) => {
+            let _ = app_server.bind(addr_str.as_str())?.start();
+        }
+        BindAddress::Unix(path) => {
+            remove_stale_socket(path)?;
+            let listener = UnixListener::bind(path)?;
+            app_server.start_incoming(listener.incoming(), false);
+        }
+    }
 
     let _ = sys.run();
 
-    match thread_handle.join() {
-        Ok(res) => res,
-        Err(_) => Err(err_msg("could not join thread")),
+    worker.join()
+}
+
+#[test]
+fn bind_address_scheme() {
+    assert_eq!(
+        BindAddress::parse("127.0.0.1:8000"),
+        BindAddress::Tcp(String::from("127.0.0.1:8000"))
+    );
+    assert_eq!(
+        BindAddress::parse("unix:/run/market.sock"),
+        BindAddress::Unix(PathBuf::from("/run/market.sock"))
+    );
+}
+
+#[test]
+fn reply_timeout() {
+    let (reply, on_reply) = oneshot::channel();
+    // a worker that takes longer than the timeout to respond
+    let worker = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        reply.send(market::msgs::Response::Updated).is_err()
+    });
+    let timed_out = Arc::new(Mutex::new(false));
+    let result = timed_out.clone();
+    actix::System::run(move || {
+        actix::Arbiter::spawn(
+            wait_reply(on_reply, Duration::from_millis(50)).then(move |r| {
+                *result.lock().unwrap() = match r {
+                    Err(AppError::Timeout) => true,
+                    _ => false,
+                };
+                actix::System::current().stop();
+                Ok(())
+            }),
+        );
+    });
+    assert!(*timed_out.lock().unwrap());
+    // the late reply is dropped rather than delivered anywhere
+    assert!(worker.join().unwrap());
+}
+
+#[test]
+fn worker_shutdown() {
+    use std::env;
+    let path = env::temp_dir().join(format!("market-shutdown-{}.db", std::process::id()));
+    let wal_path = PathBuf::from(format!("{}-wal", path.display()));
+    let _ = fs::remove_file(&path);
+    let open_wal = |create: bool| {
+        let db = Connection::open(&path).unwrap();
+        db.query_row("PRAGMA journal_mode = WAL", &[], |_| ())
+            .unwrap();
+        if create {
+            Market::create_new(db).unwrap()
+        } else {
+            Market::open_existing(db).unwrap()
+        }
+    };
+    let (joined, dropped) = (open_wal(true), open_wal(false));
+    assert!(wal_path.exists());
+    let result = Arc::new(Mutex::new(None));
+    let worker_result = result.clone();
+    actix::System::run(move || {
+        let feed = FeedHub::default().start();
+        // as when the server stops some other way than sys.run() returning
+        drop(Worker::start(dropped, feed.clone()));
+        let worker = Worker::start(joined, feed);
+        *worker_result.lock().unwrap() = Some(worker.join().is_ok());
+        actix::System::current().stop();
+    });
+    assert_eq!(*result.lock().unwrap(), Some(true));
+    // the log was checkpointed and removed as the last connection closed
+    assert!(!wal_path.exists());
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn clear_on_worker() {
+    use crate::market::msgs::Query;
+    use crate::market::types::{
+        ArgList, Cond, Dollars, Offer, OfferDetails, OfferStatus, Pred, PredValueType, Quantity,
+        User,
+    };
+    let mut market = Market::create_new_in_memory().unwrap();
+    let mut create = |item| match market.do_request(Request::Create(item)).unwrap() {
+        Response::Created(id) => id,
+        _ => panic!("expected Created!"),
+    };
+    let user = |name: &str| {
+        Item::User(User {
+            user_name: String::from(name),
+            user_locked: false,
+            user_credit_limit: Dollars::ZERO,
+            user_display_name: None,
+        })
+    };
+    let alice = create(user("alice"));
+    let bob = create(user("bob"));
+    let pred = create(Item::Pred(Pred {
+        pred_name: String::from("pred"),
+        pred_args: ArgList::from(""),
+        pred_value: None,
+        pred_value_type: PredValueType::Bool,
+    }));
+    let cond_id = create(Item::Cond(Cond {
+        cond_pred: pred,
+        cond_args: vec![],
+        cond_arg_time: None,
+        cond_value: None,
+        cond_closed: false,
+    }));
+    let offer = |user: &ID, buy: (i64, u32), sell: (i64, u32)| Offer {
+        offer_user: user.clone(),
+        offer_cond_id: cond_id.clone(),
+        offer_cond_time: None,
+        offer_details: OfferDetails {
+            offer_buy_price: Dollars::from_millibucks(buy.0),
+            offer_sell_price: Dollars::from_millibucks(sell.0),
+            offer_buy_quantity: Quantity::from_units(buy.1),
+            offer_sell_quantity: Quantity::from_units(sell.1),
+        },
+        offer_status: OfferStatus::Open,
+        offer_expiry: None,
+    };
+    let requests = vec![
+        Request::CreateOffers {
+            offers: vec![
+                offer(&alice, (500, 100), (600, 0)),
+                offer(&bob, (300, 0), (400, 40)),
+            ],
+            best_effort: false,
+        },
+        Request::Clear {
+            cond_id: cond_id.clone(),
+            max_iterations: None,
+            check_credit: false,
+        },
+        Request::Query(Query::ConditionalIOU),
+    ];
+    let result = Arc::new(Mutex::new(Vec::new()));
+    let worker_result = result.clone();
+    actix::System::run(move || {
+        let feed = FeedHub::default().start();
+        let worker = Worker::start(market, feed);
+        // one after another on the writer thread, as the server sends them
+        for request in requests {
+            let (reply, on_reply) = oneshot::channel();
+            let msg = AppMsg::Request {
+                user: None,
+                request,
+            };
+            worker.channel.lock().unwrap().send((msg, reply)).unwrap();
+            worker_result.lock().unwrap().push(on_reply.wait().unwrap());
+        }
+        worker.join().unwrap();
+        actix::System::current().stop();
+    });
+    let responses = result.lock().unwrap();
+    let trades = match &responses[1] {
+        Response::Traded { trades, complete } => {
+            assert!(complete);
+            trades
+        }
+        _ => panic!("expected Traded!"),
+    };
+    assert_eq!(trades.len(), 1);
+    assert_eq!(trades[0].units, 40);
+    // the IOUs the trade names are the ones clearing created
+    match &responses[2] {
+        Response::Items(items) => {
+            assert_eq!(items.len(), 2);
+            assert!(items.contains_key(&trades[0].buyer_iou));
+            assert!(items.contains_key(&trades[0].seller_iou));
+        }
+        _ => panic!("expected Items!"),
+    }
+}
+
+#[test]
+fn signed_requests() {
+    use crate::market::ApiKeyRow;
+    use time::get_time;
+    let db = Connection::open_in_memory().unwrap();
+    db.create_table::<ApiKeyTable>().unwrap();
+    db.insert::<ApiKeyTable>(&ApiKeyRow {
+        key_id: String::from("key"),
+        key_user: ID(String::from("foo")),
+        key_secret: String::from("secret"),
+        creation_time: get_time(),
+    })
+    .unwrap();
+    let body = b"{\"Query\":\"AllUser\"}";
+    let sign = |key_id: &str, time: i64, nonce: &str| {
+        let mut mac = Hmac::<Sha256>::new_varkey(b"secret").unwrap();
+        mac.input(format!("{}:{}:", time, nonce).as_bytes());
+        mac.input(body);
+        let signature: String = mac
+            .result()
+            .code()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        format!("HMAC-SHA256 {}:{}:{}:{}", key_id, time, nonce, signature)
+    };
+    let now = Timesecs::from(1_500_000_000);
+    let mut nonces = Nonces::default();
+    let mut check =
+        |auth: Option<&str>, body: &[u8]| authenticate(&db, &mut nonces, auth, body, now).unwrap();
+    let foo = Some(ID(String::from("foo")));
+    // the signature only matches the body it was made for
+    assert_eq!(check(Some(&sign("key", 1_500_000_000, "a")), b"{}"), None);
+    assert_eq!(check(Some(&sign("key", 1_500_000_000, "a")), body), foo);
+    // and can't be used again
+    assert_eq!(check(Some(&sign("key", 1_500_000_000, "a")), body), None);
+    assert_eq!(check(Some(&sign("key", 1_500_000_000, "b")), body), foo);
+    assert_eq!(check(Some(&sign("key", 1_500_000_100, "c")), body), foo);
+    // nor can a stale one, or one from too far ahead
+    assert_eq!(check(Some(&sign("key", 1_499_999_000, "d")), body), None);
+    assert_eq!(check(Some(&sign("key", 1_500_001_000, "e")), body), None);
+    assert_eq!(check(Some(&sign("other", 1_500_000_000, "f")), body), None);
+    // the time is signed along with the body
+    let moved = sign("key", 1_500_000_000, "g").replace(":1500000000:", ":1500000001:");
+    assert_eq!(check(Some(&moved), body), None);
+    assert_eq!(check(Some("HMAC-SHA256 key:1500000000:h:zz"), body), None);
+    assert_eq!(check(Some("Basic key"), body), None);
+    assert_eq!(check(None, body), None);
+}
+
+#[test]
+fn payload_too_large() {
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    let req = TestRequest::default().set_payload(vec![b' '; 100]).finish();
+    match req.body().limit(50).map_err(payload_error).wait() {
+        Err(AppError::PayloadTooLarge) => (),
+        _ => panic!("expected PayloadTooLarge!"),
+    }
+    let req = TestRequest::default().set_payload(vec![b' '; 50]).finish();
+    assert_eq!(req.body().limit(50).wait().unwrap().len(), 50);
+    assert_eq!(
+        make_error(AppError::PayloadTooLarge).status(),
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+}
+
+#[test]
+fn invalid_json_error() {
+    use actix_web::http::StatusCode;
+    let err = match Encoding::Json.decode::<Request>(b"{\"Query\":\n  \"AllUser\"") {
+        Err(err) => err,
+        Ok(_) => panic!("expected error!"),
+    };
+    let body = serde_json::to_value(err.to_body()).unwrap();
+    assert_eq!(body["error"], "invalid_json");
+    assert_eq!(body["line"], 2);
+    assert_eq!(body["column"], 11);
+    assert!(body["detail"].as_str().unwrap().contains("EOF"));
+    assert_eq!(make_error(err).status(), StatusCode::BAD_REQUEST);
+    let err = Encoding::Json.decode::<Request>(b"\xff").err().unwrap();
+    let body = serde_json::to_value(err.to_body()).unwrap();
+    assert_eq!(body["error"], "invalid_utf8");
+    assert!(body.get("line").is_none());
+}
+
+#[test]
+fn conflict_status() {
+    use actix_web::http::StatusCode;
+    let conflict = Response::Error(market::msgs::Error::Conflict {
+        constraint: String::from("entity.entity_name"),
+    });
+    let reply = make_reply(&conflict, vec![], Encoding::Json);
+    assert_eq!(reply.status(), StatusCode::CONFLICT);
+    let not_found = Response::Error(market::msgs::Error::NotFound {
+        id: String::from("foo"),
+    });
+    let reply = make_reply(&not_found, vec![], Encoding::Json);
+    assert_eq!(reply.status(), StatusCode::OK);
+}
+
+#[test]
+fn msgpack_round_trip() {
+    use crate::market::types::{Dollars, Timesecs, User};
+    let encoding = Encoding::from_header(Some("application/json;q=0.5, application/msgpack"));
+    assert_eq!(encoding, Encoding::MsgPack);
+    assert_eq!(Encoding::from_header(Some("text/plain")), Encoding::Json);
+    assert_eq!(Encoding::from_header(None), Encoding::Json);
+    let item = Item::User(User {
+        user_name: String::from("alice"),
+        user_locked: false,
+        user_credit_limit: Dollars::ZERO,
+        user_display_name: None,
+    });
+    let body = encoding.encode(&Request::Create(item.clone())).unwrap();
+    match encoding.decode::<Request>(&body).unwrap() {
+        Request::Create(Item::User(user)) => assert_eq!(user.user_name, "alice"),
+        _ => panic!("expected Create!"),
+    }
+    // a response has the same structure either way, item tags included
+    let response = Response::CreatedItem {
+        id: ID(String::from("foo")),
+        item,
+        creation_time: Timesecs::now(),
+    };
+    let msgpack: serde_json::Value =
+        rmp_serde::from_slice(&encoding.encode(&response).unwrap()).unwrap();
+    let json: serde_json::Value =
+        serde_json::from_slice(&Encoding::Json.encode(&response).unwrap()).unwrap();
+    assert_eq!(msgpack, json);
+    assert_eq!(msgpack["CreatedItem"]["item"]["type"], "User");
+}
+
+#[test]
+fn rate_limit() {
+    let mut limiter = RateLimiter::new(2.0, 3);
+    let alice = Some(IpAddr::from([10, 0, 0, 1]));
+    let bob = Some(IpAddr::from([10, 0, 0, 2]));
+    let start = Instant::now();
+    for _ in 0..3 {
+        assert!(limiter.allow(alice, start));
+    }
+    assert!(!limiter.allow(alice, start));
+    // other clients have their own buckets
+    assert!(limiter.allow(bob, start));
+    // tokens come back at the rate, up to the burst
+    assert!(limiter.allow(alice, start + Duration::from_millis(500)));
+    assert!(!limiter.allow(alice, start + Duration::from_millis(500)));
+    let later = start + Duration::from_secs(60);
+    for _ in 0..3 {
+        assert!(limiter.allow(alice, later));
+    }
+    assert!(!limiter.allow(alice, later));
+}
+
+#[test]
+fn request_too_complex() {
+    use crate::market::msgs::{Query, MAX_REQUEST_DEPTH, MAX_REQUEST_ELEMENTS};
+    let too_complex = Some(market::msgs::Error::RequestTooComplex {
+        max_depth: MAX_REQUEST_DEPTH,
+        max_elements: MAX_REQUEST_ELEMENTS,
+    });
+    // Multis nested in Multis, as a client would send them
+    let nested = |depth| {
+        let mut query = String::from("\"AllUser\"");
+        for _ in 1..depth {
+            query = format!("{{\"Multi\": [{}]}}", query);
+        }
+        let body = format!("{{\"Query\": {}}}", query);
+        Encoding::Json.decode::<Request>(body.as_bytes()).unwrap()
+    };
+    assert_eq!(nested(MAX_REQUEST_DEPTH).check_complexity().err(), None);
+    assert_eq!(
+        nested(MAX_REQUEST_DEPTH + 1).check_complexity().err(),
+        too_complex
+    );
+    let multi = |count| Request::Query(Query::Multi(vec![Query::AllUser; count]));
+    assert_eq!(multi(MAX_REQUEST_ELEMENTS).check_complexity().err(), None);
+    assert_eq!(
+        multi(MAX_REQUEST_ELEMENTS + 1).check_complexity().err(),
+        too_complex
+    );
+}
+
+#[test]
+fn read_only_worker() {
+    use crate::market::msgs::Query;
+    use crate::market::types::{Dollars, User};
+    use std::env;
+    let path = env::temp_dir().join(format!("market-read-only-{}.db", std::process::id()));
+    let _ = fs::remove_file(&path);
+    Market::create_new(Connection::open(&path).unwrap())
+        .unwrap()
+        .close()
+        .unwrap();
+    let market = Market::open_read_only(DB::open_read_only(&path).unwrap()).unwrap();
+    let requests = vec![
+        Request::Create(Item::User(User {
+            user_name: String::from("alice"),
+            user_locked: false,
+            user_credit_limit: Dollars::ZERO,
+            user_display_name: None,
+        })),
+        Request::Query(Query::AllUser),
+    ];
+    let result = Arc::new(Mutex::new(Vec::new()));
+    let worker_result = result.clone();
+    actix::System::run(move || {
+        let feed = FeedHub::default().start();
+        let worker = Worker::start(market, feed);
+        for request in requests {
+            let (reply, on_reply) = oneshot::channel();
+            let msg = AppMsg::Request {
+                user: None,
+                request,
+            };
+            worker.channel.lock().unwrap().send((msg, reply)).unwrap();
+            worker_result.lock().unwrap().push(on_reply.wait().unwrap());
+        }
+        assert!(worker.join().is_ok());
+        actix::System::current().stop();
+    });
+    let responses = result.lock().unwrap();
+    match &responses[0] {
+        Response::Error(err) => assert_eq!(*err, market::msgs::Error::ReadOnly),
+        _ => panic!("expected Error!"),
+    }
+    match &responses[1] {
+        Response::Items(items) => assert!(items.is_empty()),
+        _ => panic!("expected Items!"),
     }
+    let _ = fs::remove_file(&path);
 }
 
 // vi: ts=8 sts=4 et